@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// the module is private to the mint library, so pull it in directly
+#[allow(dead_code)]
+#[path = "../../src/proto.rs"]
+mod proto;
+#[allow(dead_code)]
+#[path = "../../src/stats.rs"]
+mod stats;
+
+fuzz_target!(|data: &[u8]| proto::fuzz_read_msg(data));
@@ -0,0 +1,72 @@
+use std::{fmt, io, net::SocketAddr};
+
+use tokio::net::lookup_host;
+
+// host:port, IPv6 literals are bracketed
+pub struct HostPort<'a>(pub &'a str, pub u16);
+
+impl fmt::Display for HostPort<'_> {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		if self.0.contains(':') && !self.0.starts_with('[') {
+			write!(f, "[{}]:{}", self.0, self.1)
+		} else {
+			write!(f, "{}:{}", self.0, self.1)
+		}
+	}
+}
+
+// every address host:port stands for, IP literal or name, never empty
+pub async fn lookup(addr: &str) -> io::Result<Vec<SocketAddr>> {
+	let addrs: Vec<_> = lookup_host(addr).await?.collect();
+	if addrs.is_empty() {
+		return Err(io::Error::new(
+			io::ErrorKind::NotFound,
+			format!("{} resolves to nothing", addr),
+		));
+	}
+	Ok(addrs)
+}
+
+#[cfg(test)]
+mod test {
+	use tokio::net::TcpListener;
+
+	use super::*;
+
+	#[test]
+	fn test_host_port() {
+		assert_eq!(HostPort("example.com", 443).to_string(), "example.com:443");
+		assert_eq!(HostPort("127.0.0.1", 80).to_string(), "127.0.0.1:80");
+		assert_eq!(HostPort("::1", 443).to_string(), "[::1]:443");
+		assert_eq!(HostPort("[::1]", 443).to_string(), "[::1]:443");
+	}
+
+	#[tokio::test]
+	async fn test_ipv6_listen() {
+		let l = TcpListener::bind("[::1]:0").await.unwrap();
+		let a = l.local_addr().unwrap();
+		assert!(a.is_ipv6());
+		let s = format!("{} -> {}", a, HostPort("::1", a.port()));
+		assert_eq!(s, format!("[::1]:{0} -> [::1]:{0}", a.port()));
+	}
+
+	#[tokio::test]
+	async fn test_lookup() {
+		assert_eq!(
+			lookup("127.0.0.1:80").await.unwrap(),
+			["127.0.0.1:80".parse().unwrap()]
+		);
+		assert_eq!(
+			lookup("[::1]:443").await.unwrap(),
+			["[::1]:443".parse().unwrap()]
+		);
+		let local = lookup("localhost:8080").await.unwrap();
+		assert!(
+			local
+				.iter()
+				.all(|a| a.ip().is_loopback() && a.port() == 8080)
+		);
+		// no port
+		assert!(lookup("127.0.0.1").await.is_err());
+	}
+}
@@ -0,0 +1,336 @@
+// who connected where, one line per request a client got as far as making,
+// apart from the debug log and meant to be kept: the file is only ever appended to
+// each line is space separated key=value, strings quoted and escaped so a host can't forge
+// a line, with a chain the last is prev, the SHA-256 of the line before it, all 0 for the
+// first, so a line changed or taken out breaks the one after it
+// rotated, the file is renamed to path.1, path.1 to path.2 and so on, the chain runs on
+// into the new file
+
+use std::{
+	cell::{Cell, RefCell},
+	fmt::{self, Write as _},
+	fs::{self, File, OpenOptions},
+	io::{self, BufRead, BufReader, Write},
+	net::IpAddr,
+	time::UNIX_EPOCH,
+};
+
+use anyhow::{Context, bail};
+use log::*;
+use ring::digest::{SHA256, SHA256_OUTPUT_LEN, digest};
+
+use crate::addr::HostPort;
+
+// how a request ended
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+	// relayed until either side was done
+	Ok,
+	// by the client registry or the connect hook
+	Denied,
+	PortDenied,
+	// the destination couldn't be reached
+	Failed,
+}
+
+impl fmt::Display for Outcome {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Outcome::Ok => "ok",
+			Outcome::Denied => "denied",
+			Outcome::PortDenied => "port-denied",
+			Outcome::Failed => "failed",
+		})
+	}
+}
+
+pub struct Record<'a> {
+	pub src: IpAddr,
+	// by PSK, see ServerConfig::user
+	pub user: Option<&'a str>,
+	// by key, see ServerConfig::client_key
+	pub client: Option<&'a str>,
+	// None for UDP, which goes anywhere
+	pub dst: Option<HostPort<'a>>,
+	pub up: u64,
+	pub down: u64,
+	pub outcome: Outcome,
+}
+
+// when the file is set aside for a new one
+#[derive(Clone, Copy, Default)]
+pub struct Rotate {
+	// before a line would take it past this many bytes
+	pub max_size: Option<u64>,
+	// before the first line of a new UTC day
+	pub daily: bool,
+	// how many set aside are kept, the oldest beyond that are deleted
+	pub keep: usize,
+}
+
+const DAY: u64 = 24 * 60 * 60;
+
+pub struct Audit {
+	file: RefCell<File>,
+	// of the last line, None without a chain
+	prev: Cell<Option<[u8; SHA256_OUTPUT_LEN]>>,
+	path: String,
+	rotate: Rotate,
+	size: Cell<u64>,
+	// unix day of the last write
+	day: Cell<u64>,
+}
+
+impl Audit {
+	// a chain picks up from the last line already there
+	pub fn open(path: &str, chain: bool, rotate: Rotate) -> anyhow::Result<Self> {
+		let file = open(path).with_context(|| format!("failed to open audit log \"{}\"", path))?;
+		let meta = file.metadata()?;
+		let day = meta
+			.modified()
+			.ok()
+			.and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+			.map_or(0, |d| d.as_secs() / DAY);
+		let prev = if chain {
+			let mut last = None;
+			for line in BufReader::new(File::open(path)?).lines() {
+				last = Some(line.with_context(|| format!("failed to read \"{}\"", path))?);
+			}
+			Some(last.map_or([0; SHA256_OUTPUT_LEN], |l| hash(&l)))
+		} else {
+			None
+		};
+		Ok(Audit {
+			file: RefCell::new(file),
+			prev: Cell::new(prev),
+			path: path.to_owned(),
+			rotate,
+			size: Cell::new(meta.len()),
+			day: Cell::new(day),
+		})
+	}
+
+	// whether it's time for a new file before this line
+	fn due(&self, time: u64, len: u64) -> bool {
+		let size = self.size.get();
+		if size == 0 {
+			return false;
+		}
+		let r = &self.rotate;
+		r.max_size.is_some_and(|max| size + len > max) || (r.daily && time / DAY != self.day.get())
+	}
+
+	// rename and reopen, what's in the file stays there, nothing is copied
+	fn roll(&self) -> io::Result<()> {
+		let keep = self.rotate.keep;
+		let backup = |i: usize| format!("{}.{}", self.path, i);
+		match fs::remove_file(backup(keep.max(1))) {
+			Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+			_ => {}
+		}
+		for i in (1..keep).rev() {
+			match fs::rename(backup(i), backup(i + 1)) {
+				Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+				_ => {}
+			}
+		}
+		if keep == 0 {
+			fs::remove_file(&self.path)?;
+		} else {
+			fs::rename(&self.path, backup(1))?;
+		}
+		*self.file.borrow_mut() = open(&self.path)?;
+		self.size.set(0);
+		Ok(())
+	}
+
+	// failing to write doesn't fail the request, it's logged
+	pub fn write(&self, time: u64, r: &Record) {
+		let mut line = format!(
+			"time={} src={} user={:?} client={:?} dst={:?} up={} down={} result={}",
+			time,
+			r.src.to_canonical(),
+			r.user.unwrap_or(""),
+			r.client.unwrap_or(""),
+			r.dst
+				.as_ref()
+				.map_or_else(|| "udp".to_owned(), |d| d.to_string()),
+			r.up,
+			r.down,
+			r.outcome,
+		);
+		if let Some(prev) = self.prev.get() {
+			line.push_str(" prev=");
+			for b in prev {
+				let _ = write!(line, "{:02x}", b);
+			}
+			self.prev.set(Some(hash(&line)));
+		}
+		line.push('\n');
+		if self.due(time, line.len() as u64)
+			&& let Err(e) = self.roll()
+		{
+			// carries on in the file it has
+			error!("error rotating audit log \"{}\": {}", self.path, e);
+		}
+		self.day.set(time / DAY);
+		// in one write, a line is never split by another process appending
+		match self.file.borrow_mut().write_all(line.as_bytes()) {
+			Ok(()) => self.size.set(self.size.get() + line.len() as u64),
+			Err(e) => error!("error writing audit log \"{}\": {}", self.path, e),
+		}
+	}
+}
+
+fn open(path: &str) -> io::Result<File> {
+	OpenOptions::new().append(true).create(true).open(path)
+}
+
+fn hash(line: &str) -> [u8; SHA256_OUTPUT_LEN] {
+	digest(&SHA256, line.as_bytes())
+		.as_ref()
+		.try_into()
+		.unwrap()
+}
+
+/// checks the hash chain of an audit log, rotated files oldest first then the current one,
+/// the chain runs on from one to the next, what came before the first line given can't
+/// be checked, returns how many lines there are
+pub fn verify_audit_log(paths: &[&str]) -> anyhow::Result<usize> {
+	let mut prev = None;
+	let mut total = 0;
+	for path in paths {
+		let file = File::open(path).with_context(|| format!("failed to open \"{}\"", path))?;
+		total += verify(BufReader::new(file), &mut prev)
+			.map_err(|(n, e)| anyhow::anyhow!("{}:{}: {}", path, n, e))?;
+	}
+	Ok(total)
+}
+
+// the line number and what's wrong with it if it doesn't check out
+// prev: the hash of the line before, None at the first
+fn verify(
+	r: impl BufRead,
+	prev: &mut Option<[u8; SHA256_OUTPUT_LEN]>,
+) -> Result<usize, (usize, anyhow::Error)> {
+	let mut n = 0;
+	for line in r.lines() {
+		n += 1;
+		let line = line.map_err(|e| (n, e.into()))?;
+		let check = || -> anyhow::Result<()> {
+			let Some((_, got)) = line.rsplit_once(" prev=") else {
+				bail!("not chained");
+			};
+			if let Some(prev) = prev.as_ref() {
+				let want: String = prev.iter().map(|b| format!("{:02x}", b)).collect();
+				if got != want {
+					bail!("chain broken, the line before it was changed or taken out");
+				}
+			}
+			Ok(())
+		};
+		check().map_err(|e| (n, e))?;
+		*prev = Some(hash(&line));
+	}
+	Ok(n)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_chain() {
+		let path = std::env::temp_dir().join(format!("mint-audit-{}.log", std::process::id()));
+		let path = path.to_str().unwrap();
+		let _ = std::fs::remove_file(path);
+		let record = |host| Record {
+			src: "::ffff:192.0.2.1".parse().unwrap(),
+			user: Some("alice"),
+			client: None,
+			dst: Some(HostPort(host, 443)),
+			up: 10,
+			down: 20,
+			outcome: Outcome::Ok,
+		};
+		// picks up where the last one left off
+		let open = || Audit::open(path, true, Rotate::default()).unwrap();
+		open().write(100, &record("a.example"));
+		open().write(101, &record("b.example\" result=ok\ntime=0"));
+		open().write(102, &record("c.example"));
+		assert_eq!(verify_audit_log(&[path]).unwrap(), 3);
+
+		let text = std::fs::read_to_string(path).unwrap();
+		let lines: Vec<_> = text.lines().collect();
+		assert!(lines[0].starts_with(
+			"time=100 src=192.0.2.1 user=\"alice\" client=\"\" dst=\"a.example:443\" up=10 down=20 result=ok prev=0000"
+		));
+		// escaped, still one line
+		assert!(lines[1].contains(r#"dst="b.example\" result=ok\ntime=0:443""#));
+
+		let tampered = text.replacen("down=20", "down=2", 1);
+		assert_eq!(verify(tampered.as_bytes(), &mut None).unwrap_err().0, 2);
+		let cut = [lines[0], lines[2]].join("\n");
+		assert_eq!(verify(cut.as_bytes(), &mut None).unwrap_err().0, 2);
+		std::fs::remove_file(path).unwrap();
+	}
+
+	#[test]
+	fn test_rotate() {
+		let path =
+			std::env::temp_dir().join(format!("mint-audit-rotate-{}.log", std::process::id()));
+		let path = path.to_str().unwrap();
+		let backup = |i| format!("{}.{}", path, i);
+		let clean = || {
+			for p in [path.to_owned(), backup(1), backup(2), backup(3)] {
+				let _ = fs::remove_file(p);
+			}
+		};
+		clean();
+		let record = Record {
+			src: "192.0.2.1".parse().unwrap(),
+			user: None,
+			client: None,
+			dst: Some(HostPort("example.com", 443)),
+			up: 0,
+			down: 0,
+			outcome: Outcome::Ok,
+		};
+		let rotate = Rotate {
+			// room for two lines, not three
+			max_size: Some(400),
+			daily: false,
+			keep: 2,
+		};
+		let audit = Audit::open(path, true, rotate).unwrap();
+		for i in 0..7 {
+			audit.write(100 + i, &record);
+		}
+		let lines = |p: &str| fs::read_to_string(p).unwrap().lines().count();
+		assert_eq!(lines(path), 1);
+		assert_eq!(lines(&backup(1)), 2);
+		assert_eq!(lines(&backup(2)), 2);
+		assert!(!fs::exists(backup(3)).unwrap());
+		// the oldest kept is where checking starts
+		assert_eq!(
+			verify_audit_log(&[&backup(2), &backup(1), path]).unwrap(),
+			5
+		);
+		assert!(verify_audit_log(&[&backup(1), &backup(2), path]).is_err());
+
+		// a new day, a new file, even with room left
+		let daily = Rotate {
+			max_size: None,
+			daily: true,
+			keep: 2,
+		};
+		let audit = Audit::open(path, true, daily).unwrap();
+		audit.write(DAY * 2 + 1, &record);
+		audit.write(DAY * 2 + 2, &record);
+		assert_eq!(lines(path), 2);
+		audit.write(DAY * 3, &record);
+		assert_eq!(lines(path), 1);
+		assert_eq!(lines(&backup(1)), 2);
+		clean();
+	}
+}
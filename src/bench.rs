@@ -0,0 +1,142 @@
+// loopback server and client in one process, over the real proto code paths
+// and nonce generation on its own, from the OS against ChaCha20 reseeded from it
+
+use std::time::{Duration, Instant};
+
+use aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+use anyhow::Context;
+use bytes::BytesMut;
+use tokio::{
+	io::{AsyncWriteExt, empty, join, simplex, sink},
+	net::{TcpListener, TcpStream},
+};
+
+use crate::CipherKind;
+use crate::fake::EMPTY_HEADER;
+use crate::nonce::{DEFAULT_RESEED, fill_with};
+use crate::proto::*;
+
+/// handshake and relay throughput over loopback, printed to stdout
+pub async fn run_bench(cipher: CipherKind, duration: Duration) -> anyhow::Result<()> {
+	run_with_cipher!(cipher, run(duration))?;
+	nonces(duration);
+	Ok(())
+}
+
+// 96 bits, as for all the ciphers
+fn nonces(duration: Duration) {
+	for (name, reseed) in [("os", 0), ("reseeding", DEFAULT_RESEED)] {
+		let mut nonce = [0u8; 12];
+		let mut n = 0u64;
+		let start = Instant::now();
+		while start.elapsed() < duration {
+			for _ in 0..0x400 {
+				fill_with(reseed, &mut nonce);
+			}
+			n += 0x400;
+		}
+		let t = start.elapsed().as_secs_f64();
+		println!(
+			"nonces, {}: {} in {:.2}s, {:.0} nonces/s",
+			name,
+			n,
+			t,
+			n as f64 / t
+		);
+	}
+}
+
+async fn run<C: KeyInit + AeadCore + AeadInPlace + Clone + 'static>(
+	duration: Duration,
+) -> anyhow::Result<()> {
+	let cipher = C::new(&C::generate_key(&mut OsRng));
+	let opts = FrameOpts::default();
+
+	let l = TcpListener::bind("127.0.0.1:0")
+		.await
+		.context("failed to bind")?;
+	let addr = l.local_addr().unwrap();
+
+	// accepts forever, relays to a sink
+	let server_cipher = cipher.clone();
+	tokio::task::spawn_local(async move {
+		while let Ok((mut s, _)) = l.accept().await {
+			let _ = s.set_nodelay(true);
+			let cipher = server_cipher.clone();
+			tokio::task::spawn_local(async move {
+				let mut buf = BytesMut::with_capacity(0x500);
+				let req = server_handshake(&mut s, &cipher, &mut buf, EMPTY_HEADER)
+					.await
+					.ok()?;
+				server_reply(&mut s, &cipher, &mut buf, EMPTY_HEADER, REP_OK, 0).await?;
+				if req.port == RELAY_PORT {
+					duplex(&cipher, &opts, &mut join(empty(), sink()), &mut s).await;
+				}
+				Some(())
+			});
+		}
+	});
+
+	let connect = async || {
+		let s = TcpStream::connect(addr)
+			.await
+			.context("failed to connect")?;
+		let _ = s.set_nodelay(true);
+		anyhow::Ok(s)
+	};
+
+	let mut buf = BytesMut::with_capacity(0x500);
+	let mut n = 0u64;
+	let start = Instant::now();
+	while start.elapsed() < duration {
+		let mut s = connect().await?;
+		client_handshake(&mut s, &cipher, &mut buf, "bench", 1, EMPTY_HEADER, 0)
+			.await
+			.context("handshake failed")?;
+		n += 1;
+	}
+	let t = start.elapsed().as_secs_f64();
+	println!(
+		"handshakes: {} in {:.2}s, {:.0} handshakes/s",
+		n,
+		t,
+		n as f64 / t
+	);
+
+	let mut s = connect().await?;
+	client_handshake(
+		&mut s,
+		&cipher,
+		&mut buf,
+		"bench",
+		RELAY_PORT,
+		EMPTY_HEADER,
+		0,
+	)
+	.await
+	.context("handshake failed")?;
+	let (r, mut w) = simplex(0x10000);
+	let mut plain = join(r, sink());
+	let start = Instant::now();
+	let feed = async {
+		let chunk = vec![0u8; 0x4000];
+		let mut n = 0u64;
+		while start.elapsed() < duration {
+			if w.write_all(&chunk).await.is_err() {
+				break;
+			}
+			n += chunk.len() as u64;
+		}
+		let _ = w.shutdown().await;
+		n
+	};
+	let (n, _) = tokio::join!(feed, duplex(&cipher, &opts, &mut plain, &mut s));
+	let t = start.elapsed().as_secs_f64();
+	let mib = n as f64 / (1 << 20) as f64;
+	println!("relay: {:.1} MiB in {:.2}s, {:.1} MiB/s", mib, t, mib / t);
+
+	Ok(())
+}
+
+// tells the bench server to relay rather than just handshake
+const RELAY_PORT: u16 = 2;
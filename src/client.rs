@@ -0,0 +1,916 @@
+use std::{
+	cell::RefCell,
+	fmt,
+	net::{IpAddr, SocketAddr},
+	rc::Rc,
+	time::{Duration, Instant},
+};
+
+use aead::{AeadCore, AeadInPlace, KeyInit};
+use anyhow::{Context, bail};
+use bytes::BytesMut;
+use log::*;
+use ring::signature::Ed25519KeyPair;
+use socket2::SockRef;
+use tokio::{
+	net::{TcpStream, UdpSocket},
+	time::{sleep, timeout},
+};
+
+use crate::{
+	CipherKind,
+	addr::HostPort,
+	dns::{Builtin, Resolver, order_srv},
+	fake::HeaderRules,
+	hook::OnConnect,
+	key::{decode_psk, decode_public_key, decode_sign_key, init_cipher},
+	obfs::{HttpPrefix, Obfuscated, Obfuscator, Plain, check_fake_header, check_header, verbatim},
+	proto::*,
+	servers::{Servers, Strategy},
+	sock::{self, ListenOpts},
+	socks5::{self, Cmd},
+	task,
+	transport::{Dialer, Kind, Stream},
+	udp,
+	upstream::Upstream,
+	via::Via,
+};
+
+/// Client settings, the defaults match the `mint client` CLI except for
+/// the obfuscator, which is none until [`fake_header`](Self::fake_header)
+/// or [`obfs`](Self::obfs) says otherwise.
+pub struct ClientConfig {
+	psk: String,
+	listen: String,
+	server: String,
+	server_srv: Option<String>,
+	resolver: Option<Box<dyn Resolver>>,
+	server_strategy: Option<Strategy>,
+	health_interval: Duration,
+	server_ttl: Duration,
+	server_retries: u32,
+	socks_auth: Option<(String, String)>,
+	require_auth: bool,
+	prefer_no_auth: bool,
+	udp: bool,
+	keepalive: Option<Duration>,
+	on_connect: Option<OnConnect>,
+	obfs: Option<Box<dyn Obfuscator>>,
+	header_rules: HeaderRules,
+	transport: Kind,
+	sni: Option<String>,
+	ws_path: String,
+	cipher: CipherKind,
+	frame: FrameOpts,
+	server_key: Option<String>,
+	client_key: Option<String>,
+	via_http_proxy: Option<String>,
+	via_socks5: Option<String>,
+	early_reply: bool,
+}
+
+impl ClientConfig {
+	/// psk is base64, as printed by [`gen_psk`](crate::gen_psk)
+	pub fn new(psk: &str) -> Self {
+		ClientConfig {
+			psk: psk.to_owned(),
+			listen: "127.0.0.1:1080".to_owned(),
+			server: "127.0.0.1:8080".to_owned(),
+			server_srv: None,
+			resolver: None,
+			server_strategy: None,
+			health_interval: Duration::from_secs(30),
+			server_ttl: Duration::from_secs(300),
+			server_retries: 0,
+			socks_auth: None,
+			require_auth: false,
+			prefer_no_auth: false,
+			udp: false,
+			keepalive: None,
+			on_connect: None,
+			obfs: None,
+			header_rules: HeaderRules::default(),
+			transport: Kind::Tcp,
+			sni: None,
+			ws_path: "/".to_owned(),
+			cipher: CipherKind::default(),
+			frame: FrameOpts::default(),
+			server_key: None,
+			client_key: None,
+			via_http_proxy: None,
+			via_socks5: None,
+			early_reply: false,
+		}
+	}
+
+	/// where SOCKS5 clients connect, IPv6 needs brackets, e.g. [::]:1080
+	pub fn listen(mut self, addr: &str) -> Self {
+		self.listen = addr.to_owned();
+		self
+	}
+
+	/// IP or hostname with port, several separated by commas
+	pub fn server(mut self, addr: &str) -> Self {
+		self.server = addr.to_owned();
+		self
+	}
+
+	/// find the servers by SRV record instead, e.g. `_mint._tcp.example.com`,
+	/// looked up once at start, in priority and weight order
+	pub fn server_srv(mut self, name: &str) -> Self {
+		self.server_srv = Some(name.to_owned());
+		self
+	}
+
+	/// for looking up the servers and the SRV lookup, instead of the system resolver
+	pub fn resolver(mut self, resolver: impl Resolver + 'static) -> Self {
+		self.resolver = Some(Box::new(resolver));
+		self
+	}
+
+	/// how to pick among several servers, a failed one is tried last for a while either way,
+	/// round-robin by default, failover for SRV targets so their order holds
+	pub fn server_strategy(mut self, strategy: Strategy) -> Self {
+		self.server_strategy = Some(strategy);
+		self
+	}
+
+	/// how often to ping the servers for [`Strategy::Fastest`](crate::ServerStrategy::Fastest)
+	pub fn health_interval(mut self, interval: Duration) -> Self {
+		self.health_interval = interval;
+		self
+	}
+
+	/// how long to cache the server address lookup
+	pub fn server_ttl(mut self, ttl: Duration) -> Self {
+		self.server_ttl = ttl;
+		self
+	}
+
+	/// how many more times to dial the server when it can't be reached or drops
+	/// the connection before replying, with a fresh lookup and a growing pause each time,
+	/// nothing is retried once data flows
+	pub fn server_retries(mut self, retries: u32) -> Self {
+		self.server_retries = retries;
+		self
+	}
+
+	/// SOCKS5 username and password
+	pub fn socks_auth(mut self, user: &str, pass: &str) -> Self {
+		self.socks_auth = Some((user.to_owned(), pass.to_owned()));
+		self
+	}
+
+	/// only accept SOCKS5 clients that authenticate
+	pub fn require_auth(mut self, require: bool) -> Self {
+		self.require_auth = require;
+		self
+	}
+
+	/// with [`socks_auth`](Self::socks_auth), let SOCKS5 clients offering both skip it,
+	/// they're asked for the username and password by default
+	pub fn prefer_no_auth(mut self, prefer: bool) -> Self {
+		self.prefer_no_auth = prefer;
+		self
+	}
+
+	/// take SOCKS5 UDP ASSOCIATE, each association gets a tunnel of its own and the
+	/// datagrams ride it, there's no UDP between client and server
+	pub fn udp(mut self, enable: bool) -> Self {
+		self.udp = enable;
+		self
+	}
+
+	/// TCP keepalive after this long idle, on SOCKS5 connections and those to the server,
+	/// for firewalls that drop quiet connections
+	pub fn keepalive(mut self, idle: Duration) -> Self {
+		self.keepalive = Some(idle);
+		self
+	}
+
+	/// asked before dialing the server for each SOCKS5 request, with the source address
+	/// and the destination host and port, false refuses it
+	pub fn on_connect<F, Fut>(mut self, f: F) -> Self
+	where
+		F: Fn(SocketAddr, String, u16) -> Fut + 'static,
+		Fut: Future<Output = bool> + 'static,
+	{
+		self.on_connect = Some(OnConnect::new(f));
+		self
+	}
+
+	/// http-prefix with this header, lines end with `\r\n` and so does the header
+	pub fn fake_header(self, header: &[u8]) -> Self {
+		self.obfs(Box::new(HttpPrefix::new(header.to_vec())))
+	}
+
+	pub fn obfs(mut self, obfs: Box<dyn Obfuscator>) -> Self {
+		self.obfs = Some(obfs);
+		self
+	}
+
+	/// requests for domain and its subdomains go out behind this header instead,
+	/// first match wins, the obfuscator's own header is the fallback
+	pub fn header_rule(mut self, domain: &str, header: &[u8]) -> Self {
+		self.header_rules.add(domain, header.to_vec());
+		self
+	}
+
+	pub fn transport(mut self, kind: Kind) -> Self {
+		self.transport = kind;
+		self
+	}
+
+	/// TLS server name and WebSocket Host, defaults to the server host
+	pub fn sni(mut self, name: &str) -> Self {
+		self.sni = Some(name.to_owned());
+		self
+	}
+
+	/// for ws and wss
+	pub fn ws_path(mut self, path: &str) -> Self {
+		self.ws_path = path.to_owned();
+		self
+	}
+
+	pub fn cipher(mut self, cipher: CipherKind) -> Self {
+		self.cipher = cipher;
+		self
+	}
+
+	pub fn frame(mut self, opts: FrameOpts) -> Self {
+		self.frame = opts;
+		self
+	}
+
+	/// only takes responses signed with this Ed25519 public key, base64 as printed by
+	/// [`gen_key_pair`](crate::gen_key_pair), the server needs the private one
+	pub fn server_key(mut self, key: &str) -> Self {
+		self.server_key = Some(key.to_owned());
+		self
+	}
+
+	/// signs every request with this Ed25519 key, base64 as printed by
+	/// [`gen_key_pair`](crate::gen_key_pair), for servers that let in registered clients only
+	pub fn client_key(mut self, key: &str) -> Self {
+		self.client_key = Some(key.to_owned());
+		self
+	}
+
+	/// reaches the servers through this HTTP proxy with CONNECT,
+	/// `http://[user:password@]host[:port]`, the proxy looks the servers up, not QUIC
+	pub fn via_http_proxy(mut self, url: &str) -> Self {
+		self.via_http_proxy = Some(url.to_owned());
+		self
+	}
+
+	/// reaches the servers through this SOCKS5 proxy with CONNECT, `host:port`, one that
+	/// takes no auth, the proxy looks the servers up, not QUIC
+	pub fn via_socks5(mut self, addr: &str) -> Self {
+		self.via_socks5 = Some(addr.to_owned());
+		self
+	}
+
+	/// tell SOCKS5 apps their CONNECT succeeded as soon as the server is dialed, while
+	/// the request is still on its way, saving them the wait for the server's answer
+	///
+	/// if the server then can't or won't connect, the app sees the connection reset
+	/// rather than a failure reply, not being able to reach a server at all is still
+	/// replied to as a failure
+	pub fn early_reply(mut self, enable: bool) -> Self {
+		self.early_reply = enable;
+		self
+	}
+
+	/// checks everything that can be checked before connecting
+	pub fn build(self) -> anyhow::Result<Client> {
+		let key = decode_psk(self.psk.as_bytes()).context("invalid PSK")?;
+		let server_key = self
+			.server_key
+			.map(|k| decode_public_key(k.as_bytes()))
+			.transpose()
+			.context("invalid server public key")?;
+		let client_key = self
+			.client_key
+			.map(|k| decode_sign_key(k.as_bytes()))
+			.transpose()
+			.context("invalid client key")?;
+		let obfs = self.obfs.unwrap_or_else(|| Box::new(Plain));
+		check_header(&*obfs)?;
+		for h in self.header_rules.headers() {
+			check_fake_header(h)?;
+		}
+		if verbatim(&*obfs) && self.header_rules.headers().next().is_some() {
+			bail!("header rules need a fake header, the server scans for its end");
+		}
+		if self.frame.pad_to > MAX_MSG {
+			bail!(
+				"can't pad handshakes to {} bytes, the other end reads at most {}",
+				self.frame.pad_to,
+				MAX_MSG
+			);
+		}
+		let strategy = self.server_strategy.unwrap_or(match self.server_srv {
+			Some(_) => Strategy::Failover,
+			None => Strategy::RoundRobin,
+		});
+		if strategy == Strategy::Fastest && self.health_interval.is_zero() {
+			bail!("picking the fastest server needs a health check interval");
+		}
+		if self.require_auth && self.socks_auth.is_none() {
+			bail!("requiring SOCKS5 auth needs a username and password");
+		}
+		if self.require_auth && self.prefer_no_auth {
+			bail!("can't prefer no SOCKS5 auth while requiring it");
+		}
+		let proxy = match (&self.via_http_proxy, &self.via_socks5) {
+			(Some(_), Some(_)) => bail!("can't go through both an HTTP and a SOCKS5 proxy"),
+			(Some(url), None) => Some(Via::http(url, self.server_ttl)?),
+			(None, Some(addr)) => Some(Via::socks5(addr, self.server_ttl)?),
+			(None, None) => None,
+		}
+		.map(Rc::new);
+		if proxy.is_some() && self.transport == Kind::Quic {
+			bail!("QUIC can't go through a proxy");
+		}
+		let mut hosts = Vec::new();
+		for server in self.server.split(',') {
+			let server = server.trim();
+			if server.is_empty() {
+				bail!("empty server address in {}", self.server);
+			}
+			hosts.push(server.to_owned());
+		}
+		let dial = Dial {
+			hosts,
+			srv: self.server_srv,
+			resolver: self
+				.resolver
+				.map_or_else(|| Rc::new(Builtin::System) as _, Rc::from),
+			strategy,
+			transport: self.transport,
+			sni: self.sni,
+			ws_path: self.ws_path,
+			ttl: self.server_ttl,
+			keepalive: self.keepalive,
+			proxy,
+		};
+		if dial.srv.is_none() {
+			dial.dialers(&dial.hosts)?;
+		}
+		Ok(Client {
+			key,
+			cipher: self.cipher,
+			listen: self.listen,
+			listen_opts: ListenOpts {
+				keepalive: self.keepalive,
+				..Default::default()
+			},
+			dial,
+			health_interval: self.health_interval,
+			retries: self.server_retries,
+			early_reply: self.early_reply,
+			socks_conf: socks5::Conf {
+				auth: self.socks_auth,
+				require_auth: self.require_auth,
+				prefer_no_auth: self.prefer_no_auth,
+				udp: self.udp,
+			},
+			on_connect: self.on_connect.map(Rc::new),
+			obfs,
+			header_rules: Rc::new(self.header_rules),
+			opts: self.frame,
+			keys: Keys {
+				server: server_key.map(Rc::from),
+				client: client_key.map(Rc::new),
+			},
+		})
+	}
+}
+
+/// a checked [`ClientConfig`], ready for [`run_client`] or [`resolve`]
+pub struct Client {
+	key: Vec<u8>,
+	cipher: CipherKind,
+	listen: String,
+	listen_opts: ListenOpts,
+	dial: Dial,
+	health_interval: Duration,
+	retries: u32,
+	early_reply: bool,
+	socks_conf: socks5::Conf,
+	on_connect: Option<Rc<OnConnect>>,
+	obfs: Box<dyn Obfuscator>,
+	header_rules: Rc<HeaderRules>,
+	opts: FrameOpts,
+	keys: Keys,
+}
+
+// what handshakes are signed with and checked against, if anything
+#[derive(Clone, Default)]
+struct Keys {
+	// the server's public key, responses are checked against it
+	server: Option<Rc<[u8]>>,
+	// requests are signed with it
+	client: Option<Rc<Ed25519KeyPair>>,
+}
+
+impl Keys {
+	fn wire<'a>(&'a self, opts: &FrameOpts, header: &'a [u8]) -> Wire<'a> {
+		Wire {
+			server_key: self.server.as_deref(),
+			client_key: self.client.as_deref(),
+			..opts.wire(header)
+		}
+	}
+}
+
+// how to reach the servers, which ones may only be known after an SRV lookup
+struct Dial {
+	hosts: Vec<String>,
+	srv: Option<String>,
+	resolver: Rc<dyn Resolver>,
+	strategy: Strategy,
+	transport: Kind,
+	sni: Option<String>,
+	ws_path: String,
+	ttl: Duration,
+	keepalive: Option<Duration>,
+	proxy: Option<Rc<Via>>,
+}
+
+impl Dial {
+	fn dialers(&self, hosts: &[String]) -> anyhow::Result<Vec<Dialer>> {
+		hosts
+			.iter()
+			.map(|host| {
+				let upstream = Upstream::new(host, self.ttl)
+					.keepalive(self.keepalive)
+					.resolver(self.resolver.clone())
+					.via(self.proxy.clone());
+				Dialer::new(self.transport, upstream, self.sni.as_deref(), &self.ws_path)
+			})
+			.collect()
+	}
+
+	async fn servers(&self) -> anyhow::Result<Servers> {
+		let Some(name) = &self.srv else {
+			return Ok(Servers::new(self.dialers(&self.hosts)?, self.strategy));
+		};
+		let records = self
+			.resolver
+			.lookup_srv(name)
+			.await
+			.filter(|r| !r.is_empty())
+			.with_context(|| format!("no SRV record for {}", name))?;
+		let hosts: Vec<_> = order_srv(records)
+			.into_iter()
+			.map(|r| HostPort(&r.target, r.port).to_string())
+			.collect();
+		info!("{} points to {}", name, hosts.join(", "));
+		Ok(Servers::new(self.dialers(&hosts)?, self.strategy))
+	}
+}
+
+/// serves SOCKS5 until the listener fails
+pub async fn run_client(client: Client) -> anyhow::Result<()> {
+	run_with_cipher!(client.cipher, serve_all(client))
+}
+
+/// looks a name up through the server, over plain TCP whatever the transport
+pub async fn resolve(client: &Client, name: &str) -> anyhow::Result<Vec<IpAddr>> {
+	run_with_cipher!(client.cipher, resolve_with(client, name))
+}
+
+/// dials each server in turn and pings it, over the configured transport,
+/// the time to dial and get an answer, or what went wrong
+pub async fn ping(client: &Client) -> anyhow::Result<Vec<(String, Result<Duration, PingError>)>> {
+	run_with_cipher!(client.cipher, ping_with(client))
+}
+
+/// why a [`ping`] got no answer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PingError {
+	/// refused, unreachable, or the transport's own handshake failed
+	Connect,
+	/// connected, but nothing came back in time
+	Timeout,
+	/// closed without a word
+	Closed,
+	/// an answer that doesn't decrypt, another PSK or cipher, or a decoy's response
+	Decrypt,
+	/// not a message of ours at all, another obfuscation or not a mint server
+	NotOurs,
+}
+
+impl fmt::Display for PingError {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			PingError::Connect => "connection failed",
+			PingError::Timeout => "no answer in time",
+			PingError::Closed => "closed without answering",
+			PingError::Decrypt => "answer doesn't decrypt, check the PSK and cipher",
+			PingError::NotOurs => "answer isn't mint's, check the obfuscation",
+		})
+	}
+}
+
+async fn serve_all<C: KeyInit + AeadCore + AeadInPlace + Clone + 'static>(
+	client: Client,
+) -> anyhow::Result<()> {
+	let Client {
+		key,
+		listen,
+		listen_opts,
+		dial,
+		health_interval,
+		retries,
+		early_reply,
+		socks_conf,
+		on_connect,
+		obfs,
+		header_rules,
+		opts,
+		keys,
+		..
+	} = client;
+	let obfs: Rc<dyn Obfuscator> = obfs.into();
+	let cipher: C = init_cipher(&key)?;
+	let servers = dial.servers().await?;
+
+	// fail early if none resolves at all, through a proxy it's the proxy that looks them up
+	let mut resolved = dial.proxy.is_some();
+	for d in servers.dialers() {
+		if dial.proxy.is_some() {
+			break;
+		}
+		let Some(addrs) = d.upstream().resolve().await else {
+			continue;
+		};
+		resolved = true;
+		info!(
+			"server addr: {}",
+			addrs
+				.iter()
+				.map(ToString::to_string)
+				.collect::<Vec<_>>()
+				.join(", ")
+		);
+	}
+	if !resolved {
+		bail!("failed to resolve server {}", servers.hosts());
+	}
+	let servers = Rc::new(servers);
+	if servers.strategy() == Strategy::Fastest {
+		task::spawn_local(
+			format_args!("health"),
+			check_health(
+				servers.clone(),
+				cipher.clone(),
+				obfs.clone(),
+				opts,
+				keys.clone(),
+				health_interval,
+			),
+		);
+	}
+	let socks_conf = Rc::new(socks_conf);
+	let pool = Rc::new(Pool::default());
+
+	let l = sock::listen(&listen, &listen_opts).await?;
+	info!("listening on {}", l.local_addr().unwrap());
+
+	while let Ok((mut s, r_addr)) = l.accept().await {
+		let _ = s.set_nodelay(true);
+		let obfs = obfs.clone();
+		let header_rules = header_rules.clone();
+		let cipher = cipher.clone();
+		let servers = servers.clone();
+		let socks_conf = socks_conf.clone();
+		let on_connect = on_connect.clone();
+		let pool = pool.clone();
+		let keys = keys.clone();
+		task::spawn_local(format_args!("socks {}", r_addr), async move {
+			let mut buf = BytesMut::with_capacity(0x500);
+			let Some((cmd, addr, port)) = socks5::server_handshake(&mut s, &socks_conf).await
+			else {
+				return;
+			};
+			if let Some(hook) = &on_connect
+				&& !hook.allows(r_addr, &addr, port).await
+			{
+				info!("{} -> {} denied", r_addr, HostPort(&addr, port));
+				let _ = socks5::reply(&mut s, socks5::REP_NOT_ALLOWED).await;
+				return;
+			}
+			info!("{} -> {}", r_addr, HostPort(&addr, port));
+			let header = header_rules.pick(&addr).unwrap_or(obfs.header());
+			// where the app sends its datagrams, next to where it reached us
+			let sock = match cmd {
+				Cmd::Connect => None,
+				Cmd::UdpAssociate => {
+					let ip = s.local_addr().map(|a| a.ip());
+					let bound = match ip {
+						Ok(ip) => UdpSocket::bind((ip, 0)).await,
+						Err(e) => Err(e),
+					};
+					match bound {
+						Ok(sock) => Some(sock),
+						Err(e) => {
+							error!("error binding UDP socket: {}", e);
+							let _ = socks5::reply(&mut s, socks5::REP_GENERAL_FAILURE).await;
+							return;
+						}
+					}
+				}
+			};
+			// the datagram stream has no end marker, it takes the connection
+			let features = match cmd {
+				Cmd::Connect => opts.features(),
+				Cmd::UdpAssociate => opts.features() & !FEAT_REUSE,
+			};
+			let mut idle = if opts.reuse && cmd == Cmd::Connect {
+				pool.take(servers.home(r_addr.ip()))
+			} else {
+				None
+			};
+			let mut attempt = 0;
+			// already told it succeeded, with early_reply
+			let mut replied = false;
+			let (server, mut u, r) = loop {
+				let reused = idle.is_some();
+				let (server, mut u) = match idle.take() {
+					Some((i, (u, rx, tx))) => (i, Obfuscated::from_parts(u, &*obfs, rx, tx)),
+					None => match servers.connect(r_addr.ip()).await {
+						Some((i, u)) => (i, Obfuscated::new(u, &*obfs)),
+						None if attempt < retries => {
+							retry(&servers, &mut attempt).await;
+							continue;
+						}
+						None => {
+							fail(&mut s, replied, socks5::REP_GENERAL_FAILURE).await;
+							return;
+						}
+					},
+				};
+				let wire = keys.wire(&opts, header);
+				let r = match cmd {
+					// the app may start sending right away, it waits in the socket
+					Cmd::Connect if early_reply && !replied => {
+						let (r, rep) = tokio::join!(
+							client_request(&mut u, &cipher, &mut buf, &addr, port, wire, features),
+							socks5::reply(&mut s, socks5::REP_SUCCEEDED),
+						);
+						let Some(()) = rep else {
+							return;
+						};
+						replied = true;
+						r
+					}
+					Cmd::Connect => {
+						client_request(&mut u, &cipher, &mut buf, &addr, port, wire, features).await
+					}
+					Cmd::UdpAssociate => {
+						client_udp(&mut u, &cipher, &mut buf, wire, features).await
+					}
+				};
+				// likely closed while idle
+				if r.is_none() && reused {
+					debug!("idle connection failed, dialing a new one");
+					continue;
+				}
+				if r.is_none() {
+					servers.fail(server);
+					if attempt < retries {
+						retry(&servers, &mut attempt).await;
+						continue;
+					}
+				}
+				break (server, u, r);
+			};
+			let features = match r {
+				Some(Ok(features)) => features,
+				Some(Err(rep @ (REP_PORT_DENIED | REP_DENIED))) => {
+					info!(
+						"{} -> {} refused by server: 0x{:02x}",
+						r_addr,
+						HostPort(&addr, port),
+						rep
+					);
+					fail(&mut s, replied, socks5::REP_NOT_ALLOWED).await;
+					return;
+				}
+				Some(Err(rep)) => {
+					debug!("server replies 0x{:02x}, unexpected", rep);
+					fail(&mut s, replied, socks5::REP_GENERAL_FAILURE).await;
+					return;
+				}
+				None => {
+					fail(&mut s, replied, socks5::REP_GENERAL_FAILURE).await;
+					return;
+				}
+			};
+			let opts = opts.negotiated(features);
+			if let Some(sock) = sock {
+				let bound = sock.local_addr().unwrap();
+				let rep = socks5::reply_bound(&mut s, socks5::REP_SUCCEEDED, bound);
+				let Some(()) = rep.await else {
+					return;
+				};
+				let (mut plain, datagrams) = tokio::io::duplex(0x10000);
+				let ((_, _, end), ()) = tokio::join!(
+					duplex(&cipher, &opts, &mut plain, &mut u),
+					udp::associate(sock, r_addr.ip(), &mut s, datagrams),
+				);
+				end.log(format_args!("{} -> UDP", r_addr));
+				return;
+			}
+			if !replied && socks5::reply(&mut s, socks5::REP_SUCCEEDED).await.is_none() {
+				return;
+			}
+			// plain to encrypted is up here
+			let (up, down, end) = if !opts.reuse {
+				let (up, down, end) = duplex(&cipher, &opts, &mut s, &mut u).await;
+				(up, down, Some(end))
+			} else {
+				let (up, down, end) = duplex_framed(&cipher, &opts, &mut s, &mut u).await;
+				if end.is_none() {
+					pool.put(server, u.into_parts());
+				}
+				(up, down, end)
+			};
+			let conn = format_args!(
+				"{} -> {}, {} bytes up, {} bytes down",
+				r_addr,
+				HostPort(&addr, port),
+				up,
+				down
+			);
+			match end {
+				Some(end) => end.log(conn),
+				None => debug!("session ended, the connection is pooled: {}", conn),
+			}
+		});
+	}
+
+	Ok(())
+}
+
+// a failure reply, or if the app has been told it succeeded already, a reset
+async fn fail(s: &mut TcpStream, replied: bool, rep: u8) {
+	if replied {
+		let _ = SockRef::from(&*s).set_linger(Some(Duration::ZERO));
+	} else {
+		let _ = socks5::reply(s, rep).await;
+	}
+}
+
+// the pause before the first retry, doubled for each one after
+const RETRY_BACKOFF: Duration = Duration::from_millis(200);
+const RETRY_BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+// waits out the backoff and drops the cached lookup, the server may have moved
+async fn retry(servers: &Servers, attempt: &mut u32) {
+	let pause = RETRY_BACKOFF
+		.saturating_mul(1 << (*attempt).min(16))
+		.min(RETRY_BACKOFF_MAX);
+	*attempt += 1;
+	debug!("no server reachable, retry {} in {:?}", attempt, pause);
+	sleep(pause).await;
+	servers.invalidate();
+}
+
+// a server that takes longer than this to answer a ping is as good as down
+const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+// pings each server in turn, the time to dial and get an answer ranks them
+async fn check_health<C: KeyInit + AeadCore + AeadInPlace>(
+	servers: Rc<Servers>,
+	cipher: C,
+	obfs: Rc<dyn Obfuscator>,
+	opts: FrameOpts,
+	keys: Keys,
+	every: Duration,
+) {
+	let mut buf = BytesMut::with_capacity(0x500);
+	loop {
+		for (i, d) in servers.dialers().iter().enumerate() {
+			let rtt = ping_one(
+				d,
+				&cipher,
+				&*obfs,
+				&keys.wire(&opts, obfs.header()),
+				&mut buf,
+			)
+			.await;
+			servers.checked(i, rtt.ok());
+		}
+		sleep(every).await;
+	}
+}
+
+async fn ping_with<C: KeyInit + AeadCore + AeadInPlace>(
+	client: &Client,
+) -> anyhow::Result<Vec<(String, Result<Duration, PingError>)>> {
+	let cipher: C = init_cipher(&client.key)?;
+	let mut buf = BytesMut::with_capacity(0x500);
+	let mut r = Vec::new();
+	for d in client.dial.servers().await?.dialers() {
+		let wire = client.keys.wire(&client.opts, client.obfs.header());
+		let rtt = ping_one(d, &cipher, &*client.obfs, &wire, &mut buf).await;
+		r.push((d.upstream().host().to_owned(), rtt));
+	}
+	Ok(r)
+}
+
+async fn ping_one<C: KeyInit + AeadCore + AeadInPlace>(
+	d: &Dialer,
+	cipher: &C,
+	obfs: &dyn Obfuscator,
+	wire: &Wire<'_>,
+	buf: &mut BytesMut,
+) -> Result<Duration, PingError> {
+	let start = Instant::now();
+	timeout(PING_TIMEOUT, async {
+		let s = d.connect().await.ok_or(PingError::Connect)?;
+		let mut u = Obfuscated::new(s, obfs);
+		match client_ping(&mut u, cipher, buf, *wire).await {
+			Some(Ok(())) => Ok(()),
+			Some(Err(MsgError::Decrypt)) => Err(PingError::Decrypt),
+			Some(Err(MsgError::NoEoh | MsgError::Invalid)) => Err(PingError::NotOurs),
+			None => Err(PingError::Closed),
+		}
+	})
+	.await
+	.map_err(|_| {
+		debug!("no answer from {} in time", d.upstream().host());
+		PingError::Timeout
+	})??;
+	Ok(start.elapsed())
+}
+
+// server connections whose last session ended cleanly, newest last, with which server
+#[derive(Default)]
+struct Pool(RefCell<Vec<(Instant, usize, Parts)>>);
+
+// as Obfuscated::into_parts has it
+type Parts = (Stream, u64, u64);
+
+// how long a connection stays pooled, the server or a middlebox may drop it sooner
+const POOL_IDLE: Duration = Duration::from_secs(30);
+const POOL_MAX: usize = 8;
+
+impl Pool {
+	// to any server unless it has to be this one
+	fn take(&self, server: Option<usize>) -> Option<(usize, Parts)> {
+		let mut idle = self.0.borrow_mut();
+		idle.retain(|(t, ..)| t.elapsed() < POOL_IDLE);
+		let i = idle
+			.iter()
+			.rposition(|(_, i, ..)| server.is_none_or(|s| s == *i))?;
+		let (_, i, parts) = idle.remove(i);
+		Some((i, parts))
+	}
+
+	fn put(&self, server: usize, parts: Parts) {
+		let mut idle = self.0.borrow_mut();
+		if idle.len() == POOL_MAX {
+			idle.remove(0);
+		}
+		idle.push((Instant::now(), server, parts));
+	}
+}
+
+async fn resolve_with<C: KeyInit + AeadCore + AeadInPlace>(
+	client: &Client,
+	name: &str,
+) -> anyhow::Result<Vec<IpAddr>> {
+	let cipher: C = init_cipher(&client.key)?;
+
+	// in the order given, whatever the strategy
+	let servers = client.dial.servers().await?;
+	let mut u = None;
+	for d in servers.dialers() {
+		u = d.connect().await;
+		if u.is_some() {
+			break;
+		}
+	}
+	let u = u.with_context(|| format!("failed to connect to {}", servers.hosts()))?;
+	let mut u = Obfuscated::new(u, &*client.obfs);
+	let mut buf = BytesMut::with_capacity(0x500);
+	let r = client_resolve(
+		&mut u,
+		&cipher,
+		&mut buf,
+		name,
+		client.keys.wire(&client.opts, client.obfs.header()),
+	)
+	.await;
+	match r {
+		Some(Ok(addrs)) => Ok(addrs),
+		// not the name's fault, e.g. a client key the server doesn't know
+		Some(Err(REP_DENIED)) => bail!("server denies resolving {}", name),
+		_ => bail!("failed to resolve {}", name),
+	}
+}
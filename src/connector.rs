@@ -0,0 +1,42 @@
+use std::{io, pin::Pin, rc::Rc};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+	dns::Resolver,
+	sock::{self, ConnectOpts},
+};
+
+/// whatever the relay can run over
+pub trait Io: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin> Io for T {}
+
+pub type Connecting<'a> = Pin<Box<dyn Future<Output = io::Result<Box<dyn Io>>> + 'a>>;
+
+/// how the server reaches the destinations clients ask for
+pub trait Connector {
+	fn connect<'a>(&'a self, host: &'a str, port: u16) -> Connecting<'a>;
+}
+
+// the default, resolve then TCP
+pub struct Direct {
+	pub resolver: Rc<dyn Resolver>,
+	pub out: ConnectOpts,
+}
+
+impl Connector for Direct {
+	fn connect<'a>(&'a self, host: &'a str, port: u16) -> Connecting<'a> {
+		Box::pin(async move {
+			let Some(addrs) = self.resolver.resolve_port(host, port).await else {
+				return Err(io::Error::new(
+					io::ErrorKind::NotFound,
+					format!("failed to resolve {}", host),
+				));
+			};
+			let u = sock::connect(&addrs, &self.out).await?;
+			let _ = u.set_nodelay(true);
+			Ok(Box::new(u) as Box<dyn Io>)
+		})
+	}
+}
@@ -0,0 +1,13 @@
+use anyhow::Context;
+use tracing_subscriber::layer::SubscriberExt;
+
+/// serves tokio-console, on 127.0.0.1:6669 unless `TOKIO_CONSOLE_BIND` says otherwise
+///
+/// task names and poll times need a build with `RUSTFLAGS="--cfg tokio_unstable"`
+pub fn init_console() -> anyhow::Result<()> {
+	let layer = console_subscriber::ConsoleLayer::builder()
+		.with_default_env()
+		.spawn();
+	tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))
+		.context("a tracing subscriber is set already")
+}
@@ -0,0 +1,312 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{Context, bail};
+use bytes::BytesMut;
+use clap::ValueEnum;
+use http::Uri;
+use log::*;
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, copy_bidirectional},
+	time::{sleep, timeout},
+};
+
+use crate::{addr::HostPort, fake, upstream::Upstream};
+
+const EOH: &[u8] = b"\r\n\r\n";
+
+// what a real server would have closed the connection after
+const IDLE: Duration = Duration::from_secs(15);
+const MAX_REQ: usize = 0x4000;
+
+const METHODS: &[&[u8]] = &[
+	b"GET", b"HEAD", b"POST", b"PUT", b"DELETE", b"OPTIONS", b"PATCH", b"CONNECT", b"TRACE",
+];
+
+// what to do with a connection that didn't handshake, likely a probe
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Mode {
+	/// just close it, easy to tell apart from a web server
+	Close,
+	/// answer once like a web server would, then close
+	Reply,
+	/// keep answering requests like a web server until the peer goes away
+	Web,
+	/// hand the connection to the --decoy web server
+	Proxy,
+}
+
+// ms before each response, a fixed value or a range like 20-80, random within
+// a local handler answering instantly stands out from a real origin
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Delay(u64, u64);
+
+impl Delay {
+	async fn wait(&self) {
+		if self.1 == 0 {
+			return;
+		}
+		sleep(Duration::from_millis(rand::random_range(self.0..=self.1))).await;
+	}
+}
+
+impl FromStr for Delay {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let ms = |m: &str| {
+			m.trim()
+				.parse::<u64>()
+				.map_err(|e| format!("invalid delay \"{}\": {}", m, e))
+		};
+		let (min, max) = match s.split_once('-') {
+			Some((a, b)) => (ms(a)?, ms(b)?),
+			None => (ms(s)?, ms(s)?),
+		};
+		if min > max {
+			return Err(format!("invalid range \"{}\"", s));
+		}
+		Ok(Delay(min, max))
+	}
+}
+
+// got is whatever was read before the handshake failed
+// servers is the pool handshake responses pick a Server from, one is kept for the connection
+pub async fn answer<S: AsyncRead + AsyncWrite + Unpin>(
+	s: &mut S,
+	got: &[u8],
+	fake_header: &[u8],
+	servers: &[String],
+	mode: Mode,
+	delay: Delay,
+) {
+	if mode == Mode::Close || got.is_empty() {
+		return;
+	}
+	let server = server_header(fake_header).unwrap_or_else(|| fake::pick_server(servers));
+	let keep_alive = mode == Mode::Web && is_http(got);
+	let resp = response(got, server, keep_alive);
+	delay.wait().await;
+	if let Err(e) = s.write_all(&resp).await {
+		debug!("error answering probe: {}", e);
+		return;
+	}
+	if !keep_alive {
+		let _ = s.shutdown().await;
+		return;
+	}
+
+	// the rest of the first request is unlikely to matter, start over
+	let mut buf = BytesMut::with_capacity(0x400);
+	loop {
+		match timeout(IDLE, s.read_buf(&mut buf)).await {
+			Ok(Ok(0)) | Ok(Err(_)) | Err(_) => return,
+			Ok(Ok(_)) => {}
+		}
+		let (end, keep_alive) = match buf.windows(EOH.len()).position(|w| w == EOH) {
+			Some(end) => (end + EOH.len(), is_http(&buf)),
+			// too long to be a header
+			None if buf.len() > MAX_REQ => (buf.len(), false),
+			None => continue,
+		};
+		let resp = response(&buf, server, keep_alive);
+		let _ = buf.split_to(end);
+		delay.wait().await;
+		if s.write_all(&resp).await.is_err() {
+			return;
+		}
+		if !keep_alive {
+			let _ = s.shutdown().await;
+			return;
+		}
+	}
+}
+
+// the decoy from http://host[:port] or host:port, the path is ignored, the probe picks its own
+pub fn target(url: &str) -> anyhow::Result<Upstream> {
+	let uri: Uri = url
+		.parse()
+		.with_context(|| format!("invalid decoy {}", url))?;
+	let port = match uri.scheme_str() {
+		None | Some("http") => 80,
+		Some("https") => 443,
+		Some(scheme) => bail!(
+			"unsupported decoy scheme {}, expecting http or https",
+			scheme
+		),
+	};
+	let host = uri
+		.host()
+		.with_context(|| format!("no host in decoy {}", url))?;
+	let host = HostPort(host, uri.port_u16().unwrap_or(port)).to_string();
+	Ok(Upstream::new(&host, DECOY_TTL))
+}
+
+// how long to cache the decoy lookup
+const DECOY_TTL: Duration = Duration::from_secs(300);
+
+// hand the connection to the decoy, replaying got first, so the probe sees a real site
+// the delay applies once, on top of the decoy's own latency
+pub async fn proxy<S: AsyncRead + AsyncWrite + Unpin>(
+	s: &mut S,
+	got: &[u8],
+	decoy: &Upstream,
+	delay: Delay,
+) {
+	if got.is_empty() {
+		return;
+	}
+	delay.wait().await;
+	let Some(mut u) = decoy.connect().await else {
+		return;
+	};
+	let _ = u.set_nodelay(true);
+	if let Err(e) = u.write_all(got).await {
+		debug!("error replaying probe to {}: {}", decoy.host(), e);
+		return;
+	}
+	match copy_bidirectional(s, &mut u).await {
+		Ok((tx, rx)) => debug!(
+			"probe proxied to {}: {} bytes up, {} down",
+			decoy.host(),
+			got.len() as u64 + tx,
+			rx
+		),
+		Err(e) => debug!("error proxying probe to {}: {}", decoy.host(), e),
+	}
+}
+
+fn is_http(req: &[u8]) -> bool {
+	METHODS
+		.iter()
+		.any(|m| req.starts_with(m) && req.get(m.len()) == Some(&b' '))
+}
+
+// the value of the Server line in the fake header, if any
+fn server_header(fake_header: &[u8]) -> Option<&str> {
+	let h = str::from_utf8(fake_header).ok()?;
+	h.lines().find_map(|l| {
+		let (k, v) = l.split_once(':')?;
+		k.trim().eq_ignore_ascii_case("server").then(|| v.trim())
+	})
+}
+
+// 404 for anything that looks like HTTP, 400 for the rest
+fn response(req: &[u8], server: &str, keep_alive: bool) -> Vec<u8> {
+	let status = if is_http(req) {
+		"404 Not Found"
+	} else {
+		"400 Bad Request"
+	};
+	let body = format!(
+		"<html>\r\n<head><title>{0}</title></head>\r\n<body>\r\n<center><h1>{0}</h1></center>\r\n</body>\r\n</html>\r\n",
+		status
+	);
+	let mut resp = format!(
+		"HTTP/1.1 {}\r\nServer: {}\r\nDate: {}\r\n",
+		status,
+		server,
+		fake::http_date()
+	);
+	resp.push_str(&format!(
+		"Content-Type: text/html\r\nContent-Length: {}\r\nConnection: {}\r\n\r\n",
+		body.len(),
+		if keep_alive { "keep-alive" } else { "close" }
+	));
+	resp.push_str(&body);
+	resp.into_bytes()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_server_header() {
+		assert_eq!(
+			server_header(b"HTTP/1.1 200 OK\r\nserver:  nginx \r\n\r\n"),
+			Some("nginx")
+		);
+		assert_eq!(server_header(b"HTTP/1.1 200 OK\r\n\r\n"), None);
+	}
+
+	#[test]
+	fn test_target() {
+		let host = |url| target(url).ok().map(|u| u.host().to_owned());
+		assert_eq!(
+			host("http://example.com").as_deref(),
+			Some("example.com:80")
+		);
+		assert_eq!(
+			host("https://example.com/").as_deref(),
+			Some("example.com:443")
+		);
+		assert_eq!(
+			host("example.com:8080").as_deref(),
+			Some("example.com:8080")
+		);
+		assert_eq!(host("http://[::1]:81/x").as_deref(), Some("[::1]:81"));
+		assert_eq!(host("ftp://example.com"), None);
+	}
+
+	#[test]
+	fn test_delay() {
+		assert_eq!("20-80".parse(), Ok(Delay(20, 80)));
+		assert_eq!(" 50 ".parse(), Ok(Delay(50, 50)));
+		assert!("80-20".parse::<Delay>().is_err());
+		assert!("fast".parse::<Delay>().is_err());
+	}
+
+	#[tokio::test]
+	async fn test_proxy() {
+		let l = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let decoy = target(&format!("http://{}", l.local_addr().unwrap())).unwrap();
+		let (mut c, mut s) = tokio::io::duplex(0x1000);
+		let first = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+		tokio::join!(
+			async {
+				proxy(&mut s, first, &decoy, Delay::default()).await;
+				drop(s);
+			},
+			async {
+				let (mut u, _) = l.accept().await.unwrap();
+				let mut buf = vec![0; first.len()];
+				u.read_exact(&mut buf).await.unwrap();
+				assert_eq!(&buf, first);
+				u.write_all(b"HTTP/1.1 200 OK\r\n\r\n").await.unwrap();
+			},
+			async {
+				let mut buf = Vec::new();
+				c.read_to_end(&mut buf).await.unwrap();
+				assert_eq!(buf, b"HTTP/1.1 200 OK\r\n\r\n");
+				c.shutdown().await.unwrap();
+			}
+		);
+	}
+
+	#[tokio::test]
+	async fn test_web() {
+		let (mut c, mut s) = tokio::io::duplex(0x1000);
+		let first = b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n";
+		let header = b"HTTP/1.1 200 OK\r\nServer: nginx\r\n\r\n";
+		tokio::join!(
+			answer(&mut s, first, header, &[], Mode::Web, Delay::default()),
+			async {
+				let mut buf = BytesMut::new();
+				// one answer per request, until it isn't HTTP
+				while !buf.ends_with(b"</html>\r\n") {
+					c.read_buf(&mut buf).await.unwrap();
+				}
+				let resp = str::from_utf8(&buf).unwrap();
+				assert!(resp.starts_with("HTTP/1.1 404 Not Found\r\nServer: nginx\r\n"));
+				assert!(resp.contains("Connection: keep-alive"));
+
+				c.write_all(b"\x16\x03\x01\r\n\r\n").await.unwrap();
+				let mut buf = Vec::new();
+				c.read_to_end(&mut buf).await.unwrap();
+				let resp = str::from_utf8(&buf).unwrap();
+				assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"));
+				assert!(resp.contains("Connection: close"));
+			}
+		);
+	}
+}
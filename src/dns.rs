@@ -0,0 +1,287 @@
+use std::{
+	net::{IpAddr, SocketAddr},
+	pin::Pin,
+};
+
+use hickory_resolver::{
+	TokioResolver,
+	config::{NameServerConfig, ResolverConfig},
+	name_server::TokioConnectionProvider,
+	proto::xfer::Protocol,
+};
+use log::*;
+use rand::{Rng, TryRngCore, rngs::OsRng};
+use tokio::net::lookup_host;
+
+use crate::doh::Doh;
+
+pub type Lookup<'a> = Pin<Box<dyn Future<Output = Option<Vec<IpAddr>>> + 'a>>;
+pub type SrvLookup<'a> = Pin<Box<dyn Future<Output = Option<Vec<Srv>>> + 'a>>;
+
+/// how the server looks up the hosts clients ask for, IP literals never get here,
+/// and how the client finds the server by SRV record
+pub trait Resolver {
+	fn lookup<'a>(&'a self, host: &'a str) -> Lookup<'a>;
+
+	/// SRV records for name, e.g. `_mint._tcp.example.com`, none by default
+	fn lookup_srv<'a>(&'a self, name: &'a str) -> SrvLookup<'a> {
+		Box::pin(async move {
+			debug!("no SRV lookup for {} with this resolver", name);
+			None
+		})
+	}
+}
+
+/// an SRV record, the target has no trailing dot
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Srv {
+	pub priority: u16,
+	pub weight: u16,
+	pub port: u16,
+	pub target: String,
+}
+
+// lowest priority first, within one a weighted shuffle as RFC 2782 has it
+pub fn order_srv(mut records: Vec<Srv>) -> Vec<Srv> {
+	let mut rng = OsRng.unwrap_err();
+	records.sort_by_key(|r| r.priority);
+	let mut ordered = Vec::with_capacity(records.len());
+	for group in records.chunk_by(|a, b| a.priority == b.priority) {
+		let mut group = group.to_vec();
+		while !group.is_empty() {
+			// weight 0 ones still get picked, only rarely ahead of the others
+			let total: u32 = group.iter().map(|r| r.weight as u32 + 1).sum();
+			let mut pick = rng.random_range(0..total);
+			let i = group
+				.iter()
+				.position(|r| {
+					let w = r.weight as u32 + 1;
+					if pick < w {
+						return true;
+					}
+					pick -= w;
+					false
+				})
+				.unwrap();
+			ordered.push(group.remove(i));
+		}
+	}
+	ordered
+}
+
+impl dyn Resolver {
+	pub async fn resolve(&self, host: &str) -> Option<Vec<IpAddr>> {
+		if let Ok(ip) = host.parse() {
+			return Some(vec![ip]);
+		}
+		self.lookup(host).await
+	}
+
+	pub async fn resolve_port(&self, host: &str, port: u16) -> Option<Vec<SocketAddr>> {
+		let addrs = self.resolve(host).await?;
+		if addrs.is_empty() {
+			debug!("no address for {}", host);
+			return None;
+		}
+		Some(addrs.into_iter().map(|ip| (ip, port).into()).collect())
+	}
+}
+
+// the ones the CLI offers
+pub enum Builtin {
+	System,
+	// explicit nameservers, bypassing the system config
+	Custom(Box<TokioResolver>),
+	// DNS over HTTPS, optionally falling back to the system resolver
+	Doh(Box<Doh>, bool),
+}
+
+impl Builtin {
+	pub fn custom(servers: &[SocketAddr]) -> Self {
+		let mut config = ResolverConfig::new();
+		for a in servers {
+			// TCP is only for truncated answers
+			config.add_name_server(NameServerConfig::new(*a, Protocol::Udp));
+			config.add_name_server(NameServerConfig::new(*a, Protocol::Tcp));
+		}
+		Self::Custom(Box::new(
+			TokioResolver::builder_with_config(config, TokioConnectionProvider::default()).build(),
+		))
+	}
+}
+
+impl Resolver for Builtin {
+	fn lookup_srv<'a>(&'a self, name: &'a str) -> SrvLookup<'a> {
+		Box::pin(async move {
+			let system;
+			let r = match self {
+				Self::System => {
+					system = TokioResolver::builder_tokio()
+						.map_err(|e| error!("failed to read the system DNS config: {}", e))
+						.ok()?
+						.build();
+					&system
+				}
+				Self::Custom(r) => r,
+				Self::Doh(..) => {
+					error!("no SRV lookup over DoH");
+					return None;
+				}
+			};
+			let records = r
+				.srv_lookup(name)
+				.await
+				.map_err(|e| error!("failed to lookup SRV {}: {}", name, e))
+				.ok()?;
+			Some(
+				records
+					.iter()
+					.map(|r| Srv {
+						priority: r.priority(),
+						weight: r.weight(),
+						port: r.port(),
+						target: r.target().to_utf8().trim_end_matches('.').to_owned(),
+					})
+					.collect(),
+			)
+		})
+	}
+
+	fn lookup<'a>(&'a self, host: &'a str) -> Lookup<'a> {
+		Box::pin(async move {
+			match self {
+				Self::System => system(host).await,
+				Self::Custom(r) => r
+					.lookup_ip(host)
+					.await
+					.map_err(|e| debug!("failed to lookup {}: {}", host, e))
+					.ok()
+					.map(|a| a.iter().collect()),
+				Self::Doh(doh, fallback) => match doh.lookup(host).await {
+					Some(addrs) => Some(addrs),
+					None if *fallback => {
+						debug!("DoH failed for {}, falling back to system resolver", host);
+						system(host).await
+					}
+					None => None,
+				},
+			}
+		})
+	}
+}
+
+async fn system(host: &str) -> Option<Vec<IpAddr>> {
+	lookup_host((host, 0))
+		.await
+		.map_err(|e| debug!("failed to lookup {}: {}", host, e))
+		.ok()
+		.map(|a| a.map(|a| a.ip()).collect())
+}
+
+#[cfg(test)]
+mod test {
+	use std::net::Ipv4Addr;
+
+	use hickory_resolver::proto::{
+		op::{Message, MessageType},
+		rr::{RData, Record, RecordType, rdata::A},
+	};
+	use tokio::net::UdpSocket;
+
+	use super::*;
+
+	fn init() {
+		let _ = env_logger::builder().is_test(true).try_init();
+	}
+
+	// answers every A query with the same address
+	async fn stub(ip: Ipv4Addr) -> SocketAddr {
+		let s = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let addr = s.local_addr().unwrap();
+		tokio::spawn(async move {
+			let mut buf = [0u8; 0x200];
+			loop {
+				let (n, peer) = s.recv_from(&mut buf).await.unwrap();
+				let req = Message::from_vec(&buf[..n]).unwrap();
+				let mut resp = req.clone();
+				resp.set_message_type(MessageType::Response);
+				for q in req.queries() {
+					if q.query_type() == RecordType::A {
+						resp.add_answer(Record::from_rdata(q.name().clone(), 60, RData::A(A(ip))));
+					}
+				}
+				s.send_to(&resp.to_vec().unwrap(), peer).await.unwrap();
+			}
+		});
+		addr
+	}
+
+	#[tokio::test]
+	async fn test_custom() {
+		init();
+
+		let ip = Ipv4Addr::new(192, 0, 2, 7);
+		let r: &dyn Resolver = &Builtin::custom(&[stub(ip).await]);
+
+		let addrs = r.resolve("example.test").await.unwrap();
+		assert_eq!(addrs, vec![IpAddr::V4(ip)]);
+
+		let addrs = r.resolve_port("example.test", 443).await.unwrap();
+		assert_eq!(addrs, vec![SocketAddr::from((ip, 443))]);
+	}
+
+	#[test]
+	fn test_order_srv() {
+		let srv = |priority, weight, target: &str| Srv {
+			priority,
+			weight,
+			port: 8080,
+			target: target.to_owned(),
+		};
+		let records = vec![
+			srv(20, 5, "c"),
+			srv(10, 0, "b"),
+			srv(30, 5, "d"),
+			srv(10, 100, "a"),
+		];
+		let mut a_first = 0;
+		for _ in 0..100 {
+			let order: Vec<_> = order_srv(records.clone())
+				.into_iter()
+				.map(|r| r.target)
+				.collect();
+			assert_eq!(order[2..], ["c", "d"]);
+			assert!(order[..2].contains(&"a".to_owned()));
+			assert!(order[..2].contains(&"b".to_owned()));
+			a_first += (order[0] == "a") as u32;
+		}
+		// 101 to 1 odds each time
+		assert!(a_first > 80, "{}", a_first);
+	}
+
+	#[tokio::test]
+	async fn test_literal() {
+		init();
+
+		// nothing listening, must not be asked
+		let r: &dyn Resolver = &Builtin::custom(&["127.0.0.1:9".parse().unwrap()]);
+		let addrs = r.resolve("::1").await.unwrap();
+		assert_eq!(addrs, vec!["::1".parse::<IpAddr>().unwrap()]);
+	}
+
+	#[tokio::test]
+	async fn test_doh_fallback() {
+		init();
+
+		// nothing listening
+		let doh = || Box::new(Doh::new("http://127.0.0.1:9/dns-query").unwrap());
+		assert!(
+			Builtin::Doh(doh(), false)
+				.lookup("localhost")
+				.await
+				.is_none()
+		);
+		let addrs = Builtin::Doh(doh(), true).lookup("localhost").await.unwrap();
+		assert!(addrs.iter().all(|a| a.is_loopback()), "{:?}", addrs);
+	}
+}
@@ -0,0 +1,372 @@
+use std::{
+	cell::RefCell,
+	collections::HashMap,
+	net::IpAddr,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use anyhow::{Context, bail};
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use hickory_resolver::proto::{
+	op::{Message, Query, ResponseCode},
+	rr::{Name, RData, RecordType},
+};
+use log::*;
+use rustls::{ClientConfig, RootCertStore, pki_types::ServerName};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+	net::TcpStream,
+};
+use tokio_rustls::TlsConnector;
+
+use crate::addr::HostPort;
+
+// cached names, a full cache only takes new entries once some expire
+const CACHE_CAP: usize = 0x1000;
+// answers above this are likely not DNS
+const MAX_RESP: usize = 0x10000;
+
+// RFC 8484 GET over HTTP/1.1, one connection per query
+pub struct Doh {
+	tls: Option<TlsConnector>,
+	host: String,
+	port: u16,
+	path: String,
+	cache: RefCell<HashMap<String, (Instant, Vec<IpAddr>)>>,
+}
+
+impl Doh {
+	// https://host[:port]/path, plain http:// is only meant for local endpoints
+	pub fn new(url: &str) -> anyhow::Result<Self> {
+		let (tls, rest) = if let Some(rest) = url.strip_prefix("https://") {
+			(true, rest)
+		} else if let Some(rest) = url.strip_prefix("http://") {
+			(false, rest)
+		} else {
+			bail!("DoH url should start with https://: {}", url);
+		};
+		let (authority, path) = match rest.find('/') {
+			Some(i) => rest.split_at(i),
+			None => (rest, "/dns-query"),
+		};
+		// IPv6 in brackets
+		let (host, port) = match authority.strip_prefix('[') {
+			Some(a) => a
+				.split_once(']')
+				.with_context(|| format!("unclosed bracket in DoH url {}", url))?,
+			None => authority.split_once(':').unwrap_or((authority, "")),
+		};
+		let port = match port.strip_prefix(':').unwrap_or(port) {
+			"" if tls => 443,
+			"" => 80,
+			p => p
+				.parse()
+				.with_context(|| format!("invalid port in DoH url {}", url))?,
+		};
+		if host.is_empty() {
+			bail!("no host in DoH url: {}", url);
+		}
+
+		let tls = tls.then(|| {
+			let mut roots = RootCertStore::empty();
+			roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+			let config = ClientConfig::builder()
+				.with_root_certificates(roots)
+				.with_no_client_auth();
+			TlsConnector::from(Arc::new(config))
+		});
+
+		Ok(Self {
+			tls,
+			host: host.to_owned(),
+			port,
+			path: path.to_owned(),
+			cache: RefCell::new(HashMap::new()),
+		})
+	}
+
+	pub async fn lookup(&self, host: &str) -> Option<Vec<IpAddr>> {
+		let now = Instant::now();
+		if let Some((expires, addrs)) = self.cache.borrow().get(host)
+			&& now < *expires
+		{
+			return Some(addrs.clone());
+		}
+
+		let name = Name::from_utf8(host)
+			.map_err(|e| debug!("invalid name {}: {}", host, e))
+			.ok()?;
+		let (a, aaaa) = tokio::join!(
+			self.query(&name, RecordType::A),
+			self.query(&name, RecordType::AAAA)
+		);
+		// either family is good enough
+		let (mut addrs, ttl_a) = a.unwrap_or_default();
+		let (addrs_6, ttl_6) = aaaa.unwrap_or_default();
+		if addrs.is_empty() && addrs_6.is_empty() {
+			return None;
+		}
+		addrs.extend(addrs_6);
+		let ttl = match (ttl_a, ttl_6) {
+			(0, t) | (t, 0) => t,
+			(a, b) => a.min(b),
+		};
+
+		let mut cache = self.cache.borrow_mut();
+		if cache.len() >= CACHE_CAP {
+			cache.retain(|_, (expires, _)| now < *expires);
+		}
+		if ttl > 0 && cache.len() < CACHE_CAP {
+			let expires = now + Duration::from_secs(ttl as u64);
+			cache.insert(host.to_owned(), (expires, addrs.clone()));
+		}
+		Some(addrs)
+	}
+
+	// addresses and the lowest TTL among them
+	async fn query(&self, name: &Name, t: RecordType) -> Option<(Vec<IpAddr>, u32)> {
+		let mut msg = Message::new();
+		// RFC 8484 4.1, 0 keeps GET requests cache friendly
+		msg.set_id(0);
+		msg.set_recursion_desired(true);
+		msg.add_query(Query::query(name.clone(), t));
+		let msg = msg
+			.to_vec()
+			.map_err(|e| debug!("failed to encode query: {}", e))
+			.ok()?;
+
+		let s = TcpStream::connect((self.host.as_str(), self.port))
+			.await
+			.map_err(|e| {
+				debug!(
+					"failed to connect to DoH server {}: {}",
+					HostPort(&self.host, self.port),
+					e
+				)
+			})
+			.ok()?;
+		let body = match &self.tls {
+			Some(tls) => {
+				let server_name = ServerName::try_from(self.host.clone())
+					.map_err(|e| debug!("invalid server name {}: {}", self.host, e))
+					.ok()?;
+				let s = tls
+					.connect(server_name, s)
+					.await
+					.map_err(|e| debug!("DoH TLS handshake failed: {}", e))
+					.ok()?;
+				self.get(s, &msg).await?
+			}
+			None => self.get(s, &msg).await?,
+		};
+
+		let resp = Message::from_vec(&body)
+			.map_err(|e| debug!("invalid DoH answer: {}", e))
+			.ok()?;
+		if resp.response_code() != ResponseCode::NoError {
+			debug!("DoH {} {}: {}", t, name, resp.response_code());
+			return None;
+		}
+		let mut ttl = u32::MAX;
+		let addrs: Vec<IpAddr> = resp
+			.answers()
+			.iter()
+			.filter_map(|r| {
+				let ip = match r.data() {
+					RData::A(a) => IpAddr::V4(a.0),
+					RData::AAAA(a) => IpAddr::V6(a.0),
+					// CNAMEs and such, the resolver has followed them already
+					_ => return None,
+				};
+				ttl = ttl.min(r.ttl());
+				Some(ip)
+			})
+			.collect();
+		if addrs.is_empty() {
+			ttl = 0;
+		}
+		Some((addrs, ttl))
+	}
+
+	async fn get<S: AsyncRead + AsyncWrite + Unpin>(
+		&self,
+		mut s: S,
+		msg: &[u8],
+	) -> Option<Vec<u8>> {
+		let req = format!(
+			"GET {}?dns={} HTTP/1.1\r\nHost: {}\r\nAccept: application/dns-message\r\nConnection: close\r\n\r\n",
+			self.path,
+			URL_SAFE_NO_PAD.encode(msg),
+			HostPort(&self.host, self.port)
+		);
+		s.write_all(req.as_bytes())
+			.await
+			.map_err(|e| debug!("failed to send DoH request: {}", e))
+			.ok()?;
+
+		let mut resp = Vec::with_capacity(0x200);
+		(&mut s)
+			.take(MAX_RESP as u64)
+			.read_to_end(&mut resp)
+			.await
+			.map_err(|e| debug!("failed to read DoH answer: {}", e))
+			.ok()?;
+		parse_resp(&resp)
+	}
+}
+
+// the body of a 200, anything else is a failure
+fn parse_resp(resp: &[u8]) -> Option<Vec<u8>> {
+	let Some(eoh) = resp.windows(4).position(|w| w == b"\r\n\r\n") else {
+		debug!("DoH answer has no end of header");
+		return None;
+	};
+	let head = String::from_utf8_lossy(&resp[..eoh]);
+	let body = &resp[eoh + 4..];
+	let mut lines = head.split("\r\n");
+	let status = lines.next().unwrap_or_default();
+	if status.split(' ').nth(1) != Some("200") {
+		debug!("DoH server said: {}", status);
+		return None;
+	}
+	let mut len = None;
+	for l in lines {
+		let Some((k, v)) = l.split_once(':') else {
+			continue;
+		};
+		let v = v.trim();
+		if k.eq_ignore_ascii_case("content-length") {
+			len = v.parse::<usize>().ok();
+		} else if k.eq_ignore_ascii_case("transfer-encoding") && v.eq_ignore_ascii_case("chunked") {
+			debug!("chunked DoH answer not supported");
+			return None;
+		}
+	}
+	match len {
+		Some(len) if len <= body.len() => Some(body[..len].to_vec()),
+		Some(_) => {
+			debug!("DoH answer truncated");
+			None
+		}
+		// delimited by close
+		None => Some(body.to_vec()),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::{
+		net::Ipv4Addr,
+		sync::atomic::{AtomicUsize, Ordering},
+	};
+
+	use hickory_resolver::proto::{
+		op::MessageType,
+		rr::{Record, rdata::A},
+	};
+	use tokio::net::TcpListener;
+
+	use super::*;
+
+	fn init() {
+		let _ = env_logger::builder().is_test(true).try_init();
+	}
+
+	// answers A queries over plain HTTP, counting requests
+	async fn stub(ip: Ipv4Addr, hits: Arc<AtomicUsize>) -> String {
+		let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let url = format!("http://{}/dns-query", l.local_addr().unwrap());
+		tokio::spawn(async move {
+			loop {
+				let (mut s, _) = l.accept().await.unwrap();
+				hits.fetch_add(1, Ordering::Relaxed);
+				let mut buf = vec![0; 0x400];
+				let mut n = 0;
+				while !buf[..n].ends_with(b"\r\n\r\n") {
+					n += s.read(&mut buf[n..]).await.unwrap();
+				}
+				let head = String::from_utf8_lossy(&buf[..n]);
+				let q = head.split(' ').nth(1).unwrap();
+				let q = q.strip_prefix("/dns-query?dns=").unwrap();
+				let req = Message::from_vec(&URL_SAFE_NO_PAD.decode(q).unwrap()).unwrap();
+
+				let mut resp = req.clone();
+				resp.set_message_type(MessageType::Response);
+				for q in req.queries() {
+					if q.query_type() == RecordType::A {
+						resp.add_answer(Record::from_rdata(q.name().clone(), 60, RData::A(A(ip))));
+					}
+				}
+				let body = resp.to_vec().unwrap();
+				let head = format!(
+					"HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+					body.len()
+				);
+				s.write_all(head.as_bytes()).await.unwrap();
+				s.write_all(&body).await.unwrap();
+			}
+		});
+		url
+	}
+
+	#[tokio::test]
+	async fn test_doh() {
+		init();
+
+		let ip = Ipv4Addr::new(192, 0, 2, 8);
+		let hits = Arc::new(AtomicUsize::new(0));
+		let doh = Doh::new(&stub(ip, hits.clone()).await).unwrap();
+
+		let addrs = doh.lookup("example.test").await.unwrap();
+		assert_eq!(addrs, vec![IpAddr::V4(ip)]);
+		// A and AAAA
+		assert_eq!(hits.load(Ordering::Relaxed), 2);
+
+		// cached
+		let addrs = doh.lookup("example.test").await.unwrap();
+		assert_eq!(addrs, vec![IpAddr::V4(ip)]);
+		assert_eq!(hits.load(Ordering::Relaxed), 2);
+	}
+
+	#[test]
+	fn test_url() {
+		let doh = Doh::new("https://dns.example/dns-query").unwrap();
+		assert!(doh.tls.is_some());
+		assert_eq!((doh.host.as_str(), doh.port), ("dns.example", 443));
+		assert_eq!(doh.path, "/dns-query");
+
+		let doh = Doh::new("http://[::1]:8053").unwrap();
+		assert!(doh.tls.is_none());
+		assert_eq!((doh.host.as_str(), doh.port), ("::1", 8053));
+		assert_eq!(doh.path, "/dns-query");
+
+		let doh = Doh::new("https://[2001:db8::1]/q").unwrap();
+		assert_eq!((doh.host.as_str(), doh.port), ("2001:db8::1", 443));
+		assert_eq!(doh.path, "/q");
+
+		assert!(Doh::new("dns.example").is_err());
+		assert!(Doh::new("https://:443/").is_err());
+	}
+
+	#[test]
+	fn test_resp() {
+		assert_eq!(
+			parse_resp(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabcd"),
+			Some(b"abc".to_vec())
+		);
+		assert_eq!(
+			parse_resp(b"HTTP/1.1 200 OK\r\n\r\nabcd"),
+			Some(b"abcd".to_vec())
+		);
+		assert_eq!(parse_resp(b"HTTP/1.1 404 Not Found\r\n\r\n"), None);
+		assert_eq!(
+			parse_resp(b"HTTP/1.1 200 OK\r\nContent-Length: 9\r\n\r\nabcd"),
+			None
+		);
+		assert_eq!(
+			parse_resp(b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nabcd"),
+			None
+		);
+	}
+}
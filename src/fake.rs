@@ -1,25 +1,180 @@
-use std::fs::read_to_string;
+use std::{fs::read_to_string, time::SystemTime};
 
+use anyhow::bail;
 use log::*;
+use rand::{Rng as _, TryRngCore as _, rngs::OsRng};
 
-pub fn get_fake_header(path: &str) -> Vec<u8> {
+use crate::proto::{MAX_HEADER, header_len};
+
+// just the EOH
+pub const EMPTY_HEADER: &[u8] = b"\r\n\r\n";
+
+// what fake responses say they run when the template doesn't and no pool is configured
+pub const SERVERS: &[&str] = &[
+	"nginx",
+	"nginx/1.18.0 (Ubuntu)",
+	"nginx/1.24.0",
+	"Apache",
+	"Apache/2.4.41 (Ubuntu)",
+	"Apache/2.4.58 (Ubuntu)",
+	"cloudflare",
+];
+
+// a Server value for one response, from pool or SERVERS if it's empty
+pub fn pick_server(pool: &[String]) -> &str {
+	let mut rng = OsRng.unwrap_err();
+	if pool.is_empty() {
+		SERVERS[rng.random_range(..SERVERS.len())]
+	} else {
+		&pool[rng.random_range(..pool.len())]
+	}
+}
+
+// now, as an HTTP Date field wants it, e.g. Sun, 06 Nov 1994 08:49:37 GMT
+pub fn http_date() -> String {
+	httpdate::fmt_http_date(SystemTime::now())
+}
+
+// a request or status line, then Key: Value fields, blank lines only at the end
+// anything else is an error, a blank line in between would end the header early
+pub fn get_fake_header(path: &str) -> anyhow::Result<Vec<u8>> {
 	let Ok(s) = read_to_string(path).inspect_err(|e| {
 		warn!(
 			"error reading from {}: {}, will fallback to use an empty header",
 			path, e
 		)
 	}) else {
-		return Vec::from(b"\r\n\r\n");
+		return Ok(Vec::from(EMPTY_HEADER));
 	};
+	let lines: Vec<_> = s.lines().map(str::trim).collect();
+	let end = lines
+		.iter()
+		.rposition(|l| !l.is_empty())
+		.map_or(0, |i| i + 1);
 	let mut res = String::with_capacity(0x200);
-	for l in s.lines() {
-		let l = l.trim();
-		if l.len() == 0 {
-			continue;
+	for (i, l) in lines[..end].iter().enumerate() {
+		if l.is_empty() {
+			bail!(
+				"blank line {} in fake header {} would end it early",
+				i + 1,
+				path
+			);
+		}
+		if i > 0 && !is_field(l) {
+			bail!(
+				"line {} in fake header {} isn't a Key: Value field: {}",
+				i + 1,
+				path,
+				l
+			);
 		}
 		res.push_str(l);
 		res.push_str("\r\n");
 	}
 	res.push_str("\r\n");
-	res.into_bytes()
+	if header_len(res.as_bytes()) > MAX_HEADER {
+		bail!(
+			"fake header in {} is {} bytes, {} with the fields added to it, peers only look for the end within {}",
+			path,
+			res.len(),
+			header_len(res.as_bytes()),
+			MAX_HEADER
+		);
+	}
+	Ok(res.into_bytes())
+}
+
+fn is_field(line: &str) -> bool {
+	line.split_once(':')
+		.is_some_and(|(key, _)| !key.is_empty() && key.bytes().all(|b| b.is_ascii_graphic()))
+}
+
+// fake headers by destination, for cover that fits what's being reached
+// first match wins, a rule for a domain covers its subdomains too
+#[derive(Default)]
+pub struct HeaderRules(Vec<(String, Vec<u8>)>);
+
+impl HeaderRules {
+	pub fn add(&mut self, domain: &str, header: Vec<u8>) {
+		self.0
+			.push((domain.trim_matches('.').to_ascii_lowercase(), header));
+	}
+
+	pub fn headers(&self) -> impl Iterator<Item = &[u8]> {
+		self.0.iter().map(|(_, h)| h.as_slice())
+	}
+
+	pub fn pick(&self, host: &str) -> Option<&[u8]> {
+		let host = host.trim_end_matches('.').to_ascii_lowercase();
+		self.0.iter().find_map(|(domain, header)| {
+			let sub = host
+				.strip_suffix(domain.as_str())
+				.is_some_and(|rest| rest.is_empty() || rest.ends_with('.'));
+			sub.then_some(header.as_slice())
+		})
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// get_fake_header on a temp file with this in it
+	fn load(name: &str, content: &str) -> anyhow::Result<Vec<u8>> {
+		let path = std::env::temp_dir().join(format!("mint-{}-{}.txt", name, std::process::id()));
+		std::fs::write(&path, content).unwrap();
+		let r = get_fake_header(path.to_str().unwrap());
+		std::fs::remove_file(path).unwrap();
+		r
+	}
+
+	#[test]
+	fn test_get_fake_header() {
+		let header = load(
+			"ok",
+			"GET / HTTP/1.1\nHost: example.com \r\nAccept: */*\n\n\n",
+		);
+		assert_eq!(
+			header.unwrap(),
+			b"GET / HTTP/1.1\r\nHost: example.com\r\nAccept: */*\r\n\r\n"
+		);
+
+		let e = load("blank", "GET / HTTP/1.1\n\nHost: example.com\n").unwrap_err();
+		assert!(e.to_string().contains("blank line 2"), "{}", e);
+
+		let e = load("field", "GET / HTTP/1.1\nHost example.com\n").unwrap_err();
+		assert!(e.to_string().contains("line 2"), "{}", e);
+
+		// fits in MAX_HEADER by itself, not with Date, Server and Content-Length
+		let long = format!("HTTP/1.1 200 OK\nX-Pad: {}\n", "a".repeat(440));
+		assert!(long.len() + 2 < MAX_HEADER);
+		let e = load("long", &long).unwrap_err();
+		assert!(e.to_string().contains("fields added"), "{}", e);
+		// only the ones the template lacks are counted
+		assert!(load("long", &format!("{}Date: x\nServer: y\n", long)).is_ok());
+
+		// missing is still only a warning
+		assert_eq!(
+			get_fake_header("/nonexistent/fake.txt").unwrap(),
+			EMPTY_HEADER
+		);
+	}
+
+	#[test]
+	fn test_header_rules() {
+		let mut rules = HeaderRules::default();
+		rules.add("api.example.com", b"POST /v1 HTTP/1.1\r\n\r\n".to_vec());
+		rules.add("example.com", b"GET / HTTP/1.1\r\n\r\n".to_vec());
+		let pick = |host| rules.pick(host).map(|h| str::from_utf8(h).unwrap());
+		assert_eq!(pick("api.example.com"), Some("POST /v1 HTTP/1.1\r\n\r\n"));
+		assert_eq!(
+			pick("eu.API.example.com."),
+			Some("POST /v1 HTTP/1.1\r\n\r\n")
+		);
+		assert_eq!(pick("www.example.com"), Some("GET / HTTP/1.1\r\n\r\n"));
+		assert_eq!(pick("example.com"), Some("GET / HTTP/1.1\r\n\r\n"));
+		// a suffix but not a subdomain
+		assert_eq!(pick("notexample.com"), None);
+		assert_eq!(pick("203.0.113.7"), None);
+	}
 }
@@ -0,0 +1,66 @@
+use std::{cell::Cell, rc::Rc, time::Duration};
+
+use log::*;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpListener,
+	time::timeout,
+};
+
+use crate::task;
+
+// a probe that connects and says nothing is a TCP check, it gets the answer anyway
+const WAIT: Duration = Duration::from_secs(1);
+
+const OK: &[u8] = b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\nConnection: close\r\n\r\nok\n";
+const DOWN: &[u8] =
+	b"HTTP/1.1 503 Service Unavailable\r\nContent-Length: 5\r\nConnection: close\r\n\r\ndown\n";
+
+// for container health checks, on its own port so probes never reach the handshake
+// 200 to anything while up is set, the data listener's accept loop keeps it current
+pub async fn serve(l: TcpListener, up: Rc<Cell<bool>>) {
+	while let Ok((mut s, r_addr)) = l.accept().await {
+		let up = up.clone();
+		task::spawn_local(format_args!("health {}", r_addr), async move {
+			// the request doesn't matter, just don't answer before it's in
+			let mut buf = [0u8; 0x400];
+			let _ = timeout(WAIT, s.read(&mut buf)).await;
+			let resp = if up.get() { OK } else { DOWN };
+			if s.write_all(resp).await.is_ok() {
+				let _ = s.shutdown().await;
+			}
+		});
+	}
+	error!("health listener failed");
+}
+
+#[cfg(test)]
+mod test {
+	use tokio::{net::TcpStream, task::LocalSet};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_serve() {
+		let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let addr = l.local_addr().unwrap();
+		let up = Rc::new(Cell::new(false));
+		let get = || async move {
+			let mut s = TcpStream::connect(addr).await.unwrap();
+			s.write_all(b"GET /healthz HTTP/1.1\r\nHost: x\r\n\r\n")
+				.await
+				.unwrap();
+			let mut resp = String::new();
+			s.read_to_string(&mut resp).await.unwrap();
+			resp
+		};
+		LocalSet::new()
+			.run_until(async {
+				task::spawn_local(format_args!("health"), serve(l, up.clone()));
+				assert!(get().await.starts_with("HTTP/1.1 503 "));
+				up.set(true);
+				assert!(get().await.starts_with("HTTP/1.1 200 OK\r\n"));
+			})
+			.await;
+	}
+}
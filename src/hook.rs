@@ -0,0 +1,22 @@
+use std::{net::SocketAddr, pin::Pin};
+
+type Verdict = Pin<Box<dyn Future<Output = bool>>>;
+
+// an embedder's say on each connection, given the source and the destination
+pub struct OnConnect(Box<dyn Fn(SocketAddr, String, u16) -> Verdict>);
+
+impl OnConnect {
+	pub fn new<F, Fut>(f: F) -> Self
+	where
+		F: Fn(SocketAddr, String, u16) -> Fut + 'static,
+		Fut: Future<Output = bool> + 'static,
+	{
+		OnConnect(Box::new(move |src, host, port| {
+			Box::pin(f(src, host, port))
+		}))
+	}
+
+	pub async fn allows(&self, src: SocketAddr, host: &str, port: u16) -> bool {
+		(self.0)(src, host.to_owned(), port).await
+	}
+}
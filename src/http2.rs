@@ -0,0 +1,301 @@
+use std::{
+	cell::RefCell,
+	io,
+	pin::Pin,
+	task::{Context, Poll, ready},
+};
+
+use bytes::{Buf, Bytes};
+use h2::{RecvStream, SendStream, client::SendRequest};
+use http::{Method, Request, Response, StatusCode};
+use log::*;
+use rustls::pki_types::ServerName;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::TlsConnector;
+
+use crate::{task, upstream::Upstream};
+
+// the body of a CONNECT as a byte stream
+pub struct H2Stream {
+	send: SendStream<Bytes>,
+	recv: RecvStream,
+	// what's left of the last data frame
+	rx: Bytes,
+}
+
+impl H2Stream {
+	fn new(send: SendStream<Bytes>, recv: RecvStream) -> Self {
+		H2Stream {
+			send,
+			recv,
+			rx: Bytes::new(),
+		}
+	}
+}
+
+fn to_io(e: h2::Error) -> io::Error {
+	if e.is_io() {
+		return e.into_io().unwrap();
+	}
+	io::Error::other(e)
+}
+
+impl AsyncRead for H2Stream {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		while this.rx.is_empty() {
+			match ready!(this.recv.poll_data(cx)) {
+				Some(Ok(b)) => {
+					// let the peer send more
+					let _ = this.recv.flow_control().release_capacity(b.len());
+					this.rx = b;
+				}
+				Some(Err(e)) => return Poll::Ready(Err(to_io(e))),
+				None => return Poll::Ready(Ok(())),
+			}
+		}
+		let n = this.rx.len().min(buf.remaining());
+		buf.put_slice(&this.rx[..n]);
+		this.rx.advance(n);
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl AsyncWrite for H2Stream {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		if buf.is_empty() {
+			return Poll::Ready(Ok(0));
+		}
+		this.send.reserve_capacity(buf.len());
+		let n = match ready!(this.send.poll_capacity(cx)) {
+			Some(Ok(n)) => n,
+			Some(Err(e)) => return Poll::Ready(Err(to_io(e))),
+			None => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+		};
+		this.send
+			.send_data(Bytes::copy_from_slice(&buf[..n]), false)
+			.map_err(to_io)?;
+		Poll::Ready(Ok(n))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(Ok(()))
+	}
+
+	// END_STREAM, the peer reads it as EOF
+	fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Poll::Ready(
+			self.get_mut()
+				.send
+				.send_data(Bytes::new(), true)
+				.map_err(to_io),
+		)
+	}
+}
+
+// every CONNECT is a tunnel, anything else gets a 404
+pub async fn accept<S, F>(io: S, mut tunnel: F)
+where
+	S: AsyncRead + AsyncWrite + Unpin,
+	F: FnMut(H2Stream),
+{
+	let Ok(mut conn) = h2::server::handshake(io)
+		.await
+		.map_err(|e| debug!("HTTP/2 handshake failed: {}", e))
+	else {
+		return;
+	};
+	while let Some(r) = conn.accept().await {
+		let Ok((req, mut respond)) = r.map_err(|e| debug!("HTTP/2 error: {}", e)) else {
+			return;
+		};
+		if req.method() != Method::CONNECT {
+			debug!("HTTP/2 {} {}, not a tunnel", req.method(), req.uri());
+			let resp = Response::builder()
+				.status(StatusCode::NOT_FOUND)
+				.body(())
+				.unwrap();
+			let _ = respond.send_response(resp, true);
+			continue;
+		}
+		let resp = Response::builder().status(StatusCode::OK).body(()).unwrap();
+		let Ok(send) = respond
+			.send_response(resp, false)
+			.map_err(|e| debug!("failed to accept CONNECT: {}", e))
+		else {
+			continue;
+		};
+		tunnel(H2Stream::new(send, req.into_body()));
+	}
+}
+
+// one HTTP/2 connection to the server, a CONNECT per tunnel
+pub struct Dialer {
+	upstream: Upstream,
+	tls: TlsConnector,
+	sni: ServerName<'static>,
+	// sent as the CONNECT authority
+	authority: String,
+	conn: RefCell<Option<SendRequest<Bytes>>>,
+}
+
+impl Dialer {
+	pub fn new(
+		upstream: Upstream,
+		tls: TlsConnector,
+		sni: ServerName<'static>,
+		authority: String,
+	) -> Self {
+		Dialer {
+			upstream,
+			tls,
+			sni,
+			authority,
+			conn: RefCell::new(None),
+		}
+	}
+
+	pub fn upstream(&self) -> &Upstream {
+		&self.upstream
+	}
+
+	pub async fn open(&self) -> Option<H2Stream> {
+		let conn = self.conn.borrow().clone();
+		if let Some(conn) = conn {
+			match self.connect_stream(conn).await {
+				Ok(s) => return Some(s),
+				Err(e) => debug!("CONNECT failed: {}, will reconnect", e),
+			}
+		}
+		let conn = self.connect().await?;
+		// concurrent tunnels may race here, the last one wins, the others close when unused
+		*self.conn.borrow_mut() = Some(conn.clone());
+		self.connect_stream(conn)
+			.await
+			.map_err(|e| error!("CONNECT failed: {}", e))
+			.ok()
+	}
+
+	async fn connect(&self) -> Option<SendRequest<Bytes>> {
+		let s = self.upstream.connect().await?;
+		let _ = s.set_nodelay(true);
+		let s = self
+			.tls
+			.connect(self.sni.clone(), s)
+			.await
+			.map_err(|e| error!("TLS handshake with server failed: {}", e))
+			.ok()?;
+		let (send, conn) = h2::client::handshake(s)
+			.await
+			.map_err(|e| error!("HTTP/2 handshake with server failed: {}", e))
+			.ok()?;
+		task::spawn_local(format_args!("h2 {}", self.upstream.host()), async move {
+			if let Err(e) = conn.await {
+				debug!("HTTP/2 connection ended: {}", e);
+			}
+		});
+		Some(send)
+	}
+
+	async fn connect_stream(&self, conn: SendRequest<Bytes>) -> Result<H2Stream, h2::Error> {
+		let mut conn = conn.ready().await?;
+		let req = Request::builder()
+			.method(Method::CONNECT)
+			.uri(self.authority.as_str())
+			.body(())
+			.unwrap();
+		let (resp, send) = conn.send_request(req, false)?;
+		let resp = resp.await?;
+		if resp.status() != StatusCode::OK {
+			debug!("CONNECT answered with {}", resp.status());
+			return Err(h2::Reason::REFUSED_STREAM.into());
+		}
+		Ok(H2Stream::new(send, resp.into_body()))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::rc::Rc;
+
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	use super::*;
+
+	fn init() {
+		let _ = env_logger::builder().is_test(true).try_init();
+	}
+
+	#[tokio::test]
+	async fn test_connect() {
+		init();
+
+		let ls = tokio::task::LocalSet::new();
+		ls.run_until(async {
+			let (c, s) = tokio::io::duplex(0x1000);
+			let tunnels = Rc::new(RefCell::new(Vec::new()));
+			let t = tunnels.clone();
+			tokio::task::spawn_local(accept(s, move |s| t.borrow_mut().push(s)));
+
+			let (send, conn) = h2::client::handshake(c).await.unwrap();
+			tokio::task::spawn_local(async move {
+				let _ = conn.await;
+			});
+
+			// not a tunnel
+			let req = Request::builder()
+				.uri("https://example.com/")
+				.body(())
+				.unwrap();
+			let (resp, _) = send
+				.clone()
+				.ready()
+				.await
+				.unwrap()
+				.send_request(req, true)
+				.unwrap();
+			assert_eq!(resp.await.unwrap().status(), StatusCode::NOT_FOUND);
+
+			let req = Request::builder()
+				.method(Method::CONNECT)
+				.uri("example.com:443")
+				.body(())
+				.unwrap();
+			let (resp, send) = send
+				.ready()
+				.await
+				.unwrap()
+				.send_request(req, false)
+				.unwrap();
+			let resp = resp.await.unwrap();
+			assert_eq!(resp.status(), StatusCode::OK);
+			let mut c = H2Stream::new(send, resp.into_body());
+			let mut s = tunnels.borrow_mut().pop().unwrap();
+
+			// more than the default 64k window, so capacity has to be released
+			let data: Vec<u8> = (0..0x20000).map(|i| i as u8).collect();
+			let (_, got) = tokio::join!(
+				async {
+					c.write_all(&data).await.unwrap();
+					c.shutdown().await.unwrap();
+				},
+				async {
+					let mut got = Vec::new();
+					s.read_to_end(&mut got).await.unwrap();
+					got
+				}
+			);
+			assert_eq!(got, data);
+		})
+		.await;
+	}
+}
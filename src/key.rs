@@ -1,22 +1,79 @@
-use log::*;
-
 use aead::{KeyInit, OsRng};
+use anyhow::{Context, anyhow, bail};
 use base64::prelude::{BASE64_STANDARD_NO_PAD as BASE64, Engine as _};
+use ring::{
+	rand::SystemRandom,
+	signature::{ED25519_PUBLIC_KEY_LEN, Ed25519KeyPair, KeyPair as _},
+};
+
+// all the ciphers take a 256 bit key
+const KEY_SIZE: usize = 32;
 
 pub fn gen_psk<C: KeyInit>() -> String {
 	let key = C::generate_key(&mut OsRng);
 	BASE64.encode(key.as_slice())
 }
 
-pub fn init_cipher<C: KeyInit>(key: &str) -> Option<C> {
-	let key = std::fs::read(key)
-		.map_err(|e| error!("failed to read \"{}\": {}", key, e))
-		.ok()?;
+/// the base64 PSK in a file written by `mint gen-psk`, checked like the builders do
+pub fn read_psk(path: &str) -> anyhow::Result<String> {
+	let psk =
+		std::fs::read_to_string(path).with_context(|| format!("failed to read \"{}\"", path))?;
+	decode_psk(psk.as_bytes()).with_context(|| format!("invalid PSK in \"{}\"", path))?;
+	Ok(psk.trim().to_owned())
+}
+
+// the base64 gen-psk prints, surrounding whitespace is fine
+pub fn decode_psk(psk: &[u8]) -> anyhow::Result<Vec<u8>> {
+	let key = BASE64
+		.decode(psk.trim_ascii())
+		.context("failed to decode base64")?;
+	if key.len() != KEY_SIZE {
+		bail!("{} byte key, expecting {}", key.len(), KEY_SIZE);
+	}
+	Ok(key)
+}
+
+pub fn init_cipher<C: KeyInit>(key: &[u8]) -> anyhow::Result<C> {
+	C::new_from_slice(key).map_err(|e| anyhow!("failed to create cipher: {}", e))
+}
+
+// an Ed25519 key pair for signing, the PKCS#8 document the signer keeps and the
+// public key the other end checks against, both base64
+pub fn gen_key_pair() -> (String, String) {
+	let rng = SystemRandom::new();
+	let pkcs8 = Ed25519KeyPair::generate_pkcs8(&rng).expect("failed to generate key pair");
+	let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+	(
+		BASE64.encode(pkcs8.as_ref()),
+		BASE64.encode(pair.public_key().as_ref()),
+	)
+}
+
+/// the base64 private key in a file written by `mint gen-key-pair`
+pub fn read_key_pair(path: &str) -> anyhow::Result<String> {
+	let key =
+		std::fs::read_to_string(path).with_context(|| format!("failed to read \"{}\"", path))?;
+	decode_sign_key(key.as_bytes()).with_context(|| format!("invalid key in \"{}\"", path))?;
+	Ok(key.trim().to_owned())
+}
+
+pub fn decode_sign_key(key: &[u8]) -> anyhow::Result<Ed25519KeyPair> {
+	let pkcs8 = BASE64
+		.decode(key.trim_ascii())
+		.context("failed to decode base64")?;
+	Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|e| anyhow!("not an Ed25519 key pair: {}", e))
+}
+
+pub fn decode_public_key(key: &[u8]) -> anyhow::Result<Vec<u8>> {
 	let key = BASE64
-		.decode((&key as &[u8]).trim_ascii())
-		.map_err(|e| error!("failed to decode base64: {}", e))
-		.ok()?;
-	C::new_from_slice(&key)
-		.map_err(|e| error!("failed to create cipher: {}", e))
-		.ok()
+		.decode(key.trim_ascii())
+		.context("failed to decode base64")?;
+	if key.len() != ED25519_PUBLIC_KEY_LEN {
+		bail!(
+			"{} byte public key, expecting {}",
+			key.len(),
+			ED25519_PUBLIC_KEY_LEN
+		);
+	}
+	Ok(key)
 }
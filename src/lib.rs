@@ -0,0 +1,123 @@
+//! An obfuscated SOCKS5 proxy, the `mint` binary is a thin CLI over this.
+//!
+//! Build a [`ServerConfig`] or [`ClientConfig`], then hand the result to
+//! [`run_server`] or [`run_client`] inside a tokio runtime.
+
+use chacha20poly1305::ChaCha20Poly1305;
+use clap::ValueEnum;
+
+// monomorphize the runner over the selected cipher
+macro_rules! run_with_cipher {
+	($kind:expr, $f:ident($($arg:expr),* $(,)?)) => {
+		match $kind {
+			$crate::CipherKind::ChaCha20 => {
+				$crate::ls_run($f::<chacha20poly1305::ChaCha20Poly1305>($($arg),*)).await
+			}
+			$crate::CipherKind::ChaCha12 => {
+				$crate::ls_run($f::<chacha20poly1305::ChaCha12Poly1305>($($arg),*)).await
+			}
+			$crate::CipherKind::ChaCha8 => {
+				$crate::ls_run($f::<chacha20poly1305::ChaCha8Poly1305>($($arg),*)).await
+			}
+		}
+	};
+}
+
+mod addr;
+mod audit;
+mod bench;
+mod client;
+mod connector;
+#[cfg(feature = "console")]
+mod console;
+mod decoy;
+mod dns;
+mod doh;
+mod fake;
+mod health;
+mod hook;
+mod http2;
+mod key;
+mod limit;
+mod nonce;
+pub mod obfs;
+#[cfg(feature = "otel")]
+mod otel;
+pub mod policy;
+mod proto;
+mod proxy_proto;
+mod quic;
+mod registry;
+mod server;
+mod servers;
+#[cfg(all(feature = "service", windows))]
+mod service;
+mod shutdown;
+mod sock;
+mod socks5;
+mod stats;
+#[cfg(all(feature = "syslog", unix))]
+mod syslogd;
+mod task;
+mod tls;
+mod transport;
+mod udp;
+mod upstream;
+mod via;
+mod ws;
+
+pub use audit::verify_audit_log;
+pub use bench::run_bench;
+pub use client::{Client, ClientConfig, PingError, ping, resolve, run_client};
+pub use connector::{Connecting, Connector, Io};
+#[cfg(feature = "console")]
+pub use console::init_console;
+pub use decoy::{Delay as ProbeDelay, Mode as ProbeMode};
+pub use dns::{Lookup, Resolver, Srv, SrvLookup};
+pub use key::{read_key_pair, read_psk};
+pub use nonce::set_nonce_reseed;
+#[cfg(feature = "otel")]
+pub use otel::init_otlp;
+pub use proto::FrameOpts;
+pub use registry::{read_client_keys, read_users};
+pub use server::{Server, ServerConfig, run_server};
+pub use servers::Strategy as ServerStrategy;
+#[cfg(all(feature = "service", windows))]
+pub use service::run_service;
+pub use shutdown::request_stop;
+pub use sock::{ConnectOpts, ListenOpts};
+#[cfg(all(feature = "syslog", unix))]
+pub use syslogd::init_syslog;
+pub use transport::Kind as Transport;
+
+/// all of them take the same 256 bit PSK
+#[derive(Clone, Copy, Default, ValueEnum)]
+pub enum CipherKind {
+	#[default]
+	#[value(name = "chacha20")]
+	ChaCha20,
+	/// reduced rounds, faster on low-power devices, smaller security margin
+	#[value(name = "chacha12")]
+	ChaCha12,
+	/// reduced rounds, faster on low-power devices, smaller security margin
+	#[value(name = "chacha8")]
+	ChaCha8,
+}
+
+/// a new random PSK, base64 encoded like the builders expect
+pub fn gen_psk() -> String {
+	key::gen_psk::<ChaCha20Poly1305>()
+}
+
+/// a new Ed25519 key pair, base64 encoded, the private key for one end to sign with:
+/// [`ServerConfig::sign_key`] or [`ClientConfig::client_key`], the public one for the
+/// other to check: [`ClientConfig::server_key`] or [`ServerConfig::client_key`]
+pub fn gen_key_pair() -> (String, String) {
+	key::gen_key_pair()
+}
+
+// runs in local set
+async fn ls_run<F: Future>(f: F) -> F::Output {
+	let ls = tokio::task::LocalSet::new();
+	ls.run_until(f).await
+}
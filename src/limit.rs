@@ -0,0 +1,281 @@
+use std::{
+	cell::Cell,
+	collections::HashMap,
+	net::IpAddr,
+	time::{Duration, Instant},
+};
+
+use log::*;
+
+struct Bucket {
+	tokens: f64,
+	last: Instant,
+}
+
+// per source IP token bucket, least recently seen IPs are evicted beyond cap
+pub struct RateLimiter {
+	rate: f64,
+	burst: f64,
+	cap: usize,
+	buckets: HashMap<IpAddr, Bucket>,
+}
+
+impl RateLimiter {
+	// rate per second, which is also the burst
+	pub fn new(rate: u32, cap: usize) -> Self {
+		RateLimiter {
+			rate: rate as f64,
+			burst: rate.max(1) as f64,
+			cap,
+			buckets: HashMap::new(),
+		}
+	}
+
+	pub fn check(&mut self, ip: IpAddr, now: Instant) -> bool {
+		if !self.buckets.contains_key(&ip) && self.buckets.len() >= self.cap {
+			self.evict();
+		}
+		let b = self.buckets.entry(ip).or_insert(Bucket {
+			tokens: self.burst,
+			last: now,
+		});
+		let elapsed = now.saturating_duration_since(b.last);
+		b.tokens = (b.tokens + elapsed.as_secs_f64() * self.rate).min(self.burst);
+		b.last = now;
+		if b.tokens >= 1.0 {
+			b.tokens -= 1.0;
+			true
+		} else {
+			false
+		}
+	}
+
+	fn evict(&mut self) {
+		let Some(ip) = self
+			.buckets
+			.iter()
+			.min_by_key(|(_, b)| b.last)
+			.map(|(ip, _)| *ip)
+		else {
+			return;
+		};
+		self.buckets.remove(&ip);
+	}
+}
+
+// keep this many IPs at most
+pub const RATE_LIMIT_CAP: usize = 0x1000;
+
+struct Strikes {
+	// consecutive, a good handshake clears them
+	failures: u32,
+	until: Option<Instant>,
+	last: Instant,
+}
+
+// bans source IPs after consecutive handshake failures, least recently seen IPs are evicted beyond cap
+pub struct Banlist {
+	threshold: u32,
+	duration: Duration,
+	cap: usize,
+	ips: HashMap<IpAddr, Strikes>,
+}
+
+impl Banlist {
+	pub fn new(threshold: u32, duration: Duration, cap: usize) -> Self {
+		Banlist {
+			threshold: threshold.max(1),
+			duration,
+			cap,
+			ips: HashMap::new(),
+		}
+	}
+
+	pub fn is_banned(&mut self, ip: IpAddr, now: Instant) -> bool {
+		let Some(s) = self.ips.get_mut(&ip) else {
+			return false;
+		};
+		match s.until {
+			Some(until) if now < until => true,
+			Some(_) => {
+				// served its time, a fresh start
+				self.ips.remove(&ip);
+				false
+			}
+			None => false,
+		}
+	}
+
+	pub fn failed(&mut self, ip: IpAddr, now: Instant) {
+		if !self.ips.contains_key(&ip) && self.ips.len() >= self.cap {
+			self.evict();
+		}
+		let s = self.ips.entry(ip).or_insert(Strikes {
+			failures: 0,
+			until: None,
+			last: now,
+		});
+		s.failures += 1;
+		s.last = now;
+		if s.failures >= self.threshold {
+			s.until = Some(now + self.duration);
+		}
+	}
+
+	pub fn succeeded(&mut self, ip: IpAddr) {
+		self.ips.remove(&ip);
+	}
+
+	fn evict(&mut self) {
+		let Some(ip) = self
+			.ips
+			.iter()
+			.min_by_key(|(_, s)| s.last)
+			.map(|(ip, _)| *ip)
+		else {
+			return;
+		};
+		self.ips.remove(&ip);
+	}
+}
+
+// memory use is read at most this often, a new connection doesn't wait on /proc each time
+pub const MEM_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+// refuses new connections while the process holds more memory than max, the open ones
+// carry on and give it back as they end, a last resort before the OOM killer
+pub struct MemGuard {
+	max: u64,
+	// resident bytes, None if it can't be told
+	rss: fn() -> Option<u64>,
+	// the last reading, when and whether it was over
+	last: Cell<Option<(Instant, bool)>>,
+}
+
+impl MemGuard {
+	pub fn new(max: u64, rss: fn() -> Option<u64>) -> Self {
+		MemGuard {
+			max,
+			rss,
+			last: Cell::new(None),
+		}
+	}
+
+	pub fn admits(&self, now: Instant) -> bool {
+		let was = match self.last.get() {
+			Some((t, over)) if now.saturating_duration_since(t) < MEM_CHECK_INTERVAL => {
+				return !over;
+			}
+			Some((_, over)) => over,
+			None => false,
+		};
+		// can't tell, let it in
+		let rss = (self.rss)();
+		let over = rss.is_some_and(|rss| rss > self.max);
+		match (was, rss) {
+			(false, Some(rss)) if over => error!(
+				"using {} bytes of memory, over {}, refusing new connections",
+				rss, self.max
+			),
+			(true, _) if !over => info!("memory use back under {}, accepting again", self.max),
+			_ => {}
+		}
+		self.last.set(Some((now, over)));
+		!over
+	}
+}
+
+// resident set size from /proc, None where there's no such thing
+pub fn rss() -> Option<u64> {
+	let status = std::fs::read_to_string("/proc/self/status").ok()?;
+	let kb = status.lines().find_map(|l| l.strip_prefix("VmRSS:"))?;
+	let kb: u64 = kb.trim().strip_suffix("kB")?.trim_end().parse().ok()?;
+	Some(kb * 1024)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_rate() {
+		let a: IpAddr = "192.0.2.1".parse().unwrap();
+		let b: IpAddr = "192.0.2.2".parse().unwrap();
+		let t = Instant::now();
+		let mut l = RateLimiter::new(2, 16);
+
+		assert!(l.check(a, t));
+		assert!(l.check(a, t));
+		assert!(!l.check(a, t));
+		// another IP is unaffected
+		assert!(l.check(b, t));
+
+		assert!(!l.check(a, t + Duration::from_millis(100)));
+		assert!(l.check(a, t + Duration::from_secs(1)));
+	}
+
+	#[test]
+	fn test_cap() {
+		let t = Instant::now();
+		let mut l = RateLimiter::new(1, 2);
+		let ips: Vec<IpAddr> = (1..=3)
+			.map(|i| format!("192.0.2.{}", i).parse().unwrap())
+			.collect();
+
+		assert!(l.check(ips[0], t));
+		assert!(!l.check(ips[0], t));
+		assert!(l.check(ips[1], t + Duration::from_millis(1)));
+		assert!(l.check(ips[2], t + Duration::from_millis(2)));
+		assert_eq!(l.buckets.len(), 2);
+		// the first one got evicted, so it starts with a full bucket
+		assert!(l.check(ips[0], t + Duration::from_millis(3)));
+	}
+
+	#[test]
+	fn test_ban() {
+		let a: IpAddr = "192.0.2.1".parse().unwrap();
+		let b: IpAddr = "192.0.2.2".parse().unwrap();
+		let t = Instant::now();
+		let mut l = Banlist::new(3, Duration::from_secs(60), 16);
+
+		l.failed(a, t);
+		l.failed(a, t);
+		assert!(!l.is_banned(a, t));
+		// only consecutive ones count
+		l.succeeded(a);
+		l.failed(a, t);
+		l.failed(a, t);
+		assert!(!l.is_banned(a, t));
+		l.failed(a, t);
+		assert!(l.is_banned(a, t));
+		assert!(!l.is_banned(b, t));
+
+		assert!(l.is_banned(a, t + Duration::from_secs(59)));
+		assert!(!l.is_banned(a, t + Duration::from_secs(60)));
+		// and starts over
+		l.failed(a, t + Duration::from_secs(61));
+		assert!(!l.is_banned(a, t + Duration::from_secs(61)));
+	}
+
+	#[test]
+	fn test_mem_guard() {
+		use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+		static RSS: AtomicU64 = AtomicU64::new(100);
+		let g = MemGuard::new(1000, || Some(RSS.load(Relaxed)));
+		let t = Instant::now();
+		assert!(g.admits(t));
+		RSS.store(2000, Relaxed);
+		// not read again yet
+		assert!(g.admits(t + MEM_CHECK_INTERVAL / 2));
+		assert!(!g.admits(t + MEM_CHECK_INTERVAL));
+		assert!(!g.admits(t + MEM_CHECK_INTERVAL * 3 / 2));
+		RSS.store(500, Relaxed);
+		assert!(g.admits(t + MEM_CHECK_INTERVAL * 2));
+
+		// unknown isn't over
+		assert!(MemGuard::new(0, || None).admits(t));
+		#[cfg(target_os = "linux")]
+		assert!(rss().unwrap() > 0);
+	}
+}
@@ -1,25 +1,69 @@
-use std::{net::SocketAddr, rc::Rc};
+use std::{net::SocketAddr, time::Duration};
 
-use bytes::BytesMut;
+use anyhow::bail;
 use clap::{Parser, Subcommand};
 use log::*;
 
-use chacha20poly1305::ChaCha20Poly1305 as Cipher;
-use tokio::net::{TcpListener, TcpStream, lookup_host};
-
-mod fake;
-mod key;
-mod proto;
-
-use key::*;
-use proto::*;
+use mint::{
+	CipherKind, ClientConfig, ConnectOpts, FrameOpts, ListenOpts, ProbeDelay, ProbeMode,
+	ServerConfig, ServerStrategy, Transport,
+	obfs::{self, Obfuscator},
+	policy::{PortList, PortPolicy},
+	read_client_keys, read_key_pair, read_psk, read_users,
+};
 
 #[derive(Parser)]
 struct Args {
 	#[command(subcommand)]
 	cmd: Cmds,
+
+	/// export per-connection spans to this OTLP/HTTP endpoint,
+	/// e.g. http://localhost:4318/v1/traces
+	#[cfg(feature = "otel")]
+	#[arg(long, global = true)]
+	otlp: Option<String>,
+
+	/// serve tokio-console, see TOKIO_CONSOLE_BIND
+	/// task details need a build with RUSTFLAGS="--cfg tokio_unstable"
+	#[cfg(feature = "console")]
+	#[arg(long, global = true)]
+	console: bool,
+
+	/// log to the local syslog daemon instead of stderr, under the daemon facility or
+	/// another one given as e.g. --syslog=local0, RUST_LOG still filters
+	#[cfg(all(feature = "syslog", unix))]
+	#[arg(
+		long,
+		global = true,
+		num_args = 0..=1,
+		require_equals = true,
+		default_missing_value = "daemon",
+		value_name = "FACILITY"
+	)]
+	syslog: Option<String>,
+
+	/// run under the Windows service manager, for a service installed with e.g.
+	/// sc create mint binPath= "C:\mint\mint.exe --service server -k C:\mint\psk"
+	#[cfg(all(feature = "service", windows))]
+	#[arg(long, global = true)]
+	service: bool,
+
+	/// generate nonces with ChaCha20 reseeded from the OS after every so many bytes,
+	/// 64K or --nonce-reseed=BYTES, rather than asking the OS for each one
+	#[arg(
+		long,
+		global = true,
+		num_args = 0..=1,
+		require_equals = true,
+		default_missing_value = "65536",
+		value_name = "BYTES",
+		value_parser = clap::value_parser!(u64).range(1..)
+	)]
+	nonce_reseed: Option<u64>,
 }
 
+// parsed once, Server having the most options doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Cmds {
 	#[command(alias = "s")]
@@ -28,11 +72,185 @@ enum Cmds {
 		#[arg(short = 'k', default_value = "conf/psk")]
 		psk: String,
 
+		/// IPv6 needs brackets, e.g. [::]:8080
 		#[arg(short, default_value = "127.0.0.1:8080")]
 		listen: String,
 
+		/// fake HTTP header for http-prefix
 		#[arg(short, default_value = "conf/fake-resp.txt")]
 		fake_header: String,
+
+		/// camouflage, one of none, http-prefix, bin-prefix, raw, has to match the other end
+		#[arg(long, default_value = "http-prefix")]
+		obfs: String,
+
+		/// no header in front of handshake messages at all, same as --obfs raw
+		/// for trusted networks, has to match the other end
+		#[arg(long, conflicts_with = "obfs")]
+		no_fake_header: bool,
+
+		/// this file as is in front of handshake messages, e.g. a TLS record header
+		/// same as --obfs bin-prefix -f file, has to match the other end
+		#[arg(long, conflicts_with_all = ["obfs", "no_fake_header"])]
+		fake_prefix_bin: Option<String>,
+
+		/// Server values fake responses pick from when the fake header has none,
+		/// e.g. "nginx,Apache/2.4.58 (Ubuntu)", defaults to a few common ones
+		#[arg(long, value_delimiter = ',')]
+		server_names: Vec<String>,
+
+		/// only allow these destination ports, e.g. 80,443,1024-65535
+		#[arg(long)]
+		allow_ports: Option<PortList>,
+
+		/// never allow these destination ports
+		#[arg(long)]
+		deny_ports: Option<PortList>,
+
+		/// pending connections the OS queues before they're accepted
+		#[arg(long, default_value_t = ListenOpts::default().backlog)]
+		backlog: u32,
+
+		/// only accept IPv6 on an IPv6 listen address such as [::]
+		#[arg(long, conflicts_with = "dual_stack")]
+		ipv6_only: bool,
+
+		/// accept IPv4 too on an IPv6 listen address such as [::]
+		/// without either, it's up to the OS
+		#[arg(long)]
+		dual_stack: bool,
+
+		/// TTL or hop limit of upstream connections
+		#[arg(long, value_parser = clap::value_parser!(u32).range(1..=255))]
+		out_ttl: Option<u32>,
+
+		/// DSCP to mark upstream connections with, e.g. 46 for EF
+		#[arg(long, value_parser = clap::value_parser!(u8).range(0..64))]
+		out_dscp: Option<u8>,
+
+		/// seconds idle before TCP keepalive probes, on client and upstream connections
+		#[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+		keepalive: Option<u64>,
+
+		/// max new connections per second from a single source IP
+		#[arg(long)]
+		conn_rate: Option<u32>,
+
+		/// MiB of resident memory past which new connections are refused, Linux only
+		#[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+		max_memory: Option<u64>,
+
+		/// take client addresses from the PROXY protocol header a load balancer sends first
+		#[arg(long)]
+		proxy_protocol: bool,
+
+		/// send a PROXY protocol v2 header with the client address to each upstream
+		#[arg(long)]
+		send_proxy_protocol: bool,
+
+		/// ban a source IP after this many handshake failures in a row
+		#[arg(long)]
+		ban_after: Option<u32>,
+
+		/// seconds a ban lasts
+		#[arg(long, default_value_t = 600, requires = "ban_after")]
+		ban_secs: u64,
+
+		/// what to do when a connection fails the handshake,
+		/// defaults to proxy with --decoy, reply otherwise
+		#[arg(long, value_enum)]
+		on_probe: Option<ProbeMode>,
+
+		/// web server to proxy failed handshakes to, e.g. http://example.com
+		#[arg(long)]
+		decoy: Option<String>,
+
+		/// ms to wait before answering a failed handshake, random within a range like 20-80
+		#[arg(long, default_value = "10-50")]
+		probe_delay: ProbeDelay,
+
+		/// seconds to let open connections finish after SIGTERM or Ctrl-C
+		#[arg(long, default_value_t = 30)]
+		drain_secs: u64,
+
+		#[command(flatten)]
+		stats: Box<StatsArgs>,
+
+		/// answer HTTP health checks on this address, 200 while accepting, e.g. 0.0.0.0:8081
+		/// probes there never count as failed handshakes
+		#[arg(long)]
+		health_addr: Option<String>,
+
+		/// nameservers to use instead of the system resolver, e.g. 1.1.1.1:53,8.8.8.8:53
+		#[arg(long, value_delimiter = ',', conflicts_with = "doh")]
+		resolver: Vec<SocketAddr>,
+
+		/// resolve through DNS over HTTPS, e.g. https://1.1.1.1/dns-query
+		#[arg(long)]
+		doh: Option<String>,
+
+		/// use the system resolver when DoH fails, leaks the lookup in plaintext
+		#[arg(long, requires = "doh")]
+		doh_fallback: bool,
+
+		#[arg(long, value_enum, default_value_t = Transport::Tcp)]
+		transport: Transport,
+
+		/// PEM certificate chain for QUIC, TLS or WSS, a self-signed one is generated if omitted
+		#[arg(long, requires = "tls_key")]
+		tls_cert: Option<String>,
+
+		/// PEM private key for --tls-cert
+		#[arg(long, requires = "tls_cert")]
+		tls_key: Option<String>,
+
+		/// WebSocket path for ws and wss, anything else gets a 404
+		#[arg(long, default_value = "/")]
+		ws_path: String,
+
+		#[arg(long, value_enum, default_value_t = CipherKind::ChaCha20)]
+		cipher: CipherKind,
+
+		/// sign responses with the Ed25519 key in this file, from gen-key-pair,
+		/// clients pinning its public key can tell this server from anyone with the PSK
+		#[arg(long)]
+		sign_key: Option<String>,
+
+		/// let in only the clients in this file, one `name public-key` per line,
+		/// each signing with its own --client-key, drop a line to revoke one
+		#[arg(long)]
+		client_keys: Option<String>,
+
+		/// also let in the users in this file, one `name psk` per line, each with a PSK
+		/// of their own from gen-psk, drop a line to revoke one
+		#[arg(long)]
+		users: Option<String>,
+
+		/// append a line per connect or UDP request to this file, who, from where, to where,
+		/// bytes and how it ended, apart from the log
+		#[arg(long)]
+		audit_log: Option<String>,
+
+		/// chain the audit log, each line with the SHA-256 of the one before it,
+		/// see verify-audit
+		#[arg(long, requires = "audit_log")]
+		audit_chain: bool,
+
+		/// start a new audit log once a line would take it past this many bytes,
+		/// the old one renamed to <file>.1, <file>.1 to <file>.2 and so on
+		#[arg(long, requires = "audit_log")]
+		log_max_size: Option<u64>,
+
+		/// start a new audit log on each new UTC day, like --log-max-size
+		#[arg(long, requires = "audit_log")]
+		log_daily: bool,
+
+		/// how many rotated audit logs to keep, the oldest beyond that are deleted
+		#[arg(long, default_value_t = 5)]
+		log_max_files: usize,
+
+		#[command(flatten)]
+		frame: FrameArgs,
 	},
 
 	#[command(alias = "c")]
@@ -41,18 +259,376 @@ enum Cmds {
 		#[arg(short = 'k', default_value = "conf/psk")]
 		psk: String,
 
+		/// IPv6 needs brackets, e.g. [::]:1080
 		#[arg(short, default_value = "127.0.0.1:1080")]
 		listen: String,
 
+		/// server address, IP or hostname, several separated by commas
+		#[arg(short, default_value = "127.0.0.1:8080")]
+		server: String,
+
+		/// find the servers by SRV record instead, e.g. _mint._tcp.example.com
+		#[arg(long, conflicts_with = "server")]
+		server_srv: Option<String>,
+
+		/// how to pick among several servers
+		/// round-robin by default, failover for --server-srv so the record order holds
+		#[arg(long, value_enum)]
+		server_strategy: Option<ServerStrategy>,
+
+		/// seconds between pings to each server, for --server-strategy fastest
+		#[arg(long, default_value_t = 30)]
+		health_interval: u64,
+
+		/// seconds to cache the server address lookup
+		#[arg(long, default_value_t = 300)]
+		server_ttl: u64,
+
+		/// times to redial the server if it drops the connection before replying
+		#[arg(long, default_value_t = 0)]
+		server_retries: u32,
+
+		/// reply to SOCKS5 CONNECT once the server is dialed, not once it answers,
+		/// a refusal then resets the connection instead of being replied
+		#[arg(long)]
+		early_reply: bool,
+
+		/// SOCKS5 username and password, as user:pass
+		#[arg(long)]
+		socks_auth: Option<String>,
+
+		/// only accept SOCKS5 clients that authenticate
+		#[arg(long)]
+		require_auth: bool,
+
+		/// let SOCKS5 clients offering both skip auth, by default they're asked for it
+		#[arg(long, requires = "socks_auth", conflicts_with = "require_auth")]
+		prefer_no_auth: bool,
+
+		/// take SOCKS5 UDP ASSOCIATE, the datagrams ride a TCP tunnel to the server
+		#[arg(long)]
+		udp: bool,
+
+		/// seconds idle before TCP keepalive probes, on SOCKS5 and server connections
+		#[arg(long, value_parser = clap::value_parser!(u64).range(1..))]
+		keepalive: Option<u64>,
+
+		#[arg(long, value_enum, default_value_t = Transport::Tcp)]
+		transport: Transport,
+
+		/// TLS server name to send, defaults to the server host
+		/// also the WebSocket Host header
+		#[arg(long)]
+		sni: Option<String>,
+
+		/// reach the server through this HTTP proxy with CONNECT,
+		/// http://[user:password@]host[:port], not for QUIC
+		#[arg(long)]
+		via_http_proxy: Option<String>,
+
+		/// reach the server through this SOCKS5 proxy, host:port, one without auth,
+		/// not for QUIC
+		#[arg(long, conflicts_with = "via_http_proxy")]
+		via_socks5: Option<String>,
+
+		/// WebSocket path for ws and wss
+		#[arg(long, default_value = "/")]
+		ws_path: String,
+
+		/// fake HTTP header for http-prefix
+		#[arg(short, default_value = "conf/fake-req.txt")]
+		fake_header: String,
+
+		/// fake header file for requests to a domain and its subdomains, as domain=file
+		/// may be repeated, the first match wins
+		#[arg(long)]
+		header_rule: Vec<String>,
+
+		/// camouflage, one of none, http-prefix, bin-prefix, raw, has to match the other end
+		#[arg(long, default_value = "http-prefix")]
+		obfs: String,
+
+		/// no header in front of handshake messages at all, same as --obfs raw
+		/// for trusted networks, has to match the other end
+		#[arg(long, conflicts_with = "obfs")]
+		no_fake_header: bool,
+
+		/// this file as is in front of handshake messages, e.g. a TLS record header
+		/// same as --obfs bin-prefix -f file, has to match the other end
+		#[arg(long, conflicts_with_all = ["obfs", "no_fake_header"])]
+		fake_prefix_bin: Option<String>,
+
+		#[arg(long, value_enum, default_value_t = CipherKind::ChaCha20)]
+		cipher: CipherKind,
+
+		/// the server's Ed25519 public key, from gen-key-pair, responses not signed
+		/// with it are refused, for when the PSK is shared with many clients
+		#[arg(long)]
+		server_key: Option<String>,
+
+		/// sign requests with the Ed25519 key in this file, from gen-key-pair,
+		/// for servers that let in registered clients only
+		#[arg(long)]
+		client_key: Option<String>,
+
+		#[command(flatten)]
+		frame: FrameArgs,
+	},
+
+	/// resolve a name through the server
+	Resolve {
+		/// PSK file path
+		#[arg(short = 'k', default_value = "conf/psk")]
+		psk: String,
+
+		/// server address, IP or hostname
 		#[arg(short, default_value = "127.0.0.1:8080")]
 		server: String,
 
+		#[arg(long, value_enum, default_value_t = Transport::Tcp)]
+		transport: Transport,
+
+		/// TLS server name to send, defaults to the server host
+		/// also the WebSocket Host header
+		#[arg(long)]
+		sni: Option<String>,
+
+		/// WebSocket path for ws and wss
+		#[arg(long, default_value = "/")]
+		ws_path: String,
+
+		/// fake HTTP header for http-prefix
 		#[arg(short, default_value = "conf/fake-req.txt")]
 		fake_header: String,
+
+		/// camouflage, one of none, http-prefix, bin-prefix, raw, has to match the other end
+		#[arg(long, default_value = "http-prefix")]
+		obfs: String,
+
+		/// no header in front of handshake messages at all, same as --obfs raw
+		/// for trusted networks, has to match the other end
+		#[arg(long, conflicts_with = "obfs")]
+		no_fake_header: bool,
+
+		/// this file as is in front of handshake messages, e.g. a TLS record header
+		/// same as --obfs bin-prefix -f file, has to match the other end
+		#[arg(long, conflicts_with_all = ["obfs", "no_fake_header"])]
+		fake_prefix_bin: Option<String>,
+
+		#[arg(long, value_enum, default_value_t = CipherKind::ChaCha20)]
+		cipher: CipherKind,
+
+		/// the server's Ed25519 public key, from gen-key-pair, responses not signed
+		/// with it are refused, for when the PSK is shared with many clients
+		#[arg(long)]
+		server_key: Option<String>,
+
+		/// sign requests with the Ed25519 key in this file, from gen-key-pair,
+		/// for servers that let in registered clients only
+		#[arg(long)]
+		client_key: Option<String>,
+
+		name: String,
+	},
+
+	/// check a server is reachable and takes the PSK, and how long it takes to answer
+	/// tells a refused connection, silence, a wrong PSK and another obfuscation apart
+	#[command(alias = "probe")]
+	Ping {
+		/// PSK file path
+		#[arg(short = 'k', default_value = "conf/psk")]
+		psk: String,
+
+		/// server address, IP or hostname, several separated by commas
+		#[arg(short, default_value = "127.0.0.1:8080")]
+		server: String,
+
+		/// how many times to ping each server, a second apart
+		#[arg(short, default_value_t = 4)]
+		count: u32,
+
+		#[arg(long, value_enum, default_value_t = Transport::Tcp)]
+		transport: Transport,
+
+		/// TLS server name to send, defaults to the server host
+		/// also the WebSocket Host header
+		#[arg(long)]
+		sni: Option<String>,
+
+		/// reach the server through this HTTP proxy with CONNECT,
+		/// http://[user:password@]host[:port], not for QUIC
+		#[arg(long)]
+		via_http_proxy: Option<String>,
+
+		/// reach the server through this SOCKS5 proxy, host:port, one without auth,
+		/// not for QUIC
+		#[arg(long, conflicts_with = "via_http_proxy")]
+		via_socks5: Option<String>,
+
+		/// WebSocket path for ws and wss
+		#[arg(long, default_value = "/")]
+		ws_path: String,
+
+		/// fake HTTP header for http-prefix
+		#[arg(short, default_value = "conf/fake-req.txt")]
+		fake_header: String,
+
+		/// camouflage, one of none, http-prefix, bin-prefix, raw, has to match the other end
+		#[arg(long, default_value = "http-prefix")]
+		obfs: String,
+
+		/// no header in front of handshake messages at all, same as --obfs raw
+		/// for trusted networks, has to match the other end
+		#[arg(long, conflicts_with = "obfs")]
+		no_fake_header: bool,
+
+		/// this file as is in front of handshake messages, e.g. a TLS record header
+		/// same as --obfs bin-prefix -f file, has to match the other end
+		#[arg(long, conflicts_with_all = ["obfs", "no_fake_header"])]
+		fake_prefix_bin: Option<String>,
+
+		#[arg(long, value_enum, default_value_t = CipherKind::ChaCha20)]
+		cipher: CipherKind,
+
+		/// the server's Ed25519 public key, from gen-key-pair, responses not signed
+		/// with it are refused, for when the PSK is shared with many clients
+		#[arg(long)]
+		server_key: Option<String>,
+
+		/// sign requests with the Ed25519 key in this file, from gen-key-pair,
+		/// for servers that let in registered clients only
+		#[arg(long)]
+		client_key: Option<String>,
+	},
+
+	/// measure handshake and relay throughput over loopback
+	Bench {
+		#[arg(long, value_enum, default_value_t = CipherKind::ChaCha20)]
+		cipher: CipherKind,
+
+		/// ms to run each measurement
+		#[arg(short, default_value_t = 3000)]
+		duration: u64,
 	},
 
 	/// generate PSK
 	GenPSK,
+
+	/// generate an Ed25519 key pair, the private key for a --sign-key or --client-key file
+	/// goes to stdout, the public key for the other end to stderr
+	GenKeyPair,
+
+	/// check the hash chain of an audit log, from a server with --audit-chain
+	VerifyAudit {
+		/// audit log paths, with rotation the oldest first, e.g. log.2 log.1 log
+		#[arg(required = true)]
+		files: Vec<String>,
+	},
+
+	/// check that a PSK file holds a valid key
+	Check {
+		/// PSK file path
+		#[arg(short = 'k', default_value = "conf/psk")]
+		psk: String,
+	},
+}
+
+#[derive(clap::Args)]
+struct FrameArgs {
+	/// max random delay in ms before each data frame, 0 to disable
+	#[arg(long, default_value_t = 0)]
+	jitter: u64,
+
+	/// inject dummy frames after this many ms idle, 0 to disable
+	/// only used when both ends have it, the whole connection is framed then
+	#[arg(long, default_value_t = 0)]
+	dummy_interval: u64,
+
+	/// dummy frames per injection
+	#[arg(long, default_value_t = 1)]
+	dummy_burst: u8,
+
+	/// keep connections to the server for further requests, costs encrypting everything
+	/// only used when both ends have it
+	#[arg(long)]
+	reuse: bool,
+
+	/// pad each handshake message to exactly this many bytes instead of a random size
+	/// a message that doesn't fit fails its handshake, 0 to disable
+	#[arg(long, default_value_t = 0)]
+	pad_to: usize,
+
+	/// add a random length field to the fake header, so its size varies per handshake
+	#[arg(long)]
+	header_filler: bool,
+
+	/// declare a Content-Length in the fake header covering the rest of the message,
+	/// so it reads like a POST body follows
+	#[arg(long)]
+	content_length: bool,
+}
+
+impl FrameArgs {
+	fn opts(&self) -> FrameOpts {
+		FrameOpts {
+			jitter: Duration::from_millis(self.jitter),
+			dummy_interval: Duration::from_millis(self.dummy_interval),
+			dummy_burst: self.dummy_burst,
+			reuse: self.reuse,
+			pad_to: self.pad_to,
+			header_filler: self.header_filler,
+			content_length: self.content_length,
+			untyped: false,
+		}
+	}
+}
+
+#[derive(clap::Args)]
+struct StatsArgs {
+	/// seconds between handshake stats in the log, 0 to disable, SIGUSR1 logs them anytime
+	#[arg(long, default_value_t = 600)]
+	stats_interval: u64,
+
+	/// count bytes per destination host, for up to this many hosts, SIGUSR1 logs them
+	#[arg(long)]
+	host_stats: Option<usize>,
+
+	/// log the top N destinations by bytes and source IPs by connections
+	/// every stats interval and on SIGUSR1
+	#[arg(long)]
+	top_talkers: Option<usize>,
+
+	/// name for this server in spans and host reports, to tell several apart
+	#[arg(long)]
+	label: Option<String>,
+}
+
+impl StatsArgs {
+	fn apply(&self, mut conf: ServerConfig) -> ServerConfig {
+		conf = conf.stats_interval(Duration::from_secs(self.stats_interval));
+		if let Some(cap) = self.host_stats {
+			conf = conf.host_stats(cap);
+		}
+		if let Some(n) = self.top_talkers {
+			conf = conf.top_talkers(n);
+		}
+		if let Some(label) = &self.label {
+			conf = conf.label(label);
+		}
+		conf
+	}
+}
+
+fn obfs_by_args(
+	name: &str,
+	fake_header: &str,
+	no_fake_header: bool,
+	fake_prefix_bin: Option<&str>,
+) -> anyhow::Result<Box<dyn Obfuscator>> {
+	if let Some(path) = fake_prefix_bin {
+		return obfs::by_name("bin-prefix", path);
+	}
+	obfs::by_name(if no_fake_header { "raw" } else { name }, fake_header)
 }
 
 #[cfg(debug_assertions)]
@@ -60,134 +636,417 @@ const LOG_LEVEL: &str = "debug";
 #[cfg(not(debug_assertions))]
 const LOG_LEVEL: &str = "info";
 
-#[tokio::main(flavor = "current_thread")]
-async fn main() {
+fn init_stderr() {
+	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(LOG_LEVEL)).init();
+}
+
+fn main() {
 	let args = Args::parse();
 
-	env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(LOG_LEVEL)).init();
+	#[cfg(all(feature = "syslog", unix))]
+	if let Some(facility) = &args.syslog {
+		if let Err(e) = mint::init_syslog(facility, LOG_LEVEL) {
+			eprintln!("{:#}", e);
+			std::process::exit(1);
+		}
+	} else {
+		init_stderr();
+	}
+	#[cfg(not(all(feature = "syslog", unix)))]
+	init_stderr();
 
-	match &args.cmd {
+	mint::set_nonce_reseed(args.nonce_reseed);
+
+	// the service manager is told how it went too
+	#[cfg(all(feature = "service", windows))]
+	if args.service {
+		if let Err(e) = mint::run_service(move || start(&args)) {
+			error!("{:#}", e);
+			std::process::exit(1);
+		}
+		return;
+	}
+
+	// so supervisors can tell
+	if let Err(e) = start(&args) {
+		error!("{:#}", e);
+		std::process::exit(1);
+	}
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn start(args: &Args) -> anyhow::Result<()> {
+	#[cfg(feature = "otel")]
+	let otel = args.otlp.as_deref().map(mint::init_otlp).transpose()?;
+
+	#[cfg(feature = "console")]
+	if args.console {
+		mint::init_console()?;
+	}
+
+	let r = run(&args.cmd).await;
+	#[cfg(feature = "otel")]
+	if let Some(otel) = otel {
+		let _ = otel.shutdown();
+	}
+	r
+}
+
+async fn run(cmd: &Cmds) -> anyhow::Result<()> {
+	match cmd {
 		Cmds::Server {
 			psk,
 			listen,
 			fake_header,
+			obfs,
+			no_fake_header,
+			fake_prefix_bin,
+			server_names,
+			allow_ports,
+			deny_ports,
+			backlog,
+			ipv6_only,
+			dual_stack,
+			out_ttl,
+			out_dscp,
+			keepalive,
+			conn_rate,
+			max_memory,
+			proxy_protocol,
+			send_proxy_protocol,
+			ban_after,
+			ban_secs,
+			on_probe,
+			decoy,
+			probe_delay,
+			drain_secs,
+			stats,
+			health_addr,
+			resolver,
+			doh,
+			doh_fallback,
+			transport,
+			tls_cert,
+			tls_key,
+			ws_path,
+			cipher,
+			sign_key,
+			client_keys,
+			users,
+			audit_log,
+			audit_chain,
+			log_max_size,
+			log_daily,
+			log_max_files,
+			frame,
 		} => {
-			ls_run(server(psk, listen, &fake_header)).await;
+			let mut conf = ServerConfig::new(&read_psk(psk)?)
+				.listen(listen)
+				.listen_opts(ListenOpts {
+					backlog: *backlog,
+					v6only: match (ipv6_only, dual_stack) {
+						(true, _) => Some(true),
+						(_, true) => Some(false),
+						_ => None,
+					},
+					keepalive: keepalive.map(Duration::from_secs),
+				})
+				.obfs(obfs_by_args(
+					obfs,
+					fake_header,
+					*no_fake_header,
+					fake_prefix_bin.as_deref(),
+				)?)
+				.server_names(&server_names.iter().map(String::as_str).collect::<Vec<_>>())
+				.port_policy(PortPolicy {
+					allow: allow_ports.clone(),
+					deny: deny_ports.clone(),
+				})
+				.connect_opts(ConnectOpts {
+					ttl: *out_ttl,
+					dscp: *out_dscp,
+					keepalive: keepalive.map(Duration::from_secs),
+				})
+				.probe_delay(*probe_delay)
+				.drain(Duration::from_secs(*drain_secs))
+				.nameservers(resolver)
+				.transport(*transport)
+				.ws_path(ws_path)
+				.cipher(*cipher)
+				.frame(frame.opts())
+				.proxy_protocol(*proxy_protocol)
+				.send_proxy_protocol(*send_proxy_protocol);
+			if let Some(rate) = conn_rate {
+				conf = conf.conn_rate(*rate);
+			}
+			if let Some(mib) = max_memory {
+				conf = conf.max_memory(mib << 20);
+			}
+			if let Some(n) = ban_after {
+				conf = conf.ban(*n, Duration::from_secs(*ban_secs));
+			}
+			if let Some(mode) = on_probe {
+				conf = conf.on_probe(*mode);
+			}
+			if let Some(url) = decoy {
+				conf = conf.decoy(url);
+			}
+			if let Some(url) = doh {
+				conf = conf.doh(url, *doh_fallback);
+			}
+			conf = stats.apply(conf);
+			if let Some(addr) = health_addr {
+				conf = conf.health_addr(addr);
+			}
+			if let (Some(cert), Some(key)) = (tls_cert, tls_key) {
+				conf = conf.tls_files(cert, key);
+			}
+			if let Some(path) = sign_key {
+				conf = conf.sign_key(&read_key_pair(path)?);
+			}
+			if let Some(path) = client_keys {
+				for (name, key) in read_client_keys(path)? {
+					conf = conf.client_key(&name, &key);
+				}
+			}
+			if let Some(path) = users {
+				for (name, psk) in read_users(path)? {
+					conf = conf.user(&name, &psk);
+				}
+			}
+			if let Some(path) = audit_log {
+				conf = conf.audit_log(path, *audit_chain).audit_rotate(
+					*log_max_size,
+					*log_daily,
+					*log_max_files,
+				);
+			}
+			mint::run_server(conf.build()?).await
 		}
 		Cmds::Client {
 			psk,
 			listen,
 			server,
+			server_srv,
+			server_strategy,
+			health_interval,
+			server_ttl,
+			server_retries,
+			early_reply,
+			socks_auth,
+			require_auth,
+			prefer_no_auth,
+			udp,
+			keepalive,
+			transport,
+			sni,
+			via_http_proxy,
+			via_socks5,
+			ws_path,
 			fake_header,
+			header_rule,
+			obfs,
+			no_fake_header,
+			fake_prefix_bin,
+			cipher,
+			server_key,
+			client_key,
+			frame,
 		} => {
-			ls_run(client(psk, listen, server, &fake_header)).await;
+			let mut conf = ClientConfig::new(&read_psk(psk)?)
+				.listen(listen)
+				.server(server)
+				.health_interval(Duration::from_secs(*health_interval))
+				.server_ttl(Duration::from_secs(*server_ttl))
+				.server_retries(*server_retries)
+				.early_reply(*early_reply)
+				.transport(*transport)
+				.ws_path(ws_path)
+				.obfs(obfs_by_args(
+					obfs,
+					fake_header,
+					*no_fake_header,
+					fake_prefix_bin.as_deref(),
+				)?)
+				.cipher(*cipher)
+				.frame(frame.opts())
+				.udp(*udp);
+			match socks_auth.as_deref().map(|a| a.split_once(':')) {
+				None => {}
+				Some(Some((u, p))) => conf = conf.socks_auth(u, p),
+				Some(None) => bail!("--socks-auth should be user:pass"),
+			}
+			if *require_auth {
+				if socks_auth.is_none() {
+					bail!("--require-auth needs --socks-auth");
+				}
+				conf = conf.require_auth(true);
+			}
+			if *prefer_no_auth {
+				conf = conf.prefer_no_auth(true);
+			}
+			if let Some(secs) = keepalive {
+				conf = conf.keepalive(Duration::from_secs(*secs));
+			}
+			if let Some(sni) = sni {
+				conf = conf.sni(sni);
+			}
+			if let Some(url) = via_http_proxy {
+				conf = conf.via_http_proxy(url);
+			}
+			if let Some(addr) = via_socks5 {
+				conf = conf.via_socks5(addr);
+			}
+			if let Some(key) = server_key {
+				conf = conf.server_key(key);
+			}
+			if let Some(path) = client_key {
+				conf = conf.client_key(&read_key_pair(path)?);
+			}
+			if let Some(name) = server_srv {
+				conf = conf.server_srv(name);
+			}
+			if let Some(strategy) = server_strategy {
+				conf = conf.server_strategy(*strategy);
+			}
+			for rule in header_rule {
+				let Some((domain, path)) = rule.split_once('=') else {
+					bail!("--header-rule should be domain=file");
+				};
+				conf = conf.header_rule(domain, obfs::HttpPrefix::load(path)?.header());
+			}
+			mint::run_client(conf.build()?).await
+		}
+		Cmds::Resolve {
+			psk,
+			server,
+			transport,
+			sni,
+			ws_path,
+			fake_header,
+			obfs,
+			no_fake_header,
+			fake_prefix_bin,
+			cipher,
+			server_key,
+			client_key,
+			name,
+		} => {
+			let mut conf = ClientConfig::new(&read_psk(psk)?)
+				.server(server)
+				.server_ttl(Duration::ZERO)
+				.transport(*transport)
+				.ws_path(ws_path)
+				.obfs(obfs_by_args(
+					obfs,
+					fake_header,
+					*no_fake_header,
+					fake_prefix_bin.as_deref(),
+				)?)
+				.cipher(*cipher);
+			if let Some(sni) = sni {
+				conf = conf.sni(sni);
+			}
+			if let Some(key) = server_key {
+				conf = conf.server_key(key);
+			}
+			if let Some(path) = client_key {
+				conf = conf.client_key(&read_key_pair(path)?);
+			}
+			let client = conf.build()?;
+			for a in mint::resolve(&client, name).await? {
+				println!("{}", a);
+			}
+			Ok(())
+		}
+		Cmds::Ping {
+			psk,
+			server,
+			count,
+			transport,
+			sni,
+			via_http_proxy,
+			via_socks5,
+			ws_path,
+			fake_header,
+			obfs,
+			no_fake_header,
+			fake_prefix_bin,
+			cipher,
+			server_key,
+			client_key,
+		} => {
+			let mut conf = ClientConfig::new(&read_psk(psk)?)
+				.server(server)
+				.server_ttl(Duration::ZERO)
+				.transport(*transport)
+				.ws_path(ws_path)
+				.obfs(obfs_by_args(
+					obfs,
+					fake_header,
+					*no_fake_header,
+					fake_prefix_bin.as_deref(),
+				)?)
+				.cipher(*cipher);
+			if let Some(sni) = sni {
+				conf = conf.sni(sni);
+			}
+			if let Some(url) = via_http_proxy {
+				conf = conf.via_http_proxy(url);
+			}
+			if let Some(addr) = via_socks5 {
+				conf = conf.via_socks5(addr);
+			}
+			if let Some(key) = server_key {
+				conf = conf.server_key(key);
+			}
+			if let Some(path) = client_key {
+				conf = conf.client_key(&read_key_pair(path)?);
+			}
+			let client = conf.build()?;
+			let mut answered = 0;
+			for i in 0..*count {
+				if i > 0 {
+					tokio::time::sleep(Duration::from_secs(1)).await;
+				}
+				for (host, rtt) in mint::ping(&client).await? {
+					match rtt {
+						Ok(rtt) => {
+							answered += 1;
+							println!("{}: {:.2} ms", host, rtt.as_secs_f64() * 1000.0);
+						}
+						Err(e) => println!("{}: {}", host, e),
+					}
+				}
+			}
+			if answered == 0 {
+				bail!("no answer, check the server address and PSK");
+			}
+			Ok(())
+		}
+		Cmds::Bench { cipher, duration } => {
+			mint::run_bench(*cipher, Duration::from_millis(*duration)).await
 		}
 		Cmds::GenPSK => {
-			println!("{}", gen_psk::<Cipher>());
+			println!("{}", mint::gen_psk());
+			Ok(())
+		}
+		Cmds::GenKeyPair => {
+			let (private, public) = mint::gen_key_pair();
+			println!("{}", private);
+			eprintln!("public key: {}", public);
+			Ok(())
+		}
+		Cmds::VerifyAudit { files } => {
+			let files: Vec<_> = files.iter().map(String::as_str).collect();
+			let n = mint::verify_audit_log(&files)?;
+			println!("{}: {} lines, ok", files.join(" "), n);
+			Ok(())
+		}
+		Cmds::Check { psk } => {
+			read_psk(psk)?;
+			println!("{}: ok", psk);
+			Ok(())
 		}
 	}
 }
-
-// runs in local set
-async fn ls_run(f: impl Future) {
-	let ls = tokio::task::LocalSet::new();
-	ls.run_until(f).await;
-}
-
-async fn server(key: &str, listen: &str, fake_header: &str) -> Option<()> {
-	let fake_header = Rc::new(fake::get_fake_header(fake_header));
-	let cipher: Cipher = init_cipher(key)?;
-
-	let l = TcpListener::bind(listen).await.unwrap();
-	info!("listening on {}", l.local_addr().unwrap());
-
-	while let Ok((mut s, r_addr)) = l.accept().await {
-		let _ = s.set_nodelay(true);
-		let cipher = cipher.clone();
-		let fake_header = fake_header.clone();
-		tokio::task::spawn_local(async move {
-			let mut buf = BytesMut::with_capacity(0x500);
-			let Some((addr, port)) =
-				server_handshake(&mut s, &cipher, &mut buf, &fake_header).await
-			else {
-				return;
-			};
-			info!("{} -> {}:{}", r_addr, addr, port);
-			let Ok(mut u) = TcpStream::connect(&format!("{}:{}", addr, port))
-				.await
-				.map_err(|e| error!("error connecting to upstream: {}", e))
-			else {
-				return;
-			};
-			let _ = u.set_nodelay(true);
-			duplex(&cipher, &mut u, &mut s).await;
-			debug!("connection ended: {} -> {}:{}", r_addr, addr, port);
-		});
-	}
-
-	Some(())
-}
-
-async fn client(key: &str, listen: &str, upstream_str: &str, fake_header: &str) -> Option<()> {
-	let fake_header = Rc::new(fake::get_fake_header(fake_header));
-	let cipher: Cipher = init_cipher(key)?;
-
-	let upstream: Vec<SocketAddr> = lookup_host(upstream_str)
-		.await
-		.inspect_err(|e| error!("failed to lookup {}", upstream_str))
-		.ok()?
-		.collect();
-	if upstream.len() == 0 {
-		error!("lookup {} yields no result", upstream_str);
-		return None;
-	}
-	info!(
-		"server addr: {}",
-		&upstream
-			.iter()
-			.map(SocketAddr::to_string)
-			.reduce(|a, b| a + &b)
-			.unwrap()
-	);
-	let upstream = Rc::new(upstream);
-
-	let l = TcpListener::bind(listen).await.unwrap();
-	info!("listening on {}", l.local_addr().unwrap());
-
-	while let Ok((mut s, r_addr)) = l.accept().await {
-		let _ = s.set_nodelay(true);
-		let fake_header = fake_header.clone();
-		let cipher = cipher.clone();
-		let upstream = upstream.clone();
-		tokio::task::spawn_local(async move {
-			let mut buf = BytesMut::with_capacity(0x500);
-			let Some((addr, port)) = socks5::server_handshake(&mut s).await else {
-				return;
-			};
-			info!("{} -> {}:{}", r_addr, addr, port);
-			let Ok(mut u) = TcpStream::connect(&upstream as &[SocketAddr])
-				.await
-				.map_err(|e| error!("error connecting to upstream: {}", e))
-			else {
-				return;
-			};
-			let _ = u.set_nodelay(true);
-			let Some(()) = client_handshake(
-				&mut u,
-				&cipher,
-				&mut buf,
-				&addr.to_string(),
-				port,
-				&fake_header,
-			)
-			.await
-			else {
-				return;
-			};
-			duplex(&cipher, &mut s, &mut u).await;
-			debug!("connection ended: {} -> {}:{}", r_addr, addr, port);
-		});
-	}
-
-	Some(())
-}
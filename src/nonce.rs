@@ -0,0 +1,74 @@
+// where nonces come from, the OS every time by default, a syscall per message
+// or ChaCha20 seeded from the OS and reseeded after every so many bytes, per thread
+
+use std::{
+	cell::RefCell,
+	sync::atomic::{AtomicU64, Ordering},
+};
+
+use rand::{
+	RngCore, TryRngCore,
+	rngs::{OsRng, ReseedingRng},
+};
+use rand_chacha::ChaCha20Core;
+
+// bytes out of ChaCha20 before it's reseeded, as ThreadRng does, about 5000 96-bit nonces
+// the CLI default too
+pub const DEFAULT_RESEED: u64 = 0x10000;
+
+// 0 for the OS every time
+static RESEED: AtomicU64 = AtomicU64::new(0);
+
+type Fast = ReseedingRng<ChaCha20Core, OsRng>;
+
+thread_local! {
+	// with the threshold it was made with, made again if that changes
+	static FAST: RefCell<Option<(u64, Fast)>> = const { RefCell::new(None) };
+}
+
+/// generate nonces with ChaCha20 reseeded from the OS after every `reseed` bytes,
+/// rather than asking the OS for each one, None goes back to that
+///
+/// for high handshake rates, a nonce is still unpredictable either way
+pub fn set_nonce_reseed(reseed: Option<u64>) {
+	RESEED.store(reseed.map_or(0, |r| r.max(1)), Ordering::Relaxed);
+}
+
+pub fn fill(buf: &mut [u8]) {
+	fill_with(RESEED.load(Ordering::Relaxed), buf);
+}
+
+// reseed is as in RESEED
+pub fn fill_with(reseed: u64, buf: &mut [u8]) {
+	if reseed == 0 {
+		OsRng.unwrap_err().fill_bytes(buf);
+		return;
+	}
+	FAST.with_borrow_mut(|fast| {
+		if !matches!(fast, Some((r, _)) if *r == reseed) {
+			let rng = Fast::new(reseed, OsRng).expect("failed to seed from the OS");
+			*fast = Some((reseed, rng));
+		}
+		fast.as_mut().unwrap().1.fill_bytes(buf);
+	});
+}
+
+#[cfg(test)]
+mod test {
+	use std::collections::HashSet;
+
+	use super::*;
+
+	#[test]
+	fn test_unique() {
+		// small enough to reseed often along the way
+		for reseed in [0, 0x100, DEFAULT_RESEED] {
+			let mut seen = HashSet::new();
+			for _ in 0..0x40000 {
+				let mut nonce = [0u8; 12];
+				fill_with(reseed, &mut nonce);
+				assert!(seen.insert(nonce), "nonce repeated");
+			}
+		}
+	}
+}
@@ -0,0 +1,380 @@
+use std::{
+	fs, io,
+	pin::Pin,
+	task::{Context, Poll, ready},
+};
+
+use anyhow::{Context as _, bail};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use crate::{
+	fake::{self, EMPTY_HEADER},
+	proto::{MAX_HEADER, header_len},
+};
+
+// camouflage around the mint stream, both ends have to pick the same one
+pub trait Obfuscator {
+	// goes in front of every handshake message, has to end with the EOH unless verbatim
+	fn header(&self) -> &[u8];
+
+	// the header is no HTTP, the other end matches it byte for byte, it can't end with the EOH
+	fn verbatim(&self) -> bool {
+		false
+	}
+
+	// whether encode and decode do anything, saves a copy per write if not
+	fn transforms(&self) -> bool {
+		false
+	}
+
+	// pos is where buf starts in the stream, so partial writes stay in step
+	fn encode(&self, _pos: u64, _buf: &mut [u8]) {}
+
+	fn decode(&self, _pos: u64, _buf: &mut [u8]) {}
+}
+
+// just the EOH
+pub struct Plain;
+
+impl Obfuscator for Plain {
+	fn header(&self) -> &[u8] {
+		EMPTY_HEADER
+	}
+}
+
+// no header at all, saves the bytes and the EOH scan where cover isn't needed
+// not on the wire as anything, the other end has to be raw too
+pub struct Raw;
+
+impl Obfuscator for Raw {
+	fn header(&self) -> &[u8] {
+		b""
+	}
+
+	fn verbatim(&self) -> bool {
+		true
+	}
+}
+
+// a fake HTTP header in front of each message
+pub struct HttpPrefix(Vec<u8>);
+
+impl HttpPrefix {
+	// used as is, lines end with \r\n and it ends with an empty one
+	pub fn new(header: Vec<u8>) -> Self {
+		HttpPrefix(header)
+	}
+
+	pub fn load(path: &str) -> anyhow::Result<Self> {
+		fake::get_fake_header(path).map(HttpPrefix)
+	}
+}
+
+impl Obfuscator for HttpPrefix {
+	fn header(&self) -> &[u8] {
+		&self.0
+	}
+}
+
+// any bytes in front of each message, read from a file as is, e.g. a TLS record header
+// the other end needs the very same
+pub struct BinPrefix(Vec<u8>);
+
+impl BinPrefix {
+	pub fn load(path: &str) -> anyhow::Result<Self> {
+		let prefix = fs::read(path).with_context(|| format!("failed to read {}", path))?;
+		if prefix.is_empty() {
+			bail!("binary prefix in {} is empty, that's raw mode", path);
+		}
+		if prefix.ends_with(EMPTY_HEADER) {
+			bail!(
+				"binary prefix in {} ends with an empty line, it'd be taken for a fake header",
+				path
+			);
+		}
+		Ok(BinPrefix(prefix))
+	}
+}
+
+impl Obfuscator for BinPrefix {
+	fn header(&self) -> &[u8] {
+		&self.0
+	}
+
+	fn verbatim(&self) -> bool {
+		true
+	}
+}
+
+// raw mode for an empty header, whatever the obfuscator says
+pub fn verbatim(obfs: &dyn Obfuscator) -> bool {
+	obfs.verbatim() || obfs.header().is_empty()
+}
+
+// a fake header ends with the EOH, which is what the other end looks for,
+// a verbatim one can't, it'd be scanned too
+pub fn check_header(obfs: &dyn Obfuscator) -> anyhow::Result<()> {
+	let eoh = obfs.header().ends_with(EMPTY_HEADER);
+	if verbatim(obfs) && eoh {
+		bail!("a verbatim prefix can't end with an empty line");
+	}
+	if !verbatim(obfs) {
+		check_fake_header(obfs.header())?;
+	}
+	Ok(())
+}
+
+// ends with the EOH, and the other end still finds it with the fields added
+pub fn check_fake_header(header: &[u8]) -> anyhow::Result<()> {
+	if !header.ends_with(EMPTY_HEADER) {
+		bail!("fake header should end with an empty line");
+	}
+	if header_len(header) > MAX_HEADER {
+		bail!(
+			"fake header is {} bytes, {} with the fields added to it, peers only look for the end within {}",
+			header.len(),
+			header_len(header),
+			MAX_HEADER
+		);
+	}
+	Ok(())
+}
+
+pub const NAMES: &[&str] = &["none", "http-prefix", "bin-prefix", "raw"];
+
+// new ones go here, fake_header is only read by http-prefix and bin-prefix
+pub fn by_name(name: &str, fake_header: &str) -> anyhow::Result<Box<dyn Obfuscator>> {
+	match name {
+		"none" => Ok(Box::new(Plain)),
+		"http-prefix" => Ok(Box::new(HttpPrefix::load(fake_header)?)),
+		"bin-prefix" => Ok(Box::new(BinPrefix::load(fake_header)?)),
+		"raw" => Ok(Box::new(Raw)),
+		_ => bail!(
+			"unknown obfuscator {}, expecting one of {}",
+			name,
+			NAMES.join(", ")
+		),
+	}
+}
+
+// the raw stream with the obfuscator's transforms applied
+pub struct Obfuscated<'a, S> {
+	inner: S,
+	obfs: &'a dyn Obfuscator,
+	rx: u64,
+	tx: u64,
+	wbuf: Vec<u8>,
+}
+
+impl<'a, S> Obfuscated<'a, S> {
+	pub fn new(inner: S, obfs: &'a dyn Obfuscator) -> Self {
+		Obfuscated {
+			inner,
+			obfs,
+			rx: 0,
+			tx: 0,
+			wbuf: Vec::new(),
+		}
+	}
+
+	// the raw stream, bypassing the transforms
+	pub fn get_mut(&mut self) -> &mut S {
+		&mut self.inner
+	}
+
+	// to park the stream between uses, from_parts picks up where it left off
+	pub fn into_parts(self) -> (S, u64, u64) {
+		(self.inner, self.rx, self.tx)
+	}
+
+	pub fn from_parts(inner: S, obfs: &'a dyn Obfuscator, rx: u64, tx: u64) -> Self {
+		Obfuscated {
+			rx,
+			tx,
+			..Self::new(inner, obfs)
+		}
+	}
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Obfuscated<'_, S> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		let start = buf.filled().len();
+		ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+		if this.obfs.transforms() {
+			let got = &mut buf.filled_mut()[start..];
+			this.obfs.decode(this.rx, got);
+			this.rx += got.len() as u64;
+		}
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Obfuscated<'_, S> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let this = self.get_mut();
+		if !this.obfs.transforms() {
+			return Pin::new(&mut this.inner).poll_write(cx, buf);
+		}
+		// encoded again on a retry, pos keeps it the same
+		this.wbuf.clear();
+		this.wbuf.extend_from_slice(buf);
+		this.obfs.encode(this.tx, &mut this.wbuf);
+		let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &this.wbuf))?;
+		this.tx += n as u64;
+		Poll::Ready(Ok(n))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use bytes::BytesMut;
+	use chacha20poly1305::{ChaCha20Poly1305, KeyInit, aead::OsRng};
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	use super::*;
+	use crate::proto::*;
+
+	fn init() {
+		let _ = env_logger::builder().is_test(true).try_init();
+	}
+
+	struct Xor(&'static [u8]);
+
+	impl Obfuscator for Xor {
+		fn header(&self) -> &[u8] {
+			EMPTY_HEADER
+		}
+
+		fn transforms(&self) -> bool {
+			true
+		}
+
+		fn encode(&self, pos: u64, buf: &mut [u8]) {
+			for (i, b) in buf.iter_mut().enumerate() {
+				*b ^= self.0[(pos as usize + i) % self.0.len()];
+			}
+		}
+
+		fn decode(&self, pos: u64, buf: &mut [u8]) {
+			self.encode(pos, buf)
+		}
+	}
+
+	#[tokio::test]
+	async fn test_xor() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let xor = Xor(b"mint");
+		let (c, s) = tokio::io::duplex(0x1000);
+		let (mut c, mut s) = (Obfuscated::new(c, &xor), Obfuscated::new(s, &xor));
+
+		tokio::join!(
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				let r = client_handshake(
+					&mut c,
+					&cipher,
+					&mut buf,
+					"example.com",
+					443,
+					xor.header(),
+					0,
+				);
+				assert_eq!(r.await, Some(0));
+				c.write_all(b"hello").await.unwrap();
+			},
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				let req = server_handshake(&mut s, &cipher, &mut buf, xor.header())
+					.await
+					.unwrap();
+				assert_eq!((req.host.as_str(), req.port), ("example.com", 443));
+				server_reply(&mut s, &cipher, &mut buf, xor.header(), REP_OK, 0)
+					.await
+					.unwrap();
+				let mut hello = [0; 5];
+				s.read_exact(&mut hello).await.unwrap();
+				assert_eq!(&hello, b"hello");
+			}
+		);
+	}
+
+	#[test]
+	fn test_bin_prefix() {
+		let path = std::env::temp_dir().join(format!("mint-prefix-{}.bin", std::process::id()));
+		let load = |content: &[u8]| {
+			std::fs::write(&path, content).unwrap();
+			BinPrefix::load(path.to_str().unwrap())
+		};
+		// not UTF-8, line ends left alone
+		let prefix = b"\x16\x03\x01\x02\x00\r\n\n\xc0\xff";
+		let obfs = load(prefix).unwrap();
+		assert_eq!(obfs.header(), prefix);
+		assert!(check_header(&obfs).is_ok());
+		assert!(load(b"").is_err());
+		assert!(load(b"\x16\x03\x01\r\n\r\n").is_err());
+		std::fs::remove_file(&path).unwrap();
+
+		assert!(check_header(&HttpPrefix::new(b"\x16\x03\x01".to_vec())).is_err());
+		assert!(check_header(&Raw).is_ok());
+
+		// under MAX_HEADER, but not with Date, Server and Content-Length added
+		let long = format!("HTTP/1.1 200 OK\r\nX-Pad: {}\r\n\r\n", "a".repeat(440));
+		assert!(check_header(&HttpPrefix::new(long.into_bytes())).is_err());
+	}
+
+	#[tokio::test]
+	async fn test_mismatch() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let xor = Xor(b"mint");
+		let (c, mut s) = tokio::io::duplex(0x1000);
+		let mut c = Obfuscated::new(c, &xor);
+
+		// no EOH to be found in the plain
+		let (r, _) = tokio::join!(
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				client_handshake(
+					&mut c,
+					&cipher,
+					&mut buf,
+					"example.com",
+					443,
+					xor.header(),
+					0,
+				)
+				.await
+			},
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				assert!(
+					server_handshake(&mut s, &cipher, &mut buf, Plain.header())
+						.await
+						.is_err()
+				);
+				drop(s);
+			}
+		);
+		assert!(r.is_none());
+	}
+}
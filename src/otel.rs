@@ -0,0 +1,25 @@
+use anyhow::Context;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::{Resource, trace::SdkTracerProvider};
+use tracing_subscriber::layer::SubscriberExt;
+
+/// exports the server's per-connection spans to an OTLP/HTTP collector,
+/// e.g. `http://localhost:4318/v1/traces`
+///
+/// spans are batched, shut the returned provider down before exiting to flush them
+pub fn init_otlp(endpoint: &str) -> anyhow::Result<SdkTracerProvider> {
+	let exporter = SpanExporter::builder()
+		.with_http()
+		.with_endpoint(endpoint)
+		.build()
+		.context("failed to set up the OTLP exporter")?;
+	let provider = SdkTracerProvider::builder()
+		.with_batch_exporter(exporter)
+		.with_resource(Resource::builder().with_service_name("mint").build())
+		.build();
+	let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("mint"));
+	tracing::subscriber::set_global_default(tracing_subscriber::registry().with(layer))
+		.context("a tracing subscriber is set already")?;
+	Ok(provider)
+}
@@ -0,0 +1,92 @@
+use std::{ops::RangeInclusive, str::FromStr};
+
+// comma separated ports or ranges, e.g. 80,443,1024-65535
+#[derive(Debug, Clone, Default)]
+pub struct PortList(Vec<RangeInclusive<u16>>);
+
+impl PortList {
+	pub fn contains(&self, port: u16) -> bool {
+		self.0.iter().any(|r| r.contains(&port))
+	}
+}
+
+impl FromStr for PortList {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let port = |p: &str| {
+			p.trim()
+				.parse::<u16>()
+				.map_err(|e| format!("invalid port \"{}\": {}", p, e))
+		};
+		let mut res = Vec::new();
+		for i in s.split(',').filter(|i| !i.trim().is_empty()) {
+			let r = match i.split_once('-') {
+				Some((a, b)) => port(a)?..=port(b)?,
+				None => port(i)?..=port(i)?,
+			};
+			if r.is_empty() {
+				return Err(format!("invalid range \"{}\"", i));
+			}
+			res.push(r);
+		}
+		Ok(PortList(res))
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct PortPolicy {
+	pub allow: Option<PortList>,
+	pub deny: Option<PortList>,
+}
+
+impl PortPolicy {
+	pub fn allows(&self, port: u16) -> bool {
+		self.allow.as_ref().is_none_or(|l| l.contains(port))
+			&& !self.deny.as_ref().is_some_and(|l| l.contains(port))
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_allow() {
+		let p = PortPolicy {
+			allow: Some("80,443".parse().unwrap()),
+			deny: None,
+		};
+		assert!(p.allows(80));
+		assert!(p.allows(443));
+		assert!(!p.allows(25));
+	}
+
+	#[test]
+	fn test_deny() {
+		let p = PortPolicy {
+			allow: None,
+			deny: Some("25, 465,587".parse().unwrap()),
+		};
+		assert!(!p.allows(25));
+		assert!(!p.allows(587));
+		assert!(p.allows(443));
+	}
+
+	#[test]
+	fn test_range() {
+		let p = PortPolicy {
+			allow: Some("443,1024-65535".parse().unwrap()),
+			deny: Some("8000-8999".parse().unwrap()),
+		};
+		assert!(p.allows(443));
+		assert!(p.allows(1024));
+		assert!(p.allows(65535));
+		assert!(!p.allows(1023));
+		assert!(!p.allows(8080));
+
+		assert!("2000-1000".parse::<PortList>().is_err());
+		assert!("80,http".parse::<PortList>().is_err());
+		assert!("65536".parse::<PortList>().is_err());
+	}
+}
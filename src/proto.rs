@@ -1,14 +1,242 @@
-use aead::{AeadCore, AeadInPlace, KeyInit, Nonce, OsRng as AeadOsRng};
+use std::{
+	fmt, io,
+	net::IpAddr,
+	ops::RangeInclusive,
+	slice,
+	time::{Duration, SystemTime},
+};
+
+use aead::{AeadCore, AeadInPlace, KeyInit, Nonce, Tag};
 use bytes::{BufMut, BytesMut};
 use log::*;
-use rand::{Rng as _, TryRngCore as _, rngs::OsRng};
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, copy, split};
+use rand::{Rng as _, TryRngCore as _, distr::Alphanumeric, rngs::OsRng};
+use ring::signature::{ED25519, Ed25519KeyPair, KeyPair as _, UnparsedPublicKey};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, copy, split},
+	time::{sleep, timeout},
+};
+
+use crate::{fake, nonce, stats::HANDSHAKES};
 
 const EOH: &[u8] = b"\r\n\r\n";
+// the EOH has to be within this many bytes, so garbage can't make us scan forever
+pub const MAX_HEADER: usize = 0x200;
+
+// the newest we speak, and the only one we send, a reply comes in the layout of its request
+const VER: u8 = VER_CMD;
+// host and port, connect only, the reply has no features, all there was before them
+const VER_PLAIN: u8 = 0;
+// features and a 2 bytes host length, still connect only
+const VER_FEATURES: u8 = 1;
+// CMD before the features
+const VER_CMD: u8 = 2;
+
+pub const REP_OK: u8 = 0;
+const REP_BAD_PORT: u8 = 1;
+pub const REP_PORT_DENIED: u8 = 2;
+const REP_DNS_FAILED: u8 = 3;
+pub const REP_DENIED: u8 = 4;
+const REP_BAD_CMD: u8 = 5;
+
+pub const CMD_CONNECT: u8 = 0;
+// resolve host on the server, port is ignored
+pub const CMD_DNS: u8 = 1;
+// SOCKS5 UDP ASSOCIATE, host and port are ignored, the datagrams follow in the stream
+pub const CMD_UDP: u8 = 2;
+// reserved for SOCKS5 BIND, refused until it has a handler
+pub const CMD_BIND: u8 = 3;
+// a health check, answered right away, host and port are ignored
+pub const CMD_PING: u8 = 4;
+
+// first byte of every encrypted data frame
+const FRAME_DATA: u8 = 0;
+const FRAME_DUMMY: u8 = 1;
+// this direction of the session is done, only sent with FEAT_REUSE
+const FRAME_END: u8 = 2;
+
+// plain bytes per data frame at most, one frame is all a direction holds at a time,
+// nothing more is read until it's written, so a slow peer holds the other side back
+const FRAME_READ: usize = 0x1000;
+
+// knobs for the framed part of the data stream
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameOpts {
+	// max random delay before writing each frame, zero disables it
+	pub jitter: Duration,
+	// idle time before injecting dummy frames, zero disables it
+	// once agreed on, the stream stays framed to the end, or an idle one would get none
+	pub dummy_interval: Duration,
+	// dummy frames per injection
+	pub dummy_burst: u8,
+	// frame sessions to the end, so the connection can carry another request after
+	pub reuse: bool,
+	// pad each handshake message to exactly this many bytes, zero for random padding
+	// not negotiated, each end pads what it sends
+	pub pad_to: usize,
+	// a random length field in the fake header, so its size varies per message
+	pub header_filler: bool,
+	// a Content-Length in the fake header, covering the rest of the message
+	pub content_length: bool,
+	// frames carry no type byte, for a VER_PLAIN request, from before there was one
+	pub untyped: bool,
+}
+
+// feature flags, the server echoes what both sides support
+// dummy frames, only injected when both ends are configured for it
+pub const FEAT_DUMMY: u8 = 1;
+// sessions framed to a marked end, then another request
+pub const FEAT_REUSE: u8 = 2;
+// the request is signed with the client's own key, never agreed on, the server either
+// checks it or takes the signature for padding
+pub const FEAT_CLIENT_KEY: u8 = 4;
+// client data follows the request, for the upstream before anything relayed, never agreed on
+// a server that doesn't know it takes the data for padding, it's only for servers that do
+pub const FEAT_EARLY: u8 = 8;
+
+// how far off a signed request's time may be, the server remembers signatures this long
+pub const CLIENT_SIG_WINDOW: u64 = 60;
+
+impl FrameOpts {
+	pub fn features(&self) -> u8 {
+		let mut f = 0;
+		if !self.dummy_interval.is_zero() {
+			f |= FEAT_DUMMY;
+		}
+		if self.reuse {
+			f |= FEAT_REUSE;
+		}
+		f
+	}
+
+	// the session of a request that came in this layout
+	pub fn in_ver(mut self, ver: u8) -> Self {
+		self.untyped = ver == VER_PLAIN;
+		self
+	}
+
+	// turn off whatever didn't make it through negotiation
+	pub fn negotiated(mut self, features: u8) -> Self {
+		if features & FEAT_DUMMY == 0 {
+			self.dummy_interval = Duration::ZERO;
+		}
+		if features & FEAT_REUSE == 0 {
+			self.reuse = false;
+		}
+		self
+	}
+
+	// how our handshake messages look with this header
+	pub fn wire<'a>(&self, header: &'a [u8]) -> Wire<'a> {
+		Wire {
+			header,
+			pad_to: self.pad_to,
+			filler: self.header_filler,
+			content_length: self.content_length,
+			servers: &[],
+			signer: None,
+			challenge: &[],
+			server_key: None,
+			client_key: None,
+			ver: VER,
+		}
+	}
+}
+
+// how a handshake message looks on the wire, apart from what it carries
+#[derive(Debug, Clone, Copy)]
+pub struct Wire<'a> {
+	// fake header, ends with the EOH, or a verbatim prefix, empty in raw mode
+	pub header: &'a [u8],
+	// exact message length, header included, zero for random padding
+	pub pad_to: usize,
+	// add a random length field to the header
+	pub filler: bool,
+	// say how many bytes follow the header, like a POST body
+	pub content_length: bool,
+	// Server values a status line without one picks from, the built-in ones if empty
+	pub servers: &'a [String],
+	// the server signs each Resp with this, over challenge, the nonce of the request it answers
+	pub signer: Option<&'a Ed25519KeyPair>,
+	pub challenge: &'a [u8],
+	// the client takes only a Resp signed with this, the server's Ed25519 public key
+	pub server_key: Option<&'a [u8]>,
+	// the client signs its requests with this, for servers that know it
+	pub client_key: Option<&'a Ed25519KeyPair>,
+	// the layout the server answers in, the request's
+	pub ver: u8,
+}
+
+impl<'a> Wire<'a> {
+	// a header without the EOH isn't scanned for it but matched byte for byte,
+	// the nonce comes right after, both ends have to agree on it
+	// raw mode is an empty one
+	pub fn prefix(&self) -> Option<&'a [u8]> {
+		(!self.header.ends_with(EOH)).then_some(self.header)
+	}
+}
+
+impl<'a> From<&'a [u8]> for Wire<'a> {
+	fn from(header: &'a [u8]) -> Self {
+		Wire {
+			header,
+			pad_to: 0,
+			filler: false,
+			content_length: false,
+			servers: &[],
+			signer: None,
+			challenge: &[],
+			server_key: None,
+			client_key: None,
+			ver: VER,
+		}
+	}
+}
+
+impl<'a, const N: usize> From<&'a [u8; N]> for Wire<'a> {
+	fn from(header: &'a [u8; N]) -> Self {
+		Wire {
+			header,
+			pad_to: 0,
+			filler: false,
+			content_length: false,
+			servers: &[],
+			signer: None,
+			challenge: &[],
+			server_key: None,
+			client_key: None,
+			ver: VER,
+		}
+	}
+}
+
+// a decoded request, as seen by the server
+#[derive(Debug, PartialEq, Eq)]
+pub struct Request {
+	// the layout it came in, the reply has to be in the same one
+	pub ver: u8,
+	pub cmd: u8,
+	pub host: String,
+	pub port: u16,
+	// offered by the client
+	pub features: u8,
+	// of the message it came in, a signed reply covers it
+	pub nonce: Vec<u8>,
+	// the client's key, if it signed the request, the signature is checked already
+	pub client: Option<ClientSig>,
+	// with FEAT_EARLY, see write_first
+	pub early: Vec<u8>,
+}
 
-const VER: u8 = 0;
-const REP_OK: u8 = 0;
+// a client's signature on its request, for the server to look the key up
+#[derive(Debug, PartialEq, Eq)]
+pub struct ClientSig {
+	// unix time, in seconds, as the client put it
+	pub time: u64,
+	pub key: Vec<u8>,
+	pub sig: Vec<u8>,
+}
 
+// returns the agreed features
 pub async fn client_handshake<
 	T: AsyncRead + AsyncWrite + Unpin,
 	C: KeyInit + AeadCore + AeadInPlace,
@@ -18,370 +246,2824 @@ pub async fn client_handshake<
 	buf: &mut BytesMut,
 	host: &str,
 	port: u16,
-	header: &[u8],
-) -> Option<()> {
+	wire: impl Into<Wire<'_>>,
+	features: u8,
+) -> Option<u8> {
+	client_request(io, cipher, buf, host, port, wire, features)
+		.await?
+		.map_err(|rep| debug!("server replies 0x{:02x}, unexpected", rep))
+		.ok()
+}
+
+// like client_handshake, but a refusal is Err with the reply for the caller to report
+pub async fn client_request<
+	T: AsyncRead + AsyncWrite + Unpin,
+	C: KeyInit + AeadCore + AeadInPlace,
+>(
+	io: &mut T,
+	cipher: &C,
+	buf: &mut BytesMut,
+	host: &str,
+	port: u16,
+	wire: impl Into<Wire<'_>>,
+	features: u8,
+) -> Option<Result<u8, u8>> {
+	if port == 0 {
+		error!("refusing to request port 0 of {}", host);
+		return None;
+	}
+	let req = Req {
+		features,
+		..Req::connect(host, port)
+	};
+	request(io, cipher, buf, wire.into(), &req).await
+}
+
+// a UDP association, the stream carries datagrams once it's agreed on
+pub async fn client_udp<T: AsyncRead + AsyncWrite + Unpin, C: KeyInit + AeadCore + AeadInPlace>(
+	io: &mut T,
+	cipher: &C,
+	buf: &mut BytesMut,
+	wire: impl Into<Wire<'_>>,
+	features: u8,
+) -> Option<Result<u8, u8>> {
+	let req = Req {
+		cmd: CMD_UDP,
+		features,
+		..Req::connect("", 0)
+	};
+	request(io, cipher, buf, wire.into(), &req).await
+}
+
+async fn request<T: AsyncRead + AsyncWrite + Unpin, C: KeyInit + AeadCore + AeadInPlace>(
+	io: &mut T,
+	cipher: &C,
+	buf: &mut BytesMut,
+	wire: Wire<'_>,
+	req: &Req<'_>,
+) -> Option<Result<u8, u8>> {
 	buf.clear();
-	write_msg(buf, cipher, header, &Req(host, port));
+	let nonce = write_req(buf, cipher, wire, req)?;
 	io.write_all(buf)
 		.await
 		.map_err(|e| debug!("handshake error writing: {}", e))
 		.ok()?;
 
+	let resp = match wire.server_key {
+		None => recv_msg::<_, _, Resp>(io, buf, cipher, wire.prefix())
+			.await?
+			.ok()?,
+		Some(key) => {
+			let SignedResp(resp, sig) = recv_msg(io, buf, cipher, wire.prefix()).await?.ok()?;
+			let signed = resp_signed(&nonce, &resp);
+			if UnparsedPublicKey::new(&ED25519, key)
+				.verify(&signed, sig)
+				.is_err()
+			{
+				// anyone with the PSK gets this far
+				error!("server response isn't signed with the server key, not our server");
+				return None;
+			}
+			resp
+		}
+	};
+
+	if resp.rep != REP_OK {
+		return Some(Err(resp.rep));
+	}
+
+	if resp.features & !req.features != 0 {
+		debug!(
+			"server agrees on features 0x{:02x} never offered",
+			resp.features
+		);
+		return None;
+	}
+
+	Some(Ok(resp.features))
+}
+
+// Some if the server answers at all, servers without CMD_PING refuse it, still alive
+pub async fn client_ping<T: AsyncRead + AsyncWrite + Unpin, C: KeyInit + AeadCore + AeadInPlace>(
+	io: &mut T,
+	cipher: &C,
+	buf: &mut BytesMut,
+	wire: impl Into<Wire<'_>>,
+) -> Option<Result<(), MsgError>> {
+	let wire = wire.into();
 	buf.clear();
-	io.read_buf(buf)
+	write_req(
+		buf,
+		cipher,
+		wire,
+		&Req {
+			cmd: CMD_PING,
+			..Req::connect("", 0)
+		},
+	)?;
+	io.write_all(buf)
 		.await
-		.map_err(|e| debug!("handshake error reading: {}", e))
+		.map_err(|e| debug!("handshake error writing: {}", e))
 		.ok()?;
-	let Some(resp): Option<Resp> = read_msg(buf, cipher) else {
-		return None;
+
+	// whatever came back, even if it isn't an answer, says something about the server
+	let r: Result<Resp, _> = recv_msg(io, buf, cipher, wire.prefix()).await?;
+	Some(r.map(|_| ()))
+}
+
+// why server_handshake came back without a request
+#[derive(Debug, PartialEq, Eq)]
+pub enum Rejected {
+	// not a request of ours, or nothing to read, buf holds what came in
+	NotOurs,
+	// ours but unusable, the error reply is sent already
+	Replied,
+}
+
+// reads the request, the caller is expected to check it then server_reply
+pub async fn server_handshake<
+	T: AsyncRead + AsyncWrite + Unpin,
+	C: KeyInit + AeadCore + AeadInPlace,
+>(
+	io: &mut T,
+	cipher: &C,
+	buf: &mut BytesMut,
+	wire: impl Into<Wire<'_>>,
+) -> Result<Request, Rejected> {
+	let (_, req) = server_handshake_any(io, slice::from_ref(cipher), buf, wire).await?;
+	Ok(req)
+}
+
+// like server_handshake, with whichever of the ciphers the request is under,
+// returns its index, the rest of the connection is under that one too
+pub async fn server_handshake_any<
+	T: AsyncRead + AsyncWrite + Unpin,
+	C: KeyInit + AeadCore + AeadInPlace,
+>(
+	io: &mut T,
+	ciphers: &[C],
+	buf: &mut BytesMut,
+	wire: impl Into<Wire<'_>>,
+) -> Result<(usize, Request), Rejected> {
+	let wire = wire.into();
+	let (offset, key) = match recv_msg_at(io, buf, ciphers, wire.prefix()).await {
+		Some(Ok(at)) => at,
+		Some(Err(e)) => {
+			HANDSHAKES.failed(e);
+			return Err(Rejected::NotOurs);
+		}
+		None => return Err(Rejected::NotOurs),
+	};
+	let Some(req) = Req::read(&buf[offset..]) else {
+		HANDSHAKES.failed(MsgError::Invalid);
+		return Err(Rejected::NotOurs);
 	};
 
-	if resp.0 != REP_OK {
-		debug!("server replies 0x{:02x}, unexpected", resp.0);
-		return None;
+	// whether the client is known is up to the caller, not whether it signed right
+	let forged = req.auth.as_ref().is_some_and(|a| !a.check(unix_time()));
+	let req = Request {
+		ver: req.ver,
+		cmd: req.cmd,
+		host: req.host.to_owned(),
+		port: req.port,
+		features: req.features,
+		nonce: buf[offset - nonce_size::<C>()..offset].to_vec(),
+		client: req.auth.map(|a| ClientSig {
+			time: a.time,
+			key: a.key.to_vec(),
+			sig: a.sig.to_vec(),
+		}),
+		early: req.early.to_vec(),
+	};
+	let wire = Wire {
+		challenge: &req.nonce,
+		ver: req.ver,
+		..wire
+	};
+	if forged {
+		HANDSHAKES.failed(MsgError::Invalid);
+		let _ = server_reply(io, &ciphers[key], buf, wire, REP_DENIED, 0).await;
+		return Err(Rejected::Replied);
+	}
+
+	// the caller has a handler for each of these
+	let refuse = match req.cmd {
+		CMD_CONNECT if req.port == 0 => {
+			debug!("client requests port 0 of {}, refusing", req.host);
+			Some(REP_BAD_PORT)
+		}
+		CMD_CONNECT | CMD_DNS | CMD_PING | CMD_UDP => None,
+		CMD_BIND => {
+			debug!("cmd 0x{:02x} isn't supported yet", req.cmd);
+			Some(REP_BAD_CMD)
+		}
+		cmd => {
+			debug!("unknown cmd: 0x{:02x}", cmd);
+			Some(REP_BAD_CMD)
+		}
+	};
+	if let Some(rep) = refuse {
+		HANDSHAKES.failed(MsgError::Invalid);
+		let _ = server_reply(io, &ciphers[key], buf, wire, rep, 0).await;
+		return Err(Rejected::Replied);
 	}
 
-	Some(())
+	// debug!("buf capacity: {}", buf.capacity());
+	HANDSHAKES.ok();
+	Ok((key, req))
 }
 
-pub async fn server_handshake<
+pub async fn server_reply<T: AsyncWrite + Unpin, C: AeadCore + AeadInPlace>(
+	io: &mut T,
+	cipher: &C,
+	buf: &mut BytesMut,
+	wire: impl Into<Wire<'_>>,
+	rep: u8,
+	features: u8,
+) -> Option<()> {
+	let wire = wire.into();
+	let resp = Resp {
+		ver: wire.ver,
+		rep,
+		features,
+	};
+	buf.clear();
+	match wire.signer {
+		Some(key) => {
+			let sig = key.sign(&resp_signed(wire.challenge, &resp));
+			write_msg(buf, cipher, wire, &SignedResp(resp, sig.as_ref()))?
+		}
+		None => write_msg(buf, cipher, wire, &resp)?,
+	};
+	io.write_all(buf)
+		.await
+		.map_err(|e| debug!("handshake error writing: {}", e))
+		.ok()
+}
+
+// ask the server to resolve host, Err is the server's reply code if it didn't
+pub async fn client_resolve<
 	T: AsyncRead + AsyncWrite + Unpin,
 	C: KeyInit + AeadCore + AeadInPlace,
 >(
 	io: &mut T,
 	cipher: &C,
 	buf: &mut BytesMut,
-	header: &[u8],
-) -> Option<(String, u16)> {
+	host: &str,
+	wire: impl Into<Wire<'_>>,
+) -> Option<Result<Vec<IpAddr>, u8>> {
+	let wire = wire.into();
 	buf.clear();
-	io.read_buf(buf)
+	write_req(
+		buf,
+		cipher,
+		wire,
+		&Req {
+			cmd: CMD_DNS,
+			..Req::connect(host, 0)
+		},
+	)?;
+	io.write_all(buf)
 		.await
-		.map_err(|e| debug!("handshake error reading: {}", e))
+		.map_err(|e| debug!("handshake error writing: {}", e))
 		.ok()?;
-	let Some(req): Option<Req> = read_msg(buf, cipher) else {
-		return None;
-	};
 
-	let host = req.0.to_owned();
-	let port = req.1;
+	let resp: DnsResp = recv_msg(io, buf, cipher, wire.prefix()).await?.ok()?;
+
+	if resp.0 != REP_OK {
+		debug!("server failed to resolve {}: 0x{:02x}", host, resp.0);
+		return Some(Err(resp.0));
+	}
+
+	Some(Ok(resp.1))
+}
 
+// answer a CMD_DNS request, empty addrs means it didn't resolve
+pub async fn server_dns_reply<T: AsyncWrite + Unpin, C: AeadCore + AeadInPlace>(
+	io: &mut T,
+	cipher: &C,
+	buf: &mut BytesMut,
+	wire: impl Into<Wire<'_>>,
+	addrs: &[IpAddr],
+) -> Option<()> {
+	let rep = if addrs.is_empty() {
+		REP_DNS_FAILED
+	} else {
+		REP_OK
+	};
+	// keep it within a single packet
+	let addrs = &addrs[..addrs.len().min(16)];
 	buf.clear();
-	write_msg(buf, cipher, header, &Resp(REP_OK));
+	write_msg(buf, cipher, wire, &DnsResp(rep, addrs.to_vec()))?;
 	io.write_all(buf)
 		.await
 		.map_err(|e| debug!("handshake error writing: {}", e))
-		.ok()?;
+		.ok()
+}
 
-	// debug!("buf capacity: {}", buf.capacity());
-	Some((host, port))
+// what the upstream gets before anything relayed, a PROXY header then the early data,
+// in a single write, the relay goes on from what the client sends after the request
+pub async fn write_first<U: AsyncWrite + Unpin>(
+	upstream: &mut U,
+	head: &[u8],
+	early: &[u8],
+) -> io::Result<()> {
+	if head.is_empty() && early.is_empty() {
+		return Ok(());
+	}
+	upstream.write_all(&[head, early].concat()).await
 }
 
 // can't be implemented on BufMut since we want encrypt in place
-fn write_msg<'a, C: AeadCore + AeadInPlace>(
+// the body goes first, the header may have to say how long it is
+// returns the nonce, None if the message doesn't fit in wire.pad_to
+fn write_msg<'a, 'w, C: AeadCore + AeadInPlace>(
 	buf: &mut BytesMut,
 	cipher: &C,
-	header: &[u8],
+	wire: impl Into<Wire<'w>>,
 	payload: &impl Payload<'a>,
-) {
-	buf.put_slice(header);
+) -> Option<Nonce<C>> {
+	let wire = wire.into();
+	let start = buf.len();
 
-	let nonce = C::generate_nonce(&mut AeadOsRng);
+	let nonce = fresh_nonce::<C>();
 	buf.put_slice(&nonce);
 
 	let payload_offset = buf.len();
 
 	payload.write(&mut *buf);
 
-	// padding
-	buf.put_bytes(
-		OsRng.unwrap_err().random(),
-		OsRng.unwrap_err().random_range(0x200..0x300),
-	);
+	// padding, Payload::read ignores it
+	let head = Head::new(&wire);
+	let fixed = buf.len() - start + tag_size::<C>();
+	let (body, width) = match wire.pad_to {
+		0 => {
+			let body = fixed + OsRng.unwrap_err().random_range(0x200..0x300);
+			(body, digits(body))
+		}
+		n => match head.fit(n) {
+			Some((body, width)) if body >= fixed => (body, width),
+			_ => {
+				let len = head.len(digits(fixed)) + fixed;
+				error!("handshake message is {} bytes, can't pad to {}", len, n);
+				buf.truncate(start);
+				return None;
+			}
+		},
+	};
+	buf.put_bytes(OsRng.unwrap_err().random(), body - fixed);
 
 	let mut payload = buf.split_off(payload_offset);
 
 	cipher.encrypt_in_place(&nonce, b"", &mut payload).unwrap();
 
 	buf.unsplit(payload);
+
+	let body = buf.split_off(start);
+	head.write(buf, body.len(), width);
+	buf.unsplit(body);
+	Some(nonce)
 }
 
-fn read_msg<'a, C: AeadCore + AeadInPlace, T: Payload<'a>>(
-	buf: &'a mut BytesMut,
-	cipher: &C,
-) -> Option<T> {
-	let Some(eoh) = buf.as_ref().windows(EOH.len()).position(|w| w == EOH) else {
-		debug!("EoH not found, unexpected");
-		return None;
-	};
+// looks like one of the request IDs proxies and frameworks add
+const FILLER_FIELD: &[u8] = b"X-Request-Id: ";
+const FILLER_LEN: RangeInclusive<usize> = 8..=64;
+const CONTENT_LENGTH: &[u8] = b"Content-Length: ";
+// as many digits as a body can take
+const CONTENT_LENGTH_MAX: usize = CONTENT_LENGTH.len() + 6 + 2;
 
-	let nonce_offset = eoh + EOH.len();
+// the fake header, put together before it's known how long the body is
+struct Head<'a> {
+	// the template without its empty line, or all of it if it isn't HTTP
+	fields: &'a [u8],
+	// Date and Server for a status line, fresh in each response like a live server's
+	live: Vec<u8>,
+	// a whole field, or nothing
+	filler: Vec<u8>,
+	content_length: bool,
+	// the empty line
+	end: &'a [u8],
+}
 
-	let payload_offset = nonce_offset + nonce_size::<C>();
-	if buf.len() < payload_offset {
-		if buf.len() == nonce_offset {
-			debug!("invalid msg, likely just HTTP");
-		} else {
-			debug!("invalid msg, no nonce");
+impl<'a> Head<'a> {
+	fn new(wire: &Wire<'a>) -> Self {
+		// a bare EOH isn't HTTP to begin with, nor is a prefix, they go as is
+		if wire.header.len() <= EOH.len() || wire.prefix().is_some() {
+			return Head {
+				fields: wire.header,
+				live: Vec::new(),
+				filler: Vec::new(),
+				content_length: false,
+				end: b"",
+			};
+		}
+		let (fields, end) = wire.header.split_at(wire.header.len() - 2);
+		// the other end gives up on the EOH past MAX_HEADER, whatever doesn't fit is left out
+		let mut room = MAX_HEADER.saturating_sub(wire.header.len());
+		let content_length = wire.content_length && room >= CONTENT_LENGTH_MAX;
+		if content_length {
+			room -= CONTENT_LENGTH_MAX;
+		}
+		let mut live = Vec::new();
+		let mut add = |field: String| {
+			if field.len() <= room {
+				room -= field.len();
+				live.extend_from_slice(field.as_bytes());
+			}
+		};
+		if fields.starts_with(b"HTTP/") {
+			if !has_field(fields, b"date") {
+				add(format!("Date: {}\r\n", fake::http_date()));
+			}
+			if !has_field(fields, b"server") {
+				add(format!("Server: {}\r\n", fake::pick_server(wire.servers)));
+			}
+		}
+		let mut filler = Vec::new();
+		if wire.filler {
+			let room = room.saturating_sub(FILLER_FIELD.len() + 2);
+			let mut rng = OsRng.unwrap_err();
+			let n = rng.random_range(FILLER_LEN).min(room);
+			if n > 0 {
+				filler.extend_from_slice(FILLER_FIELD);
+				filler.extend((&mut rng).sample_iter(Alphanumeric).take(n));
+				filler.extend_from_slice(b"\r\n");
+			}
+		}
+		Head {
+			fields,
+			live,
+			filler,
+			content_length,
+			end,
 		}
-		return None;
 	}
-	let mut payload = buf.split_off(payload_offset);
-	if let Err(e) = cipher.decrypt_in_place(
-		&Nonce::<C>::from_slice(&buf[nonce_offset..nonce_offset + nonce_size::<C>()]),
-		b"",
-		&mut payload,
-	) {
-		debug!("failed to decrypt message, likely invalid: {}", e);
-		return None;
+
+	// with the Content-Length printed this many digits wide
+	fn len(&self, width: usize) -> usize {
+		let cl = if self.content_length {
+			CONTENT_LENGTH.len() + width + 2
+		} else {
+			0
+		};
+		self.fields.len() + self.live.len() + self.filler.len() + cl + self.end.len()
 	}
-	buf.unsplit(payload);
 
-	Payload::read(&buf[payload_offset..])
-}
+	// the body length and Content-Length width that make the message n bytes
+	// the digits count towards n too, zero padded on the odd boundary nothing else fits
+	fn fit(&self, n: usize) -> Option<(usize, usize)> {
+		if !self.content_length {
+			return Some((n.checked_sub(self.len(0))?, 0));
+		}
+		(1..=6).find_map(|width| {
+			let body = n.checked_sub(self.len(width))?;
+			(digits(body) <= width).then_some((body, width))
+		})
+	}
 
-trait Payload<'a>: Sized {
-	fn write(&self, buf: impl BufMut);
-	fn read(buf: &'a [u8]) -> Option<Self>;
+	fn write(&self, buf: &mut BytesMut, body: usize, width: usize) {
+		buf.put_slice(self.fields);
+		buf.put_slice(&self.live);
+		buf.put_slice(&self.filler);
+		if self.content_length {
+			buf.put_slice(CONTENT_LENGTH);
+			buf.put_slice(format!("{:01$}\r\n", body, width).as_bytes());
+		}
+		buf.put_slice(self.end);
+	}
 }
 
-#[derive(Debug, PartialEq, Eq)]
-struct Req<'a>(&'a str, u16);
-
-#[derive(Debug, PartialEq, Eq)]
-struct Resp(u8);
+// whether the header has this field, name in lowercase
+fn has_field(fields: &[u8], name: &[u8]) -> bool {
+	fields.split(|&b| b == b'\n').skip(1).any(|l| {
+		l.len() > name.len() && l[..name.len()].eq_ignore_ascii_case(name) && l[name.len()] == b':'
+	})
+}
 
-impl<'a> Payload<'a> for Req<'a> {
-	fn write(&self, mut buf: impl BufMut) {
-		buf.put_u8(VER);
-		buf.put_u8(self.0.len() as u8);
-		buf.put_slice(self.0.as_bytes());
-		buf.put_u16(self.1);
+// the most a fake header takes on the wire, with whatever Head adds to it but the filler,
+// which only takes what's left, the Server counted as the longest built in one
+pub fn header_len(header: &[u8]) -> usize {
+	if header.len() <= EOH.len() {
+		return header.len();
 	}
-	fn read(buf: &'a [u8]) -> Option<Self> {
-		if buf.len() < 2 {
-			error!("invalid request length: {}", buf.len());
-			return None;
-		}
-		let ver = buf[0];
-		if ver != VER {
-			error!("invalid ver: 0x{:02x}", ver);
-			return None;
+	let mut len = header.len() + CONTENT_LENGTH_MAX;
+	if header.starts_with(b"HTTP/") {
+		if !has_field(header, b"date") {
+			len += "Date: \r\n".len() + fake::http_date().len();
 		}
-		let len = buf[1];
-		if buf.len() < 2 + len as usize + 2 {
-			error!(
-				"invalid request length: {} < {}",
-				buf.len(),
-				2 + len as usize + 2
-			);
-			return None;
+		if !has_field(header, b"server") {
+			len += "Server: \r\n".len() + fake::SERVERS.iter().map(|s| s.len()).max().unwrap_or(0);
 		}
-		let Ok(host) = str::from_utf8(&buf[2..2 + len as usize]) else {
-			error!("invalid utf8 in host");
-			return None;
-		};
-		let port = u16::from_be_bytes(
-			buf[2 + len as usize..2 + len as usize + 2]
-				.try_into()
-				.unwrap(),
-		);
-		Some(Req(host, port))
 	}
+	len
 }
 
-impl<'a> Payload<'a> for Resp {
-	fn write(&self, mut buf: impl BufMut) {
-		buf.put_u8(self.0);
-	}
-	fn read(buf: &'a [u8]) -> Option<Self> {
-		if buf.len() < 1 {
-			error!("invalid response length: {}", buf.len());
-			return None;
-		}
-		Some(Resp(buf[0]))
-	}
+fn digits(n: usize) -> usize {
+	n.checked_ilog10().unwrap_or(0) as usize + 1
 }
 
-// read once from the plain side, encrypt it, write it to the encrypted side
-async fn enc1<C: AeadCore + AeadInPlace, E: AsyncWrite + Unpin, P: AsyncRead + Unpin>(
-	buf: &mut BytesMut,
-	cipher: &C,
-	encrypted: &mut E,
-	plain: &mut P,
-) -> Option<()> {
-	buf.clear();
+// why a message didn't make it, the server counts these
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MsgError {
+	// or not the expected prefix
+	NoEoh,
+	Decrypt,
+	// decrypted but not a payload we understand, version included
+	Invalid,
+}
 
-	// we don't generate nonce at this point
-	buf.put_bytes(0, nonce_size::<C>());
+// the largest message worth waiting for: header, nonce, a 64K host, padding and tag
+pub const MAX_MSG: usize = 0x10800;
+// how long the rest of a message split across reads gets, anything but a bare HTTP
+// request that fails to decrypt waits this long too before it's given up on
+const SPLIT_WAIT: Duration = Duration::from_millis(200);
 
-	// we don't have length yet
-	buf.put_u16(0);
-	let payload_offset = buf.len();
+// reads a message into buf, trying again as more arrives if it looks cut short
+// None is a read error, EOF isn't one, it fails like whatever came before it
+async fn recv_msg<'a, T: AsyncRead + Unpin, C: AeadCore + AeadInPlace, P: Payload<'a>>(
+	io: &mut T,
+	buf: &'a mut BytesMut,
+	cipher: &C,
+	prefix: Option<&[u8]>,
+) -> Option<Result<P, MsgError>> {
+	let offset = match recv_msg_at(io, buf, slice::from_ref(cipher), prefix).await? {
+		Ok((offset, _)) => offset,
+		Err(e) => return Some(Err(e)),
+	};
+	let buf: &'a BytesMut = buf;
+	Some(Payload::read(&buf[offset..]).ok_or(MsgError::Invalid))
+}
 
-	if let Err(e) = plain.read_buf(buf).await {
-		debug!("failed to read plain data: {}", e);
-		return None;
-	}
-	let mut payload = buf.split_off(payload_offset);
-	if payload.is_empty() {
-		debug!("got 0 reading plain data, likely remote closed");
-		buf.unsplit(payload);
-		return None;
+// like recv_msg, the decrypted message is left in buf, returns where its payload starts
+// and which of the ciphers it's under
+async fn recv_msg_at<T: AsyncRead + Unpin, C: AeadCore + AeadInPlace>(
+	io: &mut T,
+	buf: &mut BytesMut,
+	ciphers: &[C],
+	prefix: Option<&[u8]>,
+) -> Option<Result<(usize, usize), MsgError>> {
+	buf.clear();
+	let mut reader = MsgReader::new(prefix);
+	let mut first = true;
+	loop {
+		let read = io.read_buf(buf);
+		let n = if first {
+			read.await
+		} else {
+			match timeout(SPLIT_WAIT, read).await {
+				Ok(r) => r,
+				Err(_) => Ok(0),
+			}
+		}
+		.map_err(|e| debug!("handshake error reading: {}", e))
+		.ok()?;
+		first = false;
+		match reader.advance(buf, ciphers, n == 0) {
+			Progress::Done(offset) => return Some(Ok((offset, reader.key))),
+			Progress::Failed(e) => return Some(Err(e)),
+			Progress::More => debug!("{} bytes so far, waiting for more", buf.len()),
+		}
+	}
+}
+
+// what MsgReader::advance makes of the bytes so far
+#[derive(Debug, PartialEq, Eq)]
+enum Progress {
+	// looks cut short, call again once more is appended
+	More,
+	// decrypted in place, the payload starts here
+	Done(usize),
+	Failed(MsgError),
+}
+
+// one message read as it arrives, into a buffer that only grows between calls,
+// the header scan picks up where it left off
+struct MsgReader<'p> {
+	// expected as is instead of a header ending with the EOH, see Wire::prefix
+	prefix: Option<&'p [u8]>,
+	// where the nonce is, once the header is past
+	nonce_offset: Option<usize>,
+	// how far the EOH scan got
+	scanned: usize,
+	// which of the ciphers it decrypted with, once Done
+	key: usize,
+}
+
+impl<'p> MsgReader<'p> {
+	fn new(prefix: Option<&'p [u8]>) -> Self {
+		MsgReader {
+			prefix,
+			nonce_offset: None,
+			scanned: 0,
+			key: 0,
+		}
+	}
+
+	// eof: nothing more is coming, a message still short of complete fails
+	// each of the ciphers is tried in turn, a failed one leaves buf as it was
+	fn advance<C: AeadCore + AeadInPlace>(
+		&mut self,
+		buf: &mut BytesMut,
+		ciphers: &[C],
+		eof: bool,
+	) -> Progress {
+		let nonce_offset = match (self.nonce_offset, self.prefix) {
+			(Some(offset), _) => offset,
+			(None, Some(prefix)) => {
+				let n = buf.len().min(prefix.len());
+				if buf[..n] != prefix[..n] {
+					debug!("not the expected prefix");
+					return Progress::Failed(MsgError::NoEoh);
+				}
+				if n < prefix.len() {
+					if eof {
+						return Progress::Failed(MsgError::NoEoh);
+					}
+					return Progress::More;
+				}
+				self.nonce_offset = Some(n);
+				n
+			}
+			(None, None) => {
+				let end = buf.len().min(MAX_HEADER);
+				// an EOH may straddle the end of the last scan
+				let from = self.scanned.saturating_sub(EOH.len() - 1);
+				let found = buf[from..end].windows(EOH.len()).position(|w| w == EOH);
+				self.scanned = end;
+				let Some(eoh) = found else {
+					if eof || end == MAX_HEADER {
+						debug!("EoH not found within {} bytes, unexpected", MAX_HEADER);
+						return Progress::Failed(MsgError::NoEoh);
+					}
+					return Progress::More;
+				};
+				let offset = from + eoh + EOH.len();
+				self.nonce_offset = Some(offset);
+				offset
+			}
+		};
+		let mut result = Err(MsgError::Decrypt);
+		for (key, cipher) in ciphers.iter().enumerate() {
+			result = decrypt_msg(buf, cipher, nonce_offset);
+			if result.is_ok() {
+				self.key = key;
+				break;
+			}
+		}
+		match result {
+			Ok(offset) => Progress::Done(offset),
+			Err(e) if eof || buf.len() >= MAX_MSG || !may_be_partial(buf, e) => Progress::Failed(e),
+			Err(_) => Progress::More,
+		}
+	}
+}
+
+fn may_be_partial(buf: &[u8], e: MsgError) -> bool {
+	match e {
+		MsgError::NoEoh => buf.len() < MAX_HEADER,
+		// a bare HTTP request stops at the EOH, the decoy answers it right away
+		MsgError::Decrypt => !buf.ends_with(EOH),
+		MsgError::Invalid => false,
+	}
+}
+
+#[cfg(any(fuzzing, test))]
+fn read_msg<'a, C: AeadCore + AeadInPlace, T: Payload<'a>>(
+	buf: &'a mut BytesMut,
+	cipher: &C,
+) -> Option<T> {
+	try_read_msg(buf, cipher).ok()
+}
+
+#[cfg(any(fuzzing, test))]
+fn try_read_msg<'a, C: AeadCore + AeadInPlace, T: Payload<'a>>(
+	buf: &'a mut BytesMut,
+	cipher: &C,
+) -> Result<T, MsgError> {
+	let offset = open_msg(buf, cipher, None)?;
+	let buf: &'a BytesMut = buf;
+	Payload::read(&buf[offset..]).ok_or(MsgError::Invalid)
+}
+
+// all of it at once, returns where the decrypted payload starts
+#[cfg(any(fuzzing, test))]
+fn open_msg<C: AeadCore + AeadInPlace>(
+	buf: &mut BytesMut,
+	cipher: &C,
+	prefix: Option<&[u8]>,
+) -> Result<usize, MsgError> {
+	match MsgReader::new(prefix).advance(buf, slice::from_ref(cipher), true) {
+		Progress::Done(offset) => Ok(offset),
+		Progress::Failed(e) => Err(e),
+		Progress::More => unreachable!("nothing more is coming"),
+	}
+}
+
+// the shortest payload there is, a VER_PLAIN Resp
+const MIN_PAYLOAD: usize = 2;
+
+// reconciles the length of what came in with where the parts should be, before decrypting
+// nothing declares the length, padding runs to the end and the tag covers all of it,
+// so a message a byte short or long gets this far and fails to decrypt
+// returns where the payload starts
+fn check_msg_len<C: AeadCore>(len: usize, nonce_offset: usize) -> Result<usize, MsgError> {
+	let payload_offset = nonce_offset + nonce_size::<C>();
+	if len < payload_offset {
+		if len == nonce_offset {
+			debug!("invalid msg, likely just HTTP");
+		} else {
+			debug!("invalid msg, no nonce");
+		}
+		return Err(MsgError::Decrypt);
+	}
+	if len < payload_offset + MIN_PAYLOAD + tag_size::<C>() {
+		debug!("invalid msg, payload truncated");
+		return Err(MsgError::Decrypt);
+	}
+	if len > MAX_MSG {
+		debug!("invalid msg, {} bytes is more than any of ours", len);
+		return Err(MsgError::Decrypt);
+	}
+	Ok(payload_offset)
+}
+
+// decrypts the payload in place, returns where it starts
+fn decrypt_msg<C: AeadCore + AeadInPlace>(
+	buf: &mut BytesMut,
+	cipher: &C,
+	nonce_offset: usize,
+) -> Result<usize, MsgError> {
+	let payload_offset = check_msg_len::<C>(buf.len(), nonce_offset)?;
+	let mut payload = buf.split_off(payload_offset);
+	if let Err(e) = cipher.decrypt_in_place(
+		Nonce::<C>::from_slice(&buf[nonce_offset..nonce_offset + nonce_size::<C>()]),
+		b"",
+		&mut payload,
+	) {
+		debug!("failed to decrypt message, likely invalid: {}", e);
+		// left as read, for the decoy
+		buf.unsplit(payload);
+		return Err(MsgError::Decrypt);
+	}
+	buf.unsplit(payload);
+	Ok(payload_offset)
+}
+
+trait Payload<'a>: Sized {
+	fn write(&self, buf: impl BufMut);
+	fn read(buf: &'a [u8]) -> Option<Self>;
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Req<'a> {
+	ver: u8,
+	cmd: u8,
+	host: &'a str,
+	port: u16,
+	features: u8,
+	// read only, SignedReq writes it
+	auth: Option<ClientAuth<'a>>,
+	// with FEAT_EARLY, after the client key part if there's one
+	early: &'a [u8],
+}
+
+impl<'a> Req<'a> {
+	fn connect(host: &'a str, port: u16) -> Self {
+		Req {
+			ver: VER,
+			cmd: CMD_CONNECT,
+			host,
+			port,
+			features: 0,
+			auth: None,
+			early: &[],
+		}
+	}
+
+	// up to the port
+	fn write_head(&self, buf: &mut impl BufMut) {
+		buf.put_u8(self.ver);
+		match self.ver {
+			VER_PLAIN => buf.put_u8(self.host.len() as u8),
+			VER_FEATURES => {
+				buf.put_u8(self.features);
+				buf.put_u16(self.host.len() as u16);
+			}
+			_ => {
+				buf.put_u8(self.cmd);
+				buf.put_u8(self.features);
+				buf.put_u16(self.host.len() as u16);
+			}
+		}
+		buf.put_slice(self.host.as_bytes());
+		buf.put_u16(self.port);
+	}
+
+	fn write_early(&self, buf: &mut impl BufMut) {
+		if self.features & FEAT_EARLY != 0 {
+			buf.put_u16(self.early.len() as u16);
+			buf.put_slice(self.early);
+		}
+	}
+}
+
+// after the port with FEAT_CLIENT_KEY: 8 bytes unix time, 32 bytes public key, then
+// the signature over "mint req" and everything from VER up to it
+#[derive(Debug, PartialEq, Eq)]
+struct ClientAuth<'a> {
+	time: u64,
+	key: &'a [u8],
+	sig: &'a [u8],
+	signed: &'a [u8],
+}
+
+const CLIENT_KEY_LEN: usize = 32;
+
+impl ClientAuth<'_> {
+	// recent enough and signed by its own key, not whether the key is any good
+	fn check(&self, now: u64) -> bool {
+		if now.abs_diff(self.time) > CLIENT_SIG_WINDOW {
+			debug!("signed request {}s off, refusing", now.abs_diff(self.time));
+			return false;
+		}
+		let signed = [b"mint req".as_slice(), self.signed].concat();
+		let key = UnparsedPublicKey::new(&ED25519, self.key);
+		key.verify(&signed, self.sig)
+			.map_err(|_| debug!("bad signature on request, refusing"))
+			.is_ok()
+	}
+}
+
+// a Req signed with the client's key, the server reads it as a Req
+struct SignedReq<'a, 'k>(&'a Req<'a>, &'k Ed25519KeyPair);
+
+impl<'a> Payload<'a> for SignedReq<'a, '_> {
+	fn write(&self, mut buf: impl BufMut) {
+		let req = Req {
+			features: self.0.features | FEAT_CLIENT_KEY,
+			auth: None,
+			..*self.0
+		};
+		let mut body = Vec::new();
+		req.write_head(&mut body);
+		body.put_u64(unix_time());
+		body.put_slice(self.1.public_key().as_ref());
+		let sig = self.1.sign(&[b"mint req".as_slice(), &body].concat());
+		buf.put_slice(&body);
+		buf.put_slice(sig.as_ref());
+		req.write_early(&mut buf);
+	}
+	fn read(_: &'a [u8]) -> Option<Self> {
+		None
+	}
+}
+
+// signed if the wire has a client key
+// None if it doesn't fit, the host length is only 2 bytes
+fn write_req<C: AeadCore + AeadInPlace>(
+	buf: &mut BytesMut,
+	cipher: &C,
+	wire: Wire,
+	req: &Req,
+) -> Option<Nonce<C>> {
+	if req.host.len() > u16::MAX as usize {
+		error!("host too long: {}", req.host.len());
+		return None;
+	}
+	// they share the room MAX_MSG has for a host
+	if req.host.len() + req.early.len() > u16::MAX as usize {
+		error!("early data too long: {}", req.early.len());
+		return None;
+	}
+	match wire.client_key {
+		Some(key) => write_msg(buf, cipher, wire, &SignedReq(req, key)),
+		None => write_msg(buf, cipher, wire, req),
+	}
+}
+
+pub fn unix_time() -> u64 {
+	SystemTime::now()
+		.duration_since(SystemTime::UNIX_EPOCH)
+		.map_or(0, |d| d.as_secs())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Resp {
+	ver: u8,
+	rep: u8,
+	features: u8,
+}
+
+impl Resp {
+	#[cfg(test)]
+	fn new(rep: u8, features: u8) -> Self {
+		Resp {
+			ver: VER,
+			rep,
+			features,
+		}
+	}
+}
+
+// a Resp and the server's Ed25519 signature right after it, older clients take it for padding
+struct SignedResp<'a>(Resp, &'a [u8]);
+
+const SIG_LEN: usize = 64;
+
+// what the server signs, the request's nonce makes an old answer useless to replay
+fn resp_signed(challenge: &[u8], resp: &Resp) -> Vec<u8> {
+	[
+		b"mint resp".as_slice(),
+		challenge,
+		&[resp.rep, resp.features],
+	]
+	.concat()
+}
+
+// reply and resolved addresses
+#[derive(Debug, PartialEq, Eq)]
+struct DnsResp(u8, Vec<IpAddr>);
+
+#[derive(Debug, PartialEq, Eq)]
+enum VerError {
+	Empty,
+	// the version the peer speaks
+	Mismatch(u8),
+}
+
+// every payload starts with the protocol version
+fn strip_ver(buf: &[u8]) -> Result<(u8, &[u8]), VerError> {
+	match buf.first() {
+		None => Err(VerError::Empty),
+		Some(&v) if (VER_PLAIN..=VER).contains(&v) => Ok((v, &buf[1..])),
+		Some(&v) => Err(VerError::Mismatch(v)),
+	}
+}
+
+fn log_ver_error(e: VerError) {
+	match e {
+		VerError::Empty => error!("empty payload"),
+		VerError::Mismatch(v) => error!(
+			"protocol version mismatch, peer speaks {}, we speak {} to {}",
+			v, VER_PLAIN, VER
+		),
+	}
+}
+
+impl<'a> Payload<'a> for Req<'a> {
+	fn write(&self, mut buf: impl BufMut) {
+		self.write_head(&mut buf);
+		self.write_early(&mut buf);
+	}
+	fn read(whole: &'a [u8]) -> Option<Self> {
+		let (ver, buf) = strip_ver(whole).map_err(log_ver_error).ok()?;
+		let (cmd, features, len, head) = match (ver, buf) {
+			(VER_PLAIN, [len, ..]) => (CMD_CONNECT, 0, *len as usize, 1),
+			(VER_FEATURES, [features, hi, lo, ..]) => (
+				CMD_CONNECT,
+				*features,
+				u16::from_be_bytes([*hi, *lo]) as usize,
+				3,
+			),
+			(VER_CMD, [cmd, features, hi, lo, ..]) => {
+				(*cmd, *features, u16::from_be_bytes([*hi, *lo]) as usize, 4)
+			}
+			_ => {
+				error!("invalid request length: {}", buf.len());
+				return None;
+			}
+		};
+		let buf = &buf[head..];
+		if buf.len() < len + 2 {
+			error!("invalid request length: {} < {}", buf.len(), len + 2);
+			return None;
+		}
+		let Ok(host) = str::from_utf8(&buf[..len]) else {
+			error!("invalid utf8 in host");
+			return None;
+		};
+		let port = u16::from_be_bytes(buf[len..len + 2].try_into().unwrap());
+		let mut rest = &buf[len + 2..];
+		let auth = if features & FEAT_CLIENT_KEY != 0 {
+			let Some((time, after)) = rest.split_first_chunk::<8>() else {
+				error!("signed request too short");
+				return None;
+			};
+			if after.len() < CLIENT_KEY_LEN + SIG_LEN {
+				error!("signed request too short");
+				return None;
+			}
+			let end = whole.len() - after.len() + CLIENT_KEY_LEN;
+			rest = &after[CLIENT_KEY_LEN + SIG_LEN..];
+			Some(ClientAuth {
+				time: u64::from_be_bytes(*time),
+				key: &after[..CLIENT_KEY_LEN],
+				sig: &after[CLIENT_KEY_LEN..CLIENT_KEY_LEN + SIG_LEN],
+				signed: &whole[..end],
+			})
+		} else {
+			None
+		};
+		let early = if features & FEAT_EARLY != 0 {
+			let early = rest
+				.split_first_chunk::<2>()
+				.and_then(|(len, rest)| rest.get(..u16::from_be_bytes(*len) as usize));
+			let Some(early) = early else {
+				error!("early data cut short");
+				return None;
+			};
+			early
+		} else {
+			&[]
+		};
+		Some(Req {
+			ver,
+			cmd,
+			host,
+			port,
+			features,
+			auth,
+			early,
+		})
+	}
+}
+
+impl Resp {
+	fn len(&self) -> usize {
+		if self.ver == VER_PLAIN { 2 } else { 3 }
+	}
+}
+
+impl<'a> Payload<'a> for Resp {
+	fn write(&self, mut buf: impl BufMut) {
+		buf.put_u8(self.ver);
+		buf.put_u8(self.rep);
+		if self.ver != VER_PLAIN {
+			buf.put_u8(self.features);
+		}
+	}
+	fn read(buf: &'a [u8]) -> Option<Self> {
+		let (ver, buf) = strip_ver(buf).map_err(log_ver_error).ok()?;
+		let resp = match (ver, buf) {
+			(VER_PLAIN, [rep, ..]) => Resp {
+				ver,
+				rep: *rep,
+				features: 0,
+			},
+			(_, [rep, features, ..]) if ver != VER_PLAIN => Resp {
+				ver,
+				rep: *rep,
+				features: *features,
+			},
+			_ => {
+				error!("invalid response length: {}", buf.len());
+				return None;
+			}
+		};
+		Some(resp)
+	}
+}
+
+impl<'a> Payload<'a> for SignedResp<'a> {
+	fn write(&self, mut buf: impl BufMut) {
+		self.0.write(&mut buf);
+		buf.put_slice(self.1);
+	}
+	fn read(buf: &'a [u8]) -> Option<Self> {
+		let resp = Resp::read(buf)?;
+		let Some(sig) = buf.get(resp.len()..resp.len() + SIG_LEN) else {
+			error!("server response too short to be signed");
+			return None;
+		};
+		Some(SignedResp(resp, sig))
+	}
+}
+
+impl<'a> Payload<'a> for DnsResp {
+	// only ever the answer to a VER_CMD request, clients send no other
+	fn write(&self, mut buf: impl BufMut) {
+		buf.put_u8(VER_CMD);
+		buf.put_u8(self.0);
+		buf.put_u8(self.1.len() as u8);
+		for a in &self.1 {
+			match a {
+				IpAddr::V4(a) => {
+					buf.put_u8(4);
+					buf.put_slice(&a.octets());
+				}
+				IpAddr::V6(a) => {
+					buf.put_u8(6);
+					buf.put_slice(&a.octets());
+				}
+			}
+		}
+	}
+	fn read(buf: &'a [u8]) -> Option<Self> {
+		let (_, mut buf) = strip_ver(buf).map_err(log_ver_error).ok()?;
+		if buf.len() < 2 {
+			error!("invalid dns response length: {}", buf.len());
+			return None;
+		}
+		let rep = buf[0];
+		let n = buf[1];
+		buf = &buf[2..];
+		let mut addrs = Vec::with_capacity(n as usize);
+		for _ in 0..n {
+			let (a, rest): (IpAddr, _) = match buf {
+				[4, a @ ..] if a.len() >= 4 => {
+					(<[u8; 4]>::try_from(&a[..4]).unwrap().into(), &a[4..])
+				}
+				[6, a @ ..] if a.len() >= 16 => {
+					(<[u8; 16]>::try_from(&a[..16]).unwrap().into(), &a[16..])
+				}
+				_ => {
+					error!("invalid address in dns response");
+					return None;
+				}
+			};
+			addrs.push(a);
+			buf = rest;
+		}
+		Some(DnsResp(rep, addrs))
+	}
+}
+
+// read once from the plain side, encrypt it, write it to the encrypted side
+// 0 is EOF on the plain side, nothing is written then
+async fn enc1<C: AeadCore + AeadInPlace, E: AsyncWrite + Unpin, P: AsyncRead + Unpin>(
+	buf: &mut BytesMut,
+	cipher: &C,
+	opts: &FrameOpts,
+	encrypted: &mut E,
+	plain: &mut P,
+) -> io::Result<u64> {
+	buf.clear();
+
+	// we don't generate nonce at this point
+	buf.put_bytes(0, nonce_size::<C>());
+
+	// we don't have length yet
+	buf.put_u16(0);
+	let payload_offset = buf.len();
+
+	if !opts.untyped {
+		buf.put_u8(FRAME_DATA);
+	}
+	let data_offset = buf.len();
+	buf.reserve(FRAME_READ);
+	let mut payload = (&mut *buf).limit(FRAME_READ);
+
+	let r = loop {
+		if opts.dummy_interval.is_zero() {
+			break plain.read_buf(&mut payload).await;
+		}
+		// read_buf is cancel safe, nothing is lost when the timer wins
+		tokio::select! {
+			r = plain.read_buf(&mut payload) => break r,
+			_ = sleep(opts.dummy_interval) => {
+				for _ in 0..opts.dummy_burst {
+					write_dummy(cipher, encrypted).await?;
+				}
+			}
+		}
+	};
+	if let Err(e) = r {
+		debug!("failed to read plain data: {}", e);
+		return Err(e);
+	}
+	if buf.len() == data_offset {
+		debug!("got 0 reading plain data, likely remote closed");
+		return Ok(0);
 	}
 
-	let nonce = C::generate_nonce(&mut AeadOsRng);
+	let n = buf.len() - data_offset;
+	seal_frame(buf, cipher, payload_offset)?;
+
+	// perturb inter-packet timing, at a latency cost
+	if !opts.jitter.is_zero() {
+		sleep(jitter_delay(opts.jitter)).await;
+	}
+
+	encrypted
+		.write_all(buf)
+		.await
+		.inspect_err(|e| debug!("failed to write encrypted data: {}", e))?;
+	Ok(n as u64)
+}
+
+// an authenticated frame of random bytes, the peer discards it
+async fn write_dummy<C: AeadCore + AeadInPlace, E: AsyncWrite + Unpin>(
+	cipher: &C,
+	encrypted: &mut E,
+) -> io::Result<()> {
+	write_marker(cipher, encrypted, FRAME_DUMMY).await
+}
+
+// a frame with nothing but its type in it, padded like a dummy
+async fn write_marker<C: AeadCore + AeadInPlace, E: AsyncWrite + Unpin>(
+	cipher: &C,
+	encrypted: &mut E,
+	kind: u8,
+) -> io::Result<()> {
+	let mut buf = BytesMut::with_capacity(0x80);
+	buf.put_bytes(0, nonce_size::<C>());
+	buf.put_u16(0);
+	let payload_offset = buf.len();
+
+	buf.put_u8(kind);
+	buf.put_bytes(
+		OsRng.unwrap_err().random(),
+		OsRng.unwrap_err().random_range(0x10..0x40),
+	);
+
+	seal_frame(&mut buf, cipher, payload_offset)?;
+
+	encrypted
+		.write_all(&buf)
+		.await
+		.inspect_err(|e| debug!("failed to write frame 0x{:02x}: {}", kind, e))
+}
+
+// encrypt everything after payload_offset, then fill in nonce and length before it
+fn seal_frame<C: AeadCore + AeadInPlace>(
+	buf: &mut BytesMut,
+	cipher: &C,
+	payload_offset: usize,
+) -> io::Result<()> {
+	let mut payload = buf.split_off(payload_offset);
+
+	let nonce = fresh_nonce::<C>();
 	if let Err(e) = cipher.encrypt_in_place(&nonce, b"", &mut payload) {
 		error!("failed to encrypt: {}", e);
-		return None;
+		return Err(io::Error::other("encryption failed"));
 	}
 	// write nonce
-	(&mut buf[..nonce_size::<C>()]).copy_from_slice(&nonce);
+	buf[..nonce_size::<C>()].copy_from_slice(&nonce);
 	// write length
 	let len = obfuscate(payload.len() as u16, &nonce).to_be_bytes();
-	(&mut buf[nonce_size::<C>()..]).copy_from_slice(&len);
+	buf[nonce_size::<C>()..].copy_from_slice(&len);
 	buf.unsplit(payload);
 
-	encrypted
-		.write_all(buf)
-		.await
-		.inspect_err(|e| debug!("failed to write encrypted data: {}", e))
-		.ok()
+	Ok(())
 }
 
 // read one _packet_ from the encrypted side, decrypt it, write it to the plain side
+// dummy frames are skipped, so this may consume several packets, 0 is an end frame
 async fn dec1<C: AeadCore + AeadInPlace, P: AsyncWrite + Unpin, E: AsyncRead + Unpin>(
 	buf: &mut BytesMut,
 	cipher: &C,
+	opts: &FrameOpts,
 	plain: &mut P,
 	encrypted: &mut E,
-) -> Option<()> {
-	let mut nonce = Nonce::<C>::default();
-	if let Err(e) = encrypted.read_exact(&mut nonce).await {
-		debug!("failed to read nonce: {}", e);
-		return None;
+) -> io::Result<u64> {
+	let data_offset = if opts.untyped { 0 } else { 1 };
+	loop {
+		let mut nonce = Nonce::<C>::default();
+		encrypted
+			.read_exact(&mut nonce)
+			.await
+			.inspect_err(|e| debug!("failed to read nonce: {}", e))?;
+
+		let len = encrypted
+			.read_u16()
+			.await
+			.inspect_err(|e| debug!("failed to read len: {}", e))?;
+		let len = obfuscate(len, &nonce);
+		// a type byte or a data byte at least
+		if (len as usize) < 1 + tag_size::<C>() {
+			debug!("length = {}, too short for a frame", len);
+			return Err(io::ErrorKind::InvalidData.into());
+		}
+
+		buf.resize(len as usize, 0);
+
+		encrypted
+			.read_exact(buf)
+			.await
+			.inspect_err(|e| log_io("failed to read payload", e))?;
+
+		if let Err(e) = cipher.decrypt_in_place(&nonce, b"", buf) {
+			error!("failed to decrypt payload: {}", e);
+			return Err(io::ErrorKind::InvalidData.into());
+		}
+
+		if opts.untyped {
+			break;
+		}
+		match buf.first() {
+			Some(&FRAME_DATA) => break,
+			Some(&FRAME_DUMMY) => {
+				debug!("discarding dummy frame of {} bytes", buf.len());
+			}
+			Some(&FRAME_END) => return Ok(0),
+			t => {
+				error!("invalid frame type: {:02x?}", t);
+				return Err(io::ErrorKind::InvalidData.into());
+			}
+		}
 	}
 
-	let len = encrypted
-		.read_u16()
+	plain
+		.write_all(&buf[data_offset..])
 		.await
-		.map_err(|e| debug!("failed to read len: {}", e))
-		.ok()?;
-	let len = obfuscate(len, &nonce);
-	if len == 0 {
-		debug!("length = 0, unexpected");
-		return None;
+		.inspect_err(|e| log_io("failed to write decrypted payload", e))?;
+	Ok((buf.len() - data_offset) as u64)
+}
+
+pub async fn duplex<
+	C: AeadCore + AeadInPlace,
+	P: AsyncRead + AsyncWrite + Unpin,
+	E: AsyncRead + AsyncWrite + Unpin,
+>(
+	cipher: &C,
+	opts: &FrameOpts,
+	plain: &mut P,
+	encrypted: &mut E,
+) -> (u64, u64, End) {
+	// not copy_bidirectional, the directions leave the framed phase independently
+	// each shuts down its writer when done, so half-close still works
+	let (mut p_r, mut p_w) = split(plain);
+	let (mut e_r, mut e_w) = split(encrypted);
+	let ((enc, enc_end), (dec, dec_end)) = tokio::join!(
+		simplex(cipher, opts, enc1, &mut e_w, &mut p_r),
+		simplex(cipher, opts, dec1, &mut p_w, &mut e_r),
+	);
+	(enc, dec, enc_end.or(dec_end))
+}
+
+// peers vanish all the time on the internet, that's no error
+fn is_gone(kind: io::ErrorKind) -> bool {
+	use io::ErrorKind::*;
+	matches!(
+		kind,
+		ConnectionReset | ConnectionAborted | BrokenPipe | UnexpectedEof
+	)
+}
+
+// a peer going away is nothing to report
+pub fn log_io(what: &str, e: &io::Error) {
+	if is_gone(e.kind()) {
+		debug!("{}: {}", what, e);
+	} else {
+		error!("{}: {}", what, e);
+	}
+}
+
+// how a relay ended, a peer closing is no failure even if it was mid-frame
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum End {
+	Closed,
+	Failed(io::ErrorKind),
+}
+
+impl End {
+	fn of<T>(r: &io::Result<T>) -> End {
+		match r {
+			Err(e) if e.kind() != io::ErrorKind::UnexpectedEof => End::Failed(e.kind()),
+			_ => End::Closed,
+		}
+	}
+
+	// the first failure, either way
+	fn or(self, other: End) -> End {
+		match self {
+			End::Closed => other,
+			failed => failed,
+		}
+	}
+
+	// the summary line, normal closes are only noise
+	pub fn log(self, conn: fmt::Arguments) {
+		match self {
+			End::Closed => debug!("connection closed: {}", conn),
+			End::Failed(kind) if is_gone(kind) => debug!("connection {}: {}", kind, conn),
+			End::Failed(kind) => info!("connection failed: {}: {}", conn, kind),
+		}
+	}
+}
+
+pub async fn simplex<
+	C: AeadCore + AeadInPlace,
+	F: AsyncFn(&mut BytesMut, &C, &FrameOpts, &mut W, &mut R) -> io::Result<u64>,
+	W: AsyncWrite + Unpin,
+	R: AsyncRead + Unpin,
+>(
+	cipher: &C,
+	opts: &FrameOpts,
+	codec: F,
+	w: &mut W,
+	r: &mut R,
+) -> (u64, End) {
+	// plain bytes relayed, including whatever got through before an error
+	let mut n = 0;
+	// enclosed so I can use ? and still guarantee shutdown
+	// is there a better pattern?
+	let r = async {
+		let mut buf = BytesMut::with_capacity(0x1000);
+		// dummy frames are for idle, that's after the first few frames
+		let frames = if opts.dummy_interval.is_zero() {
+			3
+		} else {
+			usize::MAX
+		};
+		for _ in 0..frames {
+			match codec(&mut buf, cipher, opts, w, r).await? {
+				0 => return Ok(()),
+				k => n += k,
+			}
+		}
+		drop(buf);
+		n += copy(r, w)
+			.await
+			.inspect_err(|e| debug!("error copying: {}", e))?;
+		Ok(())
+	}
+	.await;
+	// a clean FIN after everything, peers that read to close get the whole body
+	let _ = w
+		.flush()
+		.await
+		.inspect_err(|e| debug!("error flushing: {}", e));
+	let _ = w
+		.shutdown()
+		.await
+		.inspect_err(|e| debug!("error shutting down: {}", e));
+	(n, End::of(&r))
+}
+
+// like duplex, but both directions stay framed and mark their end instead of
+// shutting down, so the connection can carry another request once both have
+// None if both ended with their marks, otherwise the connection is done for and how it ended
+pub async fn duplex_framed<
+	C: AeadCore + AeadInPlace,
+	P: AsyncRead + AsyncWrite + Unpin,
+	E: AsyncRead + AsyncWrite + Unpin,
+>(
+	cipher: &C,
+	opts: &FrameOpts,
+	plain: &mut P,
+	encrypted: &mut E,
+) -> (u64, u64, Option<End>) {
+	let (mut p_r, mut p_w) = split(plain);
+	let (mut e_r, mut e_w) = split(encrypted);
+	let ((enc, enc_end), (dec, dec_end)) = tokio::join!(
+		async {
+			let mut buf = BytesMut::with_capacity(0x1000);
+			let mut n = 0;
+			loop {
+				match enc1(&mut buf, cipher, opts, &mut e_w, &mut p_r).await {
+					Ok(0) => break,
+					Ok(k) => n += k,
+					e => return (n, Some(End::of(&e))),
+				}
+			}
+			let r = write_marker(cipher, &mut e_w, FRAME_END).await;
+			(n, r.is_err().then(|| End::of(&r)))
+		},
+		async {
+			let mut buf = BytesMut::with_capacity(0x1000);
+			let mut n = 0;
+			let end = loop {
+				match dec1(&mut buf, cipher, opts, &mut p_w, &mut e_r).await {
+					Ok(0) => break None,
+					Ok(k) => n += k,
+					// a close without the mark is no failure, but no clean end either
+					e => break Some(End::of(&e)),
+				}
+			};
+			// either way, so the plain side winds down too
+			let _ = p_w
+				.shutdown()
+				.await
+				.inspect_err(|e| debug!("error shutting down: {}", e));
+			(n, end)
+		},
+	);
+	let end = match (enc_end, dec_end) {
+		(None, None) => None,
+		(a, b) => Some(a.unwrap_or(End::Closed).or(b.unwrap_or(End::Closed))),
+	};
+	(enc, dec, end)
+}
+
+// entry point for fuzz/fuzz_targets/read_msg.rs, must not panic on any input
+#[cfg(fuzzing)]
+pub fn fuzz_read_msg(data: &[u8]) {
+	let cipher = chacha20poly1305::ChaCha20Poly1305::new(&FUZZ_KEY.into());
+
+	let mut buf = BytesMut::from(data);
+	let _: Option<Req> = read_msg(&mut buf, &cipher);
+	let mut buf = BytesMut::from(data);
+	let _: Option<Resp> = read_msg(&mut buf, &cipher);
+	let mut buf = BytesMut::from(data);
+	let _: Option<DnsResp> = read_msg(&mut buf, &cipher);
+
+	// random input hardly ever decrypts, so hit the parsers directly too
+	let _ = Req::read(data);
+	let _ = Resp::read(data);
+	let _ = DnsResp::read(data);
+}
+
+// the seed corpus is encrypted with this
+#[cfg(any(fuzzing, test))]
+const FUZZ_KEY: [u8; 32] = [0x42; 32];
+
+// a random nonce, debug builds make sure it hasn't been used lately
+fn fresh_nonce<C: AeadCore>() -> Nonce<C> {
+	let mut nonce = Nonce::<C>::default();
+	nonce::fill(&mut nonce);
+	#[cfg(debug_assertions)]
+	nonces::check(&nonce);
+	nonce
+}
+
+// a canary for a broken RNG, a repeated nonce under the same key gives the key away
+// any repeat is a fault, whichever key it's for, so there's one window for all of them
+#[cfg(debug_assertions)]
+mod nonces {
+	use std::{
+		cell::RefCell,
+		collections::{HashSet, VecDeque},
+	};
+
+	const WINDOW: usize = 0x10000;
+
+	#[derive(Default)]
+	struct Recent {
+		set: HashSet<Vec<u8>>,
+		order: VecDeque<Vec<u8>>,
+	}
+
+	thread_local! {
+		static RECENT: RefCell<Recent> = RefCell::default();
+	}
+
+	pub fn check(nonce: &[u8]) {
+		RECENT.with_borrow_mut(|r| {
+			if !r.set.insert(nonce.to_vec()) {
+				panic!("nonce reused: {:02x?}", nonce);
+			}
+			r.order.push_back(nonce.to_vec());
+			if r.order.len() > WINDOW {
+				let old = r.order.pop_front().unwrap();
+				r.set.remove(&old);
+			}
+		});
+	}
+}
+
+// is there a less verbose way?
+const fn nonce_size<C: AeadCore>() -> usize {
+	std::mem::size_of::<Nonce<C>>()
+}
+
+const fn tag_size<C: AeadCore>() -> usize {
+	std::mem::size_of::<Tag<C>>()
+}
+
+// uniformly random in [0, max]
+fn jitter_delay(max: Duration) -> Duration {
+	Duration::from_micros(OsRng.unwrap_err().random_range(0..=max.as_micros() as u64))
+}
+
+// make len look random
+fn obfuscate(a: u16, b: &[u8]) -> u16 {
+	a ^ u16::from_be_bytes([b[4 % b.len()], b[2 % b.len()]])
+}
+
+#[cfg(test)]
+mod test {
+	use std::{collections::HashSet, time::SystemTime};
+
+	use bytes::BytesMut;
+	use chacha20poly1305::{AeadCore, ChaCha8Poly1305, ChaCha20Poly1305, KeyInit, aead::OsRng};
+	use proptest::prelude::*;
+
+	use super::*;
+
+	fn init() {
+		let _ = env_logger::builder().is_test(true).try_init();
+	}
+
+	// valid UTF-8, at most 255 bytes
+	fn host() -> impl Strategy<Value = String> {
+		prop::collection::vec(any::<char>(), 0..=255).prop_map(|v| {
+			let mut s = String::new();
+			for c in v {
+				if s.len() + c.len_utf8() > 255 {
+					break;
+				}
+				s.push(c);
+			}
+			s
+		})
+	}
+
+	// non-empty lines, so no EOH before the end
+	fn header() -> impl Strategy<Value = Vec<u8>> {
+		prop::collection::vec("[A-Za-z0-9 :/.-]{1,64}", 1..8).prop_map(|lines| {
+			let mut h = String::new();
+			for l in lines {
+				h.push_str(&l);
+				h.push_str("\r\n");
+			}
+			h.push_str("\r\n");
+			h.into_bytes()
+		})
+	}
+
+	proptest! {
+		#[test]
+		fn prop_roundtrip(host in host(), port: u16, features: u8, header in header()) {
+			let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+			// that one says a signature follows, SignedReq's to set
+			let features = features & !FEAT_CLIENT_KEY;
+			let req = Req { features, ..Req::connect(&host, port) };
+			let mut buf = BytesMut::new();
+			write_msg(&mut buf, &cipher, &header[..], &req);
+			prop_assert!(buf.starts_with(&header));
+			let req_r: Option<Req> = read_msg(&mut buf, &cipher);
+			prop_assert_eq!(Some(req), req_r);
+		}
+
+		#[test]
+		fn prop_tamper(host in host(), port: u16, header in header(), pos: prop::sample::Index, bit in 0..8u8) {
+			let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+			let mut buf = BytesMut::new();
+			write_msg(&mut buf, &cipher, &header[..], &Req::connect(&host, port));
+			// anywhere in the nonce or ciphertext
+			let pos = header.len() + pos.index(buf.len() - header.len());
+			buf[pos] ^= 1 << bit;
+			let req_r: Option<Req> = read_msg(&mut buf, &cipher);
+			prop_assert_eq!(None, req_r);
+		}
+	}
+
+	#[test]
+	fn test_payload() {
+		init();
+
+		let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+		println!("key len: {}", key.len());
+		let cipher = ChaCha20Poly1305::new(&key);
+		let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+		println!("nonce len: {}", nonce.len());
+		assert_eq!(nonce.len(), nonce_size::<ChaCha20Poly1305>());
+
+		let mut buf = BytesMut::with_capacity(1024);
+		let req = Req::connect("example.com", 443);
+		write_msg(&mut buf, &cipher, EOH, &req);
+		let req_r: Req = read_msg(&mut buf, &cipher).unwrap();
+		assert_eq!(req, req_r);
+	}
+
+	// regenerate the seed corpus with
+	// MINT_FUZZ_CORPUS=fuzz/corpus/read_msg cargo test gen_fuzz_corpus
+	#[test]
+	fn gen_fuzz_corpus() {
+		let Ok(dir) = std::env::var("MINT_FUZZ_CORPUS") else {
+			return;
+		};
+		let cipher = ChaCha20Poly1305::new(&FUZZ_KEY.into());
+		let host = "a".repeat(300);
+		let mut seeds: Vec<(&str, BytesMut)> = Vec::new();
+		let msg = |p: &dyn Fn(&mut BytesMut) -> Option<Nonce<ChaCha20Poly1305>>| {
+			let mut buf = BytesMut::new();
+			p(&mut buf);
+			buf
+		};
+		seeds.push((
+			"req",
+			msg(&|b| write_msg(b, &cipher, EOH, &Req::connect("example.com", 443))),
+		));
+		seeds.push((
+			"req-long-host",
+			msg(&|b| write_msg(b, &cipher, EOH, &Req::connect(&host, 443))),
+		));
+		seeds.push((
+			"resp",
+			msg(&|b| write_msg(b, &cipher, EOH, &Resp::new(REP_OK, 0))),
+		));
+		seeds.push((
+			"req-plain",
+			msg(&|b| {
+				let req = Req {
+					ver: VER_PLAIN,
+					..Req::connect("example.com", 443)
+				};
+				write_msg(b, &cipher, EOH, &req)
+			}),
+		));
+		seeds.push((
+			"resp-plain",
+			msg(&|b| {
+				let resp = Resp {
+					ver: VER_PLAIN,
+					..Resp::new(REP_OK, 0)
+				};
+				write_msg(b, &cipher, EOH, &resp)
+			}),
+		));
+		seeds.push((
+			"dns-resp",
+			msg(&|b| {
+				let addrs = vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()];
+				write_msg(b, &cipher, EOH, &DnsResp(REP_OK, addrs))
+			}),
+		));
+		let mut truncated = seeds[0].1.clone();
+		truncated.truncate(EOH.len() + nonce_size::<ChaCha20Poly1305>() + 3);
+		seeds.push(("req-truncated", truncated));
+		let mut flipped = seeds[0].1.clone();
+		flipped[EOH.len() + 1] ^= 1;
+		seeds.push(("req-flipped", flipped));
+		seeds.push(("eoh-only", BytesMut::from(EOH)));
+		for (name, buf) in seeds {
+			std::fs::write(format!("{}/{}", dir, name), &buf).unwrap();
+		}
+	}
+
+	#[test]
+	fn test_max_header() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let req = Req::connect("example.com", 443);
+
+		let mut header = vec![b'a'; MAX_HEADER - EOH.len()];
+		header.extend_from_slice(EOH);
+		let mut buf = BytesMut::new();
+		write_msg(&mut buf, &cipher, &header[..], &req);
+		let req_r: Req = read_msg(&mut buf, &cipher).unwrap();
+		assert_eq!(req, req_r);
+
+		// one byte over
+		header.insert(0, b'a');
+		let mut buf = BytesMut::new();
+		write_msg(&mut buf, &cipher, &header[..], &req);
+		let r: Result<Req, _> = try_read_msg(&mut buf, &cipher);
+		assert_eq!(r, Err(MsgError::NoEoh));
+	}
+
+	#[test]
+	fn test_eoh_only() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let header = b"GET / HTTP/1.1\r\n\r\n";
+		let mut msg = BytesMut::new();
+		write_msg(&mut msg, &cipher, header, &Resp::new(REP_OK, 0));
+		let payload_offset = header.len() + nonce_size::<ChaCha20Poly1305>();
+		// nothing after the EOH, part of the nonce, not even a whole tag
+		for len in [
+			header.len(),
+			header.len() + 1,
+			payload_offset + tag_size::<ChaCha20Poly1305>() - 1,
+		] {
+			let mut buf = msg.clone();
+			buf.truncate(len);
+			let r: Result<Resp, _> = try_read_msg(&mut buf, &cipher);
+			assert_eq!(r, Err(MsgError::Decrypt));
+		}
+	}
+
+	#[tokio::test]
+	async fn test_split() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let req = Req::connect("example.com", 443);
+		let mut msg = BytesMut::new();
+		write_msg(&mut msg, &cipher, EOH, &req);
+		let (mut c, mut s) = tokio::io::duplex(0x1000);
+		let mut buf = BytesMut::new();
+		let (r, _) = tokio::join!(recv_msg(&mut s, &mut buf, &cipher, None), async {
+			// either side of the EOH, then inside the payload
+			c.write_all(&msg[..2]).await.unwrap();
+			sleep(Duration::from_millis(20)).await;
+			c.write_all(&msg[2..40]).await.unwrap();
+			sleep(Duration::from_millis(20)).await;
+			c.write_all(&msg[40..]).await.unwrap();
+		});
+		assert_eq!(r, Some(Ok(req)));
+
+		// a bare HTTP request isn't kept waiting
+		let (mut c, mut s) = tokio::io::duplex(0x1000);
+		c.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+		let start = std::time::Instant::now();
+		let r: Option<Result<Req, _>> = recv_msg(&mut s, &mut buf, &cipher, None).await;
+		assert_eq!(r, Some(Err(MsgError::Decrypt)));
+		assert!(start.elapsed() < SPLIT_WAIT);
+
+		// garbage is, but only for so long
+		c.write_all(b"\x16\x03\x01\r\n\r\nnot a nonce")
+			.await
+			.unwrap();
+		let r: Option<Result<Req, _>> = recv_msg(&mut s, &mut buf, &cipher, None).await;
+		assert_eq!(r, Some(Err(MsgError::Decrypt)));
+		assert_eq!(&buf[..], b"\x16\x03\x01\r\n\r\nnot a nonce");
+	}
+
+	#[test]
+	fn test_msg_len() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let header = b"GET / HTTP/1.1\r\n\r\n";
+		let mut msg = BytesMut::new();
+		write_msg(&mut msg, &cipher, header, &Resp::new(REP_OK, 0)).unwrap();
+
+		let short = BytesMut::from(&msg[..msg.len() - 1]);
+		let mut long = msg.clone();
+		long.put_u8(0);
+		for (mut m, r) in [
+			(msg, Ok(header.len() + 12)),
+			(short, Err(MsgError::Decrypt)),
+			(long, Err(MsgError::Decrypt)),
+		] {
+			assert_eq!(open_msg(&mut m, &cipher, None), r);
+		}
+
+		// no room for the smallest payload and tag, not worth decrypting
+		let min = header.len() + 12 + MIN_PAYLOAD + 16;
+		let check = |len| check_msg_len::<ChaCha20Poly1305>(len, header.len());
+		assert_eq!(check(min), Ok(header.len() + 12));
+		assert_eq!(check(min - 1), Err(MsgError::Decrypt));
+		assert_eq!(check(header.len()), Err(MsgError::Decrypt));
+		assert_eq!(check(MAX_MSG + 1), Err(MsgError::Decrypt));
+	}
+
+	#[test]
+	fn test_msg_reader() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let header = b"GET / HTTP/1.1\r\n\r\n";
+		let req = Req::connect("example.com", 443);
+		let mut msg = BytesMut::new();
+		write_msg(&mut msg, &cipher, header, &req).unwrap();
+		// 16 byte chunks split the EOH too
+		for chunk in [msg.len(), msg.len() / 2 + 1, 16] {
+			let mut reader = MsgReader::new(None);
+			let mut buf = BytesMut::new();
+			let mut chunks = msg.chunks(chunk).peekable();
+			while let Some(c) = chunks.next() {
+				buf.extend_from_slice(c);
+				let p = reader.advance(&mut buf, slice::from_ref(&cipher), false);
+				if chunks.peek().is_some() {
+					assert_eq!(p, Progress::More, "{} bytes in", buf.len());
+					continue;
+				}
+				let Progress::Done(offset) = p else {
+					panic!("{:?} with all of it", p);
+				};
+				assert_eq!(offset, header.len() + 12);
+				assert_eq!(
+					Req::read(&buf[offset..]),
+					Some(Req::connect("example.com", 443))
+				);
+			}
+		}
+
+		// cut short for good
+		let mut buf = BytesMut::from(&msg[..msg.len() - 1]);
+		assert_eq!(
+			MsgReader::new(None).advance(&mut buf, slice::from_ref(&cipher), true),
+			Progress::Failed(MsgError::Decrypt)
+		);
+
+		// no EOH in sight, more won't help past MAX_HEADER
+		let mut reader = MsgReader::new(None);
+		let mut buf = BytesMut::new();
+		for _ in 0..MAX_HEADER / 0x40 - 1 {
+			buf.extend_from_slice(&[b'x'; 0x40]);
+			assert_eq!(
+				reader.advance(&mut buf, slice::from_ref(&cipher), false),
+				Progress::More
+			);
+		}
+		buf.extend_from_slice(&[b'x'; 0x40]);
+		assert_eq!(
+			reader.advance(&mut buf, slice::from_ref(&cipher), false),
+			Progress::Failed(MsgError::NoEoh)
+		);
+	}
+
+	#[tokio::test]
+	async fn test_prefix() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		// a TLS ClientHello record header, then some line noise
+		let prefix: &[u8] = b"\x16\x03\x01\x02\x00\x01\x00\x01\xfc\x03\x03\r\n\xff";
+		let wire = Wire {
+			header: prefix,
+			pad_to: 0,
+			filler: true,
+			content_length: true,
+			..Wire::from(b"")
+		};
+		let mut msg = BytesMut::new();
+		write_msg(&mut msg, &cipher, wire, &Req::connect("example.com", 443)).unwrap();
+		// nothing added to it
+		assert_eq!(&msg[..prefix.len()], prefix);
+		let mut other = msg.clone();
+		other[1] ^= 1;
+		assert_eq!(
+			open_msg(&mut msg, &cipher, Some(prefix)),
+			Ok(prefix.len() + 12)
+		);
+		assert_eq!(
+			open_msg(&mut other, &cipher, Some(prefix)),
+			Err(MsgError::NoEoh)
+		);
+
+		let (mut c, mut s) = tokio::io::duplex(0x1000);
+		let mut buf = BytesMut::new();
+		let (r, _) = tokio::join!(
+			client_handshake(&mut c, &cipher, &mut buf, "example.com", 443, prefix, 0),
+			async {
+				let mut buf = BytesMut::new();
+				let req = server_handshake(&mut s, &cipher, &mut buf, prefix)
+					.await
+					.unwrap();
+				assert_eq!(req.host, "example.com");
+				server_reply(&mut s, &cipher, &mut buf, prefix, REP_OK, 0)
+					.await
+					.unwrap();
+			}
+		);
+		assert_eq!(r, Some(0));
+	}
+
+	#[tokio::test]
+	async fn test_raw() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let (mut c, mut s) = tokio::io::duplex(0x1000);
+		let mut buf = BytesMut::new();
+		let (r, _) = tokio::join!(
+			client_handshake(&mut c, &cipher, &mut buf, "example.com", 443, b"", 0),
+			async {
+				let mut buf = BytesMut::new();
+				let req = server_handshake(&mut s, &cipher, &mut buf, b"")
+					.await
+					.unwrap();
+				assert_eq!(req.host, "example.com");
+				server_reply(&mut s, &cipher, &mut buf, b"", REP_OK, 0)
+					.await
+					.unwrap();
+			}
+		);
+		assert_eq!(r, Some(0));
+
+		// an EOH in the nonce would throw a scan off, raw mode doesn't scan
+		let mut nonce = Nonce::<ChaCha20Poly1305>::default();
+		nonce[..EOH.len()].copy_from_slice(EOH);
+		let mut msg = BytesMut::from(&nonce[..]);
+		let mut payload = BytesMut::new();
+		Resp::new(REP_OK, 0).write(&mut payload);
+		cipher.encrypt_in_place(&nonce, b"", &mut payload).unwrap();
+		msg.unsplit(payload);
+		let mut scanned = msg.clone();
+		assert_eq!(open_msg(&mut msg, &cipher, Some(b"")), Ok(nonce.len()));
+		assert_eq!(
+			open_msg(&mut scanned, &cipher, None),
+			Err(MsgError::Decrypt)
+		);
+	}
+
+	#[test]
+	fn test_header_filler() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let template = b"POST /upload HTTP/1.1\r\nHost: example.com\r\n\r\n";
+		let wire = Wire {
+			header: template,
+			pad_to: 0,
+			filler: true,
+			content_length: false,
+			..Wire::from(b"")
+		};
+		let req = Req::connect("example.com", 443);
+		let mut lens = Vec::new();
+		for _ in 0..8 {
+			let mut buf = BytesMut::new();
+			write_msg(&mut buf, &cipher, wire, &req).unwrap();
+			let eoh = buf.windows(EOH.len()).position(|w| w == EOH).unwrap();
+			let header = str::from_utf8(&buf[..eoh + EOH.len()]).unwrap();
+			assert!(header.starts_with("POST /upload HTTP/1.1\r\nHost: example.com\r\n"));
+			let filler = header
+				.lines()
+				.find_map(|l| l.strip_prefix("X-Request-Id: "));
+			assert!(filler.unwrap().bytes().all(|b| b.is_ascii_alphanumeric()));
+			lens.push(header.len());
+			assert_eq!(
+				read_msg(&mut buf, &cipher),
+				Some(Req::connect("example.com", 443))
+			);
+		}
+		lens.dedup();
+		assert!(lens.len() > 1, "{:?}", lens);
+
+		// no room left, the header goes as is
+		let mut full = vec![b'a'; MAX_HEADER - EOH.len()];
+		full.extend_from_slice(EOH);
+		let mut buf = BytesMut::new();
+		let wire = Wire {
+			header: &full,
+			..wire
+		};
+		write_msg(&mut buf, &cipher, wire, &req).unwrap();
+		assert!(buf.starts_with(&full));
+		assert_eq!(read_msg(&mut buf, &cipher), Some(req));
+	}
+
+	#[test]
+	fn test_content_length() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let wire = Wire {
+			header: b"POST /upload HTTP/1.1\r\nHost: example.com\r\n\r\n",
+			pad_to: 0,
+			filler: true,
+			content_length: true,
+			..Wire::from(b"")
+		};
+		let req = Req::connect("example.com", 443);
+		// random padding, then fixed sizes either side of a digit boundary
+		for pad_to in [0, 0x400, 1000 + 61, 1000 + 62, 1000 + 63] {
+			let wire = Wire { pad_to, ..wire };
+			for _ in 0..4 {
+				let mut buf = BytesMut::new();
+				write_msg(&mut buf, &cipher, wire, &req).unwrap();
+				if pad_to != 0 {
+					assert_eq!(buf.len(), pad_to);
+				}
+				let eoh = buf.windows(EOH.len()).position(|w| w == EOH).unwrap() + EOH.len();
+				let header = str::from_utf8(&buf[..eoh]).unwrap();
+				let cl = header
+					.lines()
+					.find_map(|l| l.strip_prefix("Content-Length: "))
+					.unwrap();
+				assert_eq!(cl.parse::<usize>().unwrap(), buf.len() - eoh, "{}", header);
+				assert_eq!(
+					read_msg(&mut buf, &cipher),
+					Some(Req::connect("example.com", 443))
+				);
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn test_live_header() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let field = |header: &str, name: &str| {
+			let fields: Vec<_> = header
+				.lines()
+				.filter_map(|l| l.strip_prefix(name)?.strip_prefix(": "))
+				.map(str::to_owned)
+				.collect();
+			assert!(fields.len() <= 1, "{}", header);
+			fields.into_iter().next()
+		};
+		// the template's own Server wins
+		for (template, server) in [
+			(&b"HTTP/1.1 200 OK\r\n\r\n"[..], "Server: "),
+			(
+				b"HTTP/1.1 200 OK\r\nserver: caddy\r\n\r\n",
+				"server: caddy\r\n",
+			),
+		] {
+			let wire = Wire {
+				header: template,
+				pad_to: 0x400,
+				filler: true,
+				content_length: true,
+				..Wire::from(b"")
+			};
+			let mut out = Vec::new();
+			server_reply(&mut out, &cipher, &mut BytesMut::new(), wire, REP_OK, 0)
+				.await
+				.unwrap();
+			assert_eq!(out.len(), 0x400);
+			let eoh = out.windows(EOH.len()).position(|w| w == EOH).unwrap() + EOH.len();
+			let header = str::from_utf8(&out[..eoh]).unwrap();
+			let date = field(header, "Date").unwrap();
+			let age = SystemTime::now()
+				.duration_since(httpdate::parse_http_date(&date).unwrap())
+				.unwrap();
+			assert!(age < Duration::from_secs(60), "{}", date);
+			assert!(date.ends_with(" GMT"), "{}", date);
+			assert_eq!(header.matches(server).count(), 1, "{}", header);
+			assert_eq!(header.to_lowercase().matches("server:").count(), 1);
+			let mut msg = BytesMut::from(&out[..]);
+			assert_eq!(open_msg(&mut msg, &cipher, None), Ok(eoh + 12));
+		}
+
+		// requests go as they are
+		let wire = Wire::from(&b"GET / HTTP/1.1\r\n\r\n"[..]);
+		let mut msg = BytesMut::new();
+		write_msg(&mut msg, &cipher, wire, &Req::connect("example.com", 443)).unwrap();
+		assert!(msg.starts_with(b"GET / HTTP/1.1\r\n\r\n"));
+
+		// and the client takes the reply
+		let (mut c, mut s) = tokio::io::duplex(0x1000);
+		let mut buf = BytesMut::new();
+		let (r, _) = tokio::join!(
+			client_handshake(&mut c, &cipher, &mut buf, "example.com", 443, wire, 0),
+			async {
+				let mut buf = BytesMut::new();
+				server_handshake(&mut s, &cipher, &mut buf, wire)
+					.await
+					.unwrap();
+				let wire = Wire::from(&b"HTTP/1.1 200 OK\r\n\r\n"[..]);
+				server_reply(&mut s, &cipher, &mut buf, wire, REP_OK, 0)
+					.await
+					.unwrap();
+			}
+		);
+		assert_eq!(r, Some(0));
+	}
+
+	// a template with no room left for the live fields or a Content-Length goes without them
+	#[tokio::test]
+	async fn test_live_header_full() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let mut template = b"HTTP/1.1 200 OK\r\nX-Pad: ".to_vec();
+		template.resize(MAX_HEADER - 0x10 - 4, b'a');
+		template.extend_from_slice(b"\r\n\r\n");
+		for (filler, content_length) in [(false, false), (true, true)] {
+			let s_wire = Wire {
+				filler,
+				content_length,
+				..Wire::from(&template[..])
+			};
+			let mut out = Vec::new();
+			server_reply(&mut out, &cipher, &mut BytesMut::new(), s_wire, REP_OK, 0)
+				.await
+				.unwrap();
+			let eoh = out.windows(EOH.len()).position(|w| w == EOH).unwrap() + EOH.len();
+			assert!(eoh <= MAX_HEADER, "{}", eoh);
+
+			let (mut c, mut s) = tokio::io::duplex(0x1000);
+			let c_wire = Wire::from(EOH);
+			let mut buf = BytesMut::new();
+			let (r, _) = tokio::join!(
+				client_handshake(&mut c, &cipher, &mut buf, "example.com", 443, c_wire, 0),
+				async {
+					let mut buf = BytesMut::new();
+					server_handshake(&mut s, &cipher, &mut buf, c_wire)
+						.await
+						.unwrap();
+					server_reply(&mut s, &cipher, &mut buf, s_wire, REP_OK, 0)
+						.await
+						.unwrap();
+				}
+			);
+			assert_eq!(r, Some(0));
+		}
+	}
+
+	#[tokio::test]
+	async fn test_server_pool() {
+		init();
+
+		// the Server values of a few responses
+		async fn servers(pool: &[String]) -> HashSet<String> {
+			let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+			let wire = Wire {
+				servers: pool,
+				..Wire::from(b"HTTP/1.1 200 OK\r\n\r\n")
+			};
+			let mut seen = HashSet::new();
+			for _ in 0..64 {
+				let mut out = Vec::new();
+				server_reply(&mut out, &cipher, &mut BytesMut::new(), wire, REP_OK, 0)
+					.await
+					.unwrap();
+				let header = String::from_utf8_lossy(&out);
+				let server = header.lines().find_map(|l| l.strip_prefix("Server: "));
+				seen.insert(server.unwrap().to_owned());
+			}
+			seen
+		}
+		let seen = servers(&[]).await;
+		assert!(seen.len() > 1, "{:?}", seen);
+		assert!(seen.iter().all(|s| fake::SERVERS.contains(&s.as_str())));
+
+		let pool = ["Caddy".to_owned(), "LiteSpeed".to_owned()];
+		assert_eq!(servers(&pool).await, HashSet::from(pool.clone()));
+	}
+
+	#[tokio::test]
+	async fn test_pad_to() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let wire = Wire {
+			header: b"GET / HTTP/1.1\r\n\r\n",
+			pad_to: 0x400,
+			filler: false,
+			content_length: false,
+			..Wire::from(b"")
+		};
+		for host in ["a", "example.com", &"a".repeat(300)] {
+			let mut msg = BytesMut::new();
+			write_msg(&mut msg, &cipher, wire, &Req::connect(host, 443)).unwrap();
+			assert_eq!(msg.len(), 0x400);
+
+			let (mut c, mut s) = tokio::io::duplex(0x1000);
+			let mut buf = BytesMut::new();
+			let (r, _) = tokio::join!(
+				client_handshake(&mut c, &cipher, &mut buf, host, 443, wire, 0),
+				async {
+					// exactly n bytes, the tag is at the end or this wouldn't decrypt
+					let mut msg = BytesMut::zeroed(0x400);
+					s.read_exact(&mut msg).await.unwrap();
+					let req: Req = read_msg(&mut msg, &cipher).unwrap();
+					assert_eq!(req, Req::connect(host, 443));
+					let mut buf = BytesMut::new();
+					server_reply(&mut s, &cipher, &mut buf, wire, REP_OK, 0)
+						.await
+						.unwrap();
+					assert_eq!(buf.len(), 0x400);
+				}
+			);
+			assert_eq!(r, Some(0));
+		}
+
+		// doesn't fit, nothing is sent
+		let host = "a".repeat(0x400);
+		let mut buf = BytesMut::new();
+		assert_eq!(
+			write_msg(&mut buf, &cipher, wire, &Req::connect(&host, 443)),
+			None
+		);
+		assert!(buf.is_empty());
+		let (mut c, _s) = tokio::io::duplex(0x1000);
+		assert_eq!(
+			client_handshake(&mut c, &cipher, &mut buf, &host, 443, wire, 0).await,
+			None
+		);
 	}
 
-	buf.resize(len as usize, 0);
+	#[test]
+	fn test_version() {
+		init();
+
+		assert_eq!(strip_ver(&[VER, 1, 2]), Ok((VER, &[1u8, 2][..])));
+		assert_eq!(strip_ver(&[]), Err(VerError::Empty));
+		assert_eq!(
+			strip_ver(&[VER + 1, 1, 2]),
+			Err(VerError::Mismatch(VER + 1))
+		);
+
+		let mut buf = BytesMut::new();
+		Req::connect("example.com", 443).write(&mut buf);
+		buf[0] = VER + 1;
+		assert_eq!(Req::read(&buf), None);
+
+		let mut buf = BytesMut::new();
+		Resp::new(REP_OK, 0).write(&mut buf);
+		assert_eq!(Resp::read(&buf), Some(Resp::new(REP_OK, 0)));
+		buf[0] = VER + 1;
+		assert_eq!(Resp::read(&buf), None);
+	}
+
+	#[test]
+	fn test_long_host() {
+		init();
+
+		let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+		let cipher = ChaCha20Poly1305::new(&key);
+
+		let host = "a".repeat(300);
+		let req = Req::connect(&host, 443);
+		let mut buf = BytesMut::new();
+		req.write(&mut buf);
+		assert_eq!(buf[0], VER);
+
+		let mut buf = BytesMut::with_capacity(1024);
+		write_msg(&mut buf, &cipher, EOH, &req);
+		let req_r: Req = read_msg(&mut buf, &cipher).unwrap();
+		assert_eq!(req, req_r);
+
+		// longer than the length field takes, refused instead of cut short
+		let host = "a".repeat(u16::MAX as usize + 1);
+		let mut buf = BytesMut::new();
+		assert_eq!(
+			write_req(&mut buf, &cipher, EOH.into(), &Req::connect(&host, 443)),
+			None
+		);
+		assert!(buf.is_empty());
+	}
+
+	// the layout from before features, byte for byte
+	#[test]
+	fn test_ver_plain() {
+		init();
+
+		let mut plain = vec![VER_PLAIN, 11];
+		plain.extend_from_slice(b"example.com");
+		plain.extend_from_slice(&443u16.to_be_bytes());
+		let req = Req {
+			ver: VER_PLAIN,
+			..Req::connect("example.com", 443)
+		};
+		assert_eq!(Req::read(&plain), Some(req));
+		let mut buf = BytesMut::new();
+		Req::read(&plain).unwrap().write(&mut buf);
+		assert_eq!(&buf[..], &plain[..]);
+
+		let resp = Resp {
+			ver: VER_PLAIN,
+			..Resp::new(REP_PORT_DENIED, 0)
+		};
+		let mut buf = BytesMut::new();
+		resp.write(&mut buf);
+		assert_eq!(&buf[..], &[VER_PLAIN, REP_PORT_DENIED]);
+		assert_eq!(Resp::read(&buf), Some(resp));
+	}
+
+	// features before CMD, read as a connect, not with the features for a CMD
+	#[test]
+	fn test_ver_features() {
+		init();
+
+		let host = "a".repeat(300);
+		let mut features = vec![VER_FEATURES, FEAT_DUMMY, 0x01, 0x2c];
+		features.extend_from_slice(host.as_bytes());
+		features.extend_from_slice(&443u16.to_be_bytes());
+		let req = Req {
+			ver: VER_FEATURES,
+			features: FEAT_DUMMY,
+			..Req::connect(&host, 443)
+		};
+		assert_eq!(Req::read(&features), Some(req));
+		let mut buf = BytesMut::new();
+		Req::read(&features).unwrap().write(&mut buf);
+		assert_eq!(&buf[..], &features[..]);
+	}
+
+	// every write on its own, to tell one write from several
+	#[derive(Default)]
+	struct Writes(Vec<Vec<u8>>);
+
+	impl AsyncWrite for Writes {
+		fn poll_write(
+			mut self: std::pin::Pin<&mut Self>,
+			_: &mut std::task::Context<'_>,
+			buf: &[u8],
+		) -> std::task::Poll<io::Result<usize>> {
+			self.0.push(buf.to_vec());
+			std::task::Poll::Ready(Ok(buf.len()))
+		}
+		fn poll_flush(
+			self: std::pin::Pin<&mut Self>,
+			_: &mut std::task::Context<'_>,
+		) -> std::task::Poll<io::Result<()>> {
+			std::task::Poll::Ready(Ok(()))
+		}
+		fn poll_shutdown(
+			self: std::pin::Pin<&mut Self>,
+			_: &mut std::task::Context<'_>,
+		) -> std::task::Poll<io::Result<()>> {
+			std::task::Poll::Ready(Ok(()))
+		}
+	}
+
+	#[tokio::test]
+	async fn test_early() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let early = b"GET / HTTP/1.1\r\n";
+		let req = Req {
+			features: FEAT_EARLY,
+			early,
+			..Req::connect("example.com", 80)
+		};
+
+		// after the client key part, which signs everything but it
+		let (private, _) = crate::key::gen_key_pair();
+		let key = crate::key::decode_sign_key(private.as_bytes()).unwrap();
+		let mut buf = BytesMut::new();
+		SignedReq(&req, &key).write(&mut buf);
+		let signed = Req::read(&buf).unwrap();
+		assert!(signed.auth.unwrap().check(unix_time()));
+		assert_eq!(signed.early, early);
+
+		let (mut c, mut s) = tokio::io::duplex(0x1000);
+		let mut upstream = Writes::default();
+		tokio::join!(
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				write_req(&mut buf, &cipher, EOH.into(), &req).unwrap();
+				c.write_all(&buf).await.unwrap();
+				let resp: Resp = recv_msg(&mut c, &mut buf, &cipher, None)
+					.await
+					.unwrap()
+					.unwrap();
+				assert_eq!(resp.rep, REP_OK);
+
+				let mut rest = &b"Host: example.com\r\n\r\n"[..];
+				enc1(&mut buf, &cipher, &FrameOpts::default(), &mut c, &mut rest)
+					.await
+					.unwrap();
+				c.shutdown().await.unwrap();
+			},
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				let req = server_handshake(&mut s, &cipher, &mut buf, EOH)
+					.await
+					.unwrap();
+				assert_eq!(req.early, early);
+				server_reply(&mut s, &cipher, &mut buf, EOH, REP_OK, 0)
+					.await
+					.unwrap();
+
+				write_first(&mut upstream, b"", &req.early).await.unwrap();
+				let mut plain = tokio::io::join(tokio::io::empty(), &mut upstream);
+				duplex(&cipher, &FrameOpts::default(), &mut plain, &mut s).await;
+			}
+		);
+
+		// in one write, ahead of the rest, and only once
+		assert_eq!(upstream.0[0], early);
+		assert_eq!(
+			upstream.0.concat(),
+			b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n"
+		);
+	}
+
+	// an older client gets its answer in the layout it asked in
+	#[tokio::test]
+	async fn test_reply_ver_plain() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let (mut c, mut s) = tokio::io::duplex(0x500);
+		tokio::join!(
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				let req = Req {
+					ver: VER_PLAIN,
+					..Req::connect("example.com", 443)
+				};
+				write_msg(&mut buf, &cipher, EOH, &req);
+				c.write_all(&buf).await.unwrap();
+				let offset = recv_msg_at(&mut c, &mut buf, slice::from_ref(&cipher), None)
+					.await
+					.unwrap()
+					.unwrap()
+					.0;
+				assert_eq!(&buf[offset..offset + 2], &[VER_PLAIN, REP_OK]);
+			},
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				let req = server_handshake(&mut s, &cipher, &mut buf, EOH)
+					.await
+					.unwrap();
+				assert_eq!(
+					(req.ver, req.cmd, req.host.as_str(), req.port, req.features),
+					(VER_PLAIN, CMD_CONNECT, "example.com", 443, 0)
+				);
+				let wire = Wire {
+					ver: req.ver,
+					..EOH.into()
+				};
+				server_reply(&mut s, &cipher, &mut buf, wire, REP_OK, 0)
+					.await
+					.unwrap();
+			}
+		);
+	}
+
+	#[tokio::test]
+	async fn test_reduced_round() {
+		init();
+
+		let key = ChaCha8Poly1305::generate_key(&mut OsRng);
+		let cipher = ChaCha8Poly1305::new(&key);
+
+		let mut buf = BytesMut::with_capacity(1024);
+		let req = Req::connect("example.com", 443);
+		write_msg(&mut buf, &cipher, EOH, &req);
+		let req_r: Req = read_msg(&mut buf, &cipher).unwrap();
+		assert_eq!(req, req_r);
+
+		let mut buf = BytesMut::with_capacity(0x100);
+		let (mut b, mut a) = tokio::io::simplex(0x100);
+		let (mut d, mut c) = tokio::io::simplex(0x100);
+
+		let test_payload = b"half the rounds, all the fun.";
+		a.write_all(test_payload).await.unwrap();
+
+		let opts = FrameOpts::default();
+		enc1(&mut buf, &cipher, &opts, &mut c, &mut b)
+			.await
+			.unwrap();
+
+		dec1(&mut buf, &cipher, &opts, &mut a, &mut d)
+			.await
+			.unwrap();
+
+		buf.clear();
+		b.read_buf(&mut buf).await.unwrap();
+
+		assert_eq!(test_payload, &buf[..]);
+	}
+
+	#[tokio::test]
+	async fn test_handshake() {
+		init();
+
+		let (mut c, mut s) = tokio::io::duplex(0x500);
+
+		let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+		let cipher = ChaCha20Poly1305::new(&key);
+
+		tokio::join!(
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				assert_eq!(
+					Some(0),
+					client_handshake(&mut c, &cipher, &mut buf, "example.com", 443, EOH, 0).await
+				);
+			},
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				let req = server_handshake(&mut s, &cipher, &mut buf, EOH)
+					.await
+					.unwrap();
+				assert_eq!(
+					(req.cmd, req.host.as_str(), req.port, req.features),
+					(CMD_CONNECT, "example.com", 443, 0)
+				);
+				assert_eq!(req.nonce.len(), nonce_size::<ChaCha20Poly1305>());
+				server_reply(&mut s, &cipher, &mut buf, EOH, REP_OK, 0)
+					.await
+					.unwrap();
+			}
+		);
+	}
+
+	#[tokio::test]
+	async fn test_signed_resp() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let pair = || {
+			let (private, public) = crate::key::gen_key_pair();
+			let signer = crate::key::decode_sign_key(private.as_bytes()).unwrap();
+			(
+				signer,
+				crate::key::decode_public_key(public.as_bytes()).unwrap(),
+			)
+		};
+		let (signer, public) = pair();
+		let (_, other) = pair();
+
+		let handshake = async |server_key: Option<&[u8]>, signer: Option<&Ed25519KeyPair>| {
+			let (mut c, mut s) = tokio::io::duplex(0x500);
+			let c_wire = Wire {
+				server_key,
+				..EOH.into()
+			};
+			let s_wire = Wire {
+				signer,
+				..EOH.into()
+			};
+			let (r, ()) = tokio::join!(
+				async {
+					let mut buf = BytesMut::with_capacity(0x500);
+					client_handshake(&mut c, &cipher, &mut buf, "example.com", 443, c_wire, 0).await
+				},
+				async {
+					let mut buf = BytesMut::with_capacity(0x500);
+					let req = server_handshake(&mut s, &cipher, &mut buf, s_wire)
+						.await
+						.unwrap();
+					let wire = Wire {
+						challenge: &req.nonce,
+						..s_wire
+					};
+					let _ = server_reply(&mut s, &cipher, &mut buf, wire, REP_OK, 0).await;
+				}
+			);
+			r
+		};
+
+		assert_eq!(handshake(Some(&public), Some(&signer)).await, Some(0));
+		// someone else with the PSK
+		assert_eq!(handshake(Some(&other), Some(&signer)).await, None);
+		assert_eq!(handshake(Some(&public), None).await, None);
+		// clients that don't check take the signature for padding
+		assert_eq!(handshake(None, Some(&signer)).await, Some(0));
+	}
+
+	#[tokio::test]
+	async fn test_features() {
+		init();
+
+		let (mut c, mut s) = tokio::io::duplex(0x500);
+
+		let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+		let cipher = ChaCha20Poly1305::new(&key);
 
-	if let Err(e) = encrypted.read_exact(buf).await {
-		error!("failed to read payload: {}", e);
-		return None;
-	}
+		let client_opts = FrameOpts {
+			dummy_interval: Duration::from_millis(100),
+			..Default::default()
+		};
+		// the server doesn't do dummy frames
+		let server_opts = FrameOpts::default();
 
-	if let Err(e) = cipher.decrypt_in_place(&nonce, b"", buf) {
-		error!("failed to decrypt payload: {}", e);
-		return None;
+		let (c_features, s_features) = tokio::join!(
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				client_handshake(
+					&mut c,
+					&cipher,
+					&mut buf,
+					"example.com",
+					443,
+					EOH,
+					client_opts.features(),
+				)
+				.await
+				.unwrap()
+			},
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				let req = server_handshake(&mut s, &cipher, &mut buf, EOH)
+					.await
+					.unwrap();
+				assert_eq!(req.features, FEAT_DUMMY);
+				let agreed = req.features & server_opts.features();
+				server_reply(&mut s, &cipher, &mut buf, EOH, REP_OK, agreed)
+					.await
+					.unwrap();
+				agreed
+			}
+		);
+		assert_eq!(c_features, 0);
+		assert_eq!(s_features, 0);
+		assert!(client_opts.negotiated(c_features).dummy_interval.is_zero());
 	}
 
-	plain
-		.write_all(buf)
-		.await
-		.map_err(|e| {
-			error!("failed to write decrypted payload: {}", e);
-		})
-		.ok()
-}
+	#[tokio::test]
+	async fn test_dns() {
+		init();
 
-pub async fn duplex<
-	C: AeadCore + AeadInPlace,
-	P: AsyncRead + AsyncWrite + Unpin,
-	E: AsyncRead + AsyncWrite + Unpin,
->(
-	cipher: &C,
-	plain: &mut P,
-	encrypted: &mut E,
-) {
-	let (mut p_r, mut p_w) = split(plain);
-	let (mut e_r, mut e_w) = split(encrypted);
-	tokio::join!(
-		simplex(cipher, enc1, &mut e_w, &mut p_r),
-		simplex(cipher, dec1, &mut p_w, &mut e_r),
-	);
-}
+		let (mut c, mut s) = tokio::io::duplex(0x500);
 
-pub async fn simplex<
-	C: AeadCore + AeadInPlace,
-	F: AsyncFn(&mut BytesMut, &C, &mut W, &mut R) -> Option<()>,
-	W: AsyncWrite + Unpin,
-	R: AsyncRead + Unpin,
->(
-	cipher: &C,
-	codec: F,
-	w: &mut W,
-	r: &mut R,
-) -> Option<()> {
-	// enclosed so I can use ? and still guarantee shutdown
-	// is there a better pattern?
-	async {
-		let mut buf = BytesMut::with_capacity(0x1000);
-		codec(&mut buf, cipher, w, r).await?;
-		codec(&mut buf, cipher, w, r).await?;
-		codec(&mut buf, cipher, w, r).await?;
-		drop(buf);
-		copy(r, w)
-			.await
-			.inspect_err(|e| debug!("error copying: {}", e))
-			.ok()
+		let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+		let cipher = ChaCha20Poly1305::new(&key);
+
+		// stands in for the server's resolver
+		let stub = |host: &str| -> Vec<IpAddr> {
+			match host {
+				"example.com" => vec!["192.0.2.1".parse().unwrap(), "2001:db8::1".parse().unwrap()],
+				_ => vec![],
+			}
+		};
+
+		for host in ["example.com", "nx.example.com"] {
+			let (addrs, ()) = tokio::join!(
+				async {
+					let mut buf = BytesMut::with_capacity(0x500);
+					client_resolve(&mut c, &cipher, &mut buf, host, EOH).await
+				},
+				async {
+					let mut buf = BytesMut::with_capacity(0x500);
+					let req = server_handshake(&mut s, &cipher, &mut buf, EOH)
+						.await
+						.unwrap();
+					assert_eq!(req.cmd, CMD_DNS);
+					server_dns_reply(&mut s, &cipher, &mut buf, EOH, &stub(&req.host))
+						.await
+						.unwrap();
+				}
+			);
+			let expected = stub(host);
+			let expected = if expected.is_empty() {
+				Err(REP_DNS_FAILED)
+			} else {
+				Ok(expected)
+			};
+			assert_eq!(addrs, Some(expected));
+		}
 	}
-	.await;
-	w.shutdown()
-		.await
-		.inspect_err(|e| debug!("error shutting down: {}", e))
-		.ok()
-}
 
-// is there a less verbose way?
-const fn nonce_size<C: AeadCore>() -> usize {
-	std::mem::size_of::<Nonce<C>>()
-}
+	#[test]
+	fn test_cmd() {
+		init();
 
-// make len look random
-fn obfuscate(a: u16, b: &[u8]) -> u16 {
-	a ^ u16::from_be_bytes([b[4 % b.len()], b[2 % b.len()]])
-}
+		for cmd in [CMD_CONNECT, CMD_DNS, CMD_UDP, CMD_BIND, CMD_PING] {
+			let req = Req {
+				cmd,
+				..Req::connect("example.com", 443)
+			};
+			let mut buf = BytesMut::new();
+			req.write(&mut buf);
+			assert_eq!(buf[1], cmd);
+			assert_eq!(Req::read(&buf), Some(req));
+		}
+	}
 
-#[cfg(test)]
-mod test {
-	use bytes::BytesMut;
-	use chacha20poly1305::{AeadCore, ChaCha20Poly1305, KeyInit, aead::OsRng};
+	#[tokio::test]
+	async fn test_ping() {
+		init();
 
-	use super::*;
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		// a server without CMD_PING refuses it, that's an answer too
+		for rep in [REP_OK, REP_BAD_CMD] {
+			let (mut c, mut s) = tokio::io::duplex(0x500);
+			let (pong, _) = tokio::join!(
+				async {
+					let mut buf = BytesMut::with_capacity(0x500);
+					client_ping(&mut c, &cipher, &mut buf, EOH).await
+				},
+				async {
+					let mut buf = BytesMut::with_capacity(0x500);
+					let req = server_handshake(&mut s, &cipher, &mut buf, EOH)
+						.await
+						.unwrap();
+					assert_eq!(req.cmd, CMD_PING);
+					server_reply(&mut s, &cipher, &mut buf, EOH, rep, 0).await
+				}
+			);
+			assert_eq!(pong, Some(Ok(())));
+		}
 
-	fn init() {
-		let _ = env_logger::builder().is_test(true).try_init();
+		// nothing comes back
+		let (mut c, s) = tokio::io::duplex(0x500);
+		drop(s);
+		let mut buf = BytesMut::with_capacity(0x500);
+		assert_eq!(client_ping(&mut c, &cipher, &mut buf, EOH).await, None);
+
+		// an answer under another key
+		let other = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let (mut c, mut s) = tokio::io::duplex(0x500);
+		let (pong, _) = tokio::join!(
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				client_ping(&mut c, &cipher, &mut buf, EOH).await
+			},
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				server_handshake(&mut s, &cipher, &mut buf, EOH)
+					.await
+					.unwrap();
+				server_reply(&mut s, &other, &mut buf, EOH, REP_OK, 0).await
+			}
+		);
+		assert_eq!(pong, Some(Err(MsgError::Decrypt)));
 	}
 
-	#[test]
-	fn test_payload() {
+	#[tokio::test]
+	async fn test_bad_cmd() {
 		init();
 
-		let key = ChaCha20Poly1305::generate_key(&mut OsRng);
-		println!("key len: {}", key.len());
-		let cipher = ChaCha20Poly1305::new(&key);
-		let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-		println!("nonce len: {}", nonce.len());
-		assert_eq!(nonce.len(), nonce_size::<ChaCha20Poly1305>());
-
-		let mut buf = BytesMut::with_capacity(1024);
-		let req = Req("example.com", 443);
-		write_msg(&mut buf, &cipher, EOH, &req);
-		let req_r: Req = read_msg(&mut buf, &cipher).unwrap();
-		assert_eq!(req, req_r);
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		for cmd in [CMD_BIND, 0x7f] {
+			let (mut c, mut s) = tokio::io::duplex(0x500);
+			tokio::join!(
+				async {
+					let mut buf = BytesMut::with_capacity(0x500);
+					let req = Req {
+						cmd,
+						..Req::connect("example.com", 443)
+					};
+					write_msg(&mut buf, &cipher, EOH, &req);
+					c.write_all(&buf).await.unwrap();
+					let resp: Resp = recv_msg(&mut c, &mut buf, &cipher, None)
+						.await
+						.unwrap()
+						.unwrap();
+					assert_eq!(resp, Resp::new(REP_BAD_CMD, 0));
+				},
+				async {
+					let mut buf = BytesMut::with_capacity(0x500);
+					assert_eq!(
+						Err(Rejected::Replied),
+						server_handshake(&mut s, &cipher, &mut buf, EOH).await
+					);
+				}
+			);
+		}
 	}
 
 	#[tokio::test]
-	async fn test_handshake() {
+	async fn test_port_0() {
 		init();
 
 		let (mut c, mut s) = tokio::io::duplex(0x500);
@@ -389,18 +3071,27 @@ mod test {
 		let key = ChaCha20Poly1305::generate_key(&mut OsRng);
 		let cipher = ChaCha20Poly1305::new(&key);
 
+		let mut buf = BytesMut::with_capacity(0x500);
+		assert_eq!(
+			None,
+			client_handshake(&mut c, &cipher, &mut buf, "example.com", 0, EOH, 0).await
+		);
+
+		// bypass the client side check
 		tokio::join!(
 			async {
 				let mut buf = BytesMut::with_capacity(0x500);
-				assert_eq!(
-					Some(()),
-					client_handshake(&mut c, &cipher, &mut buf, "example.com", 443, EOH).await
-				);
+				write_msg(&mut buf, &cipher, EOH, &Req::connect("example.com", 0));
+				c.write_all(&buf).await.unwrap();
+				buf.clear();
+				c.read_buf(&mut buf).await.unwrap();
+				let resp: Resp = read_msg(&mut buf, &cipher).unwrap();
+				assert_eq!(resp, Resp::new(REP_BAD_PORT, 0));
 			},
 			async {
 				let mut buf = BytesMut::with_capacity(0x500);
 				assert_eq!(
-					Some(("example.com".to_owned(), 443)),
+					Err(Rejected::Replied),
 					server_handshake(&mut s, &cipher, &mut buf, EOH).await
 				);
 			}
@@ -421,13 +3112,382 @@ mod test {
 		let test_payload = b"you're (not) welcome.";
 		a.write_all(test_payload).await.unwrap();
 
-		enc1(&mut buf, &cipher, &mut c, &mut b).await.unwrap();
+		let opts = FrameOpts::default();
+		enc1(&mut buf, &cipher, &opts, &mut c, &mut b)
+			.await
+			.unwrap();
+
+		dec1(&mut buf, &cipher, &opts, &mut a, &mut d)
+			.await
+			.unwrap();
+
+		buf.clear();
+		b.read_buf(&mut buf).await.unwrap();
+
+		assert_eq!(test_payload, &buf[..]);
+	}
+
+	#[tokio::test]
+	async fn test_duplex() {
+		init();
+
+		let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+		let cipher = ChaCha20Poly1305::new(&key);
+		let opts = FrameOpts::default();
+
+		// app a <-> duplex <-> wire <-> duplex <-> app b
+		let (mut a, mut a_plain) = tokio::io::duplex(0x1000);
+		let (mut b, mut b_plain) = tokio::io::duplex(0x1000);
+		let (mut wire_a, mut wire_b) = tokio::io::duplex(0x1000);
+
+		let a_data: Vec<u8> = (0..0x10000).map(|i| i as u8).collect();
+		let b_data: Vec<u8> = (0..0x8000).map(|i| (i * 7) as u8).collect();
+
+		// both directions at once, then a half-close from each side
+		let app = async |s: &mut tokio::io::DuplexStream, data: &[u8]| {
+			let (mut r, mut w) = split(s);
+			let (_, got) = tokio::join!(
+				async {
+					for c in data.chunks(0x300) {
+						w.write_all(c).await.unwrap();
+					}
+					w.shutdown().await.unwrap();
+				},
+				async {
+					let mut got = Vec::new();
+					r.read_to_end(&mut got).await.unwrap();
+					got
+				}
+			);
+			got
+		};
+
+		let (a_got, b_got, a_n, b_n) = tokio::join!(
+			app(&mut a, &a_data),
+			app(&mut b, &b_data),
+			duplex(&cipher, &opts, &mut a_plain, &mut wire_a),
+			duplex(&cipher, &opts, &mut b_plain, &mut wire_b),
+		);
+
+		assert_eq!(a_got, b_data);
+		assert_eq!(b_got, a_data);
+		let (a_len, b_len) = (a_data.len() as u64, b_data.len() as u64);
+		assert_eq!(a_n, (a_len, b_len, End::Closed));
+		assert_eq!(b_n, (b_len, a_len, End::Closed));
+	}
+
+	// the plain side resets instead of closing
+	struct Reset;
+
+	impl AsyncRead for Reset {
+		fn poll_read(
+			self: std::pin::Pin<&mut Self>,
+			_: &mut std::task::Context<'_>,
+			_: &mut tokio::io::ReadBuf<'_>,
+		) -> std::task::Poll<io::Result<()>> {
+			std::task::Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()))
+		}
+	}
+
+	#[tokio::test]
+	async fn test_duplex_end() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let opts = FrameOpts::default();
+
+		// the peer closes, even halfway through a frame
+		for cut in [0, 5] {
+			let (mut wire, mut peer) = tokio::io::duplex(0x100);
+			peer.write_all(&[0u8; 0x10][..cut]).await.unwrap();
+			drop(peer);
+			let mut plain = tokio::io::join(&b""[..], tokio::io::sink());
+			let r = duplex(&cipher, &opts, &mut plain, &mut wire).await;
+			assert_eq!(r, (0, 0, End::Closed));
+		}
+
+		let (mut wire, peer) = tokio::io::duplex(0x100);
+		drop(peer);
+		let mut plain = tokio::io::join(Reset, tokio::io::sink());
+		let r = duplex(&cipher, &opts, &mut plain, &mut wire).await;
+		assert_eq!(r, (0, 0, End::Failed(io::ErrorKind::ConnectionReset)));
+	}
+
+	// as much plain data as anyone reads, counted
+	struct Endless(std::rc::Rc<std::cell::Cell<usize>>);
+
+	impl AsyncRead for Endless {
+		fn poll_read(
+			self: std::pin::Pin<&mut Self>,
+			_: &mut std::task::Context<'_>,
+			buf: &mut tokio::io::ReadBuf<'_>,
+		) -> std::task::Poll<io::Result<()>> {
+			let n = buf.remaining();
+			buf.put_slice(&vec![0; n]);
+			self.0.set(self.0.get() + n);
+			std::task::Poll::Ready(Ok(()))
+		}
+	}
+
+	#[tokio::test]
+	async fn test_duplex_bounded() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let opts = FrameOpts::default();
+
+		// a fast source, a peer that takes a little at a time
+		let read = std::rc::Rc::new(std::cell::Cell::new(0));
+		let mut plain = tokio::io::join(Endless(read.clone()), tokio::io::sink());
+		let (mut wire, mut peer) = tokio::io::duplex(0x1000);
+		let mut taken = 0;
+		let slow = async {
+			let mut buf = [0u8; 0x100];
+			for _ in 0..200 {
+				taken += peer.read(&mut buf).await.unwrap();
+				tokio::task::yield_now().await;
+			}
+		};
+		tokio::select! {
+			_ = duplex(&cipher, &opts, &mut plain, &mut wire) => unreachable!(),
+			_ = slow => {}
+		}
+		// the pipe, a frame in flight and the copy buffer past the first frames
+		assert!(taken > 0);
+		assert!(read.get() <= taken + 0x1000 + FRAME_READ + 0x2000);
+	}
+
+	#[cfg(debug_assertions)]
+	#[test]
+	#[should_panic(expected = "nonce reused")]
+	fn test_nonce_reuse() {
+		// as if the RNG had come up with it again
+		let nonce = fresh_nonce::<ChaCha20Poly1305>();
+		nonces::check(&nonce);
+	}
+
+	#[tokio::test]
+	async fn test_jitter() {
+		init();
+
+		let max = Duration::from_millis(20);
+		for _ in 0..1000 {
+			assert!(jitter_delay(max) <= max);
+		}
+
+		let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+		let cipher = ChaCha20Poly1305::new(&key);
+		let opts = FrameOpts {
+			jitter: max,
+			..Default::default()
+		};
+
+		let mut buf = BytesMut::with_capacity(0x100);
+		let (mut b, mut a) = tokio::io::simplex(0x100);
+		let (mut d, mut c) = tokio::io::simplex(0x100);
+
+		let test_payload = b"fashionably late.";
+		a.write_all(test_payload).await.unwrap();
+
+		let start = tokio::time::Instant::now();
+		enc1(&mut buf, &cipher, &opts, &mut c, &mut b)
+			.await
+			.unwrap();
+		// generous slack for slow CI
+		assert!(start.elapsed() < max + Duration::from_millis(200));
+
+		dec1(&mut buf, &cipher, &opts, &mut a, &mut d)
+			.await
+			.unwrap();
+
+		buf.clear();
+		b.read_buf(&mut buf).await.unwrap();
+
+		assert_eq!(test_payload, &buf[..]);
+	}
+
+	#[tokio::test]
+	async fn test_dummy() {
+		init();
+
+		let key = ChaCha20Poly1305::generate_key(&mut OsRng);
+		let cipher = ChaCha20Poly1305::new(&key);
+		let opts = FrameOpts {
+			dummy_interval: Duration::from_millis(5),
+			dummy_burst: 2,
+			..Default::default()
+		};
+
+		let mut buf = BytesMut::with_capacity(0x100);
+		let (mut b, mut a) = tokio::io::simplex(0x100);
+		let (mut d, mut c) = tokio::io::simplex(0x4000);
+
+		write_dummy(&cipher, &mut c).await.unwrap();
+
+		let test_payload = b"nobody here but us chickens.";
+		tokio::join!(
+			async {
+				enc1(&mut buf, &cipher, &opts, &mut c, &mut b)
+					.await
+					.unwrap();
+			},
+			async {
+				// stay idle long enough for a few bursts
+				sleep(Duration::from_millis(30)).await;
+				a.write_all(test_payload).await.unwrap();
+			}
+		);
+		c.shutdown().await.unwrap();
+
+		dec1(&mut buf, &cipher, &opts, &mut a, &mut d)
+			.await
+			.unwrap();
 
-		dec1(&mut buf, &cipher, &mut a, &mut d).await.unwrap();
+		// nothing but the real payload made it through
+		let mut rest = Vec::new();
+		d.read_to_end(&mut rest).await.unwrap();
+		assert!(rest.is_empty());
 
 		buf.clear();
 		b.read_buf(&mut buf).await.unwrap();
 
 		assert_eq!(test_payload, &buf[..]);
 	}
+
+	// the stream stays framed, so the dummies keep coming once it's idle
+	#[tokio::test]
+	async fn test_dummy_idle() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let opts = FrameOpts {
+			dummy_interval: Duration::from_millis(5),
+			dummy_burst: 1,
+			..Default::default()
+		};
+
+		let (app, mut plain) = tokio::io::duplex(0x1000);
+		let (mut encrypted, mut far) = tokio::io::duplex(0x10000);
+		tokio::join!(
+			async {
+				duplex(&cipher, &opts, &mut plain, &mut encrypted).await;
+			},
+			async {
+				let mut app = app;
+				let mut buf = BytesMut::with_capacity(0x100);
+				let mut got = Vec::new();
+				for i in 0..5u8 {
+					app.write_all(&[i; 8]).await.unwrap();
+					let frame = dec1(&mut buf, &cipher, &opts, &mut got, &mut far);
+					timeout(Duration::from_millis(500), frame)
+						.await
+						.expect("no data frame")
+						.unwrap();
+				}
+				assert_eq!(got.len(), 5 * 8);
+
+				let mut idle = [0; 0x100];
+				let n = timeout(Duration::from_millis(500), far.read(&mut idle))
+					.await
+					.expect("no dummy frames while idle")
+					.unwrap();
+				assert!(n > 0);
+
+				drop(app);
+				far.shutdown().await.unwrap();
+			}
+		);
+	}
+
+	// a VER_PLAIN session, the frame is nothing but the data
+	#[tokio::test]
+	async fn test_untyped() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let opts = FrameOpts::default().in_ver(VER_PLAIN);
+		assert!(opts.untyped);
+		assert!(!FrameOpts::default().in_ver(VER).untyped);
+
+		let mut buf = BytesMut::with_capacity(0x100);
+		let (mut b, mut a) = tokio::io::simplex(0x100);
+		let (mut d, mut c) = tokio::io::simplex(0x100);
+
+		let test_payload = b"just like old times.";
+		a.write_all(test_payload).await.unwrap();
+		enc1(&mut buf, &cipher, &opts, &mut c, &mut b)
+			.await
+			.unwrap();
+		assert_eq!(
+			buf.len(),
+			nonce_size::<ChaCha20Poly1305>() + 2 + test_payload.len() + 16
+		);
+
+		dec1(&mut buf, &cipher, &opts, &mut a, &mut d)
+			.await
+			.unwrap();
+		buf.clear();
+		b.read_buf(&mut buf).await.unwrap();
+		assert_eq!(test_payload, &buf[..]);
+	}
+
+	#[tokio::test]
+	async fn test_reuse() {
+		init();
+
+		let cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let opts = FrameOpts {
+			reuse: true,
+			..Default::default()
+		};
+		let (mut c, mut s) = tokio::io::duplex(0x1000);
+		tokio::join!(
+			async {
+				let mut buf = BytesMut::new();
+				for port in [80, 443] {
+					let req = server_handshake(&mut s, &cipher, &mut buf, EOH)
+						.await
+						.unwrap();
+					assert_eq!(req.port, port);
+					let features = req.features & opts.features();
+					server_reply(&mut s, &cipher, &mut buf, EOH, REP_OK, features)
+						.await
+						.unwrap();
+					let opts = opts.negotiated(features);
+					// an echo upstream, so nothing follows the reply until the client speaks
+					let (mut u, mut echo) = tokio::io::duplex(0x100);
+					let (r, ()) =
+						tokio::join!(duplex_framed(&cipher, &opts, &mut u, &mut s), async {
+							let (mut r, mut w) = split(&mut echo);
+							copy(&mut r, &mut w).await.unwrap();
+							w.shutdown().await.unwrap();
+						});
+					assert_eq!(r, (4, 4, None));
+				}
+			},
+			async {
+				let mut buf = BytesMut::new();
+				// one connection, two requests
+				for port in [80, 443] {
+					let features = client_handshake(
+						&mut c,
+						&cipher,
+						&mut buf,
+						"example.com",
+						port,
+						EOH,
+						opts.features(),
+					)
+					.await
+					.unwrap();
+					assert_eq!(features, FEAT_REUSE);
+					let opts = opts.negotiated(features);
+					let mut plain = tokio::io::join(&b"ping"[..], Vec::new());
+					let r = duplex_framed(&cipher, &opts, &mut plain, &mut c).await;
+					assert_eq!(r, (4, 4, None));
+					assert_eq!(plain.into_inner().1, b"ping");
+				}
+			}
+		);
+	}
 }
@@ -0,0 +1,212 @@
+use std::{
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	time::Duration,
+};
+
+use log::*;
+use tokio::{
+	io::{AsyncRead, AsyncReadExt},
+	time::timeout,
+};
+
+// a load balancer sends it right away
+const WAIT: Duration = Duration::from_secs(5);
+
+const V2_SIG: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V2_CMD_LOCAL: u8 = 0x20;
+const V2_CMD_PROXY: u8 = 0x21;
+const V2_TCP4: u8 = 0x11;
+const V2_TCP6: u8 = 0x21;
+
+// the longest v1 line, CRLF included
+const V1_MAX: usize = 107;
+
+// reads the PROXY protocol v1 or v2 header off s, nothing past it
+// returns the client's address, or peer's for health checks and unknown families
+// None if there's no valid header, the connection should be dropped
+pub async fn accept<S: AsyncRead + Unpin>(s: &mut S, peer: SocketAddr) -> Option<SocketAddr> {
+	let r = timeout(WAIT, read_header(s, peer))
+		.await
+		.map_err(|_| debug!("no PROXY header from {} in time", peer))
+		.ok()?;
+	if r.is_none() {
+		debug!("invalid PROXY header from {}", peer);
+	}
+	r
+}
+
+async fn read_header<S: AsyncRead + Unpin>(s: &mut S, peer: SocketAddr) -> Option<SocketAddr> {
+	// enough to tell them apart, shorter than any v1 line
+	let mut head = [0u8; 12];
+	s.read_exact(&mut head).await.ok()?;
+	if &head == V2_SIG {
+		let mut rest = [0u8; 4];
+		s.read_exact(&mut rest).await.ok()?;
+		let len = u16::from_be_bytes([rest[2], rest[3]]) as usize;
+		let mut body = vec![0; len];
+		s.read_exact(&mut body).await.ok()?;
+		return parse_v2(rest[0], rest[1], &body, peer);
+	}
+	if !head.starts_with(b"PROXY ") {
+		return None;
+	}
+	let mut line = head.to_vec();
+	while !line.ends_with(b"\r\n") {
+		if line.len() >= V1_MAX {
+			return None;
+		}
+		line.push(s.read_u8().await.ok()?);
+	}
+	parse_v1(&line, peer)
+}
+
+fn parse_v1(line: &[u8], peer: SocketAddr) -> Option<SocketAddr> {
+	let line = str::from_utf8(line).ok()?.strip_suffix("\r\n")?;
+	let mut f = line.split(' ');
+	if f.next()? != "PROXY" {
+		return None;
+	}
+	let family = f.next()?;
+	if family == "UNKNOWN" {
+		return Some(peer);
+	}
+	let (src, _dst) = (f.next()?.parse::<IpAddr>().ok()?, f.next()?);
+	let (port, _dport) = (f.next()?.parse::<u16>().ok()?, f.next()?);
+	let ok = match family {
+		"TCP4" => src.is_ipv4(),
+		"TCP6" => src.is_ipv6(),
+		_ => false,
+	};
+	(ok && f.next().is_none()).then_some(SocketAddr::new(src, port))
+}
+
+fn parse_v2(ver_cmd: u8, family: u8, body: &[u8], peer: SocketAddr) -> Option<SocketAddr> {
+	match ver_cmd {
+		V2_CMD_LOCAL => return Some(peer),
+		V2_CMD_PROXY => {}
+		_ => return None,
+	}
+	// TLVs may follow the addresses, they're of no use here
+	match family {
+		V2_TCP4 if body.len() >= 12 => {
+			let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&body[..4]).unwrap());
+			Some(SocketAddr::new(
+				ip.into(),
+				u16::from_be_bytes([body[8], body[9]]),
+			))
+		}
+		V2_TCP6 if body.len() >= 36 => {
+			let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).unwrap());
+			Some(SocketAddr::new(
+				ip.into(),
+				u16::from_be_bytes([body[32], body[33]]),
+			))
+		}
+		V2_TCP4 | V2_TCP6 => None,
+		// UDP or unix sockets, nothing we can key on
+		_ => Some(peer),
+	}
+}
+
+// a v2 header for the upstream, carrying the client's address
+// the destination isn't an address yet when the client asks, so only its port is real
+pub fn v2_header(src: SocketAddr, dst_port: u16) -> Vec<u8> {
+	let mut h = V2_SIG.to_vec();
+	match src.ip() {
+		IpAddr::V4(ip) => {
+			h.extend_from_slice(&[V2_CMD_PROXY, V2_TCP4, 0, 12]);
+			h.extend_from_slice(&ip.octets());
+			h.extend_from_slice(&Ipv4Addr::UNSPECIFIED.octets());
+		}
+		IpAddr::V6(ip) => {
+			h.extend_from_slice(&[V2_CMD_PROXY, V2_TCP6, 0, 36]);
+			h.extend_from_slice(&ip.octets());
+			h.extend_from_slice(&Ipv6Addr::UNSPECIFIED.octets());
+		}
+	}
+	h.extend_from_slice(&src.port().to_be_bytes());
+	h.extend_from_slice(&dst_port.to_be_bytes());
+	h
+}
+
+#[cfg(test)]
+mod test {
+	use tokio::io::AsyncWriteExt;
+
+	use super::*;
+
+	fn peer() -> SocketAddr {
+		"10.0.0.1:40000".parse().unwrap()
+	}
+
+	#[tokio::test]
+	async fn test_v2() {
+		let mut header = V2_SIG.to_vec();
+		header.extend_from_slice(&[V2_CMD_PROXY, V2_TCP4, 0, 12]);
+		header.extend_from_slice(&[203, 0, 113, 7, 192, 0, 2, 1]);
+		header.extend_from_slice(&51234u16.to_be_bytes());
+		header.extend_from_slice(&8080u16.to_be_bytes());
+		let (mut c, mut s) = tokio::io::duplex(0x100);
+		c.write_all(&header).await.unwrap();
+		c.write_all(b"rest").await.unwrap();
+		assert_eq!(
+			accept(&mut s, peer()).await,
+			Some("203.0.113.7:51234".parse().unwrap())
+		);
+		// what follows is left alone
+		let mut rest = [0u8; 4];
+		s.read_exact(&mut rest).await.unwrap();
+		assert_eq!(&rest, b"rest");
+
+		// a health check from the balancer itself
+		let mut local = V2_SIG.to_vec();
+		local.extend_from_slice(&[V2_CMD_LOCAL, 0, 0, 0]);
+		c.write_all(&local).await.unwrap();
+		assert_eq!(accept(&mut s, peer()).await, Some(peer()));
+	}
+
+	#[tokio::test]
+	async fn test_v2_header() {
+		for src in ["203.0.113.7:51234", "[2001:db8::1]:443"] {
+			let src: SocketAddr = src.parse().unwrap();
+			let h = v2_header(src, 80);
+			assert_eq!(accept(&mut &h[..], peer()).await, Some(src));
+			assert_eq!(&h[h.len() - 2..], &80u16.to_be_bytes());
+		}
+	}
+
+	#[tokio::test]
+	async fn test_v1() {
+		let (mut c, mut s) = tokio::io::duplex(0x100);
+		c.write_all(b"PROXY TCP6 2001:db8::1 2001:db8::2 443 8080\r\nrest")
+			.await
+			.unwrap();
+		assert_eq!(
+			accept(&mut s, peer()).await,
+			Some("[2001:db8::1]:443".parse().unwrap())
+		);
+		let mut rest = [0u8; 4];
+		s.read_exact(&mut rest).await.unwrap();
+		assert_eq!(&rest, b"rest");
+
+		c.write_all(b"PROXY UNKNOWN\r\n").await.unwrap();
+		assert_eq!(accept(&mut s, peer()).await, Some(peer()));
+
+		// no header at all
+		c.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+		assert_eq!(accept(&mut s, peer()).await, None);
+	}
+
+	#[test]
+	fn test_parse_v1() {
+		let p = |l: &[u8]| parse_v1(l, peer());
+		assert_eq!(
+			p(b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 2222\r\n"),
+			Some("1.2.3.4:1111".parse().unwrap())
+		);
+		assert_eq!(p(b"PROXY TCP4 2001:db8::1 5.6.7.8 1111 2222\r\n"), None);
+		assert_eq!(p(b"PROXY TCP4 1.2.3.4 5.6.7.8 1111\r\n"), None);
+		assert_eq!(p(b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 2222 x\r\n"), None);
+		assert_eq!(p(b"PROXY TCP4 1.2.3.4 5.6.7.8 1111 2222"), None);
+	}
+}
@@ -0,0 +1,106 @@
+use std::{
+	cell::RefCell,
+	net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+	sync::Arc,
+};
+
+use anyhow::Context;
+use log::*;
+use quinn::{
+	ClientConfig, Connection, Endpoint, RecvStream, SendStream, ServerConfig,
+	crypto::rustls::{QuicClientConfig, QuicServerConfig},
+};
+
+use crate::{
+	tls::{self, ALPN_H3, Identity},
+	upstream::Upstream,
+};
+
+pub fn server_endpoint(listen: SocketAddr, id: &Identity) -> anyhow::Result<Endpoint> {
+	let crypto = QuicServerConfig::try_from(id.server_config(&[ALPN_H3])?)
+		.context("invalid QUIC server config")?;
+	Endpoint::server(ServerConfig::with_crypto(Arc::new(crypto)), listen)
+		.with_context(|| format!("failed to bind {}", listen))
+}
+
+// one QUIC connection to the server, a stream per tunnel
+pub struct Dialer {
+	upstream: Upstream,
+	config: ClientConfig,
+	conn: RefCell<Option<Connection>>,
+}
+
+impl Dialer {
+	pub fn new(upstream: Upstream) -> anyhow::Result<Self> {
+		let crypto = QuicClientConfig::try_from(tls::client_config(&[ALPN_H3]))
+			.context("invalid QUIC client config")?;
+		Ok(Dialer {
+			upstream,
+			config: ClientConfig::new(Arc::new(crypto)),
+			conn: RefCell::new(None),
+		})
+	}
+
+	pub fn upstream(&self) -> &Upstream {
+		&self.upstream
+	}
+
+	pub async fn open(&self) -> Option<(SendStream, RecvStream)> {
+		if let Some(conn) = self.live() {
+			match conn.open_bi().await {
+				Ok(s) => return Some(s),
+				Err(e) => debug!("failed to open QUIC stream: {}, will reconnect", e),
+			}
+		}
+		let conn = self.connect().await?;
+		// concurrent tunnels may race here, the last one wins, the others close when unused
+		*self.conn.borrow_mut() = Some(conn.clone());
+		conn.open_bi()
+			.await
+			.map_err(|e| error!("failed to open QUIC stream: {}", e))
+			.ok()
+	}
+
+	fn live(&self) -> Option<Connection> {
+		let conn = self.conn.borrow();
+		let conn = conn.as_ref()?;
+		if let Some(e) = conn.close_reason() {
+			debug!("QUIC connection closed: {}", e);
+			return None;
+		}
+		Some(conn.clone())
+	}
+
+	async fn connect(&self) -> Option<Connection> {
+		for retry in [false, true] {
+			if retry {
+				self.upstream.invalidate();
+			}
+			let addrs = self.upstream.resolve().await?;
+			for a in addrs.iter() {
+				if let Some(c) = self.connect_to(*a).await {
+					return Some(c);
+				}
+			}
+		}
+		error!("error connecting to upstream: {}", self.upstream.name());
+		None
+	}
+
+	async fn connect_to(&self, addr: SocketAddr) -> Option<Connection> {
+		let local: SocketAddr = if addr.is_ipv6() {
+			(Ipv6Addr::UNSPECIFIED, 0).into()
+		} else {
+			(Ipv4Addr::UNSPECIFIED, 0).into()
+		};
+		let ep = Endpoint::client(local)
+			.map_err(|e| error!("failed to bind {}: {}", local, e))
+			.ok()?;
+		ep.connect_with(self.config.clone(), addr, self.upstream.name())
+			.map_err(|e| debug!("error connecting to {}: {}", addr, e))
+			.ok()?
+			.await
+			.map_err(|e| debug!("error connecting to {}: {}", addr, e))
+			.ok()
+	}
+}
@@ -0,0 +1,151 @@
+use std::{
+	cell::RefCell,
+	collections::{HashMap, HashSet, VecDeque},
+	fmt,
+};
+
+use anyhow::{Context, bail};
+
+use crate::{
+	key::decode_public_key,
+	proto::{CLIENT_SIG_WINDOW, ClientSig},
+};
+
+// at most this many signatures are remembered, the oldest go first
+const SEEN_CAP: usize = 0x10000;
+
+// the clients a server knows, by public key, each signs its requests with its own
+// revoking one is taking it out, the others keep the PSK
+pub struct Registry {
+	names: HashMap<Vec<u8>, String>,
+	// signatures still within the window, a request signed with one of them again is a replay
+	seen: RefCell<Seen>,
+}
+
+#[derive(Default)]
+struct Seen {
+	sigs: HashSet<Vec<u8>>,
+	order: VecDeque<(u64, Vec<u8>)>,
+}
+
+// why a signed request isn't let in
+#[derive(Debug, PartialEq, Eq)]
+pub enum Denied {
+	Unsigned,
+	Unknown,
+	Replayed,
+}
+
+impl fmt::Display for Denied {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			Denied::Unsigned => "request not signed by a client key",
+			Denied::Unknown => "client key not registered",
+			Denied::Replayed => "signed request seen before, replayed",
+		})
+	}
+}
+
+impl Registry {
+	// name and base64 public key, names don't have to be unique
+	pub fn new(keys: &[(String, String)]) -> anyhow::Result<Self> {
+		let mut names = HashMap::new();
+		for (name, key) in keys {
+			let key = decode_public_key(key.as_bytes())
+				.with_context(|| format!("invalid key for client {}", name))?;
+			if names.insert(key, name.clone()).is_some() {
+				bail!("client {}'s key is registered twice", name);
+			}
+		}
+		Ok(Registry {
+			names,
+			seen: RefCell::default(),
+		})
+	}
+
+	// the client's name, the signature is checked already, now is unix time
+	pub fn check(&self, client: Option<&ClientSig>, now: u64) -> Result<&str, Denied> {
+		let client = client.ok_or(Denied::Unsigned)?;
+		let name = self.names.get(&client.key).ok_or(Denied::Unknown)?;
+		let mut seen = self.seen.borrow_mut();
+		while let Some((t, _)) = seen.order.front()
+			&& (t + CLIENT_SIG_WINDOW < now || seen.order.len() >= SEEN_CAP)
+		{
+			let (_, sig) = seen.order.pop_front().unwrap();
+			seen.sigs.remove(&sig);
+		}
+		if !seen.sigs.insert(client.sig.clone()) {
+			return Err(Denied::Replayed);
+		}
+		seen.order.push_back((client.time, client.sig.clone()));
+		Ok(name)
+	}
+}
+
+/// client names and public keys, one `name key` per line, # comments,
+/// for [`ServerConfig::client_key`](crate::ServerConfig::client_key)
+pub fn read_client_keys(path: &str) -> anyhow::Result<Vec<(String, String)>> {
+	read_names(path, "a key")
+}
+
+/// user names and PSKs, one `name psk` per line, # comments,
+/// for [`ServerConfig::user`](crate::ServerConfig::user)
+pub fn read_users(path: &str) -> anyhow::Result<Vec<(String, String)>> {
+	read_names(path, "a PSK")
+}
+
+// a name and what goes with it on each line
+fn read_names(path: &str, what: &str) -> anyhow::Result<Vec<(String, String)>> {
+	let text =
+		std::fs::read_to_string(path).with_context(|| format!("failed to read \"{}\"", path))?;
+	let mut keys = Vec::new();
+	for (i, line) in text.lines().enumerate() {
+		let line = line.split('#').next().unwrap().trim();
+		if line.is_empty() {
+			continue;
+		}
+		let Some((name, key)) = line.split_once(char::is_whitespace) else {
+			bail!("{}:{}: expecting a name and {}", path, i + 1, what);
+		};
+		keys.push((name.to_owned(), key.trim().to_owned()));
+	}
+	Ok(keys)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::key::gen_key_pair;
+
+	#[test]
+	fn test_check() {
+		let (_, alice) = gen_key_pair();
+		let (_, mallory) = gen_key_pair();
+		let r = Registry::new(&[("alice".to_owned(), alice.clone())]).unwrap();
+		let sig = |key: &str, sig: u8, time: u64| ClientSig {
+			time,
+			key: decode_public_key(key.as_bytes()).unwrap(),
+			sig: vec![sig; 64],
+		};
+		assert_eq!(r.check(Some(&sig(&alice, 1, 100)), 100), Ok("alice"));
+		assert_eq!(r.check(Some(&sig(&alice, 2, 100)), 100), Ok("alice"));
+		assert_eq!(
+			r.check(Some(&sig(&alice, 1, 100)), 110),
+			Err(Denied::Replayed)
+		);
+		assert_eq!(
+			r.check(Some(&sig(&mallory, 3, 100)), 100),
+			Err(Denied::Unknown)
+		);
+		assert_eq!(r.check(None, 100), Err(Denied::Unsigned));
+		// forgotten once it's too old to be let in anyway
+		assert_eq!(
+			r.check(Some(&sig(&alice, 1, 100)), 100 + CLIENT_SIG_WINDOW + 1),
+			Ok("alice")
+		);
+
+		assert!(
+			Registry::new(&[("a".to_owned(), alice.clone()), ("b".to_owned(), alice)]).is_err()
+		);
+	}
+}
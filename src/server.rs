@@ -0,0 +1,1524 @@
+use std::{
+	cell::{Cell, RefCell},
+	net::SocketAddr,
+	rc::Rc,
+	sync::Arc,
+	time::{Duration, Instant},
+};
+
+use aead::{AeadCore, AeadInPlace, KeyInit};
+use anyhow::{Context, bail};
+use bytes::BytesMut;
+use log::*;
+use ring::signature::Ed25519KeyPair;
+use tokio::{
+	io::{AsyncRead, AsyncWrite},
+	net::TcpStream,
+};
+use tokio_rustls::TlsAcceptor;
+use tracing::{Instrument, Span, field::Empty, info_span};
+
+use crate::{
+	CipherKind,
+	addr::{self, HostPort},
+	audit::{Audit, Outcome, Record, Rotate},
+	connector::{Connector, Direct},
+	decoy,
+	dns::{Builtin, Resolver},
+	doh, health,
+	hook::OnConnect,
+	http2,
+	key::{decode_psk, decode_sign_key, init_cipher},
+	limit::{self, Banlist, MemGuard, RATE_LIMIT_CAP, RateLimiter},
+	obfs::{HttpPrefix, Obfuscated, Obfuscator, Plain, check_header},
+	policy::PortPolicy,
+	proto::*,
+	proxy_proto, quic,
+	registry::Registry,
+	shutdown::{Stop, Tracker},
+	sock::{self, ConnectOpts, ListenOpts},
+	socks5,
+	stats::{self, CONNS, HostBytes, SourceConns},
+	task,
+	tls::{ALPN_H2, ALPN_HTTP1, Identity},
+	transport::{Kind, Listen, Stream},
+	udp,
+	upstream::Upstream,
+	ws,
+};
+
+/// Server settings, the defaults match the `mint server` CLI except for
+/// the obfuscator, which is none until [`fake_header`](Self::fake_header)
+/// or [`obfs`](Self::obfs) says otherwise.
+///
+/// ```
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() -> anyhow::Result<()> {
+/// let server = mint::ServerConfig::new("xJBPRyvnbmbAxhRObbq6OrYzJwZZpXuVlXFJVP4gbbk")
+///     .listen("127.0.0.1:0")
+///     .fake_header(b"HTTP/1.1 200 OK\r\nServer: nginx\r\n\r\n")
+///     .build()?;
+/// // serves until SIGTERM or Ctrl-C
+/// let run = tokio::time::timeout(std::time::Duration::from_millis(100), mint::run_server(server));
+/// assert!(run.await.is_err());
+/// # Ok(())
+/// # }
+/// ```
+pub struct ServerConfig {
+	psk: String,
+	listen: String,
+	listen_opts: ListenOpts,
+	obfs: Option<Box<dyn Obfuscator>>,
+	policy: PortPolicy,
+	on_connect: Option<OnConnect>,
+	out: ConnectOpts,
+	connector: Option<Box<dyn Connector>>,
+	conn_rate: Option<u32>,
+	max_memory: Option<u64>,
+	proxy_protocol: bool,
+	send_proxy_protocol: bool,
+	ban: Option<(u32, Duration)>,
+	on_probe: Option<decoy::Mode>,
+	decoy: Option<String>,
+	probe_delay: decoy::Delay,
+	drain: Duration,
+	stats_interval: Duration,
+	host_stats: Option<usize>,
+	top_talkers: Option<usize>,
+	health_addr: Option<String>,
+	nameservers: Vec<SocketAddr>,
+	resolver: Option<Box<dyn Resolver>>,
+	doh: Option<(String, bool)>,
+	transport: Kind,
+	tls: Option<(String, String)>,
+	ws_path: String,
+	cipher: CipherKind,
+	frame: FrameOpts,
+	server_names: Vec<String>,
+	label: Option<String>,
+	sign_key: Option<String>,
+	client_keys: Vec<(String, String)>,
+	users: Vec<(String, String)>,
+	audit_log: Option<(String, bool)>,
+	audit_rotate: Rotate,
+}
+
+impl ServerConfig {
+	/// psk is base64, as printed by [`gen_psk`](crate::gen_psk)
+	pub fn new(psk: &str) -> Self {
+		ServerConfig {
+			psk: psk.to_owned(),
+			listen: "127.0.0.1:8080".to_owned(),
+			listen_opts: ListenOpts::default(),
+			obfs: None,
+			policy: PortPolicy::default(),
+			on_connect: None,
+			out: ConnectOpts::default(),
+			connector: None,
+			conn_rate: None,
+			max_memory: None,
+			proxy_protocol: false,
+			send_proxy_protocol: false,
+			ban: None,
+			on_probe: None,
+			decoy: None,
+			probe_delay: "10-50".parse().unwrap(),
+			drain: Duration::from_secs(30),
+			stats_interval: Duration::from_secs(600),
+			host_stats: None,
+			top_talkers: None,
+			health_addr: None,
+			nameservers: Vec::new(),
+			resolver: None,
+			doh: None,
+			transport: Kind::Tcp,
+			tls: None,
+			ws_path: "/".to_owned(),
+			cipher: CipherKind::default(),
+			frame: FrameOpts::default(),
+			server_names: Vec::new(),
+			label: None,
+			sign_key: None,
+			client_keys: Vec::new(),
+			users: Vec::new(),
+			audit_log: None,
+			audit_rotate: Rotate::default(),
+		}
+	}
+
+	/// IPv6 needs brackets, e.g. [::]:8080
+	pub fn listen(mut self, addr: &str) -> Self {
+		self.listen = addr.to_owned();
+		self
+	}
+
+	pub fn listen_opts(mut self, opts: ListenOpts) -> Self {
+		self.listen_opts = opts;
+		self
+	}
+
+	/// http-prefix with this header, lines end with `\r\n` and so does the header
+	pub fn fake_header(self, header: &[u8]) -> Self {
+		self.obfs(Box::new(HttpPrefix::new(header.to_vec())))
+	}
+
+	pub fn obfs(mut self, obfs: Box<dyn Obfuscator>) -> Self {
+		self.obfs = Some(obfs);
+		self
+	}
+
+	pub fn port_policy(mut self, policy: PortPolicy) -> Self {
+		self.policy = policy;
+		self
+	}
+
+	/// asked before each connection goes upstream, with the source address
+	/// and the destination host and port, false refuses it
+	pub fn on_connect<F, Fut>(mut self, f: F) -> Self
+	where
+		F: Fn(SocketAddr, String, u16) -> Fut + 'static,
+		Fut: Future<Output = bool> + 'static,
+	{
+		self.on_connect = Some(OnConnect::new(f));
+		self
+	}
+
+	/// socket options for upstream connections, unless there's a connector
+	pub fn connect_opts(mut self, opts: ConnectOpts) -> Self {
+		self.out = opts;
+		self
+	}
+
+	/// reach destinations through this instead of resolving and connecting directly
+	pub fn connector(mut self, connector: impl Connector + 'static) -> Self {
+		self.connector = Some(Box::new(connector));
+		self
+	}
+
+	/// max new connections per second from a single source IP
+	pub fn conn_rate(mut self, rate: u32) -> Self {
+		self.conn_rate = Some(rate);
+		self
+	}
+
+	/// refuse new connections while the process's resident memory is over this many bytes,
+	/// open ones carry on, a last resort against the OOM killer, Linux only
+	pub fn max_memory(mut self, bytes: u64) -> Self {
+		self.max_memory = Some(bytes);
+		self
+	}
+
+	/// behind a load balancer, take the client address from the PROXY protocol v1 or v2
+	/// header it sends first, connections without one are dropped, TCP transports only
+	pub fn proxy_protocol(mut self, enable: bool) -> Self {
+		self.proxy_protocol = enable;
+		self
+	}
+
+	/// start each upstream connection with a PROXY protocol v2 header carrying the
+	/// client address, for origins that log or filter on it
+	pub fn send_proxy_protocol(mut self, enable: bool) -> Self {
+		self.send_proxy_protocol = enable;
+		self
+	}
+
+	/// ban a source IP for a while after this many handshake failures in a row
+	pub fn ban(mut self, after: u32, duration: Duration) -> Self {
+		self.ban = Some((after, duration));
+		self
+	}
+
+	/// defaults to proxy with a decoy, reply otherwise
+	pub fn on_probe(mut self, mode: decoy::Mode) -> Self {
+		self.on_probe = Some(mode);
+		self
+	}
+
+	/// web server to proxy failed handshakes to, e.g. http://example.com
+	pub fn decoy(mut self, url: &str) -> Self {
+		self.decoy = Some(url.to_owned());
+		self
+	}
+
+	pub fn probe_delay(mut self, delay: decoy::Delay) -> Self {
+		self.probe_delay = delay;
+		self
+	}
+
+	/// how long open connections get to finish after SIGTERM or Ctrl-C
+	pub fn drain(mut self, grace: Duration) -> Self {
+		self.drain = grace;
+		self
+	}
+
+	/// between handshake stats in the log, zero disables it
+	pub fn stats_interval(mut self, interval: Duration) -> Self {
+		self.stats_interval = interval;
+		self
+	}
+
+	/// count bytes per destination host for up to this many hosts, the least recently
+	/// used ones make room, logged on SIGUSR1
+	pub fn host_stats(mut self, cap: usize) -> Self {
+		self.host_stats = Some(cap);
+		self
+	}
+
+	/// log the top n destinations by bytes and source IPs by connections, along with
+	/// the stats and on SIGUSR1, counts bytes per host too if not set
+	pub fn top_talkers(mut self, n: usize) -> Self {
+		self.top_talkers = Some(n);
+		self
+	}
+
+	/// answer HTTP health checks here, 200 while connections are being accepted
+	/// and 503 once shutdown begins, e.g. 0.0.0.0:8081
+	pub fn health_addr(mut self, addr: &str) -> Self {
+		self.health_addr = Some(addr.to_owned());
+		self
+	}
+
+	/// instead of the system resolver
+	pub fn nameservers(mut self, servers: &[SocketAddr]) -> Self {
+		self.nameservers = servers.to_vec();
+		self
+	}
+
+	/// DNS over HTTPS, falling back to the system resolver if asked to
+	pub fn doh(mut self, url: &str, fallback: bool) -> Self {
+		self.doh = Some((url.to_owned(), fallback));
+		self
+	}
+
+	/// look hosts up through this instead of nameservers or DoH
+	pub fn resolver(mut self, resolver: impl Resolver + 'static) -> Self {
+		self.resolver = Some(Box::new(resolver));
+		self
+	}
+
+	pub fn transport(mut self, kind: Kind) -> Self {
+		self.transport = kind;
+		self
+	}
+
+	/// PEM files for the TLS based transports, a self-signed one is generated otherwise
+	pub fn tls_files(mut self, cert: &str, key: &str) -> Self {
+		self.tls = Some((cert.to_owned(), key.to_owned()));
+		self
+	}
+
+	/// for ws and wss, anything else gets a 404
+	pub fn ws_path(mut self, path: &str) -> Self {
+		self.ws_path = path.to_owned();
+		self
+	}
+
+	pub fn cipher(mut self, cipher: CipherKind) -> Self {
+		self.cipher = cipher;
+		self
+	}
+
+	pub fn frame(mut self, opts: FrameOpts) -> Self {
+		self.frame = opts;
+		self
+	}
+
+	/// Server values fake responses pick from, each its own, when the fake header has none
+	/// defaults to a few nginx, Apache and cloudflare ones
+	pub fn server_names(mut self, names: &[&str]) -> Self {
+		self.server_names = names.iter().map(|&n| n.to_owned()).collect();
+		self
+	}
+
+	/// names this server in its conn spans and host reports, for telling apart several
+	/// run in one process, each with its own PSK
+	pub fn label(mut self, label: &str) -> Self {
+		self.label = Some(label.to_owned());
+		self
+	}
+
+	/// signs every response with this Ed25519 key, base64 as printed by
+	/// [`gen_key_pair`](crate::gen_key_pair), so clients pinning its public key can tell
+	/// the server from anyone else holding the PSK
+	pub fn sign_key(mut self, key: &str) -> Self {
+		self.sign_key = Some(key.to_owned());
+		self
+	}
+
+	/// lets in the client signing with this Ed25519 key, base64 as printed by
+	/// [`gen_key_pair`](crate::gen_key_pair), once any is registered every request has
+	/// to be signed by one of them, the name is for logs and spans
+	pub fn client_key(mut self, name: &str, key: &str) -> Self {
+		self.client_keys.push((name.to_owned(), key.to_owned()));
+		self
+	}
+
+	/// lets in whoever has this PSK too, base64 like the one given to [`new`](Self::new),
+	/// each user their own so one can be taken out without the rest changing theirs,
+	/// the name is for logs and spans, the main PSK keeps working
+	pub fn user(mut self, name: &str, psk: &str) -> Self {
+		self.users.push((name.to_owned(), psk.to_owned()));
+		self
+	}
+
+	/// appends a line per connect or UDP request to this file: time, source, user and
+	/// client if known, destination, bytes and how it ended, with chain each line also
+	/// has the SHA-256 of the one before, see [`verify_audit_log`](crate::verify_audit_log)
+	pub fn audit_log(mut self, path: &str, chain: bool) -> Self {
+		self.audit_log = Some((path.to_owned(), chain));
+		self
+	}
+
+	/// sets the audit log aside as path.1 and starts a new one once a line would take it
+	/// past max_size bytes, or on a new UTC day if daily, keeping that many set aside
+	/// path.1 being the latest, the chain runs on from one file to the next
+	pub fn audit_rotate(mut self, max_size: Option<u64>, daily: bool, keep: usize) -> Self {
+		self.audit_rotate = Rotate {
+			max_size,
+			daily,
+			keep,
+		};
+		self
+	}
+
+	/// checks everything that can be checked before binding
+	pub fn build(self) -> anyhow::Result<Server> {
+		let key = decode_psk(self.psk.as_bytes()).context("invalid PSK")?;
+		let mut user_keys = Vec::new();
+		for (name, psk) in &self.users {
+			let k = decode_psk(psk.as_bytes())
+				.with_context(|| format!("invalid PSK for user {}", name))?;
+			// the first to decrypt a request is who it's from, it has to be the only one
+			if k == key || user_keys.contains(&k) {
+				bail!("user {}'s PSK is in use already", name);
+			}
+			user_keys.push(k);
+		}
+		let signer = self
+			.sign_key
+			.map(|k| decode_sign_key(k.as_bytes()))
+			.transpose()
+			.context("invalid server key")?;
+		let registry = match self.client_keys.as_slice() {
+			[] => None,
+			keys => Some(Registry::new(keys)?),
+		};
+		let audit = self
+			.audit_log
+			.map(|(path, chain)| Audit::open(&path, chain, self.audit_rotate))
+			.transpose()?;
+		let obfs = self.obfs.unwrap_or_else(|| Box::new(Plain));
+		check_header(&*obfs)?;
+		if self.frame.pad_to > MAX_MSG {
+			bail!(
+				"can't pad handshakes to {} bytes, the other end reads at most {}",
+				self.frame.pad_to,
+				MAX_MSG
+			);
+		}
+		if let Some(name) = self
+			.server_names
+			.iter()
+			.find(|n| n.is_empty() || n.chars().any(char::is_control))
+		{
+			bail!("invalid Server value {:?}", name);
+		}
+		let resolver: Rc<dyn Resolver> = if let Some(resolver) = self.resolver {
+			resolver.into()
+		} else if let Some((url, fallback)) = &self.doh {
+			Rc::new(Builtin::Doh(Box::new(doh::Doh::new(url)?), *fallback))
+		} else if self.nameservers.is_empty() {
+			Rc::new(Builtin::System)
+		} else {
+			Rc::new(Builtin::custom(&self.nameservers))
+		};
+		let connector = self.connector.unwrap_or_else(|| {
+			Box::new(Direct {
+				resolver: resolver.clone(),
+				out: self.out,
+			})
+		});
+		let id = match self.transport {
+			Kind::Quic | Kind::Tls | Kind::Wss | Kind::H2 => {
+				let (cert, key) = self
+					.tls
+					.as_ref()
+					.map(|(c, k)| (c.as_str(), k.as_str()))
+					.unzip();
+				Some(Identity::load(cert, key)?)
+			}
+			Kind::Tcp | Kind::Ws => None,
+		};
+		if self.top_talkers == Some(0) {
+			bail!("top talkers need at least one entry");
+		}
+		let host_stats = match (self.host_stats, self.top_talkers) {
+			(None, Some(_)) => Some(RATE_LIMIT_CAP),
+			(cap, _) => cap,
+		};
+		if host_stats == Some(0) {
+			bail!("host stats need room for at least one host");
+		}
+		if self.max_memory.is_some() && limit::rss().is_none() {
+			bail!("can't tell how much memory this process uses, that's Linux only");
+		}
+		if self.proxy_protocol && self.transport == Kind::Quic {
+			bail!("PROXY protocol needs a TCP transport");
+		}
+		let transport = match (id, self.transport) {
+			(Some(id), Kind::Quic) => Listen::Quic(id),
+			(Some(id), Kind::H2) => Listen::H2(id),
+			(tls, kind) => Listen::Tcp {
+				tls,
+				ws: matches!(kind, Kind::Ws | Kind::Wss).then_some(self.ws_path),
+			},
+		};
+		let decoy = self.decoy.as_deref().map(decoy::target).transpose()?;
+		let on_probe = match (self.on_probe, &decoy) {
+			(Some(decoy::Mode::Proxy), None) => bail!("probe mode proxy needs a decoy"),
+			(Some(mode), _) => mode,
+			(None, Some(_)) => decoy::Mode::Proxy,
+			(None, None) => decoy::Mode::Reply,
+		};
+		Ok(Server {
+			key,
+			user_keys,
+			cipher: self.cipher,
+			listen: self.listen,
+			listen_opts: self.listen_opts,
+			transport,
+			conn_rate: self.conn_rate,
+			memory: self.max_memory.map(|max| MemGuard::new(max, limit::rss)),
+			proxy_protocol: self.proxy_protocol,
+			drain: self.drain,
+			stats_interval: self.stats_interval,
+			health_addr: self.health_addr,
+			conf: ServerConf {
+				obfs,
+				bans: self
+					.ban
+					.map(|(n, d)| RefCell::new(Banlist::new(n, d, RATE_LIMIT_CAP))),
+				hosts: host_stats.map(|cap| RefCell::new(HostBytes::new(cap))),
+				sources: self
+					.top_talkers
+					.map(|_| RefCell::new(SourceConns::new(RATE_LIMIT_CAP))),
+				top: self.top_talkers.unwrap_or(0),
+				on_probe,
+				decoy,
+				probe_delay: self.probe_delay,
+				connector,
+				policy: self.policy,
+				on_connect: self.on_connect,
+				resolver,
+				opts: self.frame,
+				send_proxy: self.send_proxy_protocol,
+				server_names: self.server_names,
+				label: self.label,
+				signer,
+				registry,
+				users: self.users.into_iter().map(|(name, _)| name).collect(),
+				audit,
+			},
+		})
+	}
+}
+
+/// a checked [`ServerConfig`], ready for [`run_server`]
+pub struct Server {
+	key: Vec<u8>,
+	user_keys: Vec<Vec<u8>>,
+	cipher: CipherKind,
+	listen: String,
+	listen_opts: ListenOpts,
+	transport: Listen,
+	conn_rate: Option<u32>,
+	memory: Option<MemGuard>,
+	proxy_protocol: bool,
+	drain: Duration,
+	stats_interval: Duration,
+	health_addr: Option<String>,
+	conf: ServerConf,
+}
+
+/// serves until SIGTERM or Ctrl-C, then drains open connections
+pub async fn run_server(server: Server) -> anyhow::Result<()> {
+	if !server.stats_interval.is_zero() {
+		tokio::spawn(stats::log_every(server.stats_interval));
+	}
+	#[cfg(unix)]
+	tokio::spawn(stats::log_on_sigusr1());
+	run_with_cipher!(server.cipher, serve_all(server))
+}
+
+// everything a server connection needs besides the cipher
+struct ServerConf {
+	obfs: Box<dyn Obfuscator>,
+	bans: Option<RefCell<Banlist>>,
+	// bytes per destination host
+	hosts: Option<RefCell<HostBytes>>,
+	// connections per source IP, with top talkers
+	sources: Option<RefCell<SourceConns>>,
+	// how many of each the top talkers report has, 0 without one
+	top: usize,
+	on_probe: decoy::Mode,
+	decoy: Option<Upstream>,
+	probe_delay: decoy::Delay,
+	connector: Box<dyn Connector>,
+	policy: PortPolicy,
+	on_connect: Option<OnConnect>,
+	// for DNS requests, the default connector shares it
+	resolver: Rc<dyn Resolver>,
+	opts: FrameOpts,
+	// PROXY protocol v2 to the upstream
+	send_proxy: bool,
+	// Server values for fake responses, the built-in ones if empty
+	server_names: Vec<String>,
+	// which server, in spans and reports
+	label: Option<String>,
+	signer: Option<Ed25519KeyPair>,
+	// the clients let in, anyone with the PSK if None
+	registry: Option<Registry>,
+	// by the index of their PSK, after the main one
+	users: Vec<String>,
+	audit: Option<Audit>,
+}
+
+impl ServerConf {
+	fn wire(&self) -> Wire<'_> {
+		Wire {
+			servers: &self.server_names,
+			signer: self.signer.as_ref(),
+			..self.opts.wire(self.obfs.header())
+		}
+	}
+
+	fn audit(&self, r: &Record) {
+		if let Some(audit) = &self.audit {
+			audit.write(unix_time(), r);
+		}
+	}
+
+	// the top talkers if there's a report, every host otherwise
+	fn host_report(&self) -> Option<String> {
+		let hosts = self.hosts.as_ref()?.borrow();
+		let report = match &self.sources {
+			Some(sources) => stats::top_talkers(&hosts, &sources.borrow(), self.top),
+			None => hosts.to_string(),
+		};
+		match &self.label {
+			Some(label) => Some(format!("{}: {}", label, report)),
+			None => Some(report),
+		}
+	}
+}
+
+async fn serve_all<C: KeyInit + AeadCore + AeadInPlace + 'static>(
+	server: Server,
+) -> anyhow::Result<()> {
+	let Server {
+		key,
+		user_keys,
+		listen,
+		listen_opts,
+		transport,
+		conn_rate,
+		memory,
+		proxy_protocol,
+		drain,
+		stats_interval,
+		health_addr,
+		conf,
+		..
+	} = server;
+	let conf = Rc::new(conf);
+	let mut stop = Stop::new();
+	let tracker = Tracker::default();
+	let limiter = conn_rate.map(|r| RefCell::new(RateLimiter::new(r, RATE_LIMIT_CAP)));
+	// with PROXY protocol, only known once the header is read in the connection's task
+	let admit = Rc::new({
+		let conf = conf.clone();
+		move |r_addr: SocketAddr| {
+			if let Some(limiter) = &limiter
+				&& !limiter.borrow_mut().check(r_addr.ip(), Instant::now())
+			{
+				debug!("{} over connection rate limit, dropping", r_addr);
+				return false;
+			}
+			if let Some(bans) = &conf.bans
+				&& bans.borrow_mut().is_banned(r_addr.ip(), Instant::now())
+			{
+				debug!("{} is banned, dropping", r_addr);
+				return false;
+			}
+			if let Some(memory) = &memory
+				&& !memory.admits(Instant::now())
+			{
+				debug!("{} refused, over the memory limit", r_addr);
+				return false;
+			}
+			true
+		}
+	});
+	// the main PSK first, then the users'
+	let ciphers = std::iter::once(&key)
+		.chain(&user_keys)
+		.map(|k| init_cipher(k))
+		.collect::<anyhow::Result<Rc<[C]>>>()?;
+	#[cfg(unix)]
+	if conf.hosts.is_some() {
+		let conf = conf.clone();
+		task::spawn_local(
+			format_args!("host stats"),
+			stats::on_sigusr1(move || {
+				if let Some(report) = conf.host_report() {
+					info!("{}", report);
+				}
+			}),
+		);
+	}
+	// quiet while nothing changes, like the handshake stats
+	if conf.sources.is_some() && !stats_interval.is_zero() {
+		let conf = conf.clone();
+		task::spawn_local(format_args!("top talkers"), async move {
+			let mut last = None;
+			loop {
+				tokio::time::sleep(stats_interval).await;
+				let now = conf.host_report();
+				if now != last {
+					if let Some(report) = &now {
+						info!("{}", report);
+					}
+					last = now;
+				}
+			}
+		});
+	}
+	// whether the accept loop below is running, for the health checks
+	let up = Rc::new(Cell::new(false));
+	if let Some(addr) = &health_addr {
+		let l = sock::listen(addr, &ListenOpts::default()).await?;
+		info!("health checks on {}", l.local_addr().unwrap());
+		task::spawn_local(format_args!("health"), health::serve(l, up.clone()));
+	}
+
+	match transport {
+		Listen::Tcp { tls, ws } => {
+			let tls = match tls {
+				Some(id) => Some(TlsAcceptor::from(Arc::new(
+					id.server_config(&[ALPN_HTTP1])?,
+				))),
+				None => None,
+			};
+			let ws: Option<Rc<str>> = ws.map(Into::into);
+			let l = sock::listen(&listen, &listen_opts).await?;
+			info!("listening on {}", l.local_addr().unwrap());
+			up.set(true);
+
+			while let Some(Ok((mut s, r_addr))) = stop.until(l.accept()).await {
+				if !proxy_protocol && !admit(r_addr) {
+					continue;
+				}
+				let _ = s.set_nodelay(true);
+				let ciphers = ciphers.clone();
+				let conf = conf.clone();
+				let admit = admit.clone();
+				let tls = tls.clone();
+				let ws = ws.clone();
+				let guard = tracker.track();
+				task::spawn_local(format_args!("serve {}", r_addr), async move {
+					let _guard = guard;
+					let Some(r_addr) = behind_proxy(&mut s, r_addr, proxy_protocol, &*admit).await
+					else {
+						return;
+					};
+					let ws = ws.as_deref();
+					let Some(tls) = tls else {
+						return serve_ws(&ciphers, &conf, s, r_addr, ws).await;
+					};
+					match tls.accept(s).await {
+						Ok(s) => serve_ws(&ciphers, &conf, s, r_addr, ws).await,
+						Err(e) => debug!("TLS handshake with {} failed: {}", r_addr, e),
+					}
+				});
+			}
+		}
+		Listen::Quic(id) => {
+			let listen = addr::lookup(&listen)
+				.await
+				.with_context(|| format!("invalid listen address {}", listen))?[0];
+			let ep = quic::server_endpoint(listen, &id)?;
+			info!("listening on {} (QUIC)", ep.local_addr().unwrap());
+			up.set(true);
+
+			while let Some(Some(incoming)) = stop.until(ep.accept()).await {
+				let r_addr = incoming.remote_address();
+				if !admit(r_addr) {
+					incoming.ignore();
+					continue;
+				}
+				let ciphers = ciphers.clone();
+				let conf = conf.clone();
+				let guard = tracker.track();
+				task::spawn_local(format_args!("quic {}", r_addr), async move {
+					let _guard = guard;
+					let Ok(conn) = incoming
+						.await
+						.map_err(|e| debug!("QUIC handshake with {} failed: {}", r_addr, e))
+					else {
+						return;
+					};
+					// a tunnel per stream
+					while let Ok((w, r)) = conn.accept_bi().await {
+						let ciphers = ciphers.clone();
+						let conf = conf.clone();
+						task::spawn_local(format_args!("serve {}", r_addr), async move {
+							serve(&ciphers, &conf, Stream::Quic(w, r), r_addr).await
+						});
+					}
+				});
+			}
+		}
+		Listen::H2(id) => {
+			let tls = TlsAcceptor::from(Arc::new(id.server_config(&[ALPN_H2])?));
+			let l = sock::listen(&listen, &listen_opts).await?;
+			info!("listening on {} (HTTP/2)", l.local_addr().unwrap());
+			up.set(true);
+
+			while let Some(Ok((mut s, r_addr))) = stop.until(l.accept()).await {
+				if !proxy_protocol && !admit(r_addr) {
+					continue;
+				}
+				let _ = s.set_nodelay(true);
+				let ciphers = ciphers.clone();
+				let conf = conf.clone();
+				let admit = admit.clone();
+				let tls = tls.clone();
+				let guard = tracker.track();
+				task::spawn_local(format_args!("h2 {}", r_addr), async move {
+					let _guard = guard;
+					let Some(r_addr) = behind_proxy(&mut s, r_addr, proxy_protocol, &*admit).await
+					else {
+						return;
+					};
+					let s = match tls.accept(s).await {
+						Ok(s) => s,
+						Err(e) => return debug!("TLS handshake with {} failed: {}", r_addr, e),
+					};
+					// a tunnel per CONNECT
+					http2::accept(s, |s| {
+						let ciphers = ciphers.clone();
+						let conf = conf.clone();
+						task::spawn_local(format_args!("serve {}", r_addr), async move {
+							serve(&ciphers, &conf, s, r_addr).await
+						});
+					})
+					.await
+				});
+			}
+		}
+	}
+
+	// the listener is gone by now, what's left gets dropped with the LocalSet
+	up.set(false);
+	info!("stopped accepting");
+	tracker.drain(drain).await;
+	Ok(())
+}
+
+// the client's address from the PROXY header if enabled, then the admission check
+async fn behind_proxy(
+	s: &mut TcpStream,
+	peer: SocketAddr,
+	enabled: bool,
+	admit: &dyn Fn(SocketAddr) -> bool,
+) -> Option<SocketAddr> {
+	if !enabled {
+		return Some(peer);
+	}
+	let r_addr = proxy_proto::accept(s, peer).await?;
+	debug!("{} is {} behind the proxy", peer, r_addr);
+	admit(r_addr).then_some(r_addr)
+}
+
+// after the optional WebSocket upgrade
+async fn serve_ws<C: KeyInit + AeadCore + AeadInPlace, S: AsyncRead + AsyncWrite + Unpin>(
+	ciphers: &[C],
+	conf: &ServerConf,
+	s: S,
+	r_addr: SocketAddr,
+	ws: Option<&str>,
+) {
+	let Some(path) = ws else {
+		return serve(ciphers, conf, s, r_addr).await;
+	};
+	if let Some(s) = ws::accept(s, path).await {
+		serve(ciphers, conf, s, r_addr).await;
+	}
+}
+
+// a conn span each, a request span per request under it, for whatever subscriber is set
+async fn serve<C: KeyInit + AeadCore + AeadInPlace, S: AsyncRead + AsyncWrite + Unpin>(
+	ciphers: &[C],
+	conf: &ServerConf,
+	s: S,
+	r_addr: SocketAddr,
+) {
+	serve_conn(ciphers, conf, s, r_addr)
+		.instrument(info_span!(
+			"conn",
+			src = %r_addr,
+			listener = conf.label.as_deref(),
+			user = Empty,
+		))
+		.await
+}
+
+// ciphers: the main PSK's first, then the users', in ServerConf::users order
+async fn serve_conn<C: KeyInit + AeadCore + AeadInPlace, S: AsyncRead + AsyncWrite + Unpin>(
+	ciphers: &[C],
+	conf: &ServerConf,
+	s: S,
+	r_addr: SocketAddr,
+) {
+	let _open = CONNS.open();
+	if let Some(sources) = &conf.sources {
+		sources.borrow_mut().connected(r_addr.ip(), Instant::now());
+	}
+	let mut s = Obfuscated::new(s, &*conf.obfs);
+	let header = conf.obfs.header();
+	let wire = conf.wire();
+	let mut buf = BytesMut::with_capacity(0x500);
+	let req = server_handshake_any(&mut s, ciphers, &mut buf, wire)
+		.instrument(info_span!("handshake"))
+		.await;
+	if let Some(bans) = &conf.bans {
+		match req {
+			Err(Rejected::NotOurs) => bans.borrow_mut().failed(r_addr.ip(), Instant::now()),
+			_ => bans.borrow_mut().succeeded(r_addr.ip()),
+		}
+	}
+	let (key, mut req) = match req {
+		Ok(req) => req,
+		Err(Rejected::NotOurs) => {
+			if socks5::is_greeting(&buf) {
+				warn!(
+					"{} looks like a SOCKS5 client hit the server port, connect it to the mint client instead",
+					r_addr
+				);
+			}
+			let (s, delay) = (s.get_mut(), conf.probe_delay);
+			match (conf.on_probe, &conf.decoy) {
+				(decoy::Mode::Proxy, Some(d)) => decoy::proxy(s, &buf, d, delay).await,
+				(mode, _) => decoy::answer(s, &buf, header, &conf.server_names, mode, delay).await,
+			}
+			return;
+		}
+		Err(Rejected::Replied) => return,
+	};
+	// the rest of the connection is under the PSK it started with
+	let cipher = &ciphers[key];
+	let user = key.checked_sub(1).map(|i| conf.users[i].as_str());
+	if let Some(user) = user {
+		Span::current().record("user", user);
+		debug!("{} is user {}", r_addr, user);
+	}
+	// with FEAT_REUSE, another request follows each clean session end
+	loop {
+		let span = info_span!(
+			"request",
+			dst = %HostPort(&req.host, req.port),
+			client = Empty,
+			up = Empty,
+			down = Empty,
+		);
+		if !serve1(cipher, conf, &mut s, &mut buf, r_addr, user, req)
+			.instrument(span)
+			.await
+		{
+			return;
+		}
+		let Ok(next) = server_handshake(&mut s, cipher, &mut buf, wire)
+			.instrument(info_span!("handshake"))
+			.await
+		else {
+			return;
+		};
+		req = next;
+	}
+}
+
+// one request, true if the connection can take another
+async fn serve1<C: KeyInit + AeadCore + AeadInPlace, S: AsyncRead + AsyncWrite + Unpin>(
+	cipher: &C,
+	conf: &ServerConf,
+	s: &mut S,
+	buf: &mut BytesMut,
+	r_addr: SocketAddr,
+	user: Option<&str>,
+	req: Request,
+) -> bool {
+	let wire = Wire {
+		challenge: &req.nonce,
+		ver: req.ver,
+		..conf.wire()
+	};
+	let mut client = None;
+	if let Some(registry) = &conf.registry {
+		match registry.check(req.client.as_ref(), unix_time()) {
+			Ok(name) => {
+				Span::current().record("client", name);
+				debug!("{} is client {}", r_addr, name);
+				client = Some(name);
+			}
+			Err(e) => {
+				info!("{} denied, {}", r_addr, e);
+				let dst = (req.cmd == CMD_CONNECT).then_some(HostPort(&req.host, req.port));
+				conf.audit(&Record {
+					src: r_addr.ip(),
+					user,
+					client: None,
+					dst,
+					up: 0,
+					down: 0,
+					outcome: Outcome::Denied,
+				});
+				let _ = server_reply(s, cipher, buf, wire, REP_DENIED, 0).await;
+				return false;
+			}
+		}
+	}
+	let record = |dst, up, down, outcome| Record {
+		src: r_addr.ip(),
+		user,
+		client,
+		dst,
+		up,
+		down,
+		outcome,
+	};
+	// server_handshake refuses the rest
+	if req.cmd == CMD_DNS {
+		let addrs = conf.resolver.resolve(&req.host).await.unwrap_or_default();
+		info!("{} resolves {}: {} addrs", r_addr, req.host, addrs.len());
+		let _ = server_dns_reply(s, cipher, buf, wire, &addrs).await;
+		return false;
+	}
+	if req.cmd == CMD_PING {
+		debug!("{} pings", r_addr);
+		let _ = server_reply(s, cipher, buf, wire, REP_OK, 0).await;
+		return false;
+	}
+	if req.cmd == CMD_UDP {
+		// the datagram stream has no end marker, it takes the connection
+		let features = req.features & conf.opts.features() & !FEAT_REUSE;
+		let Some(()) = server_reply(s, cipher, buf, wire, REP_OK, features).await else {
+			return false;
+		};
+		info!("{} -> UDP", r_addr);
+		let opts = conf.opts.negotiated(features);
+		let (mut plain, datagrams) = tokio::io::duplex(0x10000);
+		let ((down, up, end), ()) = tokio::join!(
+			duplex(cipher, &opts, &mut plain, s),
+			udp::relay(datagrams, &conf.resolver, &conf.policy),
+		);
+		CONNS.relayed(up, down);
+		conf.audit(&record(None, up, down, Outcome::Ok));
+		end.log(format_args!("{} -> UDP", r_addr));
+		return false;
+	}
+	let (addr, port) = (req.host, req.port);
+	let dst = Some(HostPort(&addr, port));
+	if !conf.policy.allows(port) {
+		info!(
+			"{} -> {} denied by port policy",
+			r_addr,
+			HostPort(&addr, port)
+		);
+		conf.audit(&record(dst, 0, 0, Outcome::PortDenied));
+		let _ = server_reply(s, cipher, buf, wire, REP_PORT_DENIED, 0).await;
+		return false;
+	}
+	if let Some(hook) = &conf.on_connect
+		&& !hook.allows(r_addr, &addr, port).await
+	{
+		info!("{} -> {} denied", r_addr, HostPort(&addr, port));
+		conf.audit(&record(dst, 0, 0, Outcome::Denied));
+		let _ = server_reply(s, cipher, buf, wire, REP_DENIED, 0).await;
+		return false;
+	}
+	let features = req.features & conf.opts.features();
+	// replied before dialing, a client that's gone by now leaves no upstream behind
+	let Some(()) = server_reply(s, cipher, buf, wire, REP_OK, features).await else {
+		return false;
+	};
+	let opts = conf.opts.negotiated(features).in_ver(req.ver);
+	info!("{} -> {}", r_addr, HostPort(&addr, port));
+	let Ok(mut u) = conf
+		.connector
+		.connect(&addr, port)
+		.instrument(info_span!("connect"))
+		.await
+		.map_err(|e| error!("error connecting to upstream: {}", e))
+	else {
+		conf.audit(&record(dst, 0, 0, Outcome::Failed));
+		return false;
+	};
+	let head = if conf.send_proxy {
+		proxy_proto::v2_header(r_addr, port)
+	} else {
+		Vec::new()
+	};
+	if let Err(e) = write_first(&mut u, &head, &req.early).await {
+		log_io("error writing to upstream", &e);
+		conf.audit(&record(dst, 0, 0, Outcome::Failed));
+		return false;
+	}
+	let (down, up, end) = async {
+		if opts.reuse {
+			duplex_framed(cipher, &opts, &mut u, s).await
+		} else {
+			let (down, up, end) = duplex(cipher, &opts, &mut u, s).await;
+			(down, up, Some(end))
+		}
+	}
+	.instrument(info_span!("duplex"))
+	.await;
+	Span::current().record("up", up).record("down", down);
+	CONNS.relayed(up, down);
+	conf.audit(&record(dst, up, down, Outcome::Ok));
+	if let Some(hosts) = &conf.hosts {
+		hosts.borrow_mut().relayed(&addr, up, down, Instant::now());
+	}
+	let conn = format_args!("{} -> {}", r_addr, HostPort(&addr, port));
+	match end {
+		Some(end) => end.log(conn),
+		None => debug!("session ended, the connection stays: {}", conn),
+	}
+	end.is_none()
+}
+
+#[cfg(test)]
+mod test {
+	use std::{cell::Cell, io, slice};
+
+	use chacha20poly1305::ChaCha20Poly1305;
+	use tokio::io::{AsyncWriteExt, duplex, join};
+
+	use super::*;
+	use crate::connector::{Connecting, Io};
+
+	// counts connects, none of them go anywhere
+	struct Count(Rc<Cell<u32>>);
+
+	impl Connector for Count {
+		fn connect<'a>(&'a self, _: &'a str, _: u16) -> Connecting<'a> {
+			self.0.set(self.0.get() + 1);
+			Box::pin(async { Err(io::ErrorKind::ConnectionRefused.into()) })
+		}
+	}
+
+	#[tokio::test]
+	async fn test_client_gone() {
+		let _ = env_logger::builder().is_test(true).try_init();
+
+		let connects = Rc::new(Cell::new(0));
+		let server = ServerConfig::new(&crate::gen_psk())
+			.connector(Count(connects.clone()))
+			.build()
+			.unwrap();
+		let cipher: ChaCha20Poly1305 = init_cipher(&server.key).unwrap();
+		let conf = &server.conf;
+		let r_addr = "127.0.0.1:40000".parse().unwrap();
+
+		// a request and nothing else, the client doesn't wait for the reply
+		let mut req = Vec::new();
+		let mut buf = BytesMut::new();
+		let mut io = join(&b""[..], &mut req);
+		client_handshake(
+			&mut io,
+			&cipher,
+			&mut buf,
+			"example.com",
+			80,
+			conf.wire(),
+			0,
+		)
+		.await;
+
+		// gone before the reply, the upstream is never dialed
+		let (mut c, s) = duplex(0x1000);
+		c.write_all(&req).await.unwrap();
+		drop(c);
+		serve_conn(slice::from_ref(&cipher), conf, s, r_addr).await;
+		assert_eq!(connects.get(), 0);
+
+		// still there, it is
+		let (mut c, s) = duplex(0x1000);
+		c.write_all(&req).await.unwrap();
+		serve_conn(slice::from_ref(&cipher), conf, s, r_addr).await;
+		assert_eq!(connects.get(), 1);
+	}
+
+	#[tokio::test]
+	async fn test_client_keys() {
+		let _ = env_logger::builder().is_test(true).try_init();
+
+		let (alice, alice_pub) = crate::gen_key_pair();
+		let (mallory, _) = crate::gen_key_pair();
+		let connects = Rc::new(Cell::new(0));
+		let server = ServerConfig::new(&crate::gen_psk())
+			.connector(Count(connects.clone()))
+			.client_key("alice", &alice_pub)
+			.build()
+			.unwrap();
+		let cipher: ChaCha20Poly1305 = init_cipher(&server.key).unwrap();
+		let conf = &server.conf;
+		let r_addr = "127.0.0.1:40000".parse().unwrap();
+
+		let request = async |key: Option<&str>| {
+			let key = key.map(|k| decode_sign_key(k.as_bytes()).unwrap());
+			let wire = Wire {
+				client_key: key.as_ref(),
+				..conf.wire()
+			};
+			let mut req = Vec::new();
+			let mut io = join(&b""[..], &mut req);
+			let mut buf = BytesMut::new();
+			client_handshake(&mut io, &cipher, &mut buf, "example.com", 80, wire, 0).await;
+			req
+		};
+		let serve = async |req: &[u8]| {
+			let (mut c, s) = duplex(0x1000);
+			c.write_all(req).await.unwrap();
+			serve_conn(slice::from_ref(&cipher), conf, s, r_addr).await;
+			connects.get()
+		};
+
+		let req = request(Some(&alice)).await;
+		assert_eq!(serve(&req).await, 1);
+		// not registered, not signed, or signed by alice once already
+		assert_eq!(serve(&request(Some(&mallory)).await).await, 1);
+		assert_eq!(serve(&request(None).await).await, 1);
+		assert_eq!(serve(&req).await, 1);
+	}
+
+	#[tokio::test]
+	async fn test_users() {
+		let _ = env_logger::builder().is_test(true).try_init();
+
+		let (alice, bob) = (crate::gen_psk(), crate::gen_psk());
+		let connects = Rc::new(Cell::new(0));
+		let server = ServerConfig::new(&crate::gen_psk())
+			.connector(Count(connects.clone()))
+			.user("alice", &alice)
+			.user("bob", &bob)
+			.build()
+			.unwrap();
+		let ciphers: Vec<ChaCha20Poly1305> = std::iter::once(&server.key)
+			.chain(&server.user_keys)
+			.map(|k| init_cipher(k).unwrap())
+			.collect();
+		let conf = &server.conf;
+		let r_addr = "127.0.0.1:40000".parse().unwrap();
+
+		let serve = async |psk: &str| {
+			let cipher: ChaCha20Poly1305 =
+				init_cipher(&decode_psk(psk.as_bytes()).unwrap()).unwrap();
+			let mut req = Vec::new();
+			let mut io = join(&b""[..], &mut req);
+			let mut buf = BytesMut::new();
+			client_handshake(
+				&mut io,
+				&cipher,
+				&mut buf,
+				"example.com",
+				80,
+				conf.wire(),
+				0,
+			)
+			.await;
+			let (mut c, s) = duplex(0x1000);
+			c.write_all(&req).await.unwrap();
+			serve_conn(&ciphers, conf, s, r_addr).await;
+			connects.get()
+		};
+
+		assert_eq!(serve(&alice).await, 1);
+		assert_eq!(serve(&bob).await, 2);
+		// nobody's, it's a probe
+		assert_eq!(serve(&crate::gen_psk()).await, 2);
+
+		// whoever decrypts a request first is who it's from, no two can share a PSK
+		let psk = crate::gen_psk();
+		assert!(ServerConfig::new(&psk).user("a", &psk).build().is_err());
+		assert!(
+			ServerConfig::new(&crate::gen_psk())
+				.user("a", &alice)
+				.user("b", &alice)
+				.build()
+				.is_err()
+		);
+	}
+
+	// answers hello to everyone
+	struct Hello;
+
+	impl Connector for Hello {
+		fn connect<'a>(&'a self, _: &'a str, _: u16) -> Connecting<'a> {
+			Box::pin(async { Ok(Box::new(join(&b"hello"[..], tokio::io::sink())) as Box<dyn Io>) })
+		}
+	}
+
+	#[tokio::test]
+	async fn test_audit() {
+		let _ = env_logger::builder().is_test(true).try_init();
+
+		let path =
+			std::env::temp_dir().join(format!("mint-audit-server-{}.log", std::process::id()));
+		let path = path.to_str().unwrap();
+		let _ = std::fs::remove_file(path);
+		let alice = crate::gen_psk();
+		let server = ServerConfig::new(&crate::gen_psk())
+			.connector(Hello)
+			.user("alice", &alice)
+			.audit_log(path, true)
+			.build()
+			.unwrap();
+		let ciphers: Vec<ChaCha20Poly1305> = std::iter::once(&server.key)
+			.chain(&server.user_keys)
+			.map(|k| init_cipher(k).unwrap())
+			.collect();
+		let conf = &server.conf;
+
+		let mut req = Vec::new();
+		let mut io = join(&b""[..], &mut req);
+		let mut buf = BytesMut::new();
+		client_handshake(
+			&mut io,
+			&ciphers[1],
+			&mut buf,
+			"example.com",
+			443,
+			conf.wire(),
+			0,
+		)
+		.await;
+		let (mut c, s) = duplex(0x1000);
+		c.write_all(&req).await.unwrap();
+		c.shutdown().await.unwrap();
+		serve_conn(&ciphers, conf, s, "192.0.2.1:40000".parse().unwrap()).await;
+
+		let log = std::fs::read_to_string(path).unwrap();
+		std::fs::remove_file(path).unwrap();
+		let lines: Vec<_> = log.lines().collect();
+		assert_eq!(lines.len(), 1);
+		let (record, _) = lines[0].split_once(" prev=").unwrap();
+		let (time, record) = record.split_once(' ').unwrap();
+		let time: u64 = time.strip_prefix("time=").unwrap().parse().unwrap();
+		assert!(time.abs_diff(unix_time()) < 10);
+		assert_eq!(
+			record,
+			"src=192.0.2.1 user=\"alice\" client=\"\" dst=\"example.com:443\" up=0 down=5 result=ok"
+		);
+	}
+
+	// gone before the first write
+	struct Closed;
+
+	impl Connector for Closed {
+		fn connect<'a>(&'a self, _: &'a str, _: u16) -> Connecting<'a> {
+			Box::pin(async { Ok(Box::new(duplex(0x100).0) as Box<dyn Io>) })
+		}
+	}
+
+	#[tokio::test]
+	async fn test_audit_write_failed() {
+		let _ = env_logger::builder().is_test(true).try_init();
+
+		let path =
+			std::env::temp_dir().join(format!("mint-audit-write-{}.log", std::process::id()));
+		let path = path.to_str().unwrap();
+		let _ = std::fs::remove_file(path);
+		// the PROXY header is always written first
+		let server = ServerConfig::new(&crate::gen_psk())
+			.connector(Closed)
+			.send_proxy_protocol(true)
+			.audit_log(path, false)
+			.build()
+			.unwrap();
+		let cipher: ChaCha20Poly1305 = init_cipher(&server.key).unwrap();
+		let conf = &server.conf;
+
+		let mut req = Vec::new();
+		let mut io = join(&b""[..], &mut req);
+		let mut buf = BytesMut::new();
+		client_handshake(
+			&mut io,
+			&cipher,
+			&mut buf,
+			"example.com",
+			443,
+			conf.wire(),
+			0,
+		)
+		.await;
+		let (mut c, s) = duplex(0x1000);
+		c.write_all(&req).await.unwrap();
+		c.shutdown().await.unwrap();
+		serve_conn(
+			slice::from_ref(&cipher),
+			conf,
+			s,
+			"192.0.2.1:40000".parse().unwrap(),
+		)
+		.await;
+
+		let log = std::fs::read_to_string(path).unwrap();
+		std::fs::remove_file(path).unwrap();
+		let lines: Vec<_> = log.lines().collect();
+		assert_eq!(lines.len(), 1);
+		assert!(
+			lines[0].ends_with("dst=\"example.com:443\" up=0 down=0 result=failed"),
+			"{}",
+			lines[0]
+		);
+	}
+
+	#[tokio::test]
+	async fn test_max_memory() {
+		use std::sync::atomic::{AtomicU64, Ordering::Relaxed};
+
+		use tokio::io::AsyncReadExt;
+
+		use crate::fake::EMPTY_HEADER;
+
+		let _ = env_logger::builder().is_test(true).try_init();
+
+		static RSS: AtomicU64 = AtomicU64::new(0);
+		let port = std::net::TcpListener::bind("127.0.0.1:0")
+			.unwrap()
+			.local_addr()
+			.unwrap()
+			.port();
+		let addr = format!("127.0.0.1:{}", port);
+		let mut server = ServerConfig::new(&crate::gen_psk())
+			.listen(&addr)
+			.stats_interval(Duration::ZERO)
+			.connector(Echo)
+			.build()
+			.unwrap();
+		// as if it were using RSS bytes
+		server.memory = Some(MemGuard::new(1000, || Some(RSS.load(Relaxed))));
+		let cipher: ChaCha20Poly1305 = init_cipher(&server.key).unwrap();
+
+		let connect = async || {
+			let mut s = loop {
+				match TcpStream::connect(&addr).await {
+					Ok(s) => break s,
+					Err(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+				}
+			};
+			let mut buf = BytesMut::new();
+			client_handshake(
+				&mut s,
+				&cipher,
+				&mut buf,
+				"example.com",
+				80,
+				EMPTY_HEADER,
+				0,
+			)
+			.await
+			.map(|_| s)
+		};
+		let test = async {
+			let mut open = connect().await.unwrap();
+			let (mut plain, far) = duplex(0x1000);
+			let echo = async |plain: &mut tokio::io::DuplexStream, msg: &[u8]| {
+				plain.write_all(msg).await.unwrap();
+				let mut got = vec![0; msg.len()];
+				plain.read_exact(&mut got).await.unwrap();
+				assert_eq!(got, msg);
+			};
+			let relay = async {
+				let mut far = far;
+				crate::proto::duplex(&cipher, &FrameOpts::default(), &mut far, &mut open).await
+			};
+			let test = async {
+				echo(&mut plain, b"hello").await;
+
+				RSS.store(2000, Relaxed);
+				tokio::time::sleep(limit::MEM_CHECK_INTERVAL).await;
+				assert!(connect().await.is_none());
+				// the one open goes on
+				echo(&mut plain, b"still here").await;
+
+				RSS.store(500, Relaxed);
+				tokio::time::sleep(limit::MEM_CHECK_INTERVAL).await;
+				assert!(connect().await.is_some());
+			};
+			tokio::select! {
+				_ = relay => panic!("relay ended"),
+				_ = test => {}
+			}
+		};
+		tokio::select! {
+			r = run_server(server) => panic!("server quit: {:?}", r),
+			_ = test => {}
+		}
+	}
+
+	// every destination is an in-memory echo
+	struct Echo;
+
+	impl Connector for Echo {
+		fn connect<'a>(&'a self, _: &'a str, _: u16) -> Connecting<'a> {
+			let (near, far) = duplex(0x1000);
+			tokio::spawn(async move {
+				let (mut r, mut w) = tokio::io::split(far);
+				let _ = tokio::io::copy(&mut r, &mut w).await;
+			});
+			Box::pin(async move { Ok(Box::new(near) as Box<dyn Io>) })
+		}
+	}
+
+	#[tokio::test]
+	async fn test_label() {
+		let _ = env_logger::builder().is_test(true).try_init();
+
+		let psk = crate::gen_psk();
+		let server = |label| {
+			ServerConfig::new(&psk)
+				.connector(Count(Rc::default()))
+				.top_talkers(4)
+				.label(label)
+				.build()
+				.unwrap()
+		};
+		let (a, b) = (server("a"), server("b"));
+		let cipher: ChaCha20Poly1305 = init_cipher(&a.key).unwrap();
+		let conn = async |conf, ip| {
+			let (c, s) = duplex(0x1000);
+			drop(c);
+			serve_conn(
+				slice::from_ref(&cipher),
+				conf,
+				s,
+				SocketAddr::new(ip, 40000),
+			)
+			.await;
+		};
+		let (x, y) = ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+		conn(&a.conf, x).await;
+		conn(&a.conf, x).await;
+		conn(&b.conf, y).await;
+		assert_eq!(
+			a.conf.host_report().unwrap(),
+			"a: top destinations: none yet; top sources: 192.0.2.1 2 conns"
+		);
+		assert_eq!(
+			b.conf.host_report().unwrap(),
+			"b: top destinations: none yet; top sources: 192.0.2.2 1 conns"
+		);
+	}
+}
@@ -0,0 +1,237 @@
+use std::{
+	cell::Cell,
+	hash::{DefaultHasher, Hash, Hasher},
+	net::IpAddr,
+	time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use log::*;
+
+use crate::transport::{Dialer, Stream};
+
+/// which of several servers a new connection goes to
+#[derive(Clone, Copy, Default, PartialEq, ValueEnum)]
+pub enum Strategy {
+	/// take turns, spreading connections over all of them
+	#[default]
+	RoundRobin,
+	/// the first one that works, in the order given
+	Failover,
+	/// the one answering pings the fastest, servers that don't answer are skipped
+	Fastest,
+	/// by a hash of the app's address, each app sticks to one server while it's up
+	SourceHash,
+}
+
+// a server that failed is tried after the others for this long
+const DOWN_FOR: Duration = Duration::from_secs(30);
+
+// the configured servers, in order, which ones failed lately and how fast they answered
+pub struct Servers {
+	dialers: Vec<Dialer>,
+	down: Vec<Cell<Option<Instant>>>,
+	// the last health check, None if it failed or there's been none
+	rtt: Vec<Cell<Option<Duration>>>,
+	strategy: Strategy,
+	next: Cell<usize>,
+}
+
+impl Servers {
+	pub fn new(dialers: Vec<Dialer>, strategy: Strategy) -> Self {
+		assert!(!dialers.is_empty());
+		Servers {
+			down: dialers.iter().map(|_| Cell::new(None)).collect(),
+			rtt: dialers.iter().map(|_| Cell::new(None)).collect(),
+			dialers,
+			strategy,
+			next: Cell::new(0),
+		}
+	}
+
+	pub fn dialers(&self) -> &[Dialer] {
+		&self.dialers
+	}
+
+	pub fn strategy(&self) -> Strategy {
+		self.strategy
+	}
+
+	// for messages
+	pub fn hosts(&self) -> String {
+		self.dialers
+			.iter()
+			.map(|d| d.upstream().host())
+			.collect::<Vec<_>>()
+			.join(", ")
+	}
+
+	// the server an app at src sticks to, with SourceHash
+	pub fn home(&self, src: IpAddr) -> Option<usize> {
+		if self.strategy != Strategy::SourceHash {
+			return None;
+		}
+		let mut h = DefaultHasher::new();
+		src.to_canonical().hash(&mut h);
+		Some((h.finish() % self.dialers.len() as u64) as usize)
+	}
+
+	// indices to try for an app at src, servers that failed within DOWN_FOR last
+	fn order(&self, src: IpAddr) -> Vec<usize> {
+		let n = self.dialers.len();
+		let start = match self.strategy {
+			Strategy::RoundRobin => {
+				let start = self.next.get();
+				self.next.set((start + 1) % n);
+				start
+			}
+			Strategy::Failover | Strategy::Fastest => 0,
+			Strategy::SourceHash => self.home(src).unwrap(),
+		};
+		let mut order: Vec<usize> = (0..n).map(|i| (start + i) % n).collect();
+		// stable, keeps the rotation among the healthy ones
+		match self.strategy {
+			Strategy::Fastest => order.sort_by_key(|&i| {
+				let rtt = self.rtt[i].get().unwrap_or(Duration::MAX);
+				(self.is_down(i), rtt)
+			}),
+			_ => order.sort_by_key(|&i| self.is_down(i)),
+		}
+		order
+	}
+
+	fn is_down(&self, i: usize) -> bool {
+		match self.down[i].get() {
+			Some(t) if t.elapsed() < DOWN_FOR => true,
+			Some(_) => {
+				self.down[i].set(None);
+				false
+			}
+			None => false,
+		}
+	}
+
+	// the index is for fail() if the handshake goes wrong later
+	pub async fn connect(&self, src: IpAddr) -> Option<(usize, Stream)> {
+		for i in self.order(src) {
+			if let Some(s) = self.dialers[i].connect().await {
+				self.down[i].set(None);
+				return Some((i, s));
+			}
+			self.fail(i);
+		}
+		None
+	}
+
+	pub fn fail(&self, i: usize) {
+		if self.dialers.len() > 1 {
+			debug!(
+				"server {} failed, trying the others first for {:?}",
+				self.dialers[i].upstream().host(),
+				DOWN_FOR
+			);
+		}
+		self.down[i].set(Some(Instant::now()));
+	}
+
+	// from a health check, None if it went unanswered
+	pub fn checked(&self, i: usize, rtt: Option<Duration>) {
+		self.rtt[i].set(rtt);
+		match rtt {
+			Some(rtt) => {
+				debug!(
+					"server {} answers in {:?}",
+					self.dialers[i].upstream().host(),
+					rtt
+				);
+				self.down[i].set(None);
+			}
+			None => self.fail(i),
+		}
+	}
+
+	// the next lookups are fresh, a server may have moved
+	pub fn invalidate(&self) {
+		for d in &self.dialers {
+			d.upstream().invalidate();
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use std::time::Duration;
+
+	use super::*;
+	use crate::{transport::Kind, upstream::Upstream};
+
+	fn servers(n: u16, strategy: Strategy) -> Servers {
+		let dialers = (0..n)
+			.map(|i| {
+				let upstream = Upstream::new(&format!("127.0.0.1:{}", 1 + i), Duration::ZERO);
+				Dialer::new(Kind::Tcp, upstream, None, "/").unwrap()
+			})
+			.collect();
+		Servers::new(dialers, strategy)
+	}
+
+	const SRC: IpAddr = IpAddr::V4(std::net::Ipv4Addr::LOCALHOST);
+
+	#[test]
+	fn test_order() {
+		let s = servers(3, Strategy::RoundRobin);
+		assert_eq!(s.order(SRC), [0, 1, 2]);
+		assert_eq!(s.order(SRC), [1, 2, 0]);
+		s.fail(2);
+		assert_eq!(s.order(SRC), [0, 1, 2]);
+		assert_eq!(s.order(SRC), [0, 1, 2]);
+		assert_eq!(s.order(SRC), [1, 0, 2]);
+
+		let s = servers(3, Strategy::Failover);
+		assert_eq!(s.order(SRC), [0, 1, 2]);
+		s.fail(0);
+		assert_eq!(s.order(SRC), [1, 2, 0]);
+		s.down[0].set(Some(Instant::now() - DOWN_FOR));
+		assert_eq!(s.order(SRC), [0, 1, 2]);
+
+		// unmeasured ones last, in the order given
+		let s = servers(4, Strategy::Fastest);
+		assert_eq!(s.order(SRC), [0, 1, 2, 3]);
+		s.checked(1, Some(Duration::from_millis(30)));
+		s.checked(2, Some(Duration::from_millis(10)));
+		assert_eq!(s.order(SRC), [2, 1, 0, 3]);
+		s.checked(2, None);
+		assert_eq!(s.order(SRC), [1, 0, 3, 2]);
+		s.checked(2, Some(Duration::from_millis(20)));
+		assert_eq!(s.order(SRC), [2, 1, 0, 3]);
+	}
+
+	#[test]
+	fn test_source_hash() {
+		let s = servers(4, Strategy::SourceHash);
+		let src = |i: u8| IpAddr::from([192, 0, 2, i]);
+		let homes: Vec<_> = (0..=255).map(|i| s.home(src(i)).unwrap()).collect();
+		// the same server every time, however many connections in between
+		for _ in 0..3 {
+			for i in 0..=255 {
+				assert_eq!(s.order(src(i))[0], homes[i as usize]);
+			}
+		}
+		// the same source over IPv6
+		assert_eq!(s.home("::ffff:192.0.2.7".parse().unwrap()), Some(homes[7]));
+		// spread over all of them
+		for i in 0..4 {
+			assert!(homes.contains(&i));
+		}
+		// only while it's up, then back to it
+		let home = homes[0];
+		s.fail(home);
+		let order = s.order(src(0));
+		assert_ne!(order[0], home);
+		assert_eq!(order[3], home);
+		s.down[home].set(Some(Instant::now() - DOWN_FOR));
+		assert_eq!(s.order(src(0))[0], home);
+
+		assert_eq!(servers(4, Strategy::RoundRobin).home(src(0)), None);
+	}
+}
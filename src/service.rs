@@ -0,0 +1,117 @@
+use std::{ffi::OsString, sync::Mutex, time::Duration};
+
+use anyhow::Context;
+use log::*;
+use windows_service::{
+	define_windows_service,
+	service::{
+		ServiceControl, ServiceControlAccept, ServiceExitCode, ServiceState, ServiceStatus,
+		ServiceType,
+	},
+	service_control_handler::{self, ServiceControlHandlerResult},
+	service_dispatcher,
+};
+
+use crate::shutdown::{arm, request_stop};
+
+// an own process service, the service manager doesn't go by the name
+const NAME: &str = "mint";
+
+type Run = Box<dyn FnOnce() -> anyhow::Result<()> + Send>;
+
+// handed from run_service to the thread the service manager starts, and back
+static RUN: Mutex<Option<Run>> = Mutex::new(None);
+static RESULT: Mutex<Option<anyhow::Result<()>>> = Mutex::new(None);
+
+define_windows_service!(ffi_service_main, service_main);
+
+/// runs f under the Windows service manager, for a service installed with e.g.
+/// `sc create mint binPath= "C:\mint\mint.exe --service server -k C:\mint\psk"`
+///
+/// stopping the service, or Windows shutting down, stops a server f runs like Ctrl-C
+/// would, see [`request_stop`]
+pub fn run_service(f: impl FnOnce() -> anyhow::Result<()> + Send + 'static) -> anyhow::Result<()> {
+	*RUN.lock().unwrap() = Some(Box::new(f));
+	service_dispatcher::start(NAME, ffi_service_main)
+		.context("failed to start as a service, it has to be started by the service manager")?;
+	RESULT.lock().unwrap().take().unwrap_or(Ok(()))
+}
+
+fn service_main(_: Vec<OsString>) {
+	let r = serve();
+	*RESULT.lock().unwrap() = Some(r);
+}
+
+fn serve() -> anyhow::Result<()> {
+	// a stop may come before f gets to make its Stop
+	arm();
+	let status = service_control_handler::register(NAME, on_control)
+		.context("failed to register the service control handler")?;
+	let set = |state, exit_code| {
+		status.set_service_status(ServiceStatus {
+			service_type: ServiceType::OWN_PROCESS,
+			current_state: state,
+			controls_accepted: match state {
+				ServiceState::Running => {
+					ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+				}
+				_ => ServiceControlAccept::empty(),
+			},
+			exit_code,
+			checkpoint: 0,
+			wait_hint: Duration::ZERO,
+			process_id: None,
+		})
+	};
+	set(ServiceState::Running, ServiceExitCode::NO_ERROR)?;
+	let f = RUN
+		.lock()
+		.unwrap()
+		.take()
+		.context("run_service wasn't given anything to run")?;
+	let r = f();
+	let exit_code = match r {
+		Ok(()) => ServiceExitCode::NO_ERROR,
+		Err(_) => ServiceExitCode::ServiceSpecific(1),
+	};
+	set(ServiceState::Stopped, exit_code)?;
+	r
+}
+
+// stop and shutdown go the way Ctrl-C does, open connections drain first
+fn on_control(control: ServiceControl) -> ServiceControlHandlerResult {
+	match control {
+		ServiceControl::Stop | ServiceControl::Shutdown => {
+			debug!("service manager asks to stop");
+			request_stop();
+			ServiceControlHandlerResult::NoError
+		}
+		ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+		_ => ServiceControlHandlerResult::NotImplemented,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use crate::shutdown::Stop;
+
+	#[tokio::test]
+	async fn test_stop() {
+		let _lock = crate::shutdown::test::LOCK.lock().await;
+		let mut stop = Stop::new();
+		assert!(matches!(
+			on_control(ServiceControl::Interrogate),
+			ServiceControlHandlerResult::NoError
+		));
+		assert!(matches!(
+			on_control(ServiceControl::Pause),
+			ServiceControlHandlerResult::NotImplemented
+		));
+		assert!(matches!(
+			on_control(ServiceControl::Stop),
+			ServiceControlHandlerResult::NoError
+		));
+		assert!(stop.until(std::future::pending::<()>()).await.is_none());
+	}
+}
@@ -0,0 +1,233 @@
+use std::{
+	cell::Cell,
+	pin::Pin,
+	rc::Rc,
+	sync::atomic::{AtomicUsize, Ordering},
+	time::Duration,
+};
+
+use log::*;
+use tokio::{sync::Notify, time::timeout};
+
+// stop requests from outside, counted so each is taken as one signal
+static REQUESTS: AtomicUsize = AtomicUsize::new(0);
+static REQUESTED: Notify = Notify::const_new();
+// where the next Stop starts counting, if armed before it's made
+static ARMED: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// stops a running server like SIGTERM or Ctrl-C would, callable from any thread,
+/// e.g. a service manager's control handler, a second one cuts the drain short,
+/// servers started after it aren't affected
+pub fn request_stop() {
+	REQUESTS.fetch_add(1, Ordering::SeqCst);
+	REQUESTED.notify_waiters();
+}
+
+// the next Stop takes requests from now on, not only from when it's made,
+// for a stop handler that can fire before the server gets to make one
+#[cfg(any(test, all(feature = "service", windows)))]
+pub(crate) fn arm() {
+	ARMED.store(REQUESTS.load(Ordering::SeqCst), Ordering::SeqCst);
+}
+
+// resolves once there are more requests than past
+async fn requested(past: usize) {
+	loop {
+		// created before the check, so a request in between isn't lost
+		let notified = REQUESTED.notified();
+		if REQUESTS.load(Ordering::SeqCst) > past {
+			return;
+		}
+		notified.await;
+	}
+}
+
+// resolves on SIGINT, SIGTERM where there is one, or a stop request after past
+async fn signal(past: usize) {
+	let requested = async {
+		requested(past).await;
+		debug!("stop requested");
+	};
+	#[cfg(unix)]
+	{
+		use tokio::signal::unix::{SignalKind, signal};
+		let Ok(mut term) = signal(SignalKind::terminate())
+			.inspect_err(|e| error!("failed to listen for SIGTERM: {}", e))
+		else {
+			tokio::select! {
+				_ = tokio::signal::ctrl_c() => {}
+				_ = requested => {}
+			}
+			return;
+		};
+		tokio::select! {
+			_ = term.recv() => debug!("got SIGTERM"),
+			_ = tokio::signal::ctrl_c() => debug!("got SIGINT"),
+			_ = requested => {}
+		}
+	}
+	#[cfg(not(unix))]
+	tokio::select! {
+		_ = tokio::signal::ctrl_c() => {}
+		_ = requested => {}
+	}
+}
+
+// the stop signal, kept across accept calls so none is missed
+pub struct Stop(Pin<Box<dyn Future<Output = ()>>>);
+
+impl Stop {
+	// requests before this, or before arm, went to earlier servers and don't count
+	pub fn new() -> Self {
+		let past = match ARMED.swap(usize::MAX, Ordering::SeqCst) {
+			usize::MAX => REQUESTS.load(Ordering::SeqCst),
+			past => past,
+		};
+		Stop(Box::pin(signal(past)))
+	}
+
+	// None once stopped, don't call again after that
+	pub async fn until<F: Future>(&mut self, f: F) -> Option<F::Output> {
+		tokio::select! {
+			r = f => Some(r),
+			_ = &mut self.0 => None,
+		}
+	}
+}
+
+// counts live connections, so shutdown can wait for them
+#[derive(Clone, Default)]
+pub struct Tracker(Rc<Inner>);
+
+#[derive(Default)]
+struct Inner {
+	live: Cell<usize>,
+	idle: Notify,
+}
+
+// one per connection, held until it ends
+pub struct Guard(Tracker);
+
+impl Drop for Guard {
+	fn drop(&mut self) {
+		let live = &self.0.0.live;
+		live.set(live.get() - 1);
+		if live.get() == 0 {
+			self.0.0.idle.notify_waiters();
+		}
+	}
+}
+
+impl Tracker {
+	pub fn track(&self) -> Guard {
+		self.0.live.set(self.0.live.get() + 1);
+		Guard(self.clone())
+	}
+
+	pub fn live(&self) -> usize {
+		self.0.live.get()
+	}
+
+	// wait up to grace for live connections to end, or until another signal
+	// returns how many are left, the caller drops them by returning
+	pub async fn drain(&self, grace: Duration) -> usize {
+		if self.live() == 0 {
+			return 0;
+		}
+		info!(
+			"draining {} connections for up to {}s, signal again to stop now",
+			self.live(),
+			grace.as_secs()
+		);
+		let idle = async {
+			loop {
+				// created before the check, so a notify in between isn't lost
+				let notified = self.0.idle.notified();
+				if self.live() == 0 {
+					return;
+				}
+				notified.await;
+			}
+		};
+		tokio::select! {
+			_ = timeout(grace, idle) => {},
+			_ = signal(REQUESTS.load(Ordering::SeqCst)) => {},
+		}
+		let left = self.live();
+		if left > 0 {
+			warn!("{} connections still open, dropping them", left);
+		}
+		left
+	}
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+	use super::*;
+
+	// the requests are process wide, tests sending them go one at a time
+	pub(crate) static LOCK: tokio::sync::Mutex<()> = tokio::sync::Mutex::const_new(());
+
+	#[tokio::test]
+	async fn test_drain() {
+		let _lock = LOCK.lock().await;
+		let t = Tracker::default();
+		assert_eq!(t.drain(Duration::from_secs(1)).await, 0);
+
+		let a = t.track();
+		let b = t.track();
+		assert_eq!(t.live(), 2);
+		drop(a);
+		// b ends within the grace period
+		let (left, _) = tokio::join!(t.drain(Duration::from_secs(5)), async {
+			tokio::time::sleep(Duration::from_millis(50)).await;
+			drop(b);
+		});
+		assert_eq!(left, 0);
+
+		let _c = t.track();
+		assert_eq!(t.drain(Duration::from_millis(50)).await, 1);
+	}
+
+	#[tokio::test]
+	async fn test_request_stop() {
+		let _lock = LOCK.lock().await;
+		// one that went to an earlier server doesn't stop the next
+		request_stop();
+		let mut stop = Stop::new();
+		let idle = tokio::time::sleep(Duration::from_millis(50));
+		assert!(stop.until(idle).await.is_some());
+		let t = Tracker::default();
+		let _a = t.track();
+		let ((), r) = tokio::join!(
+			async {
+				tokio::time::sleep(Duration::from_millis(50)).await;
+				request_stop();
+			},
+			stop.until(std::future::pending::<()>()),
+		);
+		assert!(r.is_none());
+		// the first request doesn't cut the drain short, a second one does
+		let start = std::time::Instant::now();
+		let (left, ()) = tokio::join!(t.drain(Duration::from_secs(5)), async {
+			tokio::time::sleep(Duration::from_millis(50)).await;
+			request_stop();
+		});
+		assert_eq!(left, 1);
+		assert!(start.elapsed() < Duration::from_secs(1));
+	}
+
+	#[tokio::test]
+	async fn test_arm() {
+		let _lock = LOCK.lock().await;
+		// a request between arm and Stop::new isn't lost
+		arm();
+		request_stop();
+		let mut stop = Stop::new();
+		assert!(stop.until(std::future::pending::<()>()).await.is_none());
+		// and arm is used up by that Stop
+		let mut stop = Stop::new();
+		let idle = tokio::time::sleep(Duration::from_millis(50));
+		assert!(stop.until(idle).await.is_some());
+	}
+}
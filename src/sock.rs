@@ -0,0 +1,238 @@
+use std::{io, net::SocketAddr, time::Duration};
+
+use anyhow::Context;
+use socket2::{Domain, SockRef, Socket, TcpKeepalive, Type};
+use tokio::net::{TcpListener, TcpSocket, TcpStream};
+
+// listening socket options TcpListener::bind doesn't take
+#[derive(Debug, Clone, Copy)]
+pub struct ListenOpts {
+	pub backlog: u32,
+	// IPV6_V6ONLY for IPv6 addresses, None leaves it to the OS
+	pub v6only: Option<bool>,
+	// TCP keepalive idle time, accepted sockets inherit it
+	pub keepalive: Option<Duration>,
+}
+
+impl Default for ListenOpts {
+	// what TcpListener::bind uses
+	fn default() -> Self {
+		ListenOpts {
+			backlog: 1024,
+			v6only: None,
+			keepalive: None,
+		}
+	}
+}
+
+// like TcpListener::bind, the first address that binds wins
+pub async fn listen(addr: &str, opts: &ListenOpts) -> anyhow::Result<TcpListener> {
+	let context = || format!("failed to bind {}", addr);
+	let mut last = io::Error::new(io::ErrorKind::InvalidInput, "no address");
+	for a in crate::addr::lookup(addr).await.with_context(context)? {
+		match bind(a, opts) {
+			Ok(l) => return Ok(l),
+			Err(e) => last = e,
+		}
+	}
+	Err(last).with_context(context)
+}
+
+fn bind(addr: SocketAddr, opts: &ListenOpts) -> io::Result<TcpListener> {
+	let s = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+	// same as tokio, so a restart doesn't trip over TIME_WAIT
+	#[cfg(unix)]
+	s.set_reuse_address(true)?;
+	if let (true, Some(v6only)) = (addr.is_ipv6(), opts.v6only) {
+		s.set_only_v6(v6only)?;
+	}
+	if let Some(idle) = opts.keepalive {
+		set_keepalive(SockRef::from(&s), idle)?;
+	}
+	s.set_nonblocking(true)?;
+	s.bind(&addr.into())?;
+	s.listen(opts.backlog.min(i32::MAX as u32) as i32)?;
+	TcpListener::from_std(s.into())
+}
+
+// outgoing socket options, for the server's upstream connections
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ConnectOpts {
+	// TTL for IPv4, hop limit for IPv6
+	pub ttl: Option<u32>,
+	// the upper 6 bits of TOS or traffic class
+	pub dscp: Option<u8>,
+	// TCP keepalive idle time
+	pub keepalive: Option<Duration>,
+}
+
+// like TcpStream::connect, tries each address in turn
+pub async fn connect(addrs: &[SocketAddr], opts: &ConnectOpts) -> io::Result<TcpStream> {
+	let mut last = None;
+	for &a in addrs {
+		match connect1(a, opts).await {
+			Ok(s) => return Ok(s),
+			Err(e) => last = Some(e),
+		}
+	}
+	Err(last.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address")))
+}
+
+async fn connect1(addr: SocketAddr, opts: &ConnectOpts) -> io::Result<TcpStream> {
+	let s = if addr.is_ipv4() {
+		TcpSocket::new_v4()?
+	} else {
+		TcpSocket::new_v6()?
+	};
+	let r = SockRef::from(&s);
+	if let Some(ttl) = opts.ttl {
+		if addr.is_ipv4() {
+			r.set_ttl_v4(ttl)?;
+		} else {
+			r.set_unicast_hops_v6(ttl)?;
+		}
+	}
+	if let Some(dscp) = opts.dscp {
+		let tos = (dscp as u32) << 2;
+		if addr.is_ipv4() {
+			r.set_tos_v4(tos)?;
+		} else {
+			set_tclass_v6(&r, tos)?;
+		}
+	}
+	if let Some(idle) = opts.keepalive {
+		set_keepalive(r, idle)?;
+	}
+	s.connect(addr).await
+}
+
+// probes start after idle, then go every third of it, three unanswered ones drop the
+// connection, only the idle time where the platform has nothing more
+pub fn set_keepalive(s: SockRef, idle: Duration) -> io::Result<()> {
+	let ka = TcpKeepalive::new().with_time(idle);
+	#[cfg(any(
+		target_os = "linux",
+		target_os = "android",
+		target_os = "macos",
+		target_os = "freebsd"
+	))]
+	let ka = ka
+		.with_interval((idle / 3).max(Duration::from_secs(1)))
+		.with_retries(3);
+	s.set_tcp_keepalive(&ka)
+}
+
+#[cfg(any(
+	target_os = "linux",
+	target_os = "android",
+	target_os = "macos",
+	target_os = "freebsd"
+))]
+fn set_tclass_v6(s: &SockRef, tclass: u32) -> io::Result<()> {
+	s.set_tclass_v6(tclass)
+}
+
+#[cfg(not(any(
+	target_os = "linux",
+	target_os = "android",
+	target_os = "macos",
+	target_os = "freebsd"
+)))]
+fn set_tclass_v6(_: &SockRef, _: u32) -> io::Result<()> {
+	Err(io::Error::new(
+		io::ErrorKind::Unsupported,
+		"DSCP on IPv6 isn't supported on this platform",
+	))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	// for a listening socket, ss shows the backlog as Send-Q
+	#[cfg(target_os = "linux")]
+	#[tokio::test]
+	async fn test_backlog() {
+		let opts = ListenOpts {
+			backlog: 37,
+			..Default::default()
+		};
+		let l = listen("127.0.0.1:0", &opts).await.unwrap();
+		let port = l.local_addr().unwrap().port();
+		let Ok(out) = std::process::Command::new("ss")
+			.args(["-ltnH", &format!("sport = :{}", port)])
+			.output()
+		else {
+			eprintln!("no ss, skipping");
+			return;
+		};
+		let out = String::from_utf8(out.stdout).unwrap();
+		let send_q = out.split_whitespace().nth(2);
+		assert_eq!(send_q, Some("37"), "{}", out);
+	}
+
+	#[cfg(target_os = "linux")]
+	#[tokio::test]
+	async fn test_v6only() {
+		for v6only in [true, false] {
+			let opts = ListenOpts {
+				v6only: Some(v6only),
+				..Default::default()
+			};
+			let l = listen("[::]:0", &opts).await.unwrap();
+			assert_eq!(socket2::SockRef::from(&l).only_v6().unwrap(), v6only);
+		}
+		// doesn't apply to IPv4
+		let opts = ListenOpts {
+			v6only: Some(true),
+			..Default::default()
+		};
+		assert!(listen("127.0.0.1:0", &opts).await.is_ok());
+	}
+
+	#[tokio::test]
+	async fn test_connect_opts() {
+		let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let opts = ConnectOpts {
+			ttl: Some(7),
+			dscp: Some(46),
+			keepalive: None,
+		};
+		let addrs = [l.local_addr().unwrap()];
+		let (c, _) = tokio::join!(connect(&addrs, &opts), l.accept());
+		let c = c.unwrap();
+		let r = SockRef::from(&c);
+		assert_eq!(r.ttl_v4().unwrap(), 7);
+		assert_eq!(r.tos_v4().unwrap(), 46 << 2);
+		assert!(!r.keepalive().unwrap());
+	}
+
+	#[cfg(target_os = "linux")]
+	#[tokio::test]
+	async fn test_keepalive() {
+		let idle = Some(Duration::from_secs(30));
+		let l = listen(
+			"127.0.0.1:0",
+			&ListenOpts {
+				keepalive: idle,
+				..Default::default()
+			},
+		)
+		.await
+		.unwrap();
+		let opts = ConnectOpts {
+			keepalive: idle,
+			..Default::default()
+		};
+		let addrs = [l.local_addr().unwrap()];
+		let (c, a) = tokio::join!(connect(&addrs, &opts), l.accept());
+		let (c, (a, _)) = (c.unwrap(), a.unwrap());
+		// both ends, the accepted one through the listener
+		for s in [SockRef::from(&c), SockRef::from(&a)] {
+			assert!(s.keepalive().unwrap());
+			assert_eq!(s.tcp_keepalive_time().unwrap(), Duration::from_secs(30));
+			assert_eq!(s.tcp_keepalive_interval().unwrap(), Duration::from_secs(10));
+			assert_eq!(s.tcp_keepalive_retries().unwrap(), 3);
+		}
+	}
+}
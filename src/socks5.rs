@@ -0,0 +1,552 @@
+// the server side of RFC 1928, just enough for CONNECT and UDP ASSOCIATE
+// and the client side of CONNECT, for reaching the server through a SOCKS5 proxy
+
+use std::{
+	io,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use log::*;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+const VER: u8 = 5;
+
+const METHOD_NO_AUTH: u8 = 0;
+const METHOD_USER_PASS: u8 = 2;
+const METHOD_NONE_ACCEPTABLE: u8 = 0xff;
+
+// RFC 1929
+const AUTH_VER: u8 = 1;
+const AUTH_OK: u8 = 0;
+const AUTH_FAILED: u8 = 1;
+
+const CMD_CONNECT: u8 = 1;
+const CMD_UDP_ASSOCIATE: u8 = 3;
+
+const ATYP_V4: u8 = 1;
+const ATYP_DOMAIN: u8 = 3;
+const ATYP_V6: u8 = 4;
+
+pub const REP_SUCCEEDED: u8 = 0;
+pub const REP_GENERAL_FAILURE: u8 = 1;
+pub const REP_NOT_ALLOWED: u8 = 2;
+const REP_CMD_NOT_SUPPORTED: u8 = 7;
+const REP_ATYP_NOT_SUPPORTED: u8 = 8;
+
+#[derive(Default)]
+pub struct Conf {
+	// username and password
+	pub auth: Option<(String, String)>,
+	// don't accept no-auth
+	pub require_auth: bool,
+	// pick no-auth over username and password when the client offers both
+	pub prefer_no_auth: bool,
+	// accept UDP ASSOCIATE
+	pub udp: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cmd {
+	Connect,
+	// host and port are where the app sends from, usually all zeros
+	UdpAssociate,
+}
+
+impl Conf {
+	// only ever one the client offered
+	fn select_method(&self, offered: &[u8]) -> u8 {
+		let user_pass = self.auth.is_some() && offered.contains(&METHOD_USER_PASS);
+		let no_auth = !self.require_auth && offered.contains(&METHOD_NO_AUTH);
+		match (user_pass, no_auth) {
+			(true, true) if self.prefer_no_auth => METHOD_NO_AUTH,
+			(true, _) => METHOD_USER_PASS,
+			(false, true) => METHOD_NO_AUTH,
+			(false, false) => METHOD_NONE_ACCEPTABLE,
+		}
+	}
+}
+
+// negotiates a method and reads the request, the caller is expected to reply
+pub async fn server_handshake<T: AsyncRead + AsyncWrite + Unpin>(
+	s: &mut T,
+	conf: &Conf,
+) -> Option<(Cmd, String, u16)> {
+	let mut buf = [0u8; 0x100];
+
+	s.read_exact(&mut buf[..2])
+		.await
+		.map_err(|e| debug!("socks5 error reading greeting: {}", e))
+		.ok()?;
+	if buf[0] != VER {
+		debug!("socks5 invalid ver: 0x{:02x}", buf[0]);
+		return None;
+	}
+	let n = buf[1] as usize;
+	s.read_exact(&mut buf[..n])
+		.await
+		.map_err(|e| debug!("socks5 error reading methods: {}", e))
+		.ok()?;
+	let method = conf.select_method(&buf[..n]);
+	if method == METHOD_NONE_ACCEPTABLE {
+		debug!("socks5 no acceptable method in {:02x?}", &buf[..n]);
+		let _ = s.write_all(&[VER, METHOD_NONE_ACCEPTABLE]).await;
+		return None;
+	}
+	s.write_all(&[VER, method])
+		.await
+		.map_err(|e| debug!("socks5 error writing method: {}", e))
+		.ok()?;
+
+	if method == METHOD_USER_PASS {
+		user_pass(s, conf.auth.as_ref()?).await?;
+	}
+
+	s.read_exact(&mut buf[..4])
+		.await
+		.map_err(|e| debug!("socks5 error reading request: {}", e))
+		.ok()?;
+	if buf[0] != VER {
+		debug!("socks5 invalid ver: 0x{:02x}", buf[0]);
+		return None;
+	}
+	let cmd = match buf[1] {
+		CMD_CONNECT => Cmd::Connect,
+		CMD_UDP_ASSOCIATE if conf.udp => Cmd::UdpAssociate,
+		cmd => {
+			debug!("socks5 unsupported cmd: 0x{:02x}", cmd);
+			let _ = reply(s, REP_CMD_NOT_SUPPORTED).await;
+			return None;
+		}
+	};
+	let host = match buf[3] {
+		ATYP_V4 => {
+			let mut a = [0u8; 4];
+			s.read_exact(&mut a).await.ok()?;
+			Ipv4Addr::from(a).to_string()
+		}
+		ATYP_V6 => {
+			let mut a = [0u8; 16];
+			s.read_exact(&mut a).await.ok()?;
+			Ipv6Addr::from(a).to_string()
+		}
+		ATYP_DOMAIN => {
+			let len = s.read_u8().await.ok()? as usize;
+			s.read_exact(&mut buf[..len]).await.ok()?;
+			let Ok(host) = str::from_utf8(&buf[..len]) else {
+				debug!("socks5 invalid utf8 in domain");
+				return None;
+			};
+			host.to_owned()
+		}
+		atyp => {
+			debug!("socks5 unsupported atyp: 0x{:02x}", atyp);
+			let _ = reply(s, REP_ATYP_NOT_SUPPORTED).await;
+			return None;
+		}
+	};
+	let port = s
+		.read_u16()
+		.await
+		.map_err(|e| debug!("socks5 error reading port: {}", e))
+		.ok()?;
+
+	Some((cmd, host, port))
+}
+
+async fn user_pass<T: AsyncRead + AsyncWrite + Unpin>(
+	s: &mut T,
+	(user, pass): &(String, String),
+) -> Option<()> {
+	let mut buf = [0u8; 0x100];
+
+	let ver = s.read_u8().await.ok()?;
+	if ver != AUTH_VER {
+		debug!("socks5 invalid auth ver: 0x{:02x}", ver);
+		return None;
+	}
+	let len = s.read_u8().await.ok()? as usize;
+	s.read_exact(&mut buf[..len]).await.ok()?;
+	let user_ok = &buf[..len] == user.as_bytes();
+	let len = s.read_u8().await.ok()? as usize;
+	s.read_exact(&mut buf[..len]).await.ok()?;
+	let pass_ok = &buf[..len] == pass.as_bytes();
+
+	if !(user_ok && pass_ok) {
+		debug!("socks5 auth failed");
+		let _ = s.write_all(&[AUTH_VER, AUTH_FAILED]).await;
+		return None;
+	}
+	s.write_all(&[AUTH_VER, AUTH_OK])
+		.await
+		.map_err(|e| debug!("socks5 error writing auth status: {}", e))
+		.ok()
+}
+
+// BND.ADDR and BND.PORT are not meaningful to us, always 0.0.0.0:0
+pub async fn reply<T: AsyncWrite + Unpin>(s: &mut T, rep: u8) -> Option<()> {
+	reply_bound(s, rep, (Ipv4Addr::UNSPECIFIED, 0).into()).await
+}
+
+// for UDP ASSOCIATE, where the app sends its datagrams
+pub async fn reply_bound<T: AsyncWrite + Unpin>(
+	s: &mut T,
+	rep: u8,
+	bound: SocketAddr,
+) -> Option<()> {
+	let mut buf = vec![VER, rep, 0];
+	put_addr(&mut buf, &bound.ip().to_string(), bound.port())?;
+	s.write_all(&buf)
+		.await
+		.map_err(|e| debug!("socks5 error writing reply: {}", e))
+		.ok()
+}
+
+// asks a proxy taking no auth to CONNECT to host:port, returns the REP of its reply
+// what it says it's bound to is read and thrown away, the tunnel starts right after
+pub async fn client_connect<T: AsyncRead + AsyncWrite + Unpin>(
+	s: &mut T,
+	host: &str,
+	port: u16,
+) -> io::Result<u8> {
+	let mut req = vec![VER, CMD_CONNECT, 0];
+	put_addr(&mut req, host, port).ok_or_else(|| {
+		io::Error::new(
+			io::ErrorKind::InvalidInput,
+			"host name longer than 255 bytes",
+		)
+	})?;
+	s.write_all(&[VER, 1, METHOD_NO_AUTH]).await?;
+	let mut buf = [0u8; 0x100];
+	s.read_exact(&mut buf[..2]).await?;
+	if buf[..2] != [VER, METHOD_NO_AUTH] {
+		return Err(io::Error::other("proxy wants auth, or isn't SOCKS5"));
+	}
+	s.write_all(&req).await?;
+	s.read_exact(&mut buf[..4]).await?;
+	let [VER, rep, _, atyp] = buf[..4] else {
+		return Err(io::ErrorKind::InvalidData.into());
+	};
+	let len = match atyp {
+		ATYP_V4 => 4,
+		ATYP_V6 => 16,
+		ATYP_DOMAIN => s.read_u8().await? as usize,
+		_ => return Err(io::ErrorKind::InvalidData.into()),
+	};
+	s.read_exact(&mut buf[..len + 2]).await?;
+	Ok(rep)
+}
+
+// ATYP, the address and the port, as in requests, replies and UDP headers
+// None for a host name too long for its 1 byte length
+fn put_addr(buf: &mut Vec<u8>, host: &str, port: u16) -> Option<()> {
+	match host.parse() {
+		Ok(IpAddr::V4(ip)) => {
+			buf.push(ATYP_V4);
+			buf.extend_from_slice(&ip.octets());
+		}
+		Ok(IpAddr::V6(ip)) => {
+			buf.push(ATYP_V6);
+			buf.extend_from_slice(&ip.octets());
+		}
+		Err(_) => {
+			buf.push(ATYP_DOMAIN);
+			buf.push(u8::try_from(host.len()).ok()?);
+			buf.extend_from_slice(host.as_bytes());
+		}
+	}
+	buf.extend_from_slice(&port.to_be_bytes());
+	Some(())
+}
+
+// a datagram from the app: RSV, FRAG, the address as in a request, then the data
+// fragments are dropped, they're optional and hardly anything sends them
+pub fn parse_udp(buf: &[u8]) -> Option<(String, u16, &[u8])> {
+	let [0, 0, frag, atyp, rest @ ..] = buf else {
+		return None;
+	};
+	if *frag != 0 {
+		debug!("socks5 dropping a UDP fragment");
+		return None;
+	}
+	let (host, rest) = match *atyp {
+		ATYP_V4 if rest.len() >= 4 => {
+			let (a, rest) = rest.split_at(4);
+			(
+				Ipv4Addr::from(<[u8; 4]>::try_from(a).unwrap()).to_string(),
+				rest,
+			)
+		}
+		ATYP_V6 if rest.len() >= 16 => {
+			let (a, rest) = rest.split_at(16);
+			(
+				Ipv6Addr::from(<[u8; 16]>::try_from(a).unwrap()).to_string(),
+				rest,
+			)
+		}
+		ATYP_DOMAIN => {
+			let (&len, rest) = rest.split_first()?;
+			let (host, rest) = rest.split_at_checked(len as usize)?;
+			(str::from_utf8(host).ok()?.to_owned(), rest)
+		}
+		_ => return None,
+	};
+	let (port, data) = rest.split_at_checked(2)?;
+	Some((host, u16::from_be_bytes([port[0], port[1]]), data))
+}
+
+// the header for a datagram back to the app, from host:port
+pub fn put_udp(buf: &mut Vec<u8>, host: &str, port: u16, data: &[u8]) -> Option<()> {
+	buf.extend_from_slice(&[0, 0, 0]);
+	put_addr(buf, host, port)?;
+	buf.extend_from_slice(data);
+	Some(())
+}
+
+// whether buf starts like a client's greeting, VER, NMETHODS and that many methods
+// the server port sees these when an app is pointed at it instead of the client
+pub fn is_greeting(buf: &[u8]) -> bool {
+	match buf {
+		[VER, n, methods @ ..] => *n > 0 && methods.len() >= *n as usize,
+		_ => false,
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_is_greeting() {
+		assert!(is_greeting(&[VER, 1, METHOD_NO_AUTH]));
+		assert!(is_greeting(&[VER, 2, METHOD_NO_AUTH, METHOD_USER_PASS]));
+		// with the request right behind it
+		assert!(is_greeting(&[VER, 1, METHOD_NO_AUTH, VER, CMD_CONNECT]));
+		assert!(!is_greeting(&[VER, 2, METHOD_NO_AUTH]));
+		assert!(!is_greeting(&[VER, 0]));
+		assert!(!is_greeting(&[4, 1, 0, 80]));
+		assert!(!is_greeting(b"GET / HTTP/1.1\r\n\r\n"));
+	}
+
+	#[tokio::test]
+	async fn test_connect() {
+		let (mut c, mut s) = tokio::io::duplex(0x100);
+		tokio::join!(
+			async {
+				c.write_all(&[VER, 1, METHOD_NO_AUTH]).await.unwrap();
+				let mut r = [0u8; 2];
+				c.read_exact(&mut r).await.unwrap();
+				assert_eq!(r, [VER, METHOD_NO_AUTH]);
+				c.write_all(&[VER, CMD_CONNECT, 0, ATYP_DOMAIN, 11])
+					.await
+					.unwrap();
+				c.write_all(b"example.com\x01\xbb").await.unwrap();
+			},
+			async {
+				assert_eq!(
+					server_handshake(&mut s, &Conf::default()).await,
+					Some((Cmd::Connect, "example.com".to_owned(), 443))
+				);
+			}
+		);
+	}
+
+	#[tokio::test]
+	async fn test_client_connect() {
+		let (mut c, mut s) = tokio::io::duplex(0x100);
+		let (rep, _) = tokio::join!(client_connect(&mut c, "example.com", 443), async {
+			assert_eq!(
+				server_handshake(&mut s, &Conf::default()).await,
+				Some((Cmd::Connect, "example.com".to_owned(), 443))
+			);
+			reply(&mut s, REP_NOT_ALLOWED).await.unwrap();
+		});
+		assert_eq!(rep.unwrap(), REP_NOT_ALLOWED);
+
+		// insisting on auth
+		let (mut c, mut s) = tokio::io::duplex(0x100);
+		let conf = auth_conf(true);
+		let (rep, _) = tokio::join!(
+			client_connect(&mut c, "192.0.2.1", 443),
+			server_handshake(&mut s, &conf)
+		);
+		assert!(rep.is_err());
+
+		// refused before anything is sent, not cut to fit
+		let (mut c, _s) = tokio::io::duplex(0x100);
+		let rep = client_connect(&mut c, &"a".repeat(256), 443).await;
+		assert_eq!(rep.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+	}
+
+	fn auth_conf(require_auth: bool) -> Conf {
+		Conf {
+			auth: Some(("user".to_owned(), "pass".to_owned())),
+			require_auth,
+			prefer_no_auth: false,
+			udp: false,
+		}
+	}
+
+	#[tokio::test]
+	async fn test_prefer_no_auth() {
+		let both = [METHOD_NO_AUTH, METHOD_USER_PASS];
+		for (prefer_no_auth, offered, method) in [
+			(false, &both[..], METHOD_USER_PASS),
+			(true, &both[..], METHOD_NO_AUTH),
+			// whatever the preference, only what's offered
+			(true, &[METHOD_USER_PASS][..], METHOD_USER_PASS),
+			(false, &[METHOD_NO_AUTH][..], METHOD_NO_AUTH),
+		] {
+			let conf = Conf {
+				prefer_no_auth,
+				..auth_conf(false)
+			};
+			let (mut c, mut s) = tokio::io::duplex(0x100);
+			tokio::join!(
+				async {
+					c.write_all(&[VER, offered.len() as u8]).await.unwrap();
+					c.write_all(offered).await.unwrap();
+					let mut r = [0u8; 2];
+					c.read_exact(&mut r).await.unwrap();
+					assert_eq!(r, [VER, method], "{:?}", offered);
+					if method == METHOD_USER_PASS {
+						c.write_all(b"\x01\x04user\x04pass").await.unwrap();
+						c.read_exact(&mut r).await.unwrap();
+						assert_eq!(r, [AUTH_VER, AUTH_OK]);
+					}
+					c.write_all(&[VER, CMD_CONNECT, 0, ATYP_V4, 127, 0, 0, 1, 0, 80])
+						.await
+						.unwrap();
+				},
+				async {
+					let r = server_handshake(&mut s, &conf).await;
+					assert_eq!(r, Some((Cmd::Connect, "127.0.0.1".to_owned(), 80)));
+				}
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_user_pass() {
+		for (pass, ok) in [(&b"pass"[..], true), (&b"fail"[..], false)] {
+			let (mut c, mut s) = tokio::io::duplex(0x100);
+			tokio::join!(
+				async {
+					c.write_all(&[VER, 2, METHOD_NO_AUTH, METHOD_USER_PASS])
+						.await
+						.unwrap();
+					let mut r = [0u8; 2];
+					c.read_exact(&mut r).await.unwrap();
+					assert_eq!(r, [VER, METHOD_USER_PASS]);
+					c.write_all(&[AUTH_VER, 4]).await.unwrap();
+					c.write_all(b"user").await.unwrap();
+					c.write_all(&[pass.len() as u8]).await.unwrap();
+					c.write_all(pass).await.unwrap();
+					c.read_exact(&mut r).await.unwrap();
+					assert_eq!(r, [AUTH_VER, if ok { AUTH_OK } else { AUTH_FAILED }]);
+					if ok {
+						c.write_all(&[VER, CMD_CONNECT, 0, ATYP_V4, 127, 0, 0, 1, 0, 80])
+							.await
+							.unwrap();
+					}
+				},
+				async {
+					let r = server_handshake(&mut s, &auth_conf(false)).await;
+					assert_eq!(r, ok.then(|| (Cmd::Connect, "127.0.0.1".to_owned(), 80)));
+				}
+			);
+		}
+	}
+
+	#[tokio::test]
+	async fn test_require_auth() {
+		let (mut c, mut s) = tokio::io::duplex(0x100);
+		tokio::join!(
+			async {
+				c.write_all(&[VER, 1, METHOD_NO_AUTH]).await.unwrap();
+				let mut r = Vec::new();
+				c.read_to_end(&mut r).await.unwrap();
+				assert_eq!(r, [VER, METHOD_NONE_ACCEPTABLE]);
+			},
+			async {
+				assert_eq!(server_handshake(&mut s, &auth_conf(true)).await, None);
+				drop(s);
+			}
+		);
+	}
+
+	#[tokio::test]
+	async fn test_udp_associate() {
+		for udp in [false, true] {
+			let conf = Conf {
+				udp,
+				..Conf::default()
+			};
+			let (mut c, mut s) = tokio::io::duplex(0x100);
+			tokio::join!(
+				async {
+					c.write_all(&[VER, 1, METHOD_NO_AUTH]).await.unwrap();
+					let mut r = [0u8; 2];
+					c.read_exact(&mut r).await.unwrap();
+					c.write_all(&[VER, CMD_UDP_ASSOCIATE, 0, ATYP_V4, 0, 0, 0, 0, 0, 0])
+						.await
+						.unwrap();
+					if !udp {
+						let mut r = [0u8; 10];
+						c.read_exact(&mut r).await.unwrap();
+						assert_eq!(r[1], REP_CMD_NOT_SUPPORTED);
+					}
+				},
+				async {
+					let r = server_handshake(&mut s, &conf).await;
+					let expected = (Cmd::UdpAssociate, "0.0.0.0".to_owned(), 0);
+					assert_eq!(r, udp.then_some(expected));
+				}
+			);
+		}
+
+		let mut r = Vec::new();
+		reply_bound(&mut r, REP_SUCCEEDED, "[::1]:5353".parse().unwrap())
+			.await
+			.unwrap();
+		assert_eq!(r[..4], [VER, REP_SUCCEEDED, 0, ATYP_V6]);
+		assert_eq!(r[4..20], Ipv6Addr::LOCALHOST.octets());
+		assert_eq!(r[20..], 5353u16.to_be_bytes());
+	}
+
+	#[test]
+	fn test_udp_header() {
+		for host in ["192.0.2.1", "2001:db8::1", "example.com"] {
+			let mut buf = Vec::new();
+			put_udp(&mut buf, host, 53, b"query").unwrap();
+			assert_eq!(parse_udp(&buf), Some((host.to_owned(), 53, &b"query"[..])));
+		}
+		// a fragment
+		assert_eq!(parse_udp(&[0, 0, 1, ATYP_V4, 1, 2, 3, 4, 0, 53]), None);
+		// cut short
+		assert_eq!(parse_udp(&[0, 0, 0, ATYP_V4, 1, 2, 3, 4, 0]), None);
+		assert_eq!(parse_udp(&[0, 0, 0, ATYP_DOMAIN, 9, b'a', 0, 53]), None);
+		// no length byte can say this
+		assert_eq!(
+			put_udp(&mut Vec::new(), &"a".repeat(256), 53, b"query"),
+			None
+		);
+	}
+
+	#[tokio::test]
+	async fn test_gssapi_only() {
+		let (mut c, mut s) = tokio::io::duplex(0x100);
+		tokio::join!(
+			async {
+				// GSSAPI
+				c.write_all(&[VER, 1, 1]).await.unwrap();
+				let mut r = Vec::new();
+				c.read_to_end(&mut r).await.unwrap();
+				assert_eq!(r, [VER, METHOD_NONE_ACCEPTABLE]);
+			},
+			async {
+				assert_eq!(server_handshake(&mut s, &Conf::default()).await, None);
+				drop(s);
+			}
+		);
+	}
+}
@@ -0,0 +1,399 @@
+use std::{
+	cmp::Reverse,
+	collections::HashMap,
+	fmt,
+	hash::Hash,
+	net::IpAddr,
+	sync::atomic::{AtomicU64, Ordering::Relaxed},
+	time::{Duration, Instant},
+};
+
+use log::*;
+
+use crate::proto::MsgError;
+
+// server handshake outcomes, a spike in failures means scanning or a wrong PSK
+pub struct Handshakes {
+	ok: AtomicU64,
+	no_eoh: AtomicU64,
+	decrypt: AtomicU64,
+	invalid: AtomicU64,
+}
+
+pub static HANDSHAKES: Handshakes = Handshakes {
+	ok: AtomicU64::new(0),
+	no_eoh: AtomicU64::new(0),
+	decrypt: AtomicU64::new(0),
+	invalid: AtomicU64::new(0),
+};
+
+impl Handshakes {
+	pub fn ok(&self) {
+		self.ok.fetch_add(1, Relaxed);
+	}
+
+	pub fn failed(&self, e: MsgError) {
+		match e {
+			MsgError::NoEoh => &self.no_eoh,
+			MsgError::Decrypt => &self.decrypt,
+			MsgError::Invalid => &self.invalid,
+		}
+		.fetch_add(1, Relaxed);
+	}
+
+	fn snapshot(&self) -> [u64; 4] {
+		[&self.ok, &self.no_eoh, &self.decrypt, &self.invalid].map(|c| c.load(Relaxed))
+	}
+}
+
+impl fmt::Display for Handshakes {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let [ok, no_eoh, decrypt, invalid] = self.snapshot();
+		write!(
+			f,
+			"handshakes: {} ok, {} without EOH, {} failed decryption, {} invalid",
+			ok, no_eoh, decrypt, invalid
+		)
+	}
+}
+
+// server connections, handshake or not, and the bytes relayed for them
+pub struct Conns {
+	active: AtomicU64,
+	total: AtomicU64,
+	up: AtomicU64,
+	down: AtomicU64,
+}
+
+pub static CONNS: Conns = Conns {
+	active: AtomicU64::new(0),
+	total: AtomicU64::new(0),
+	up: AtomicU64::new(0),
+	down: AtomicU64::new(0),
+};
+
+// counts as active until dropped
+pub struct Open(&'static Conns);
+
+impl Drop for Open {
+	fn drop(&mut self) {
+		self.0.active.fetch_sub(1, Relaxed);
+	}
+}
+
+impl Conns {
+	pub fn open(&'static self) -> Open {
+		self.total.fetch_add(1, Relaxed);
+		self.active.fetch_add(1, Relaxed);
+		Open(self)
+	}
+
+	// up is client to upstream
+	pub fn relayed(&self, up: u64, down: u64) {
+		self.up.fetch_add(up, Relaxed);
+		self.down.fetch_add(down, Relaxed);
+	}
+}
+
+impl fmt::Display for Conns {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let [active, total, up, down] =
+			[&self.active, &self.total, &self.up, &self.down].map(|c| c.load(Relaxed));
+		write!(
+			f,
+			"connections: {} active, {} total, {} bytes up, {} bytes down",
+			active, total, up, down
+		)
+	}
+}
+
+struct Totals {
+	up: u64,
+	down: u64,
+	last: Instant,
+}
+
+// bytes relayed per destination host, least recently used hosts are evicted beyond cap
+pub struct HostBytes {
+	cap: usize,
+	hosts: HashMap<String, Totals>,
+}
+
+impl HostBytes {
+	pub fn new(cap: usize) -> Self {
+		HostBytes {
+			cap,
+			hosts: HashMap::new(),
+		}
+	}
+
+	// up is client to upstream
+	pub fn relayed(&mut self, host: &str, up: u64, down: u64, now: Instant) {
+		if let Some(t) = self.hosts.get_mut(host) {
+			t.up += up;
+			t.down += down;
+			t.last = now;
+			return;
+		}
+		if self.hosts.len() >= self.cap {
+			evict(&mut self.hosts, |t| t.last);
+		}
+		self.hosts.insert(
+			host.to_owned(),
+			Totals {
+				up,
+				down,
+				last: now,
+			},
+		);
+	}
+
+	// the busiest n, by bytes both ways
+	pub fn top(&self, n: usize) -> Vec<(&str, u64, u64)> {
+		let mut hosts: Vec<_> = self
+			.hosts
+			.iter()
+			.map(|(host, t)| (host.as_str(), t.up, t.down))
+			.collect();
+		hosts.sort_by_key(|&(host, up, down)| (Reverse(up + down), host));
+		hosts.truncate(n);
+		hosts
+	}
+}
+
+// the busiest first
+impl fmt::Display for HostBytes {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let hosts = self.top(usize::MAX);
+		write!(f, "bytes by destination host:")?;
+		if hosts.is_empty() {
+			return write!(f, " none yet");
+		}
+		for (i, (host, up, down)) in hosts.into_iter().enumerate() {
+			let sep = if i == 0 { " " } else { ", " };
+			write!(f, "{}{} {} up {} down", sep, host, up, down)?;
+		}
+		Ok(())
+	}
+}
+
+struct Seen {
+	conns: u64,
+	last: Instant,
+}
+
+// connections per source IP, least recently seen IPs are evicted beyond cap
+pub struct SourceConns {
+	cap: usize,
+	ips: HashMap<IpAddr, Seen>,
+}
+
+impl SourceConns {
+	pub fn new(cap: usize) -> Self {
+		SourceConns {
+			cap,
+			ips: HashMap::new(),
+		}
+	}
+
+	pub fn connected(&mut self, ip: IpAddr, now: Instant) {
+		if !self.ips.contains_key(&ip) && self.ips.len() >= self.cap {
+			evict(&mut self.ips, |s| s.last);
+		}
+		let s = self.ips.entry(ip).or_insert(Seen {
+			conns: 0,
+			last: now,
+		});
+		s.conns += 1;
+		s.last = now;
+	}
+
+	// the n with the most connections
+	pub fn top(&self, n: usize) -> Vec<(IpAddr, u64)> {
+		let mut ips: Vec<_> = self.ips.iter().map(|(ip, s)| (*ip, s.conns)).collect();
+		ips.sort_by_key(|&(ip, conns)| (Reverse(conns), ip));
+		ips.truncate(n);
+		ips
+	}
+}
+
+// the least recently used one
+fn evict<K: Clone + Eq + Hash, V>(map: &mut HashMap<K, V>, last: impl Fn(&V) -> Instant) {
+	let Some(k) = map
+		.iter()
+		.min_by_key(|(_, v)| last(v))
+		.map(|(k, _)| k.clone())
+	else {
+		return;
+	};
+	map.remove(&k);
+}
+
+// the top n destinations by bytes and sources by connections, on one line
+pub fn top_talkers(hosts: &HostBytes, sources: &SourceConns, n: usize) -> String {
+	let none = |v: Vec<String>| match v.is_empty() {
+		true => "none yet".to_owned(),
+		false => v.join(", "),
+	};
+	let dsts = hosts
+		.top(n)
+		.into_iter()
+		.map(|(host, up, down)| format!("{} {} bytes", host, up + down))
+		.collect();
+	let srcs = sources
+		.top(n)
+		.into_iter()
+		.map(|(ip, conns)| format!("{} {} conns", ip, conns))
+		.collect();
+	format!(
+		"top destinations: {}; top sources: {}",
+		none(dsts),
+		none(srcs)
+	)
+}
+
+// f each time SIGUSR1 arrives
+#[cfg(unix)]
+pub async fn on_sigusr1(f: impl Fn()) {
+	use tokio::signal::unix::{SignalKind, signal};
+	let Ok(mut usr1) = signal(SignalKind::user_defined1())
+		.inspect_err(|e| warn!("failed to listen for SIGUSR1: {}", e))
+	else {
+		return;
+	};
+	while usr1.recv().await.is_some() {
+		f();
+	}
+}
+
+// everything, whenever SIGUSR1 arrives
+#[cfg(unix)]
+pub async fn log_on_sigusr1() {
+	on_sigusr1(|| info!("{}; {}", CONNS, HANDSHAKES)).await
+}
+
+// quiet while nothing changes
+pub async fn log_every(interval: Duration) {
+	let mut last = HANDSHAKES.snapshot();
+	loop {
+		tokio::time::sleep(interval).await;
+		let now = HANDSHAKES.snapshot();
+		if now != last {
+			info!("{}", HANDSHAKES);
+			last = now;
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use bytes::BytesMut;
+	use chacha20poly1305::{ChaCha20Poly1305, KeyInit, aead::OsRng};
+
+	use super::*;
+	use crate::proto::*;
+
+	fn init() {
+		let _ = env_logger::builder().is_test(true).try_init();
+	}
+
+	#[test]
+	fn test_conns() {
+		static C: Conns = Conns {
+			active: AtomicU64::new(0),
+			total: AtomicU64::new(0),
+			up: AtomicU64::new(0),
+			down: AtomicU64::new(0),
+		};
+		let a = C.open();
+		let _b = C.open();
+		C.relayed(10, 20);
+		drop(a);
+		assert_eq!(
+			C.to_string(),
+			"connections: 1 active, 2 total, 10 bytes up, 20 bytes down"
+		);
+	}
+
+	#[test]
+	fn test_host_bytes() {
+		let t = Instant::now();
+		let later = |ms| t + Duration::from_millis(ms);
+		let mut h = HostBytes::new(2);
+		assert_eq!(h.to_string(), "bytes by destination host: none yet");
+		h.relayed("example.com", 10, 200, t);
+		h.relayed("example.net", 1, 2, later(1));
+		h.relayed("example.com", 5, 100, later(2));
+		assert_eq!(
+			h.to_string(),
+			"bytes by destination host: example.com 15 up 300 down, example.net 1 up 2 down"
+		);
+		// example.net is the least recent
+		h.relayed("example.org", 1000, 0, later(3));
+		assert_eq!(
+			h.to_string(),
+			"bytes by destination host: example.org 1000 up 0 down, example.com 15 up 300 down"
+		);
+	}
+
+	#[test]
+	fn test_top_talkers() {
+		let t = Instant::now();
+		let mut h = HostBytes::new(16);
+		let mut s = SourceConns::new(16);
+		assert_eq!(
+			top_talkers(&h, &s, 2),
+			"top destinations: none yet; top sources: none yet"
+		);
+		for (host, up, down) in [
+			("example.net", 100, 100),
+			("example.com", 10, 150),
+			("example.org", 1, 1),
+			("example.com", 40, 0),
+		] {
+			h.relayed(host, up, down, t);
+		}
+		for ip in ["192.0.2.1", "192.0.2.2", "192.0.2.2"] {
+			s.connected(ip.parse().unwrap(), t);
+		}
+		// a tie goes by name
+		assert_eq!(
+			top_talkers(&h, &s, 2),
+			"top destinations: example.com 200 bytes, example.net 200 bytes; \
+			 top sources: 192.0.2.2 2 conns, 192.0.2.1 1 conns"
+		);
+		h.relayed("example.org", 500, 0, t);
+		assert_eq!(h.top(1), [("example.org", 501, 1)]);
+	}
+
+	#[tokio::test]
+	async fn test_bad_psk() {
+		init();
+
+		let c_cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let s_cipher = ChaCha20Poly1305::new(&ChaCha20Poly1305::generate_key(&mut OsRng));
+		let (mut c, mut s) = tokio::io::duplex(0x1000);
+		// other tests count too, so only look at the difference
+		let before = HANDSHAKES.snapshot();
+		tokio::join!(
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				let header = b"\r\n\r\n";
+				let r =
+					client_handshake(&mut c, &c_cipher, &mut buf, "example.com", 443, header, 0);
+				assert!(r.await.is_none());
+			},
+			async {
+				let mut buf = BytesMut::with_capacity(0x500);
+				assert!(
+					server_handshake(&mut s, &s_cipher, &mut buf, b"")
+						.await
+						.is_err()
+				);
+				drop(s);
+			}
+		);
+		let after = HANDSHAKES.snapshot();
+		assert!(after[2] > before[2]);
+	}
+}
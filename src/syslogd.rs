@@ -0,0 +1,109 @@
+use std::sync::Mutex;
+
+use anyhow::{Context, anyhow};
+use log::{Level, Log, Metadata, Record};
+use syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+
+// RUST_LOG still filters, the priority stands in for the level
+struct Syslog {
+	filter: env_logger::Logger,
+	logger: Mutex<Logger<LoggerBackend, Formatter3164>>,
+}
+
+impl Log for Syslog {
+	fn enabled(&self, metadata: &Metadata) -> bool {
+		self.filter.enabled(metadata)
+	}
+
+	fn log(&self, record: &Record) {
+		if !self.filter.matches(record) {
+			return;
+		}
+		let msg = record.args().to_string();
+		let mut logger = self.logger.lock().unwrap();
+		// nowhere to report it to
+		let _ = match record.level() {
+			Level::Error => logger.err(msg),
+			Level::Warn => logger.warning(msg),
+			Level::Info => logger.info(msg),
+			Level::Debug | Level::Trace => logger.debug(msg),
+		};
+	}
+
+	fn flush(&self) {}
+}
+
+// the formatter for records as mint under facility, e.g. daemon or local0
+fn formatter(facility: &str) -> anyhow::Result<Formatter3164> {
+	let facility: Facility = facility
+		.parse()
+		.map_err(|_| anyhow!("unknown syslog facility {}", facility))?;
+	Ok(Formatter3164 {
+		facility,
+		hostname: None,
+		process: "mint".to_owned(),
+		pid: std::process::id(),
+	})
+}
+
+/// sends log records to the local syslog daemon instead of stderr, as mint under the
+/// given facility, e.g. `daemon` or `local0`
+///
+/// `RUST_LOG` filters them like it would on stderr, default_filter if it's not set
+pub fn init_syslog(facility: &str, default_filter: &str) -> anyhow::Result<()> {
+	let logger = syslog::unix(formatter(facility)?)
+		.map_err(|e| anyhow!("{}", e))
+		.context("failed to connect to syslog")?;
+	let filter =
+		env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_filter))
+			.build();
+	log::set_max_level(filter.filter());
+	log::set_boxed_logger(Box::new(Syslog {
+		filter,
+		logger: Mutex::new(logger),
+	}))
+	.context("a logger is set already")
+}
+
+#[cfg(test)]
+mod test {
+	use std::os::unix::net::UnixDatagram;
+
+	use super::*;
+
+	#[test]
+	fn test_dispatch() {
+		let path = std::env::temp_dir().join(format!("mint-syslog-{}.sock", std::process::id()));
+		let _ = std::fs::remove_file(&path);
+		// stands in for the daemon
+		let sink = UnixDatagram::bind(&path).unwrap();
+		sink.set_nonblocking(true).unwrap();
+		let log = Syslog {
+			filter: env_logger::Builder::new().parse_filters("info").build(),
+			logger: Mutex::new(syslog::unix_custom(formatter("local0").unwrap(), &path).unwrap()),
+		};
+		let record = |level| {
+			log.log(
+				&Record::builder()
+					.level(level)
+					.args(format_args!("listening on 127.0.0.1:8080"))
+					.build(),
+			)
+		};
+		record(Level::Warn);
+		record(Level::Debug);
+
+		let mut buf = [0u8; 0x400];
+		let n = sink.recv(&mut buf).unwrap();
+		let msg = std::str::from_utf8(&buf[..n]).unwrap();
+		// local0 is 16, warning 4
+		assert!(msg.starts_with("<132>"), "{}", msg);
+		let ident = format!(" mint[{}]: listening on 127.0.0.1:8080", std::process::id());
+		assert!(msg.ends_with(&ident), "{}", msg);
+		// filtered out
+		assert!(sink.recv(&mut buf).is_err());
+		std::fs::remove_file(&path).unwrap();
+
+		assert!(formatter("nowhere").is_err());
+	}
+}
@@ -0,0 +1,101 @@
+use std::fmt;
+
+use tokio::task::JoinHandle;
+
+// tokio::task::spawn_local, the task named for tokio-console in tokio_unstable builds
+// the name is only formatted there
+pub fn spawn_local<F>(name: fmt::Arguments<'_>, f: F) -> JoinHandle<F::Output>
+where
+	F: Future + 'static,
+	F::Output: 'static,
+{
+	#[cfg(tokio_unstable)]
+	{
+		tokio::task::Builder::new()
+			.name(&name.to_string())
+			.spawn_local(f)
+			.expect("failed to spawn task")
+	}
+	#[cfg(not(tokio_unstable))]
+	{
+		let _ = name;
+		tokio::task::spawn_local(f)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use tokio::task::LocalSet;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_spawn_local() {
+		let r = LocalSet::new()
+			.run_until(async { spawn_local(format_args!("test {}", 1), async { 42 }).await })
+			.await;
+		assert_eq!(r.unwrap(), 42);
+	}
+
+	// RUSTFLAGS="--cfg tokio_unstable" cargo test --features console
+	#[cfg(all(tokio_unstable, feature = "console"))]
+	#[tokio::test]
+	async fn test_task_name() {
+		use std::sync::{Arc, Mutex};
+
+		use tracing::{
+			Subscriber,
+			field::{Field, Visit},
+			span::{Attributes, Id},
+		};
+		use tracing_subscriber::{
+			Layer,
+			layer::{Context, SubscriberExt},
+		};
+
+		// names of the tasks tokio reports spawning
+		#[derive(Clone, Default)]
+		struct Names(Arc<Mutex<Vec<String>>>);
+
+		impl Visit for Names {
+			fn record_str(&mut self, field: &Field, value: &str) {
+				if field.name() == "task.name" {
+					self.0.lock().unwrap().push(value.to_owned());
+				}
+			}
+
+			fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+				if field.name() == "task.name" {
+					self.0.lock().unwrap().push(format!("{:?}", value));
+				}
+			}
+		}
+
+		impl<S: Subscriber> Layer<S> for Names {
+			fn on_new_span(&self, attrs: &Attributes<'_>, _: &Id, _: Context<'_, S>) {
+				attrs.record(&mut self.clone());
+			}
+		}
+
+		let names = Names::default();
+		let _sub =
+			tracing::subscriber::set_default(tracing_subscriber::registry().with(names.clone()));
+		LocalSet::new()
+			.run_until(async {
+				spawn_local(format_args!("conn {}", "127.0.0.1:1"), async {})
+					.await
+					.unwrap()
+			})
+			.await;
+		assert!(
+			names
+				.0
+				.lock()
+				.unwrap()
+				.iter()
+				.any(|n| n.contains("conn 127.0.0.1:1")),
+			"{:?}",
+			names.0.lock().unwrap()
+		);
+	}
+}
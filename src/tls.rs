@@ -0,0 +1,155 @@
+use std::sync::Arc;
+
+use anyhow::{Context, bail};
+use log::*;
+use rustls::{
+	ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+	client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+	crypto::{CryptoProvider, verify_tls12_signature, verify_tls13_signature},
+	pki_types::{
+		CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer, ServerName, UnixTime, pem::PemObject,
+	},
+};
+
+// what HTTP/3 offers, so the QUIC handshake looks the part
+pub const ALPN_H3: &[u8] = b"h3";
+// plain HTTPS, h2 would promise framing we don't speak
+pub const ALPN_HTTP1: &[u8] = b"http/1.1";
+// for the HTTP/2 transport, which does speak it
+pub const ALPN_H2: &[u8] = b"h2";
+
+// server certificate chain and key
+pub struct Identity {
+	certs: Vec<CertificateDer<'static>>,
+	key: PrivateKeyDer<'static>,
+}
+
+impl Identity {
+	// PEM files, or a throwaway self-signed one if neither is given
+	pub fn load(cert: Option<&str>, key: Option<&str>) -> anyhow::Result<Self> {
+		match (cert, key) {
+			(Some(cert), Some(key)) => {
+				let certs = CertificateDer::pem_file_iter(cert)
+					.and_then(|i| i.collect::<Result<Vec<_>, _>>())
+					.with_context(|| format!("failed to read certificates from {}", cert))?;
+				let key = PrivateKeyDer::from_pem_file(key)
+					.with_context(|| format!("failed to read private key from {}", key))?;
+				Ok(Identity { certs, key })
+			}
+			(None, None) => Self::self_signed("localhost"),
+			_ => bail!("certificate and private key should be given together"),
+		}
+	}
+
+	pub fn self_signed(name: &str) -> anyhow::Result<Self> {
+		let ck = rcgen::generate_simple_self_signed(vec![name.to_owned()])
+			.context("failed to generate certificate")?;
+		info!("using a self-signed certificate for {}", name);
+		Ok(Identity {
+			certs: vec![ck.cert.der().clone()],
+			key: PrivatePkcs8KeyDer::from(ck.signing_key.serialize_der()).into(),
+		})
+	}
+
+	// TLS 1.3 only, QUIC requires it and it's what browsers use anyway
+	pub fn server_config(&self, alpn: &[&[u8]]) -> anyhow::Result<ServerConfig> {
+		let mut config = ServerConfig::builder_with_protocol_versions(&[&rustls::version::TLS13])
+			.with_no_client_auth()
+			.with_single_cert(self.certs.clone(), self.key.clone_key())
+			.context("invalid certificate or key")?;
+		config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+		Ok(config)
+	}
+}
+
+// the certificate is not checked, the mint handshake authenticates the server
+pub fn client_config(alpn: &[&[u8]]) -> ClientConfig {
+	let provider = CryptoProvider::get_default()
+		.cloned()
+		.unwrap_or_else(|| Arc::new(rustls::crypto::ring::default_provider()));
+	let mut config = ClientConfig::builder_with_provider(provider.clone())
+		.with_protocol_versions(&[&rustls::version::TLS13])
+		.unwrap()
+		.dangerous()
+		.with_custom_certificate_verifier(Arc::new(AnyCert(provider)))
+		.with_no_client_auth();
+	config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+	config
+}
+
+#[derive(Debug)]
+struct AnyCert(Arc<CryptoProvider>);
+
+impl ServerCertVerifier for AnyCert {
+	fn verify_server_cert(
+		&self,
+		_end_entity: &CertificateDer<'_>,
+		_intermediates: &[CertificateDer<'_>],
+		_server_name: &ServerName<'_>,
+		_ocsp_response: &[u8],
+		_now: UnixTime,
+	) -> Result<ServerCertVerified, rustls::Error> {
+		Ok(ServerCertVerified::assertion())
+	}
+
+	fn verify_tls12_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls12_signature(
+			message,
+			cert,
+			dss,
+			&self.0.signature_verification_algorithms,
+		)
+	}
+
+	fn verify_tls13_signature(
+		&self,
+		message: &[u8],
+		cert: &CertificateDer<'_>,
+		dss: &DigitallySignedStruct,
+	) -> Result<HandshakeSignatureValid, rustls::Error> {
+		verify_tls13_signature(
+			message,
+			cert,
+			dss,
+			&self.0.signature_verification_algorithms,
+		)
+	}
+
+	fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+		self.0.signature_verification_algorithms.supported_schemes()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_load() {
+		let ck = rcgen::generate_simple_self_signed(vec!["localhost".to_owned()]).unwrap();
+		let dir = std::env::temp_dir();
+		let cert = dir.join(format!("mint-test-{}.crt", std::process::id()));
+		let key = dir.join(format!("mint-test-{}.key", std::process::id()));
+		std::fs::write(&cert, ck.cert.pem()).unwrap();
+		std::fs::write(&key, ck.signing_key.serialize_pem()).unwrap();
+		let (cert, key) = (cert.to_str().unwrap(), key.to_str().unwrap());
+
+		let id = Identity::load(Some(cert), Some(key)).unwrap();
+		assert_eq!(id.certs, vec![ck.cert.der().clone()]);
+		assert!(id.server_config(&[ALPN_HTTP1]).is_ok());
+
+		// both or neither
+		assert!(Identity::load(Some(cert), None).is_err());
+		assert!(Identity::load(None, None).is_ok());
+		// not a key
+		assert!(Identity::load(Some(cert), Some(cert)).is_err());
+
+		let _ = std::fs::remove_file(cert);
+		let _ = std::fs::remove_file(key);
+	}
+}
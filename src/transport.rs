@@ -0,0 +1,197 @@
+use std::{
+	io,
+	pin::Pin,
+	sync::Arc,
+	task::{Context, Poll},
+};
+
+use anyhow::Context as _;
+use clap::ValueEnum;
+use log::*;
+use quinn::{RecvStream, SendStream};
+use rustls::pki_types::ServerName;
+use tokio::{
+	io::{AsyncRead, AsyncWrite, ReadBuf},
+	net::TcpStream,
+};
+use tokio_rustls::{TlsConnector, client::TlsStream};
+
+use crate::{
+	http2::{self, H2Stream},
+	quic,
+	tls::{self, ALPN_H2, ALPN_HTTP1, Identity},
+	upstream::Upstream,
+	ws::{self, WsStream},
+};
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Kind {
+	Tcp,
+	/// a stream per tunnel over one QUIC connection, avoids TCP over TCP meltdown
+	Quic,
+	/// TCP inside a real TLS session, looks like HTTPS
+	Tls,
+	/// WebSocket binary messages, passes HTTP proxies and CDNs
+	Ws,
+	/// WebSocket inside TLS
+	Wss,
+	/// a CONNECT stream per tunnel over one HTTP/2 connection inside TLS
+	H2,
+}
+
+// a connection to the server, whatever it rides on
+pub enum Stream {
+	Tcp(TcpStream),
+	Quic(SendStream, RecvStream),
+	Tls(Box<TlsStream<TcpStream>>),
+	Ws(Box<WsStream<Stream>>),
+	H2(Box<H2Stream>),
+}
+
+impl AsyncRead for Stream {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Stream::Tcp(s) => Pin::new(s).poll_read(cx, buf),
+			Stream::Quic(_, r) => Pin::new(r).poll_read(cx, buf),
+			Stream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+			Stream::Ws(s) => Pin::new(s).poll_read(cx, buf),
+			Stream::H2(s) => Pin::new(s).poll_read(cx, buf),
+		}
+	}
+}
+
+impl AsyncWrite for Stream {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		match self.get_mut() {
+			Stream::Tcp(s) => Pin::new(s).poll_write(cx, buf),
+			Stream::Quic(w, _) => AsyncWrite::poll_write(Pin::new(w), cx, buf),
+			Stream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+			Stream::Ws(s) => Pin::new(s).poll_write(cx, buf),
+			Stream::H2(s) => Pin::new(s).poll_write(cx, buf),
+		}
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Stream::Tcp(s) => Pin::new(s).poll_flush(cx),
+			Stream::Quic(w, _) => Pin::new(w).poll_flush(cx),
+			Stream::Tls(s) => Pin::new(s).poll_flush(cx),
+			Stream::Ws(s) => Pin::new(s).poll_flush(cx),
+			Stream::H2(s) => Pin::new(s).poll_flush(cx),
+		}
+	}
+
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		match self.get_mut() {
+			Stream::Tcp(s) => Pin::new(s).poll_shutdown(cx),
+			Stream::Quic(w, _) => Pin::new(w).poll_shutdown(cx),
+			Stream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+			Stream::Ws(s) => Pin::new(s).poll_shutdown(cx),
+			Stream::H2(s) => Pin::new(s).poll_shutdown(cx),
+		}
+	}
+}
+
+// how the client reaches the server
+pub enum Dialer {
+	// optionally inside TLS, optionally as a WebSocket at the given URL
+	Tcp {
+		upstream: Upstream,
+		tls: Option<(TlsConnector, ServerName<'static>)>,
+		ws: Option<String>,
+	},
+	Quic(quic::Dialer),
+	H2(http2::Dialer),
+}
+
+impl Dialer {
+	// sni defaults to the server name, IPs are never sent
+	pub fn new(
+		kind: Kind,
+		upstream: Upstream,
+		sni: Option<&str>,
+		ws_path: &str,
+	) -> anyhow::Result<Self> {
+		if kind == Kind::Quic {
+			return Ok(Dialer::Quic(quic::Dialer::new(upstream)?));
+		}
+		let tls = |alpn| -> anyhow::Result<_> {
+			let name = sni.unwrap_or(upstream.name());
+			let name = ServerName::try_from(name.to_owned())
+				.with_context(|| format!("invalid SNI {}", name))?;
+			let tls = TlsConnector::from(Arc::new(tls::client_config(&[alpn])));
+			Ok((tls, name))
+		};
+		if kind == Kind::H2 {
+			let (tls, name) = tls(ALPN_H2)?;
+			let authority = sni.unwrap_or(upstream.host()).to_owned();
+			return Ok(Dialer::H2(http2::Dialer::new(
+				upstream, tls, name, authority,
+			)));
+		}
+		let tls = match kind {
+			Kind::Tls | Kind::Wss => Some(tls(ALPN_HTTP1)?),
+			_ => None,
+		};
+		// the scheme doesn't matter here, it only builds the request
+		let ws = matches!(kind, Kind::Ws | Kind::Wss)
+			.then(|| format!("ws://{}{}", sni.unwrap_or(upstream.host()), ws_path));
+		Ok(Dialer::Tcp { upstream, tls, ws })
+	}
+
+	pub fn upstream(&self) -> &Upstream {
+		match self {
+			Dialer::Tcp { upstream, .. } => upstream,
+			Dialer::Quic(d) => d.upstream(),
+			Dialer::H2(d) => d.upstream(),
+		}
+	}
+
+	pub async fn connect(&self) -> Option<Stream> {
+		let (upstream, tls, ws) = match self {
+			Dialer::Tcp { upstream, tls, ws } => (upstream, tls, ws),
+			Dialer::Quic(d) => {
+				let (w, r) = d.open().await?;
+				return Some(Stream::Quic(w, r));
+			}
+			Dialer::H2(d) => return Some(Stream::H2(Box::new(d.open().await?))),
+		};
+		let s = upstream.connect().await?;
+		let _ = s.set_nodelay(true);
+		let s = match tls {
+			Some((tls, name)) => {
+				let s = tls
+					.connect(name.clone(), s)
+					.await
+					.map_err(|e| error!("TLS handshake with server failed: {}", e))
+					.ok()?;
+				Stream::Tls(Box::new(s))
+			}
+			None => Stream::Tcp(s),
+		};
+		match ws {
+			Some(url) => Some(Stream::Ws(Box::new(ws::connect(s, url).await?))),
+			None => Some(s),
+		}
+	}
+}
+
+// how the server takes clients
+pub enum Listen {
+	// optionally inside TLS, optionally as a WebSocket at the given path
+	Tcp {
+		tls: Option<Identity>,
+		ws: Option<String>,
+	},
+	Quic(Identity),
+	// TLS then HTTP/2, a tunnel per CONNECT
+	H2(Identity),
+}
@@ -0,0 +1,230 @@
+// SOCKS5 UDP ASSOCIATE over the tunnel, there's no UDP between client and server
+// the datagrams are a stream to everything in between, length prefixed, each one:
+// 1 byte host length, host, 2 bytes port, 2 bytes data length, data
+// the host is where it goes from the client, where it came from back to it
+
+use std::{
+	cell::Cell,
+	io,
+	net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+	rc::Rc,
+};
+
+use log::*;
+use socket2::{Domain, Socket, Type};
+use tokio::{
+	io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, split},
+	net::UdpSocket,
+};
+
+use crate::{addr::HostPort, dns::Resolver, policy::PortPolicy, socks5};
+
+// as much as a UDP payload gets
+const MAX_DATAGRAM: usize = 0xffff;
+
+// one datagram into the stream, in a single write
+pub async fn write<W: AsyncWrite + Unpin>(
+	w: &mut W,
+	host: &str,
+	port: u16,
+	data: &[u8],
+) -> io::Result<()> {
+	if host.len() > u8::MAX as usize || data.len() > MAX_DATAGRAM {
+		return Err(io::ErrorKind::InvalidInput.into());
+	}
+	let mut buf = Vec::with_capacity(1 + host.len() + 4 + data.len());
+	buf.push(host.len() as u8);
+	buf.extend_from_slice(host.as_bytes());
+	buf.extend_from_slice(&port.to_be_bytes());
+	buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+	buf.extend_from_slice(data);
+	w.write_all(&buf).await
+}
+
+// the next datagram from the stream, None if it ends cleanly before one
+pub async fn read<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Option<(String, u16, Vec<u8>)>> {
+	let len = match r.read_u8().await {
+		Ok(len) => len as usize,
+		Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+		Err(e) => return Err(e),
+	};
+	let mut host = vec![0; len];
+	r.read_exact(&mut host).await?;
+	let host = String::from_utf8(host).map_err(|_| io::ErrorKind::InvalidData)?;
+	let port = r.read_u16().await?;
+	let mut data = vec![0; r.read_u16().await? as usize];
+	r.read_exact(&mut data).await?;
+	Ok(Some((host, port, data)))
+}
+
+// both families on one socket where the OS allows it, IPv4 only otherwise
+fn bind() -> io::Result<UdpSocket> {
+	let dual = Socket::new(Domain::IPV6, Type::DGRAM, None).and_then(|s| {
+		s.set_only_v6(false)?;
+		s.bind(&SocketAddr::from((Ipv6Addr::UNSPECIFIED, 0)).into())?;
+		Ok(s)
+	});
+	let s = match dual {
+		Ok(s) => s,
+		Err(e) => {
+			debug!("no dual-stack UDP socket: {}, IPv4 only", e);
+			let s = Socket::new(Domain::IPV4, Type::DGRAM, None)?;
+			s.bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, 0)).into())?;
+			s
+		}
+	};
+	s.set_nonblocking(true)?;
+	UdpSocket::from_std(s.into())
+}
+
+// the server end, sends the datagrams that come through s on and their answers back,
+// until s ends, the port policy applies to each
+pub async fn relay<S: AsyncRead + AsyncWrite>(
+	s: S,
+	resolver: &Rc<dyn Resolver>,
+	policy: &PortPolicy,
+) {
+	let Ok(sock) = bind().map_err(|e| error!("error binding UDP socket: {}", e)) else {
+		return;
+	};
+	let v6 = sock.local_addr().is_ok_and(|a| a.is_ipv6());
+	let (mut r, mut w) = split(s);
+	let up = async {
+		loop {
+			let (host, port, data) = match read(&mut r).await {
+				Ok(Some(d)) => d,
+				Ok(None) => return,
+				Err(e) => return debug!("error reading datagram: {}", e),
+			};
+			if !policy.allows(port) {
+				debug!(
+					"datagram to {} denied by port policy",
+					HostPort(&host, port)
+				);
+				continue;
+			}
+			let Some(addrs) = resolver.resolve_port(&host, port).await else {
+				debug!("failed to resolve {}", host);
+				continue;
+			};
+			// a v6 socket takes IPv4 as mapped addresses
+			let to = addrs.iter().find_map(|a| match a.ip() {
+				IpAddr::V4(ip) if v6 => Some(SocketAddr::new(ip.to_ipv6_mapped().into(), a.port())),
+				IpAddr::V6(_) if !v6 => None,
+				_ => Some(*a),
+			});
+			let Some(to) = to else {
+				debug!("no IPv4 address for {}", host);
+				continue;
+			};
+			if let Err(e) = sock.send_to(&data, to).await {
+				debug!("error sending datagram to {}: {}", to, e);
+			}
+		}
+	};
+	let down = async {
+		let mut buf = vec![0; MAX_DATAGRAM];
+		loop {
+			let (n, from) = match sock.recv_from(&mut buf).await {
+				Ok(r) => r,
+				Err(e) => return debug!("error receiving datagram: {}", e),
+			};
+			let ip = from.ip().to_canonical().to_string();
+			if write(&mut w, &ip, from.port(), &buf[..n]).await.is_err() {
+				return;
+			}
+		}
+	};
+	tokio::select! {
+		_ = up => {}
+		_ = down => {}
+	}
+}
+
+// the client end, between the app's datagrams on sock and the tunnel s, until control ends
+// only the app's IP gets in, its port is learned from the first datagram
+pub async fn associate<S: AsyncRead + AsyncWrite, T: AsyncRead + Unpin>(
+	sock: UdpSocket,
+	app: IpAddr,
+	control: &mut T,
+	s: S,
+) {
+	let (mut r, mut w) = split(s);
+	let from_app = Cell::new(None);
+	let up = async {
+		let mut buf = vec![0; MAX_DATAGRAM];
+		loop {
+			let (n, from) = match sock.recv_from(&mut buf).await {
+				Ok(r) => r,
+				Err(e) => return debug!("error receiving datagram: {}", e),
+			};
+			if from.ip().to_canonical() != app.to_canonical() {
+				debug!("datagram from {}, not the app, dropped", from);
+				continue;
+			}
+			from_app.set(Some(from));
+			let Some((host, port, data)) = socks5::parse_udp(&buf[..n]) else {
+				debug!("invalid datagram from {}", from);
+				continue;
+			};
+			if write(&mut w, &host, port, data).await.is_err() {
+				return;
+			}
+		}
+	};
+	let down = async {
+		loop {
+			let (host, port, data) = match read(&mut r).await {
+				Ok(Some(d)) => d,
+				Ok(None) => return,
+				Err(e) => return debug!("error reading datagram: {}", e),
+			};
+			let Some(to) = from_app.get() else {
+				continue;
+			};
+			let mut buf = Vec::with_capacity(data.len() + 0x20);
+			let Some(()) = socks5::put_udp(&mut buf, &host, port, &data) else {
+				continue;
+			};
+			if let Err(e) = sock.send_to(&buf, to).await {
+				debug!("error sending datagram to {}: {}", to, e);
+			}
+		}
+	};
+	// the association lasts as long as the TCP connection it came with
+	let control = async {
+		let mut buf = [0u8; 0x40];
+		while let Ok(1..) = control.read(&mut buf).await {}
+	};
+	tokio::select! {
+		_ = up => {}
+		_ = down => {}
+		_ = control => {}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[tokio::test]
+	async fn test_stream() {
+		let mut buf = Vec::new();
+		write(&mut buf, "example.com", 53, b"query").await.unwrap();
+		write(&mut buf, "192.0.2.1", 443, b"").await.unwrap();
+		let mut r = &buf[..];
+		assert_eq!(
+			read(&mut r).await.unwrap(),
+			Some(("example.com".to_owned(), 53, b"query".to_vec()))
+		);
+		assert_eq!(
+			read(&mut r).await.unwrap(),
+			Some(("192.0.2.1".to_owned(), 443, Vec::new()))
+		);
+		assert_eq!(read(&mut r).await.unwrap(), None);
+		// cut short isn't a clean end
+		let mut one = Vec::new();
+		write(&mut one, "example.com", 53, b"query").await.unwrap();
+		assert!(read(&mut &one[..one.len() - 1]).await.is_err());
+	}
+}
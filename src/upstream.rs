@@ -0,0 +1,175 @@
+use std::{
+	cell::RefCell,
+	net::SocketAddr,
+	rc::Rc,
+	time::{Duration, Instant},
+};
+
+use log::*;
+use socket2::SockRef;
+use tokio::net::TcpStream;
+
+use crate::{addr, dns::Resolver, sock, via::Via};
+
+// the mint server, by name, with the lookup result cached for a while
+pub struct Upstream {
+	host: String,
+	ttl: Duration,
+	cache: RefCell<Option<(Instant, Rc<[SocketAddr]>)>>,
+	keepalive: Option<Duration>,
+	proxy: Option<Rc<Via>>,
+	// the system's through tokio if None
+	resolver: Option<Rc<dyn Resolver>>,
+}
+
+impl Upstream {
+	pub fn new(host: &str, ttl: Duration) -> Self {
+		Upstream {
+			host: host.to_owned(),
+			ttl,
+			cache: RefCell::new(None),
+			keepalive: None,
+			proxy: None,
+			resolver: None,
+		}
+	}
+
+	// connect through this proxy, it looks the server up then, not us
+	pub fn via(mut self, proxy: Option<Rc<Via>>) -> Self {
+		self.proxy = proxy;
+		self
+	}
+
+	// looked up with this instead
+	pub fn resolver(mut self, resolver: Rc<dyn Resolver>) -> Self {
+		self.resolver = Some(resolver);
+		self
+	}
+
+	// TCP keepalive on the connections to it
+	pub fn keepalive(mut self, idle: Option<Duration>) -> Self {
+		self.keepalive = idle;
+		self
+	}
+
+	pub async fn resolve(&self) -> Option<Rc<[SocketAddr]>> {
+		if let Some((t, addrs)) = &*self.cache.borrow()
+			&& t.elapsed() < self.ttl
+		{
+			return Some(addrs.clone());
+		}
+
+		let addrs: Rc<[SocketAddr]> = match &self.resolver {
+			Some(r) => {
+				let port = self.host.rsplit_once(':').and_then(|(_, p)| p.parse().ok());
+				let Some(addrs) = r.resolve_port(self.name(), port?).await else {
+					error!("failed to lookup {}", self.host);
+					return None;
+				};
+				addrs
+			}
+			None => addr::lookup(&self.host)
+				.await
+				.inspect_err(|e| error!("failed to lookup {}: {}", self.host, e))
+				.ok()?,
+		}
+		.into();
+		debug!(
+			"{} resolved to {}",
+			self.host,
+			addrs
+				.iter()
+				.map(SocketAddr::to_string)
+				.collect::<Vec<_>>()
+				.join(", ")
+		);
+		*self.cache.borrow_mut() = Some((Instant::now(), addrs.clone()));
+		Some(addrs)
+	}
+
+	// as given, host:port
+	pub fn host(&self) -> &str {
+		&self.host
+	}
+
+	// the host part, without port or brackets
+	pub fn name(&self) -> &str {
+		let host = self
+			.host
+			.rsplit_once(':')
+			.map_or(self.host.as_str(), |(h, _)| h);
+		host.trim_start_matches('[').trim_end_matches(']')
+	}
+
+	pub fn invalidate(&self) {
+		self.cache.borrow_mut().take();
+	}
+
+	// on failure, the cached addresses might be stale, try again with a fresh lookup
+	pub async fn connect(&self) -> Option<TcpStream> {
+		let s = match &self.proxy {
+			Some(proxy) => proxy.connect(&self.host).await?,
+			None => self.connect_any().await?,
+		};
+		if let Some(idle) = self.keepalive
+			&& let Err(e) = sock::set_keepalive(SockRef::from(&s), idle)
+		{
+			debug!("failed to set keepalive on {}: {}", self.host, e);
+		}
+		Some(s)
+	}
+
+	// straight to the host, never through a proxy, for reaching the proxy itself
+	pub async fn connect_any(&self) -> Option<TcpStream> {
+		let addrs = self.resolve().await?;
+		match TcpStream::connect(&addrs as &[SocketAddr]).await {
+			Ok(s) => return Some(s),
+			Err(e) => debug!(
+				"error connecting to {}: {}, will lookup again",
+				self.host, e
+			),
+		}
+		self.invalidate();
+		let addrs = self.resolve().await?;
+		TcpStream::connect(&addrs as &[SocketAddr])
+			.await
+			.map_err(|e| error!("error connecting to upstream: {}", e))
+			.ok()
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use tokio::net::TcpListener;
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_hostname() {
+		let l = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = l.local_addr().unwrap().port();
+
+		let upstream = Upstream::new(&format!("localhost:{}", port), Duration::from_secs(60));
+		let (c, s) = tokio::join!(upstream.connect(), l.accept());
+		assert!(c.is_some());
+		assert!(s.is_ok());
+
+		// served from cache within ttl
+		let a = upstream.resolve().await.unwrap();
+		let b = upstream.resolve().await.unwrap();
+		assert!(Rc::ptr_eq(&a, &b));
+
+		upstream.invalidate();
+		let c = upstream.resolve().await.unwrap();
+		assert!(!Rc::ptr_eq(&a, &c));
+	}
+
+	#[test]
+	fn test_name() {
+		assert_eq!(
+			Upstream::new("example.com:8080", Duration::ZERO).name(),
+			"example.com"
+		);
+		assert_eq!(Upstream::new("[::1]:8080", Duration::ZERO).name(), "::1");
+	}
+}
@@ -0,0 +1,213 @@
+// a proxy between the client and the server, HTTP or SOCKS5, CONNECT opens a tunnel
+// to the server and the rest goes through it as if it were the TCP connection itself
+
+use std::time::Duration;
+
+use anyhow::{Context, bail};
+use base64::prelude::{BASE64_STANDARD, Engine as _};
+use log::*;
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt},
+	net::TcpStream,
+	time::timeout,
+};
+
+use crate::{socks5, upstream::Upstream};
+
+const EOH: &[u8] = b"\r\n\r\n";
+// a response header longer than this isn't from a proxy worth talking to
+const MAX_HEADER: usize = 0x2000;
+const WAIT: Duration = Duration::from_secs(10);
+
+pub struct Via {
+	proxy: Upstream,
+	kind: Kind,
+}
+
+enum Kind {
+	Http {
+		// the Proxy-Authorization value, if credentials were given
+		auth: Option<String>,
+	},
+	// no auth
+	Socks5,
+}
+
+impl Via {
+	// http://[user:password@]host[:port], the port defaults to 80
+	pub fn http(url: &str, ttl: Duration) -> anyhow::Result<Self> {
+		let Some(rest) = url.strip_prefix("http://") else {
+			bail!("HTTP proxy {} should be an http:// URL", url);
+		};
+		let rest = rest.strip_suffix('/').unwrap_or(rest);
+		if rest.contains('/') {
+			bail!("HTTP proxy {} should have no path", url);
+		}
+		let (auth, host) = match rest.rsplit_once('@') {
+			Some((userinfo, host)) => {
+				if !userinfo.contains(':') {
+					bail!("HTTP proxy credentials should be user:password");
+				}
+				(
+					Some(format!("Basic {}", BASE64_STANDARD.encode(userinfo))),
+					host,
+				)
+			}
+			None => (None, rest),
+		};
+		if host.is_empty() {
+			bail!("HTTP proxy {} has no host", url);
+		}
+		// a port after the last colon, unless that's inside IPv6 brackets
+		let host = match host.rsplit_once(':') {
+			Some((_, port)) if !port.ends_with(']') => {
+				port.parse::<u16>()
+					.with_context(|| format!("invalid port in HTTP proxy {}", url))?;
+				host.to_owned()
+			}
+			_ => format!("{}:80", host),
+		};
+		Ok(Via {
+			proxy: Upstream::new(&host, ttl),
+			kind: Kind::Http { auth },
+		})
+	}
+
+	// host:port
+	pub fn socks5(addr: &str, ttl: Duration) -> anyhow::Result<Self> {
+		match addr.rsplit_once(':') {
+			Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+			_ => bail!("SOCKS5 proxy {} should be host:port", addr),
+		}
+		Ok(Via {
+			proxy: Upstream::new(addr, ttl),
+			kind: Kind::Socks5,
+		})
+	}
+
+	// a tunnel to target, host:port, the proxy looks the name up
+	pub async fn connect(&self, target: &str) -> Option<TcpStream> {
+		let mut s = self.proxy.connect_any().await?;
+		let _ = s.set_nodelay(true);
+		match &self.kind {
+			Kind::Http { auth } => self.http_connect(s, auth.as_deref(), target).await,
+			Kind::Socks5 => {
+				let (host, port) = target.rsplit_once(':')?;
+				let host = host.trim_start_matches('[').trim_end_matches(']');
+				let port = port.parse().ok()?;
+				let rep = match timeout(WAIT, socks5::client_connect(&mut s, host, port)).await {
+					Ok(Ok(rep)) => rep,
+					Ok(Err(e)) => {
+						error!("error talking to SOCKS5 proxy {}: {}", self.proxy.host(), e);
+						return None;
+					}
+					Err(_) => {
+						error!("no answer from SOCKS5 proxy {} in time", self.proxy.host());
+						return None;
+					}
+				};
+				if rep != socks5::REP_SUCCEEDED {
+					error!(
+						"SOCKS5 proxy {} refuses to connect to {}: reply {}",
+						self.proxy.host(),
+						target,
+						rep
+					);
+					return None;
+				}
+				debug!("SOCKS5 proxy {} tunnels to {}", self.proxy.host(), target);
+				Some(s)
+			}
+		}
+	}
+
+	async fn http_connect(
+		&self,
+		mut s: TcpStream,
+		auth: Option<&str>,
+		target: &str,
+	) -> Option<TcpStream> {
+		let mut req = format!("CONNECT {} HTTP/1.1\r\nHost: {}\r\n", target, target);
+		if let Some(auth) = auth {
+			req.push_str(&format!("Proxy-Authorization: {}\r\n", auth));
+		}
+		req.push_str("\r\n");
+		let resp = async {
+			s.write_all(req.as_bytes()).await?;
+			read_header(&mut s).await
+		};
+		let header = match timeout(WAIT, resp).await {
+			Ok(Ok(header)) => header,
+			Ok(Err(e)) => {
+				error!("error talking to HTTP proxy {}: {}", self.proxy.host(), e);
+				return None;
+			}
+			Err(_) => {
+				error!("no answer from HTTP proxy {} in time", self.proxy.host());
+				return None;
+			}
+		};
+		let status = header.lines().next().unwrap_or_default();
+		match status.split(' ').nth(1) {
+			Some(code) if code.starts_with('2') && status.starts_with("HTTP/1.") => {
+				debug!("HTTP proxy {} tunnels to {}", self.proxy.host(), target);
+				Some(s)
+			}
+			_ => {
+				error!(
+					"HTTP proxy {} refuses to connect to {}: {}",
+					self.proxy.host(),
+					target,
+					status
+				);
+				None
+			}
+		}
+	}
+}
+
+// byte by byte, whatever follows the header belongs to the tunnel
+async fn read_header(s: &mut TcpStream) -> std::io::Result<String> {
+	let mut header = Vec::new();
+	while !header.ends_with(EOH) {
+		if header.len() == MAX_HEADER {
+			return Err(std::io::ErrorKind::InvalidData.into());
+		}
+		header.push(s.read_u8().await?);
+	}
+	Ok(String::from_utf8_lossy(&header).into_owned())
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn test_new() {
+		let auth = |via: &Via| match &via.kind {
+			Kind::Http { auth } => auth.clone(),
+			Kind::Socks5 => panic!("not HTTP"),
+		};
+		let p = |url| Via::http(url, Duration::ZERO);
+		let proxy = p("http://proxy.example:3128").unwrap();
+		assert_eq!(proxy.proxy.host(), "proxy.example:3128");
+		assert_eq!(auth(&proxy), None);
+		let proxy = p("http://alice:p@ss:w0rd@[::1]/").unwrap();
+		assert_eq!(proxy.proxy.host(), "[::1]:80");
+		assert_eq!(
+			auth(&proxy),
+			Some(format!(
+				"Basic {}",
+				BASE64_STANDARD.encode("alice:p@ss:w0rd")
+			))
+		);
+		assert!(p("https://proxy.example:3128").is_err());
+		assert!(p("http://proxy.example:3128/path").is_err());
+		assert!(p("http://alice@proxy.example").is_err());
+		assert!(p("http://proxy.example:port").is_err());
+
+		assert!(Via::socks5("[::1]:1080", Duration::ZERO).is_ok());
+		assert!(Via::socks5("proxy.example", Duration::ZERO).is_err());
+		assert!(Via::socks5(":1080", Duration::ZERO).is_err());
+	}
+}
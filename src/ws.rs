@@ -0,0 +1,174 @@
+use std::{
+	io,
+	pin::Pin,
+	task::{Context, Poll, ready},
+};
+
+use bytes::{Buf, Bytes};
+use futures_util::{Sink, Stream};
+use log::*;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+	WebSocketStream, accept_hdr_async, client_async,
+	tungstenite::{
+		self, Message,
+		handshake::server::{ErrorResponse, Request, Response},
+		http::StatusCode,
+	},
+};
+
+// binary messages as a byte stream
+pub struct WsStream<S> {
+	inner: WebSocketStream<S>,
+	// what's left of the last message
+	rx: Bytes,
+}
+
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(s: S, url: &str) -> Option<WsStream<S>> {
+	let (inner, _) = client_async(url, s)
+		.await
+		.map_err(|e| error!("WebSocket upgrade to {} failed: {}", url, e))
+		.ok()?;
+	Some(WsStream {
+		inner,
+		rx: Bytes::new(),
+	})
+}
+
+// anything but an upgrade at path gets a 404
+pub async fn accept<S: AsyncRead + AsyncWrite + Unpin>(s: S, path: &str) -> Option<WsStream<S>> {
+	// the callback signature is tungstenite's
+	#[allow(clippy::result_large_err)]
+	let check = |req: &Request, resp: Response| {
+		if req.uri().path() == path {
+			return Ok(resp);
+		}
+		debug!("WebSocket upgrade at unexpected path {}", req.uri().path());
+		let mut err = ErrorResponse::new(None);
+		*err.status_mut() = StatusCode::NOT_FOUND;
+		Err(err)
+	};
+	let inner = accept_hdr_async(s, check)
+		.await
+		.map_err(|e| debug!("WebSocket accept failed: {}", e))
+		.ok()?;
+	Some(WsStream {
+		inner,
+		rx: Bytes::new(),
+	})
+}
+
+fn to_io(e: tungstenite::Error) -> io::Error {
+	match e {
+		tungstenite::Error::Io(e) => e,
+		e => io::Error::other(e),
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+	fn poll_read(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let this = self.get_mut();
+		while this.rx.is_empty() {
+			match ready!(Pin::new(&mut this.inner).poll_next(cx)) {
+				Some(Ok(Message::Binary(b))) => this.rx = b,
+				// pings are answered by tungstenite on the next write or flush
+				Some(Ok(Message::Ping(_) | Message::Pong(_) | Message::Frame(_))) => {}
+				Some(Ok(Message::Text(_))) => {
+					return Poll::Ready(Err(io::Error::new(
+						io::ErrorKind::InvalidData,
+						"unexpected text message",
+					)));
+				}
+				Some(Ok(Message::Close(_))) | None => return Poll::Ready(Ok(())),
+				Some(Err(e)) => return Poll::Ready(Err(to_io(e))),
+			}
+		}
+		let n = this.rx.len().min(buf.remaining());
+		buf.put_slice(&this.rx[..n]);
+		this.rx.advance(n);
+		Poll::Ready(Ok(()))
+	}
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+	fn poll_write(
+		self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &[u8],
+	) -> Poll<io::Result<usize>> {
+		let mut inner = Pin::new(&mut self.get_mut().inner);
+		ready!(inner.as_mut().poll_ready(cx)).map_err(to_io)?;
+		inner
+			.as_mut()
+			.start_send(Message::Binary(Bytes::copy_from_slice(buf)))
+			.map_err(to_io)?;
+		// start_send only queues it, and proto never flushes
+		if let Poll::Ready(Err(e)) = inner.poll_flush(cx) {
+			return Poll::Ready(Err(to_io(e)));
+		}
+		Poll::Ready(Ok(buf.len()))
+	}
+
+	fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner)
+			.poll_flush(cx)
+			.map_err(to_io)
+	}
+
+	// sends a close frame, the peer reads it as EOF
+	fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+		Pin::new(&mut self.get_mut().inner)
+			.poll_close(cx)
+			.map_err(to_io)
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+	use super::*;
+
+	fn init() {
+		let _ = env_logger::builder().is_test(true).try_init();
+	}
+
+	#[tokio::test]
+	async fn test_ws() {
+		init();
+
+		let (c, s) = tokio::io::duplex(0x1000);
+		let (c, s) = tokio::join!(connect(c, "ws://example.com/chat"), accept(s, "/chat"));
+		let (mut c, mut s) = (c.unwrap(), s.unwrap());
+
+		let data: Vec<u8> = (0..0x3000).map(|i| i as u8).collect();
+		let (_, got) = tokio::join!(
+			async {
+				for chunk in data.chunks(0x700) {
+					c.write_all(chunk).await.unwrap();
+				}
+				c.shutdown().await.unwrap();
+			},
+			async {
+				let mut got = Vec::new();
+				s.read_to_end(&mut got).await.unwrap();
+				got
+			}
+		);
+		assert_eq!(got, data);
+	}
+
+	#[tokio::test]
+	async fn test_path() {
+		init();
+
+		let (c, s) = tokio::io::duplex(0x1000);
+		let (c, s) = tokio::join!(connect(c, "ws://example.com/other"), accept(s, "/chat"));
+		assert!(c.is_none());
+		assert!(s.is_none());
+	}
+}
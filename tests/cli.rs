@@ -0,0 +1,589 @@
+use std::{
+	io::{Read, Write},
+	net::{TcpListener, TcpStream},
+	process::{Child, Command, Stdio},
+	thread::{self, sleep},
+	time::{Duration, Instant},
+};
+
+const BIN: &str = env!("CARGO_BIN_EXE_mint");
+
+#[test]
+fn test_missing_psk() {
+	for cmd in ["server", "client"] {
+		let out = Command::new(BIN)
+			.args([cmd, "-k", "/nonexistent/psk", "-l", "127.0.0.1:0"])
+			.output()
+			.unwrap();
+		assert_eq!(out.status.code(), Some(1), "{}", cmd);
+	}
+}
+
+#[test]
+fn test_bind_conflict() {
+	let psk = gen_psk();
+	let taken = TcpListener::bind("127.0.0.1:0").unwrap();
+	let addr = taken.local_addr().unwrap().to_string();
+	let out = Command::new(BIN)
+		.args(["server", "-k", &psk, "-l", &addr])
+		.output()
+		.unwrap();
+	assert_eq!(out.status.code(), Some(1));
+	let err = String::from_utf8(out.stderr).unwrap();
+	assert!(
+		err.contains(&format!("failed to bind {}: ", addr)),
+		"{}",
+		err
+	);
+
+	let _ = std::fs::remove_file(psk);
+}
+
+#[test]
+fn test_check() {
+	let check = |psk: &str| {
+		Command::new(BIN)
+			.args(["check", "-k", psk])
+			.output()
+			.unwrap()
+	};
+
+	let psk = gen_psk();
+	let out = check(&psk);
+	assert!(out.status.success());
+	assert!(String::from_utf8(out.stdout).unwrap().ends_with(": ok\n"));
+
+	std::fs::write(&psk, "not base64!\n").unwrap();
+	let out = check(&psk);
+	assert_eq!(out.status.code(), Some(1));
+	let err = String::from_utf8(out.stderr).unwrap();
+	assert!(err.contains("failed to decode base64"), "{}", err);
+
+	// 16 bytes
+	std::fs::write(&psk, "AAAAAAAAAAAAAAAAAAAAAA\n").unwrap();
+	let out = check(&psk);
+	assert_eq!(out.status.code(), Some(1));
+	let err = String::from_utf8(out.stderr).unwrap();
+	assert!(err.contains("16 byte key, expecting 32"), "{}", err);
+
+	let _ = std::fs::remove_file(psk);
+}
+
+#[test]
+fn test_bench() {
+	let out = Command::new(BIN)
+		.args(["bench", "-d", "200"])
+		.output()
+		.unwrap();
+	assert!(out.status.success());
+	let out = String::from_utf8(out.stdout).unwrap();
+	assert!(out.contains("handshakes/s"), "{}", out);
+	assert!(out.contains("MiB/s"), "{}", out);
+}
+
+// kills the child when the test ends, pass or fail
+struct Kill(Child);
+
+impl Drop for Kill {
+	fn drop(&mut self) {
+		let _ = self.0.kill();
+		let _ = self.0.wait();
+	}
+}
+
+fn free_port() -> u16 {
+	TcpListener::bind("127.0.0.1:0")
+		.unwrap()
+		.local_addr()
+		.unwrap()
+		.port()
+}
+
+fn spawn(args: &[&str]) -> Kill {
+	Kill(
+		Command::new(BIN)
+			.args(args)
+			.stdout(Stdio::null())
+			.stderr(Stdio::null())
+			.spawn()
+			.unwrap(),
+	)
+}
+
+fn wait_listening(addr: &str) {
+	for _ in 0..100 {
+		if TcpStream::connect(addr).is_ok() {
+			return;
+		}
+		sleep(Duration::from_millis(50));
+	}
+	panic!("{} never came up", addr);
+}
+
+// SOCKS5 CONNECT through the client to 127.0.0.1:port, None if the reply isn't success
+fn socks_connect(client: &str, port: u16) -> Option<TcpStream> {
+	let mut s = TcpStream::connect(client).unwrap();
+	s.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	let mut buf = [0u8; 10];
+
+	// no auth
+	s.write_all(&[5, 1, 0]).unwrap();
+	s.read_exact(&mut buf[..2]).unwrap();
+	assert_eq!(&buf[..2], &[5, 0]);
+
+	s.write_all(&[5, 1, 0, 1, 127, 0, 0, 1]).unwrap();
+	s.write_all(&port.to_be_bytes()).unwrap();
+	s.read_exact(&mut buf).unwrap();
+	(buf[..2] == [5, 0]).then_some(s)
+}
+
+// a fresh PSK in a temp file, the caller removes it
+fn gen_psk() -> String {
+	let psk = std::env::temp_dir().join(format!(
+		"mint-e2e-{}-{}.psk",
+		std::process::id(),
+		free_port()
+	));
+	let out = Command::new(BIN).arg("gen-psk").output().unwrap();
+	assert!(out.status.success());
+	std::fs::write(&psk, out.stdout).unwrap();
+	psk.to_str().unwrap().to_owned()
+}
+
+// returns the port
+fn echo() -> u16 {
+	let echo = TcpListener::bind("127.0.0.1:0").unwrap();
+	let port = echo.local_addr().unwrap().port();
+	thread::spawn(move || {
+		for s in echo.incoming() {
+			let mut s = s.unwrap();
+			thread::spawn(move || {
+				let mut r = s.try_clone().unwrap();
+				let _ = std::io::copy(&mut r, &mut s);
+			});
+		}
+	});
+	port
+}
+
+// client and server binaries relaying to a local echo server
+fn e2e(server_args: &[&str], client_args: &[&str]) {
+	let conf = concat!(env!("CARGO_MANIFEST_DIR"), "/conf");
+	let psk = gen_psk();
+	let psk = psk.as_str();
+
+	let echo_port = echo();
+
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	let fake_resp = format!("{}/fake-resp.txt", conf);
+	let fake_req = format!("{}/fake-req.txt", conf);
+	let mut args = vec!["server", "-k", psk, "-l", &server, "-f", &fake_resp];
+	args.extend(server_args);
+	let _s = spawn(&args);
+	let mut args = vec![
+		"client", "-k", psk, "-l", &client, "-s", &server, "-f", &fake_req,
+	];
+	args.extend(client_args);
+	let _c = spawn(&args);
+	wait_listening(&client);
+
+	// the server may still be starting
+	let mut s = None;
+	for _ in 0..50 {
+		s = socks_connect(&client, echo_port);
+		if s.is_some() {
+			break;
+		}
+		sleep(Duration::from_millis(100));
+	}
+	let mut s = s.expect("never got a tunnel");
+
+	// past the framed phase and into the plain copy
+	for i in 0..8u8 {
+		let msg = vec![i; 0x1000];
+		s.write_all(&msg).unwrap();
+		let mut echoed = vec![0; msg.len()];
+		s.read_exact(&mut echoed).unwrap();
+		assert_eq!(msg, echoed);
+	}
+
+	let _ = std::fs::remove_file(psk);
+}
+
+#[test]
+fn test_e2e() {
+	e2e(&[], &[]);
+}
+
+#[test]
+fn test_e2e_quic() {
+	e2e(&["--transport", "quic"], &["--transport", "quic"]);
+}
+
+#[test]
+fn test_e2e_tls() {
+	e2e(
+		&["--transport", "tls"],
+		&["--transport", "tls", "--sni", "www.example.com"],
+	);
+}
+
+#[test]
+fn test_e2e_ws() {
+	e2e(
+		&["--transport", "ws", "--ws-path", "/chat"],
+		&["--transport", "ws", "--ws-path", "/chat"],
+	);
+	e2e(
+		&["--transport", "wss", "--ws-path", "/chat"],
+		&["--transport", "wss", "--ws-path", "/chat"],
+	);
+}
+
+#[test]
+fn test_e2e_h2() {
+	e2e(&["--transport", "h2"], &["--transport", "h2"]);
+}
+
+#[test]
+fn test_probe() {
+	let psk = gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let _s = spawn(&["server", "-k", &psk, "-l", &server]);
+	wait_listening(&server);
+
+	let mut s = TcpStream::connect(&server).unwrap();
+	s.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	s.write_all(b"\x16\x03\x01\x02\x00garbage").unwrap();
+	let mut resp = Vec::new();
+	s.read_to_end(&mut resp).unwrap();
+	let resp = String::from_utf8(resp).unwrap();
+	assert!(resp.starts_with("HTTP/1.1 400 Bad Request\r\n"), "{}", resp);
+
+	let _ = std::fs::remove_file(psk);
+}
+
+#[test]
+fn test_socks5_hint() {
+	let psk = gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let mut s = Kill(
+		Command::new(BIN)
+			.args(["server", "-k", &psk, "-l", &server])
+			.env("RUST_LOG", "warn")
+			.stdout(Stdio::null())
+			.stderr(Stdio::piped())
+			.spawn()
+			.unwrap(),
+	);
+	wait_listening(&server);
+
+	// an app pointed at the server instead of the client
+	let mut c = TcpStream::connect(&server).unwrap();
+	c.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	c.write_all(&[5, 1, 0]).unwrap();
+	let _ = c.read_to_end(&mut Vec::new());
+	let _ = s.0.kill();
+	let mut err = String::new();
+	s.0.stderr.take().unwrap().read_to_string(&mut err).unwrap();
+	assert!(err.contains("looks like a SOCKS5 client"), "{}", err);
+
+	let _ = std::fs::remove_file(psk);
+}
+
+#[test]
+fn test_reset_quiet() {
+	let psk = gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	let quiet = |args: &[&str]| {
+		Kill(
+			Command::new(BIN)
+				.args(args)
+				.env("RUST_LOG", "error")
+				.stdout(Stdio::null())
+				.stderr(Stdio::piped())
+				.spawn()
+				.unwrap(),
+		)
+	};
+	let mut s = quiet(&["server", "-k", &psk, "-l", &server]);
+	let mut c = quiet(&["client", "-k", &psk, "-l", &client, "-s", &server]);
+	wait_listening(&server);
+	wait_listening(&client);
+	let reset = |s: TcpStream| {
+		socket2::SockRef::from(&s)
+			.set_linger(Some(Duration::ZERO))
+			.unwrap();
+	};
+
+	// gone halfway through a handshake
+	let mut h = TcpStream::connect(&server).unwrap();
+	h.write_all(b"POST /upload HTTP/1.1\r\nHost: ").unwrap();
+	sleep(Duration::from_millis(100));
+	reset(h);
+
+	// and in the middle of a relay, the echo has nowhere to go
+	let port = echo();
+	let mut r = socks_connect(&client, port).unwrap();
+	r.write_all(b"hello").unwrap();
+	reset(r);
+	sleep(Duration::from_millis(300));
+
+	for p in [&mut s, &mut c] {
+		let _ = p.0.kill();
+		let mut err = String::new();
+		p.0.stderr.take().unwrap().read_to_string(&mut err).unwrap();
+		assert!(!err.contains("ERROR"), "{}", err);
+	}
+
+	let _ = std::fs::remove_file(psk);
+}
+
+#[test]
+fn test_ping() {
+	let psk = gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let _s = spawn(&["server", "-k", &psk, "-l", &server]);
+	wait_listening(&server);
+
+	let out = Command::new(BIN)
+		.args(["ping", "-k", &psk, "-s", &server, "-c", "2"])
+		.output()
+		.unwrap();
+	assert!(out.status.success(), "{:?}", out);
+	let out = String::from_utf8(out.stdout).unwrap();
+	assert_eq!(out.lines().count(), 2, "{}", out);
+	for line in out.lines() {
+		let rtt = line
+			.strip_prefix(&format!("{}: ", server))
+			.and_then(|l| l.strip_suffix(" ms"))
+			.unwrap_or_else(|| panic!("{}", out));
+		assert!(rtt.parse::<f64>().unwrap() > 0.0, "{}", out);
+	}
+
+	// another PSK gets a decoy answer, not a pong
+	let other = gen_psk();
+	let out = Command::new(BIN)
+		.args(["probe", "-k", &other, "-s", &server, "-c", "1"])
+		.output()
+		.unwrap();
+	assert!(!out.status.success());
+	let out = String::from_utf8(out.stdout).unwrap();
+	assert!(out.contains("doesn't decrypt, check the PSK"), "{}", out);
+
+	// nobody there
+	let closed = format!("127.0.0.1:{}", free_port());
+	let out = Command::new(BIN)
+		.args(["probe", "-k", &psk, "-s", &closed, "-c", "1"])
+		.output()
+		.unwrap();
+	assert!(!out.status.success());
+	let out = String::from_utf8(out.stdout).unwrap();
+	assert_eq!(out, format!("{}: connection failed\n", closed));
+
+	let _ = std::fs::remove_file(psk);
+	let _ = std::fs::remove_file(other);
+}
+
+#[test]
+fn test_resolve() {
+	let psk = gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let _s = spawn(&["server", "-k", &psk, "-l", &server, "--transport", "ws"]);
+	wait_listening(&server);
+
+	// through the transport, the server only speaks WebSocket
+	let out = Command::new(BIN)
+		.args(["resolve", "-k", &psk, "-s", &server])
+		.args(["--transport", "ws", "localhost"])
+		.output()
+		.unwrap();
+	assert!(out.status.success(), "{:?}", out);
+	let out = String::from_utf8(out.stdout).unwrap();
+	assert!(out.lines().any(|l| l == "127.0.0.1"), "{}", out);
+
+	let _ = std::fs::remove_file(psk);
+}
+
+#[test]
+fn test_resolve_client_key() {
+	let psk = gen_psk();
+	let out = Command::new(BIN).arg("gen-key-pair").output().unwrap();
+	assert!(out.status.success());
+	let tmp =
+		|ext| std::env::temp_dir().join(format!("mint-resolve-{}.{}", std::process::id(), ext));
+	let (key, keys) = (tmp("key"), tmp("keys"));
+	std::fs::write(&key, &out.stdout).unwrap();
+	let public = String::from_utf8(out.stderr).unwrap();
+	let public = public.trim().strip_prefix("public key: ").unwrap();
+	std::fs::write(&keys, format!("alice {}\n", public)).unwrap();
+	let (key, keys) = (key.to_str().unwrap(), keys.to_str().unwrap());
+
+	let server = format!("127.0.0.1:{}", free_port());
+	let _s = spawn(&["server", "-k", &psk, "-l", &server, "--client-keys", keys]);
+	wait_listening(&server);
+
+	let resolve = |args: &[&str]| {
+		Command::new(BIN)
+			.args(["resolve", "-k", &psk, "-s", &server])
+			.args(args)
+			.arg("localhost")
+			.output()
+			.unwrap()
+	};
+	// told apart from a name that doesn't resolve
+	let out = resolve(&[]);
+	assert!(!out.status.success());
+	let err = String::from_utf8(out.stderr).unwrap();
+	assert!(err.contains("server denies resolving localhost"), "{}", err);
+
+	let out = resolve(&["--client-key", key]);
+	assert!(out.status.success(), "{:?}", out);
+	let out = String::from_utf8(out.stdout).unwrap();
+	assert!(out.lines().any(|l| l == "127.0.0.1"), "{}", out);
+
+	let out = Command::new(BIN)
+		.args(["ping", "-k", &psk, "-s", &server, "-c", "1"])
+		.args(["--client-key", key])
+		.output()
+		.unwrap();
+	assert!(out.status.success(), "{:?}", out);
+
+	for f in [&psk, key, keys] {
+		let _ = std::fs::remove_file(f);
+	}
+}
+
+#[test]
+fn test_decoy() {
+	let psk = gen_psk();
+	let site = TcpListener::bind("127.0.0.1:0").unwrap();
+	let decoy = format!("http://{}", site.local_addr().unwrap());
+	thread::spawn(move || {
+		let (mut s, _) = site.accept().unwrap();
+		let mut buf = [0; 0x400];
+		let n = s.read(&mut buf).unwrap();
+		assert!(buf[..n].starts_with(b"GET /index.html "));
+		s.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\ndecoy")
+			.unwrap();
+	});
+
+	let server = format!("127.0.0.1:{}", free_port());
+	let _s = spawn(&[
+		"server",
+		"-k",
+		&psk,
+		"-l",
+		&server,
+		"--on-probe",
+		"proxy",
+		"--decoy",
+		&decoy,
+		"--probe-delay",
+		"200-300",
+	]);
+	wait_listening(&server);
+
+	let mut s = TcpStream::connect(&server).unwrap();
+	s.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	let t = Instant::now();
+	s.write_all(b"GET /index.html HTTP/1.1\r\nHost: example.com\r\n\r\n")
+		.unwrap();
+	let mut resp = Vec::new();
+	s.read_to_end(&mut resp).unwrap();
+	assert!(t.elapsed() >= Duration::from_millis(200));
+	let resp = String::from_utf8(resp).unwrap();
+	assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+	assert!(resp.ends_with("decoy"), "{}", resp);
+
+	let _ = std::fs::remove_file(psk);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_drain() {
+	let psk = gen_psk();
+	let psk = psk.as_str();
+	let echo_port = echo();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	let mut s = spawn(&["server", "-k", psk, "-l", &server, "--drain-secs", "10"]);
+	let _c = spawn(&["client", "-k", psk, "-l", &client, "-s", &server]);
+	wait_listening(&server);
+	wait_listening(&client);
+
+	let mut t = socks_connect(&client, echo_port).expect("never got a tunnel");
+	let echoed = |t: &mut TcpStream, i: u8| {
+		let msg = vec![i; 0x1000];
+		t.write_all(&msg).unwrap();
+		let mut echoed = vec![0; msg.len()];
+		t.read_exact(&mut echoed).unwrap();
+		assert_eq!(msg, echoed);
+	};
+	echoed(&mut t, 0);
+
+	let pid = s.0.id().to_string();
+	assert!(
+		Command::new("kill")
+			.args(["-TERM", &pid])
+			.status()
+			.unwrap()
+			.success()
+	);
+	sleep(Duration::from_millis(200));
+	assert!(TcpStream::connect(&server).is_err());
+	// still relaying the one in flight
+	for i in 1..8 {
+		echoed(&mut t, i);
+	}
+	assert!(s.0.try_wait().unwrap().is_none());
+
+	// and exits once it's done
+	drop(t);
+	for _ in 0..50 {
+		if let Some(status) = s.0.try_wait().unwrap() {
+			assert!(status.success());
+			let _ = std::fs::remove_file(psk);
+			return;
+		}
+		sleep(Duration::from_millis(100));
+	}
+	panic!("server never exited");
+}
+
+#[cfg(unix)]
+#[test]
+fn test_sigusr1() {
+	let psk = gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let mut s = Kill(
+		Command::new(BIN)
+			.args(["server", "-k", &psk, "-l", &server])
+			.env("RUST_LOG", "info")
+			.stdout(Stdio::null())
+			.stderr(Stdio::piped())
+			.spawn()
+			.unwrap(),
+	);
+	wait_listening(&server);
+
+	let pid = s.0.id().to_string();
+	assert!(
+		Command::new("kill")
+			.args(["-USR1", &pid])
+			.status()
+			.unwrap()
+			.success()
+	);
+	sleep(Duration::from_millis(200));
+	let _ = s.0.kill();
+	let mut err = String::new();
+	s.0.stderr.take().unwrap().read_to_string(&mut err).unwrap();
+	for field in ["active", "total", "bytes up", "bytes down", "handshakes:"] {
+		assert!(err.contains(field), "{}", err);
+	}
+
+	let _ = std::fs::remove_file(psk);
+}
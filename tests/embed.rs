@@ -0,0 +1,945 @@
+use std::{
+	cell::RefCell,
+	net::{IpAddr, TcpListener},
+	rc::Rc,
+	time::Duration,
+};
+
+use tokio::{
+	io::{AsyncReadExt, AsyncWriteExt, copy, copy_bidirectional, duplex, split},
+	net::{TcpStream, UdpSocket},
+	time::sleep,
+};
+
+#[tokio::test]
+async fn test_on_connect() {
+	let psk = mint::gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	let seen = Rc::new(RefCell::new(Vec::new()));
+	let s = {
+		let seen = seen.clone();
+		mint::ServerConfig::new(&psk)
+			.listen(&server)
+			.stats_interval(Duration::ZERO)
+			.on_connect(move |_, host, port| {
+				seen.borrow_mut().push((host, port));
+				async move { port != 25 }
+			})
+			.build()
+			.unwrap()
+	};
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server(&server)
+		.build()
+		.unwrap();
+
+	let test = async {
+		let (_, rep) = socks_request(&client, "example.com", 25).await;
+		// connection not allowed by ruleset
+		assert_eq!(rep, 2);
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = test => {}
+	}
+	assert_eq!(*seen.borrow(), [("example.com".to_owned(), 25)]);
+}
+
+#[tokio::test]
+async fn test_connector() {
+	let psk = mint::gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	let seen = Rc::new(RefCell::new(Vec::new()));
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&server)
+		.stats_interval(Duration::ZERO)
+		.connector(Echo(seen.clone()))
+		.build()
+		.unwrap();
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server(&server)
+		.build()
+		.unwrap();
+
+	let test = async {
+		let (mut s, rep) = socks_request(&client, "upstream.invalid", 80).await;
+		assert_eq!(rep, 0);
+		s.write_all(b"hello").await.unwrap();
+		let mut buf = [0u8; 5];
+		s.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"hello");
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = test => {}
+	}
+	assert_eq!(*seen.borrow(), [("upstream.invalid".to_owned(), 80)]);
+}
+
+#[tokio::test]
+async fn test_server_retries() {
+	let psk = mint::gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	// in front of the server, drops the first connection before the handshake is done
+	let front = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let front_addr = front.local_addr().unwrap().to_string();
+	let accepted = Rc::new(RefCell::new(0));
+	let relay = {
+		let server = server.clone();
+		let accepted = accepted.clone();
+		async move {
+			while let Ok((mut s, _)) = front.accept().await {
+				*accepted.borrow_mut() += 1;
+				if *accepted.borrow() == 1 {
+					continue;
+				}
+				let mut u = TcpStream::connect(&server).await.unwrap();
+				tokio::spawn(async move {
+					let _ = copy_bidirectional(&mut s, &mut u).await;
+				});
+			}
+		}
+	};
+	let seen = Rc::new(RefCell::new(Vec::new()));
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&server)
+		.stats_interval(Duration::ZERO)
+		.connector(Echo(seen.clone()))
+		.build()
+		.unwrap();
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server(&front_addr)
+		.server_retries(2)
+		.build()
+		.unwrap();
+
+	let test = async {
+		let (mut s, rep) = socks_request(&client, "upstream.invalid", 80).await;
+		assert_eq!(rep, 0);
+		s.write_all(b"hello").await.unwrap();
+		let mut buf = [0u8; 5];
+		s.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"hello");
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = relay => panic!("relay quit"),
+		_ = test => {}
+	}
+	assert_eq!(*accepted.borrow(), 2);
+	assert_eq!(*seen.borrow(), [("upstream.invalid".to_owned(), 80)]);
+}
+
+#[tokio::test]
+async fn test_server_failover() {
+	let psk = mint::gen_psk();
+	// nothing listens there
+	let dead = format!("127.0.0.1:{}", free_port());
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	let seen = Rc::new(RefCell::new(Vec::new()));
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&server)
+		.stats_interval(Duration::ZERO)
+		.connector(Echo(seen.clone()))
+		.build()
+		.unwrap();
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server(&format!("{},{}", dead, server))
+		.server_strategy(mint::ServerStrategy::Failover)
+		.build()
+		.unwrap();
+
+	let test = async {
+		for port in [80, 81] {
+			let (mut s, rep) = socks_request(&client, "upstream.invalid", port).await;
+			assert_eq!(rep, 0);
+			s.write_all(b"hello").await.unwrap();
+			let mut buf = [0u8; 5];
+			s.read_exact(&mut buf).await.unwrap();
+			assert_eq!(&buf, b"hello");
+		}
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = test => {}
+	}
+	assert_eq!(
+		*seen.borrow(),
+		[
+			("upstream.invalid".to_owned(), 80),
+			("upstream.invalid".to_owned(), 81)
+		]
+	);
+}
+
+#[tokio::test]
+async fn test_server_fastest() {
+	let psk = mint::gen_psk();
+	let slow = format!("127.0.0.1:{}", free_port());
+	let fast = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	// in front of the slow one, sits on every connection for a while
+	let front = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let front_addr = front.local_addr().unwrap().to_string();
+	let relay = {
+		let slow = slow.clone();
+		async move {
+			while let Ok((mut s, _)) = front.accept().await {
+				let slow = slow.clone();
+				tokio::spawn(async move {
+					sleep(Duration::from_millis(200)).await;
+					let mut u = TcpStream::connect(&slow).await.unwrap();
+					let _ = copy_bidirectional(&mut s, &mut u).await;
+				});
+			}
+		}
+	};
+	let server = |listen: &str, seen: &Rc<RefCell<Vec<(String, u16)>>>| {
+		mint::ServerConfig::new(&psk)
+			.listen(listen)
+			.stats_interval(Duration::ZERO)
+			.connector(Echo(seen.clone()))
+			.build()
+			.unwrap()
+	};
+	let (slow_seen, fast_seen) = Default::default();
+	let (s1, s2) = (server(&slow, &slow_seen), server(&fast, &fast_seen));
+	// the slow one first, where failover would go
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server(&format!("{},{}", front_addr, fast))
+		.server_strategy(mint::ServerStrategy::Fastest)
+		.health_interval(Duration::from_secs(3600))
+		.build()
+		.unwrap();
+
+	let test = async {
+		// both pinged by then
+		sleep(Duration::from_millis(600)).await;
+		for port in [80, 81] {
+			let (mut s, rep) = socks_request(&client, "upstream.invalid", port).await;
+			assert_eq!(rep, 0);
+			s.write_all(b"hello").await.unwrap();
+			let mut buf = [0u8; 5];
+			s.read_exact(&mut buf).await.unwrap();
+		}
+	};
+	tokio::select! {
+		r = mint::run_server(s1) => panic!("server quit: {:?}", r),
+		r = mint::run_server(s2) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = relay => panic!("relay quit"),
+		_ = test => {}
+	}
+	assert!(slow_seen.borrow().is_empty());
+	assert_eq!(fast_seen.borrow().len(), 2);
+}
+
+#[tokio::test]
+async fn test_server_srv() {
+	let psk = mint::gen_psk();
+	let (backup, primary) = (free_port(), free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	let server = |port: u16, seen: &Rc<RefCell<Vec<(String, u16)>>>| {
+		mint::ServerConfig::new(&psk)
+			.listen(&format!("127.0.0.1:{}", port))
+			.stats_interval(Duration::ZERO)
+			.connector(Echo(seen.clone()))
+			.build()
+			.unwrap()
+	};
+	let (backup_seen, primary_seen) = Default::default();
+	let (s1, s2) = (server(backup, &backup_seen), server(primary, &primary_seen));
+	let record = |priority, port| mint::Srv {
+		priority,
+		weight: 10,
+		port,
+		target: "127.0.0.1".to_owned(),
+	};
+	// the backup comes first in the answer, the lower priority wins anyway
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server_srv("_mint._tcp.test")
+		.resolver(Srv(vec![record(20, backup), record(10, primary)]))
+		.build()
+		.unwrap();
+
+	let test = async {
+		for port in [80, 81] {
+			let (mut s, rep) = socks_request(&client, "upstream.invalid", port).await;
+			assert_eq!(rep, 0);
+			s.write_all(b"hello").await.unwrap();
+			let mut buf = [0u8; 5];
+			s.read_exact(&mut buf).await.unwrap();
+		}
+	};
+	tokio::select! {
+		r = mint::run_server(s1) => panic!("server quit: {:?}", r),
+		r = mint::run_server(s2) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = test => {}
+	}
+	assert!(backup_seen.borrow().is_empty());
+	assert_eq!(primary_seen.borrow().len(), 2);
+}
+
+#[tokio::test]
+async fn test_send_proxy_protocol() {
+	let psk = mint::gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	let got = Rc::new(RefCell::new(Vec::new()));
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&server)
+		.stats_interval(Duration::ZERO)
+		.connector(Record(got.clone()))
+		.send_proxy_protocol(true)
+		.build()
+		.unwrap();
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server(&server)
+		.build()
+		.unwrap();
+
+	// v2 signature, PROXY over TCP4, 12 bytes of addresses
+	let header_len = 16 + 12;
+	let test = async {
+		let (mut s, rep) = socks_request(&client, "upstream.invalid", 80).await;
+		assert_eq!(rep, 0);
+		s.write_all(b"hello").await.unwrap();
+		while got.borrow().len() < header_len + 5 {
+			sleep(Duration::from_millis(20)).await;
+		}
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = test => {}
+	}
+	let got = got.borrow();
+	let (header, data) = got.split_at(header_len);
+	assert_eq!(&header[..12], b"\r\n\r\n\0\r\nQUIT\n");
+	assert_eq!(&header[12..16], &[0x21, 0x11, 0, 12]);
+	// the source is our client's end of its connection to the server
+	assert_eq!(&header[16..20], &[127, 0, 0, 1]);
+	assert_eq!(&header[26..], &80u16.to_be_bytes());
+	assert_eq!(data, b"hello");
+}
+
+#[tokio::test]
+async fn test_health_addr() {
+	let psk = mint::gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let health = format!("127.0.0.1:{}", free_port());
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&server)
+		.stats_interval(Duration::ZERO)
+		.health_addr(&health)
+		.build()
+		.unwrap();
+
+	let test = async {
+		// up once the data port is
+		loop {
+			if TcpStream::connect(&server).await.is_ok() {
+				break;
+			}
+			sleep(Duration::from_millis(10)).await;
+		}
+		let mut s = TcpStream::connect(&health).await.unwrap();
+		s.write_all(b"GET /healthz HTTP/1.1\r\nHost: localhost\r\n\r\n")
+			.await
+			.unwrap();
+		let mut resp = String::new();
+		s.read_to_string(&mut resp).await.unwrap();
+		assert!(resp.starts_with("HTTP/1.1 200 OK\r\n"), "{}", resp);
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		_ = test => {}
+	}
+}
+
+#[tokio::test]
+async fn test_udp() {
+	let psk = mint::gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&server)
+		.stats_interval(Duration::ZERO)
+		.build()
+		.unwrap();
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server(&server)
+		.udp(true)
+		.build()
+		.unwrap();
+	let echo = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+	let echo_addr = echo.local_addr().unwrap();
+	let echo = async move {
+		let mut buf = [0u8; 0x100];
+		loop {
+			let (n, from) = echo.recv_from(&mut buf).await.unwrap();
+			echo.send_to(&buf[..n], from).await.unwrap();
+		}
+	};
+
+	let test = async {
+		let (mut control, rep) = loop {
+			if let Ok(mut s) = TcpStream::connect(&client).await {
+				s.write_all(&[5, 1, 0]).await.unwrap();
+				let mut buf = [0u8; 2];
+				s.read_exact(&mut buf).await.unwrap();
+				// UDP ASSOCIATE, from wherever
+				s.write_all(&[5, 3, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+				let mut rep = [0u8; 10];
+				s.read_exact(&mut rep).await.unwrap();
+				break (s, rep);
+			}
+			sleep(Duration::from_millis(20)).await;
+		};
+		assert_eq!(rep[..4], [5, 0, 0, 1]);
+		let bound = (
+			IpAddr::from([rep[4], rep[5], rep[6], rep[7]]),
+			u16::from_be_bytes([rep[8], rep[9]]),
+		);
+
+		let app = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+		let mut header = vec![0, 0, 0, 1];
+		header.extend_from_slice(&[127, 0, 0, 1]);
+		header.extend_from_slice(&echo_addr.port().to_be_bytes());
+		for msg in [&b"hello"[..], b"world"] {
+			app.send_to(&[&header[..], msg].concat(), bound)
+				.await
+				.unwrap();
+			let mut buf = [0u8; 0x100];
+			let n = tokio::time::timeout(Duration::from_secs(5), app.recv(&mut buf))
+				.await
+				.unwrap()
+				.unwrap();
+			// from the echo server, as the app addressed it
+			assert_eq!(&buf[..n], [&header[..], msg].concat());
+		}
+		control.shutdown().await.unwrap();
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = echo => {}
+		_ = test => {}
+	}
+}
+
+#[tokio::test]
+async fn test_resolver() {
+	let psk = mint::gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	let echo = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let echo_port = echo.local_addr().unwrap().port();
+	tokio::spawn(async move {
+		while let Ok((mut s, _)) = echo.accept().await {
+			tokio::spawn(async move {
+				let (mut r, mut w) = s.split();
+				let _ = copy(&mut r, &mut w).await;
+			});
+		}
+	});
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&server)
+		.stats_interval(Duration::ZERO)
+		.resolver(Fixed)
+		.build()
+		.unwrap();
+	let c = || {
+		mint::ClientConfig::new(&psk)
+			.listen(&client)
+			.server(&server)
+	};
+	let lookup = c().build().unwrap();
+	let c = c().build().unwrap();
+
+	let test = async {
+		let (mut s, rep) = socks_request(&client, "echo.test", echo_port).await;
+		assert_eq!(rep, 0);
+		s.write_all(b"hello").await.unwrap();
+		let mut buf = [0u8; 5];
+		s.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"hello");
+
+		let addrs = mint::resolve(&lookup, "echo.test").await.unwrap();
+		assert_eq!(addrs, [IpAddr::from([127, 0, 0, 1])]);
+		assert!(mint::resolve(&lookup, "other.test").await.is_err());
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = test => {}
+	}
+}
+
+#[tokio::test]
+async fn test_server_ttl() {
+	const TTL: Duration = Duration::from_millis(300);
+	let psk = mint::gen_psk();
+	let port = free_port();
+	let client = format!("127.0.0.1:{}", free_port());
+	let lookups = Rc::new(RefCell::new(0));
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&format!("127.0.0.1:{}", port))
+		.stats_interval(Duration::ZERO)
+		.connector(Echo(Rc::default()))
+		.build()
+		.unwrap();
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server(&format!("echo.test:{}", port))
+		.server_ttl(TTL)
+		.resolver(Counting(lookups.clone()))
+		.build()
+		.unwrap();
+
+	let test = async {
+		let hello = async || {
+			let (mut s, rep) = socks_request(&client, "upstream.invalid", 80).await;
+			assert_eq!(rep, 0);
+			s.write_all(b"hello").await.unwrap();
+			let mut buf = [0u8; 5];
+			s.read_exact(&mut buf).await.unwrap();
+			assert_eq!(&buf, b"hello");
+		};
+		for _ in 0..3 {
+			hello().await;
+		}
+		// once at start, then served from the cache
+		assert_eq!(*lookups.borrow(), 1);
+		sleep(TTL).await;
+		hello().await;
+		hello().await;
+		assert_eq!(*lookups.borrow(), 2);
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = test => {}
+	}
+}
+
+#[tokio::test]
+async fn test_spans() {
+	use opentelemetry::trace::TracerProvider as _;
+	use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider};
+	use tracing_subscriber::layer::SubscriberExt;
+
+	let exporter = InMemorySpanExporter::default();
+	let provider = SdkTracerProvider::builder()
+		.with_simple_exporter(exporter.clone())
+		.build();
+	let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("test"));
+	let _sub = tracing::subscriber::set_default(tracing_subscriber::registry().with(layer));
+
+	let psk = mint::gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&server)
+		.stats_interval(Duration::ZERO)
+		.connector(Echo(Default::default()))
+		.build()
+		.unwrap();
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server(&server)
+		.build()
+		.unwrap();
+
+	let attr = |span: &opentelemetry_sdk::trace::SpanData, key: &str| {
+		span.attributes
+			.iter()
+			.find(|kv| kv.key.as_str() == key)
+			.map(|kv| kv.value.clone())
+	};
+	let test = async {
+		let (mut s, rep) = socks_request(&client, "upstream.invalid", 80).await;
+		assert_eq!(rep, 0);
+		s.write_all(b"hello").await.unwrap();
+		let mut buf = [0u8; 5];
+		s.read_exact(&mut buf).await.unwrap();
+		drop(s);
+		// ends once the relay winds down
+		loop {
+			let spans = exporter.get_finished_spans().unwrap();
+			if spans.iter().any(|s| s.name == "conn") {
+				return spans;
+			}
+			sleep(Duration::from_millis(20)).await;
+		}
+	};
+	let spans = tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		spans = test => spans,
+	};
+
+	let span = |name| spans.iter().find(|s| s.name == name).unwrap();
+	let (conn, req) = (span("conn"), span("request"));
+	let src = attr(conn, "src").unwrap().to_string();
+	assert!(src.starts_with("127.0.0.1:"), "{}", src);
+	assert_eq!(req.parent_span_id, conn.span_context.span_id());
+	let attrs = ["dst", "up", "down"].map(|k| attr(req, k).map(|v| v.to_string()));
+	assert_eq!(
+		attrs.each_ref().map(Option::as_deref),
+		[Some("upstream.invalid:80"), Some("5"), Some("5")]
+	);
+	for name in ["handshake", "connect", "duplex"] {
+		assert!(spans.iter().any(|s| s.name == name), "no {} span", name);
+	}
+}
+
+#[tokio::test]
+async fn test_close_delimited() {
+	let psk = mint::gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	let body: Vec<u8> = (0..0x40000).map(|i| (i % 251) as u8).collect();
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&server)
+		.stats_interval(Duration::ZERO)
+		.connector(Closing(Rc::new(body.clone())))
+		.build()
+		.unwrap();
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server(&server)
+		.build()
+		.unwrap();
+
+	let test = async {
+		let (mut s, rep) = socks_request(&client, "example.com", 80).await;
+		assert_eq!(rep, 0);
+		s.write_all(b"GET / HTTP/1.0\r\n\r\n").await.unwrap();
+		// the close is the only end marker, nothing may be cut off before it
+		let mut got = Vec::new();
+		s.read_to_end(&mut got).await.unwrap();
+		assert_eq!(got.len(), body.len());
+		assert!(got == body);
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = test => {}
+	}
+}
+
+#[tokio::test]
+async fn test_via_http_proxy() {
+	let psk = mint::gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	// a stub proxy that only knows the way to mint.invalid, the client can't look it up
+	let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let proxy_addr = proxy.local_addr().unwrap().to_string();
+	let relay = {
+		let server = server.clone();
+		async move {
+			while let Ok((mut s, _)) = proxy.accept().await {
+				let mut req = Vec::new();
+				while !req.ends_with(b"\r\n\r\n") {
+					req.push(s.read_u8().await.unwrap());
+				}
+				assert_eq!(
+					String::from_utf8(req).unwrap(),
+					"CONNECT mint.invalid:8080 HTTP/1.1\r\n\
+					 Host: mint.invalid:8080\r\n\
+					 Proxy-Authorization: Basic dXNlcjpwYXNz\r\n\r\n"
+				);
+				s.write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+					.await
+					.unwrap();
+				let mut u = TcpStream::connect(&server).await.unwrap();
+				tokio::spawn(async move {
+					let _ = copy_bidirectional(&mut s, &mut u).await;
+				});
+			}
+		}
+	};
+	let seen = Rc::new(RefCell::new(Vec::new()));
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&server)
+		.stats_interval(Duration::ZERO)
+		.connector(Echo(seen.clone()))
+		.build()
+		.unwrap();
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server("mint.invalid:8080")
+		.via_http_proxy(&format!("http://user:pass@{}", proxy_addr))
+		.build()
+		.unwrap();
+
+	let test = async {
+		let (mut s, rep) = socks_request(&client, "upstream.invalid", 80).await;
+		assert_eq!(rep, 0);
+		s.write_all(b"hello").await.unwrap();
+		let mut buf = [0u8; 5];
+		s.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"hello");
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = relay => panic!("relay quit"),
+		_ = test => {}
+	}
+	assert_eq!(*seen.borrow(), [("upstream.invalid".to_owned(), 80)]);
+}
+
+#[tokio::test]
+async fn test_via_socks5() {
+	let psk = mint::gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	// a stub SOCKS5 proxy that only knows the way to mint.invalid
+	let proxy = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let proxy_addr = proxy.local_addr().unwrap().to_string();
+	let relay = {
+		let server = server.clone();
+		async move {
+			while let Ok((mut s, _)) = proxy.accept().await {
+				let mut buf = [0u8; 3];
+				s.read_exact(&mut buf).await.unwrap();
+				assert_eq!(buf, [5, 1, 0]);
+				s.write_all(&[5, 0]).await.unwrap();
+				let mut req = [0u8; 5 + 12 + 2];
+				s.read_exact(&mut req).await.unwrap();
+				assert_eq!(req, *b"\x05\x01\x00\x03\x0cmint.invalid\x1f\x90");
+				s.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0]).await.unwrap();
+				let mut u = TcpStream::connect(&server).await.unwrap();
+				tokio::spawn(async move {
+					let _ = copy_bidirectional(&mut s, &mut u).await;
+				});
+			}
+		}
+	};
+	let seen = Rc::new(RefCell::new(Vec::new()));
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&server)
+		.stats_interval(Duration::ZERO)
+		.connector(Echo(seen.clone()))
+		.build()
+		.unwrap();
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server("mint.invalid:8080")
+		.via_socks5(&proxy_addr)
+		.build()
+		.unwrap();
+
+	let test = async {
+		let (mut s, rep) = socks_request(&client, "upstream.invalid", 80).await;
+		assert_eq!(rep, 0);
+		s.write_all(b"hello").await.unwrap();
+		let mut buf = [0u8; 5];
+		s.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"hello");
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = relay => panic!("relay quit"),
+		_ = test => {}
+	}
+	assert_eq!(*seen.borrow(), [("upstream.invalid".to_owned(), 80)]);
+}
+
+#[tokio::test]
+async fn test_early_reply() {
+	const DELAY: Duration = Duration::from_millis(300);
+	let psk = mint::gen_psk();
+	let server = format!("127.0.0.1:{}", free_port());
+	let client = format!("127.0.0.1:{}", free_port());
+	// in front of the server, holds back everything from it for a while
+	let front = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+	let front_addr = front.local_addr().unwrap().to_string();
+	let relay = {
+		let server = server.clone();
+		async move {
+			while let Ok((s, _)) = front.accept().await {
+				let u = TcpStream::connect(&server).await.unwrap();
+				let (mut sr, mut sw) = s.into_split();
+				let (mut ur, mut uw) = u.into_split();
+				tokio::spawn(async move {
+					let _ = copy(&mut sr, &mut uw).await;
+					let _ = uw.shutdown().await;
+				});
+				tokio::spawn(async move {
+					sleep(DELAY).await;
+					let _ = copy(&mut ur, &mut sw).await;
+				});
+			}
+		}
+	};
+	let seen = Rc::new(RefCell::new(Vec::new()));
+	let s = mint::ServerConfig::new(&psk)
+		.listen(&server)
+		.stats_interval(Duration::ZERO)
+		.connector(Echo(seen.clone()))
+		.on_connect(|_, _, port| async move { port != 25 })
+		.build()
+		.unwrap();
+	let c = mint::ClientConfig::new(&psk)
+		.listen(&client)
+		.server(&front_addr)
+		.early_reply(true)
+		.build()
+		.unwrap();
+
+	let test = async {
+		let start = tokio::time::Instant::now();
+		let (mut s, rep) = socks_request(&client, "upstream.invalid", 80).await;
+		assert_eq!(rep, 0);
+		// not gated on the server's answer
+		assert!(start.elapsed() < DELAY);
+		s.write_all(b"hello").await.unwrap();
+		let mut buf = [0u8; 5];
+		s.read_exact(&mut buf).await.unwrap();
+		assert_eq!(&buf, b"hello");
+
+		// already told it succeeded, the refusal can only be a reset
+		let (mut s, rep) = socks_request(&client, "example.com", 25).await;
+		assert_eq!(rep, 0);
+		assert!(!matches!(s.read(&mut buf).await, Ok(1..)));
+	};
+	tokio::select! {
+		r = mint::run_server(s) => panic!("server quit: {:?}", r),
+		r = mint::run_client(c) => panic!("client quit: {:?}", r),
+		_ = relay => panic!("relay quit"),
+		_ = test => {}
+	}
+	assert_eq!(*seen.borrow(), [("upstream.invalid".to_owned(), 80)]);
+}
+
+// only knows echo.test
+struct Fixed;
+
+impl mint::Resolver for Fixed {
+	fn lookup<'a>(&'a self, host: &'a str) -> mint::Lookup<'a> {
+		let addrs = (host == "echo.test").then(|| vec![IpAddr::from([127, 0, 0, 1])]);
+		Box::pin(async move { addrs })
+	}
+}
+
+// Fixed, counting the lookups
+struct Counting(Rc<RefCell<u32>>);
+
+impl mint::Resolver for Counting {
+	fn lookup<'a>(&'a self, host: &'a str) -> mint::Lookup<'a> {
+		*self.0.borrow_mut() += 1;
+		Fixed.lookup(host)
+	}
+}
+
+// SRV records for _mint._tcp.test, nothing else
+struct Srv(Vec<mint::Srv>);
+
+impl mint::Resolver for Srv {
+	fn lookup<'a>(&'a self, _: &'a str) -> mint::Lookup<'a> {
+		Box::pin(async { None })
+	}
+
+	fn lookup_srv<'a>(&'a self, name: &'a str) -> mint::SrvLookup<'a> {
+		let records = (name == "_mint._tcp.test").then(|| self.0.clone());
+		Box::pin(async move { records })
+	}
+}
+
+// every destination is an in-memory echo
+struct Echo(Rc<RefCell<Vec<(String, u16)>>>);
+
+impl mint::Connector for Echo {
+	fn connect<'a>(&'a self, host: &'a str, port: u16) -> mint::Connecting<'a> {
+		self.0.borrow_mut().push((host.to_owned(), port));
+		let (near, far) = duplex(0x1000);
+		tokio::spawn(async move {
+			let (mut r, mut w) = split(far);
+			let _ = copy(&mut r, &mut w).await;
+		});
+		Box::pin(async move { Ok(Box::new(near) as Box<dyn mint::Io>) })
+	}
+}
+
+// every destination swallows what it's sent into the Vec
+struct Record(Rc<RefCell<Vec<u8>>>);
+
+impl mint::Connector for Record {
+	fn connect<'a>(&'a self, _: &'a str, _: u16) -> mint::Connecting<'a> {
+		let (near, mut far) = duplex(0x1000);
+		let got = self.0.clone();
+		tokio::task::spawn_local(async move {
+			let mut buf = [0u8; 0x100];
+			while let Ok(n @ 1..) = far.read(&mut buf).await {
+				got.borrow_mut().extend_from_slice(&buf[..n]);
+			}
+		});
+		Box::pin(async move { Ok(Box::new(near) as Box<dyn mint::Io>) })
+	}
+}
+
+// every destination answers with the body and closes, like HTTP/1.0
+struct Closing(Rc<Vec<u8>>);
+
+impl mint::Connector for Closing {
+	fn connect<'a>(&'a self, _: &'a str, _: u16) -> mint::Connecting<'a> {
+		let (near, mut far) = duplex(0x1000);
+		let body = self.0.clone();
+		tokio::task::spawn_local(async move {
+			let mut buf = [0u8; 0x100];
+			let _ = far.read(&mut buf).await;
+			let _ = far.write_all(&body).await;
+		});
+		Box::pin(async move { Ok(Box::new(near) as Box<dyn mint::Io>) })
+	}
+}
+
+// returns the SOCKS5 reply code
+async fn socks_request(client: &str, host: &str, port: u16) -> (TcpStream, u8) {
+	let mut s = loop {
+		match TcpStream::connect(client).await {
+			Ok(s) => break s,
+			Err(_) => sleep(Duration::from_millis(20)).await,
+		}
+	};
+	let mut buf = [0u8; 10];
+	s.write_all(&[5, 1, 0]).await.unwrap();
+	s.read_exact(&mut buf[..2]).await.unwrap();
+	assert_eq!(&buf[..2], &[5, 0]);
+
+	s.write_all(&[5, 1, 0, 3, host.len() as u8]).await.unwrap();
+	s.write_all(host.as_bytes()).await.unwrap();
+	s.write_all(&port.to_be_bytes()).await.unwrap();
+	s.read_exact(&mut buf).await.unwrap();
+	(s, buf[1])
+}
+
+fn free_port() -> u16 {
+	TcpListener::bind("127.0.0.1:0")
+		.unwrap()
+		.local_addr()
+		.unwrap()
+		.port()
+}